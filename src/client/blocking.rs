@@ -0,0 +1,483 @@
+//! A blocking client, for when a thread can wait for its responses.
+//!
+//! The requests are still made by an asynchronous `Client`, on its own
+//! event loop thread, so that connections are kept alive and reused the
+//! same way. The calling thread waits for the response head, and then reads
+//! the body as it arrives.
+//!
+//! ```no_run
+//! use std::io::Read;
+//! use hyper::client::blocking;
+//!
+//! let mut res = blocking::get("http://example.domain/").unwrap();
+//! let mut body = String::new();
+//! res.read_to_string(&mut body).unwrap();
+//! ```
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Duration;
+
+use url::{self, Url};
+
+use header::{ContentLength, Header, Headers};
+use http::{Control, Decoder, Encoder, Next, RawStatus};
+use method::Method;
+use net::Transport;
+use status::StatusCode;
+use version::HttpVersion;
+use super::{Config, Connect, Request};
+
+/// How much of a body is read ahead of the reader, before the event loop
+/// waits for the reader to catch up.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+/// A value that can be turned into a `Url`.
+pub trait IntoUrl {
+    /// Consume the value, and parse it as a `Url`.
+    fn into_url(self) -> Result<Url, url::ParseError>;
+}
+
+impl IntoUrl for Url {
+    fn into_url(self) -> Result<Url, url::ParseError> {
+        Ok(self)
+    }
+}
+
+impl<'a> IntoUrl for &'a str {
+    fn into_url(self) -> Result<Url, url::ParseError> {
+        Url::parse(self)
+    }
+}
+
+impl<'a> IntoUrl for &'a String {
+    fn into_url(self) -> Result<Url, url::ParseError> {
+        Url::parse(self)
+    }
+}
+
+impl IntoUrl for String {
+    fn into_url(self) -> Result<Url, url::ParseError> {
+        Url::parse(&self)
+    }
+}
+
+/// Make a `GET` request with a new `Client`.
+///
+/// The `Client` is closed once the `Response` is dropped, so use a `Client`
+/// to reuse connections between requests.
+pub fn get<U: IntoUrl>(url: U) -> ::Result<Response> {
+    request(Method::Get, url).send()
+}
+
+/// Build a request with a new `Client`.
+///
+/// The `Client` is closed once the `Response` is dropped.
+pub fn request<U: IntoUrl>(method: Method, url: U) -> RequestBuilder {
+    match Client::new() {
+        Ok(client) => client.request(method, url),
+        Err(e) => RequestBuilder {
+            client: None,
+            timeout: None,
+            method: method,
+            url: Err(e),
+            headers: Headers::new(),
+            body: Vec::new(),
+        },
+    }
+}
+
+/// A blocking client, that waits for its responses.
+///
+/// Every clone of a `Client` uses the same connections. The event loop is
+/// closed once the last of them, and of their `Response`s, is dropped.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+    timeout: Option<Duration>,
+}
+
+impl Client {
+    /// Create a new client with the default config.
+    pub fn new() -> ::Result<Client> {
+        Client::configured(super::Client::configure())
+    }
+
+    /// Create a new client with a `Config`, such as to change the
+    /// `Connect`, or the limits on connections.
+    pub fn configured<C>(config: Config<C>) -> ::Result<Client>
+    where C: Connect + Send + 'static {
+        let client = try!(config.build());
+        Ok(Client {
+            inner: Arc::new(Inner(Some(client))),
+            timeout: Some(Duration::from_secs(30)),
+        })
+    }
+
+    /// Set how long to wait to read or write a request or response, before
+    /// it fails with `Error::Timeout`.
+    ///
+    /// Connecting is limited by `Config::connect_timeout` instead.
+    ///
+    /// Default is 30 seconds.
+    #[inline]
+    pub fn timeout(mut self, val: Option<Duration>) -> Client {
+        self.timeout = val;
+        self
+    }
+
+    /// Build a `GET` request.
+    #[inline]
+    pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.request(Method::Get, url)
+    }
+
+    /// Build a request.
+    pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> RequestBuilder {
+        RequestBuilder {
+            client: Some(self.inner.clone()),
+            timeout: self.timeout,
+            method: method,
+            url: url.into_url().map_err(From::from),
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// The asynchronous `Client`, closed once it is no longer used.
+struct Inner(Option<super::Client<Call>>);
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(client) = self.0.take() {
+            client.close();
+        }
+    }
+}
+
+/// A request to send, and wait for the response of.
+pub struct RequestBuilder {
+    client: Option<Arc<Inner>>,
+    timeout: Option<Duration>,
+    method: Method,
+    url: ::Result<Url>,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl RequestBuilder {
+    /// Set a header of the request.
+    #[inline]
+    pub fn header<H: Header>(mut self, header: H) -> RequestBuilder {
+        self.headers.set(header);
+        self
+    }
+
+    /// Set several headers of the request.
+    #[inline]
+    pub fn headers(mut self, headers: Headers) -> RequestBuilder {
+        self.headers.extend(headers.iter());
+        self
+    }
+
+    /// Set the body of the request, which is sent with a `Content-Length`.
+    #[inline]
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> RequestBuilder {
+        self.body = body.into();
+        self
+    }
+
+    /// Send the request, and wait for the head of its response.
+    pub fn send(self) -> ::Result<Response> {
+        let url = try!(self.url);
+        let inner = self.client.expect("RequestBuilder.client is missing");
+        let (tx, rx) = mpsc::channel();
+        let flow = Arc::new(Mutex::new(Flow {
+            buffered: 0,
+            waiting: false,
+            ctrl: None,
+        }));
+        let call = Call {
+            method: self.method,
+            headers: self.headers,
+            body: self.body,
+            written: 0,
+            timeout: self.timeout,
+            tx: tx,
+            flow: flow.clone(),
+        };
+        let sent = match inner.0 {
+            Some(ref client) => client.request(url, call).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(closed().into());
+        }
+        match rx.recv() {
+            Ok(Event::Head(head)) => Ok(Response {
+                head: head,
+                chunk: io::Cursor::new(Vec::new()),
+                rx: rx,
+                flow: flow,
+                timeout: self.timeout,
+                eof: false,
+                _client: inner,
+            }),
+            Ok(Event::Error(e)) => Err(e),
+            Ok(_) => Err(::Error::Incomplete),
+            Err(_) => Err(closed().into()),
+        }
+    }
+}
+
+impl fmt::Debug for RequestBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestBuilder")
+            .field("method", &self.method)
+            .field("url", &self.url)
+            .field("headers", &self.headers)
+            .field("body", &self.body.len())
+            .finish()
+    }
+}
+
+/// A response, whose body is read as it arrives.
+pub struct Response {
+    head: super::Response,
+    chunk: io::Cursor<Vec<u8>>,
+    rx: mpsc::Receiver<Event>,
+    flow: Arc<Mutex<Flow>>,
+    timeout: Option<Duration>,
+    eof: bool,
+    _client: Arc<Inner>,
+}
+
+impl Response {
+    /// Get the status from the server.
+    #[inline]
+    pub fn status(&self) -> &StatusCode {
+        self.head.status()
+    }
+
+    /// Get the raw status code and reason.
+    #[inline]
+    pub fn status_raw(&self) -> &RawStatus {
+        self.head.status_raw()
+    }
+
+    /// Get the headers from the server.
+    #[inline]
+    pub fn headers(&self) -> &Headers {
+        self.head.headers()
+    }
+
+    /// Get the HTTP version of this response from the server.
+    #[inline]
+    pub fn version(&self) -> &HttpVersion {
+        self.head.version()
+    }
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.position() as usize >= self.chunk.get_ref().len() {
+            if self.eof {
+                return Ok(0);
+            }
+            match self.rx.recv() {
+                Ok(Event::Chunk(chunk)) => {
+                    let mut flow = lock(&self.flow);
+                    flow.buffered -= chunk.len();
+                    if flow.waiting && flow.buffered < MAX_BUFFERED / 2 {
+                        // the event loop waits for the body to be read
+                        flow.waiting = false;
+                        if let Some(ref ctrl) = flow.ctrl {
+                            let _ = ctrl.ready(read(self.timeout));
+                        }
+                    }
+                    self.chunk = io::Cursor::new(chunk);
+                }
+                Ok(Event::End) => self.eof = true,
+                Ok(Event::Error(::Error::Io(e))) => return Err(e),
+                Ok(Event::Error(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                Ok(Event::Head(..)) |
+                Err(_) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "response body is incomplete")),
+            }
+        }
+        self.chunk.read(buf)
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        // wake up the event loop, so that it finds the body is no longer read
+        let mut flow = lock(&self.flow);
+        if flow.waiting {
+            flow.waiting = false;
+            if let Some(ref ctrl) = flow.ctrl {
+                let _ = ctrl.ready(Next::read());
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("head", &self.head)
+            .field("eof", &self.eof)
+            .finish()
+    }
+}
+
+enum Event {
+    Head(super::Response),
+    Chunk(Vec<u8>),
+    End,
+    Error(::Error),
+}
+
+/// How much of the body was read ahead, shared by a `Call` and its
+/// `Response`.
+struct Flow {
+    buffered: usize,
+    /// Whether the `Call` waits for the `Response` to read.
+    waiting: bool,
+    ctrl: Option<Control>,
+}
+
+fn lock(flow: &Mutex<Flow>) -> ::std::sync::MutexGuard<Flow> {
+    match flow.lock() {
+        Ok(flow) => flow,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn read(timeout: Option<Duration>) -> Next {
+    match timeout {
+        Some(timeout) => Next::read().timeout(timeout),
+        None => Next::read(),
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "client loop closed")
+}
+
+/// The `Handler` of a blocking request, that passes the response on to the
+/// thread waiting for it.
+struct Call {
+    method: Method,
+    headers: Headers,
+    body: Vec<u8>,
+    written: usize,
+    timeout: Option<Duration>,
+    tx: mpsc::Sender<Event>,
+    flow: Arc<Mutex<Flow>>,
+}
+
+impl Call {
+    fn write(&self) -> Next {
+        match self.timeout {
+            Some(timeout) => Next::write().timeout(timeout),
+            None => Next::write(),
+        }
+    }
+}
+
+impl fmt::Debug for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Call")
+            .field("method", &self.method)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+impl<T: Transport> super::Handler<T> for Call {
+    fn on_request(&mut self, req: &mut Request) -> Next {
+        // a redirect or retry sends the request again
+        self.written = 0;
+        req.set_method(self.method.clone());
+        req.headers_mut().extend(self.headers.iter());
+        if self.body.is_empty() {
+            read(self.timeout)
+        } else {
+            req.headers_mut().set(ContentLength(self.body.len() as u64));
+            self.write()
+        }
+    }
+
+    fn on_request_writable(&mut self, encoder: &mut Encoder<T>) -> Next {
+        while self.written < self.body.len() {
+            match encoder.write(&self.body[self.written..]) {
+                Ok(n) => self.written += n,
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => return self.write(),
+                    _ => {
+                        let _ = self.tx.send(Event::Error(e.into()));
+                        return Next::remove();
+                    }
+                },
+            }
+        }
+        read(self.timeout)
+    }
+
+    fn on_response(&mut self, res: super::Response) -> Next {
+        match self.tx.send(Event::Head(res)) {
+            Ok(()) => read(self.timeout),
+            Err(_) => Next::remove(),
+        }
+    }
+
+    fn on_response_readable(&mut self, decoder: &mut Decoder<T>) -> Next {
+        let mut buf = vec![0; 8192];
+        match decoder.read(&mut buf) {
+            Ok(0) => {
+                let _ = self.tx.send(Event::End);
+                Next::end()
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                let mut flow = lock(&self.flow);
+                flow.buffered += n;
+                if self.tx.send(Event::Chunk(buf)).is_err() {
+                    // the response was dropped before its body was read
+                    return Next::remove();
+                }
+                if flow.buffered >= MAX_BUFFERED {
+                    flow.waiting = true;
+                    Next::wait()
+                } else {
+                    read(self.timeout)
+                }
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock => read(self.timeout),
+                _ => {
+                    let _ = self.tx.send(Event::Error(e.into()));
+                    Next::remove()
+                }
+            },
+        }
+    }
+
+    fn on_error(&mut self, err: ::Error) -> Next {
+        let _ = self.tx.send(Event::Error(err));
+        Next::remove()
+    }
+
+    fn on_control(&mut self, ctrl: Control) {
+        lock(&self.flow).ctrl = Some(ctrl);
+    }
+}
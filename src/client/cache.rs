@@ -0,0 +1,974 @@
+//! A private HTTP cache for the client, following RFC 7234.
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use httparse;
+use rotor;
+use time;
+use url::Url;
+
+use header::{CacheControl, CacheDirective, ContentLength, Date, Expires, Headers, HttpDate};
+use header::{LastModified, Pragma, Vary};
+use http::{self, Control, Decoder, Next, RawStatus, RequestHead, ResponseHead};
+use method::Method;
+use net::Transport;
+use version::HttpVersion;
+use super::Handler;
+use super::response;
+
+/// The largest body that is stored. The body of a larger response is only
+/// passed on to the handler.
+const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+const MAX_HEADERS: usize = 100;
+
+/// Headers that only concern one connection, and are not stored.
+static HOP_BY_HOP: &'static [&'static str] = &[
+    "Connection", "Keep-Alive", "Proxy-Connection", "TE", "Trailer",
+    "Transfer-Encoding", "Upgrade",
+];
+
+/// A place to keep the responses a `Client` caches.
+///
+/// `MemoryCache` keeps them in memory, and `DiskCache` in files. Implement
+/// this to keep them somewhere else. A `Mutex` of a store in an `Arc` is a
+/// store too, so that it can be shared by several `Client`s.
+pub trait CacheStore: Send {
+    /// Get the response stored for a URL.
+    fn get(&mut self, url: &str) -> Option<CachedResponse>;
+
+    /// Store the response for a URL, replacing any stored before.
+    fn put(&mut self, url: &str, response: CachedResponse);
+
+    /// Remove the response stored for a URL.
+    fn remove(&mut self, url: &str);
+}
+
+impl<S: CacheStore + ?Sized> CacheStore for Box<S> {
+    fn get(&mut self, url: &str) -> Option<CachedResponse> {
+        (**self).get(url)
+    }
+
+    fn put(&mut self, url: &str, response: CachedResponse) {
+        (**self).put(url, response)
+    }
+
+    fn remove(&mut self, url: &str) {
+        (**self).remove(url)
+    }
+}
+
+impl<S: CacheStore + ?Sized> CacheStore for Arc<Mutex<S>> {
+    fn get(&mut self, url: &str) -> Option<CachedResponse> {
+        match self.lock() {
+            Ok(mut store) => store.get(url),
+            Err(poisoned) => poisoned.into_inner().get(url),
+        }
+    }
+
+    fn put(&mut self, url: &str, response: CachedResponse) {
+        match self.lock() {
+            Ok(mut store) => store.put(url, response),
+            Err(poisoned) => poisoned.into_inner().put(url, response),
+        }
+    }
+
+    fn remove(&mut self, url: &str) {
+        match self.lock() {
+            Ok(mut store) => store.remove(url),
+            Err(poisoned) => poisoned.into_inner().remove(url),
+        }
+    }
+}
+
+/// A response kept by a `CacheStore`, along with what is needed to tell
+/// whether it is still fresh.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    version: HttpVersion,
+    status: RawStatus,
+    headers: Headers,
+    body: Vec<u8>,
+    /// The headers of the request, that the response named in `Vary`.
+    vary: Headers,
+    /// When the request was sent, in seconds since the epoch.
+    request_time: i64,
+    /// When the response was received, in seconds since the epoch.
+    response_time: i64,
+}
+
+impl CachedResponse {
+    fn new(head: &ResponseHead, request: &Headers, request_time: i64, response_time: i64) -> CachedResponse {
+        let mut headers = head.headers.clone();
+        for name in HOP_BY_HOP {
+            headers.remove_raw(name);
+        }
+        let mut vary = Headers::new();
+        if let Some(&Vary::Items(ref names)) = head.headers.get::<Vary>() {
+            for name in names {
+                if let Some(raw) = request.get_raw(&name[..]) {
+                    vary.set_raw(name.to_string(), raw.clone());
+                }
+            }
+        }
+        CachedResponse {
+            version: head.version,
+            status: head.subject.clone(),
+            headers: headers,
+            body: Vec::new(),
+            vary: vary,
+            request_time: request_time,
+            response_time: response_time,
+        }
+    }
+
+    /// The status code of the response.
+    pub fn status(&self) -> u16 {
+        self.status.0
+    }
+
+    /// The headers of the response.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// The body of the response, with any content coding the `Client`
+    /// decoded removed.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Write the response in a format that `read_from` reads back.
+    pub fn write_to<W: Write>(&self, dst: &mut W) -> io::Result<()> {
+        try!(write!(dst, "hyper-cache {} {} {}\r\n", self.version, self.request_time, self.response_time));
+        // the request headers are written as a request, and the response
+        // as a response, so that both parse as HTTP/1.1
+        try!(dst.write_all(b"GET / HTTP/1.1\r\n"));
+        try!(write_headers(dst, &self.vary));
+        try!(write!(dst, "HTTP/1.1 {} {}\r\n", self.status.0, self.status.1));
+        try!(write_headers(dst, &self.headers));
+        dst.write_all(&self.body)
+    }
+
+    /// Read a response that `write_to` wrote.
+    pub fn read_from<R: Read>(src: &mut R) -> io::Result<CachedResponse> {
+        let mut buf = Vec::new();
+        try!(src.read_to_end(&mut buf));
+        let end = try!(buf.windows(2).position(|w| w == b"\r\n").ok_or_else(invalid));
+        let (version, request_time, response_time) = {
+            let line = try!(str::from_utf8(&buf[..end]).map_err(|_| invalid()));
+            let mut parts = line.split(' ');
+            if parts.next() != Some("hyper-cache") {
+                return Err(invalid());
+            }
+            let version = match parts.next() {
+                Some("HTTP/1.0") => HttpVersion::Http10,
+                Some("HTTP/1.1") => HttpVersion::Http11,
+                Some("h2") => HttpVersion::H2,
+                Some("h2c") => HttpVersion::H2c,
+                _ => return Err(invalid()),
+            };
+            let request_time = try!(parts.next().and_then(|t| t.parse().ok()).ok_or_else(invalid));
+            let response_time = try!(parts.next().and_then(|t| t.parse().ok()).ok_or_else(invalid));
+            (version, request_time, response_time)
+        };
+        let mut pos = end + 2;
+
+        let vary = {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(&buf[pos..]) {
+                Ok(httparse::Status::Complete(len)) => {
+                    pos += len;
+                    try!(Headers::from_raw(req.headers).map_err(|_| invalid()))
+                }
+                _ => return Err(invalid()),
+            }
+        };
+        let (status, headers) = {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut res = httparse::Response::new(&mut headers);
+            match res.parse(&buf[pos..]) {
+                Ok(httparse::Status::Complete(len)) => {
+                    pos += len;
+                    let code = try!(res.code.ok_or_else(invalid));
+                    let reason = res.reason.unwrap_or("").to_owned();
+                    let headers = try!(Headers::from_raw(res.headers).map_err(|_| invalid()));
+                    (RawStatus(code, reason.into()), headers)
+                }
+                _ => return Err(invalid()),
+            }
+        };
+
+        Ok(CachedResponse {
+            version: version,
+            status: status,
+            headers: headers,
+            body: buf[pos..].to_vec(),
+            vary: vary,
+            request_time: request_time,
+            response_time: response_time,
+        })
+    }
+
+    fn head(&self) -> ResponseHead {
+        http::MessageHead {
+            version: self.version,
+            subject: self.status.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+
+    /// Whether the request asks for this response, rather than another
+    /// variant of it.
+    fn matches(&self, request: &Headers) -> bool {
+        match self.headers.get::<Vary>() {
+            Some(&Vary::Items(ref names)) => names.iter().all(|name| {
+                request.get_raw(&name[..]) == self.vary.get_raw(&name[..])
+            }),
+            Some(&Vary::Any) => false,
+            None => true,
+        }
+    }
+
+    fn date(&self) -> i64 {
+        match self.headers.get::<Date>() {
+            Some(&Date(HttpDate(ref tm))) => tm.to_timespec().sec,
+            None => self.response_time,
+        }
+    }
+
+    /// How long the response is fresh for after it was created, in seconds.
+    fn freshness_lifetime(&self) -> i64 {
+        let directives = directives(&self.headers);
+        if directives.contains(&CacheDirective::NoCache) {
+            return 0;
+        }
+        for directive in directives {
+            if let CacheDirective::MaxAge(secs) = *directive {
+                return secs as i64;
+            }
+        }
+        if self.headers.get_raw("Expires").is_some() {
+            // an invalid date means the response already expired
+            return match self.headers.get::<Expires>() {
+                Some(&Expires(HttpDate(ref tm))) => tm.to_timespec().sec - self.date(),
+                None => 0,
+            };
+        }
+        // a tenth of the time since it was last modified, as RFC 7234
+        // suggests for responses without explicit freshness
+        match self.headers.get::<LastModified>() {
+            Some(&LastModified(HttpDate(ref tm))) => cmp::max(0, self.date() - tm.to_timespec().sec) / 10,
+            None => 0,
+        }
+    }
+
+    /// How old the response is, in seconds, as of `now`.
+    fn age(&self, now: i64) -> i64 {
+        let apparent_age = cmp::max(0, self.response_time - self.date());
+        let age_value = self.headers.get_raw("Age")
+            .and_then(|raw| raw.one())
+            .and_then(|value| str::from_utf8(value).ok())
+            .and_then(|value| value.trim().parse::<i64>().ok())
+            .unwrap_or(0);
+        let response_delay = self.response_time - self.request_time;
+        let corrected_initial_age = cmp::max(apparent_age, age_value + response_delay);
+        corrected_initial_age + (now - self.response_time)
+    }
+
+    fn has_validator(&self) -> bool {
+        self.headers.get_raw("ETag").is_some() || self.headers.get_raw("Last-Modified").is_some()
+    }
+
+    /// Update the response with the headers of a `304 Not Modified` that
+    /// revalidated it.
+    fn revalidated(mut self, head: &ResponseHead, request_time: i64, response_time: i64) -> CachedResponse {
+        for header in head.headers.iter() {
+            let name = header.name();
+            if name.eq_ignore_ascii_case("Content-Length") ||
+                    HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop)) {
+                continue;
+            }
+            if let Some(raw) = head.headers.get_raw(name) {
+                self.headers.set_raw(name.to_owned(), raw.clone());
+            }
+        }
+        self.request_time = request_time;
+        self.response_time = response_time;
+        self
+    }
+}
+
+fn write_headers<W: Write>(dst: &mut W, headers: &Headers) -> io::Result<()> {
+    for header in headers.iter() {
+        if let Some(raw) = headers.get_raw(header.name()) {
+            for line in raw {
+                try!(write!(dst, "{}: ", header.name()));
+                try!(dst.write_all(line));
+                try!(dst.write_all(b"\r\n"));
+            }
+        }
+    }
+    dst.write_all(b"\r\n")
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid cached response")
+}
+
+fn now() -> i64 {
+    time::get_time().sec
+}
+
+fn directives(headers: &Headers) -> &[CacheDirective] {
+    match headers.get::<CacheControl>() {
+        Some(&CacheControl(ref directives)) => directives,
+        None => &[],
+    }
+}
+
+/// The key a response to `url` is stored under.
+fn key(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.into_string()
+}
+
+/// Whether a response to a `GET` request may be stored.
+///
+/// This is a private cache, so responses that are `private` are stored,
+/// and `s-maxage` does not apply.
+fn storable(request: &Headers, response: &ResponseHead) -> bool {
+    let response_directives = directives(&response.headers);
+    if directives(request).contains(&CacheDirective::NoStore) ||
+            response_directives.contains(&CacheDirective::NoStore) {
+        return false;
+    }
+    if let Some(&Vary::Any) = response.headers.get::<Vary>() {
+        return false;
+    }
+    let explicit = response.headers.get_raw("Expires").is_some() ||
+        response_directives.iter().any(|directive| match *directive {
+            CacheDirective::MaxAge(..) => true,
+            _ => false,
+        });
+    let status = match response.subject.0 {
+        // these may be fresh for a while, even without saying so
+        200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501 => true,
+        206 | 304 => false,
+        code => explicit && code >= 200,
+    };
+    // without a way to tell that it is fresh, or to revalidate it, a
+    // stored response would never be used
+    status && (explicit || response.headers.get_raw("Last-Modified").is_some() ||
+        response.headers.get_raw("ETag").is_some())
+}
+
+/// What a request asks of the cache, in its `Cache-Control`.
+struct Request {
+    no_store: bool,
+    no_cache: bool,
+    max_age: Option<i64>,
+    max_stale: Option<i64>,
+    min_fresh: Option<i64>,
+}
+
+impl Request {
+    fn new(headers: &Headers) -> Request {
+        let mut request = Request {
+            no_store: false,
+            no_cache: false,
+            max_age: None,
+            max_stale: None,
+            min_fresh: None,
+        };
+        match headers.get::<CacheControl>() {
+            Some(&CacheControl(ref directives)) => {
+                for directive in directives {
+                    match *directive {
+                        CacheDirective::NoStore => request.no_store = true,
+                        CacheDirective::NoCache => request.no_cache = true,
+                        CacheDirective::MaxAge(secs) => request.max_age = Some(secs as i64),
+                        CacheDirective::MaxStale(secs) => request.max_stale = Some(secs as i64),
+                        CacheDirective::MinFresh(secs) => request.min_fresh = Some(secs as i64),
+                        _ => (),
+                    }
+                }
+            }
+            // Pragma only counts without Cache-Control
+            None => request.no_cache = headers.get::<Pragma>() == Some(&Pragma::NoCache),
+        }
+        request
+    }
+
+    /// Whether a stored response with this age and freshness lifetime may
+    /// be used without revalidating it.
+    fn accepts(&self, cached: &CachedResponse, age: i64, lifetime: i64) -> bool {
+        if self.no_cache || self.max_age.map_or(false, |max_age| age > max_age) {
+            return false;
+        }
+        if age < lifetime {
+            self.min_fresh.map_or(true, |min_fresh| lifetime - age >= min_fresh)
+        } else {
+            !directives(&cached.headers).contains(&CacheDirective::MustRevalidate) &&
+                self.max_stale.map_or(false, |max_stale| age - lifetime <= max_stale)
+        }
+    }
+}
+
+/// What the cache has for a request.
+pub enum Lookup {
+    /// A response that can be used as it is, and its age.
+    Fresh(CachedResponse, i64),
+    /// A response that has to be revalidated first.
+    Stale(CachedResponse),
+    Miss,
+}
+
+/// A `CacheStore` shared by every message of a `Client`.
+#[derive(Clone)]
+pub struct Shared(Arc<Mutex<Box<CacheStore>>>);
+
+impl Shared {
+    pub fn new(store: Box<CacheStore>) -> Shared {
+        Shared(Arc::new(Mutex::new(store)))
+    }
+
+    /// Find what is stored for a request, once its handler prepared it.
+    pub fn lookup(&mut self, url: &Url, request: &RequestHead) -> Lookup {
+        if request.subject.0 != Method::Get {
+            return Lookup::Miss;
+        }
+        let directives = Request::new(&request.headers);
+        if directives.no_store {
+            return Lookup::Miss;
+        }
+        let cached = match self.0.get(&key(url)) {
+            Some(cached) => cached,
+            None => return Lookup::Miss,
+        };
+        if !cached.matches(&request.headers) {
+            return Lookup::Miss;
+        }
+        let age = cached.age(now());
+        let lifetime = cached.freshness_lifetime();
+        if directives.accepts(&cached, age, lifetime) {
+            Lookup::Fresh(cached, age)
+        } else if cached.has_validator() {
+            Lookup::Stale(cached)
+        } else {
+            Lookup::Miss
+        }
+    }
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("CacheStore")
+    }
+}
+
+/// The part of the cache in one message of a `Client`.
+///
+/// A response that is stored has its body read through the exchange, which
+/// keeps a copy as it passes the body on to the handler.
+pub struct Exchange {
+    store: Shared,
+    key: String,
+    method: Method,
+    request: Headers,
+    request_time: i64,
+    /// A stored response that the request revalidates.
+    stale: Option<CachedResponse>,
+    /// Whether the request was made conditional to revalidate `stale`.
+    conditional: bool,
+    /// The response to store, once all of its body was read.
+    storing: Option<CachedResponse>,
+    /// The body read, that the handler did not read yet.
+    body: io::Cursor<Vec<u8>>,
+    replaying: bool,
+    eof: bool,
+    next: Next,
+}
+
+impl Exchange {
+    pub fn new(store: Shared, url: &Url, stale: Option<CachedResponse>) -> Exchange {
+        Exchange {
+            store: store,
+            key: key(url),
+            method: Method::Get,
+            request: Headers::new(),
+            request_time: 0,
+            stale: stale,
+            conditional: false,
+            storing: None,
+            body: io::Cursor::new(Vec::new()),
+            replaying: false,
+            eof: false,
+            next: Next::read(),
+        }
+    }
+
+    /// Note the request as it is sent, making it conditional if it
+    /// revalidates a stored response, unless the handler already did.
+    pub fn on_request(&mut self, head: &mut RequestHead) {
+        self.method = head.subject.0.clone();
+        self.request_time = now();
+        if let Some(ref stale) = self.stale {
+            let conditional = head.headers.get_raw("If-None-Match").is_some() ||
+                head.headers.get_raw("If-Modified-Since").is_some();
+            if self.method == Method::Get && !conditional {
+                if let Some(etag) = stale.headers.get_raw("ETag") {
+                    head.headers.set_raw("If-None-Match", etag.clone());
+                }
+                if let Some(last_modified) = stale.headers.get_raw("Last-Modified") {
+                    head.headers.set_raw("If-Modified-Since", last_modified.clone());
+                }
+                self.conditional = true;
+            }
+        }
+        self.request = head.headers.clone();
+    }
+
+    /// Store or invalidate what the response calls for. A `304` that
+    /// revalidated the stored response is replaced with it.
+    pub fn on_response(&mut self, head: &mut ResponseHead) {
+        let status = head.subject.0;
+        if !self.method.safe() {
+            // a successful unsafe request may change the resource
+            if status >= 200 && status < 400 {
+                self.store.0.remove(&self.key);
+            }
+            return;
+        }
+        if self.method != Method::Get {
+            return;
+        }
+        let response_time = now();
+        if status == 304 && self.conditional {
+            if let Some(stale) = self.stale.take() {
+                trace!("revalidated {}", self.key);
+                let cached = stale.revalidated(head, self.request_time, response_time);
+                *head = cached.head();
+                self.store.0.put(&self.key, cached.clone());
+                self.body = io::Cursor::new(cached.body);
+                self.replaying = true;
+                return;
+            }
+        }
+        if storable(&self.request, head) {
+            self.storing = Some(CachedResponse::new(head, &self.request, self.request_time, response_time));
+            self.replaying = true;
+        } else if self.stale.is_some() && status != 304 {
+            self.store.0.remove(&self.key);
+        }
+    }
+
+    /// Whether the body is read through `replay`.
+    pub fn is_replaying(&self) -> bool {
+        self.replaying
+    }
+
+    /// Read the body from the transport, passing it to `readable` as it
+    /// is read, and store the response once it was all read.
+    pub fn replay<T, F>(&mut self, transport: &mut Decoder<T>, mut readable: F) -> io::Result<Next>
+    where T: Read, F: FnMut(&mut Decoder<T>) -> Next {
+        loop {
+            if self.remaining() == 0 && !self.eof {
+                let mut buf = [0; 4096];
+                let n = match transport.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::WouldBlock {
+                            return Ok(self.next.clone());
+                        }
+                        self.storing = None;
+                        return Err(e);
+                    }
+                };
+                self.body = io::Cursor::new(buf[..n].to_vec());
+                if n == 0 {
+                    self.eof = true;
+                    self.finish();
+                } else if let Some(mut storing) = self.storing.take() {
+                    if storing.body.len() + n <= MAX_BODY_SIZE {
+                        storing.body.extend_from_slice(&buf[..n]);
+                        self.storing = Some(storing);
+                    } else {
+                        debug!("response to {} is too large to store", self.key);
+                    }
+                }
+            }
+            let eof = self.eof && self.remaining() == 0;
+            let next = readable(&mut Decoder::cached(&mut self.body, Some(transport.get_ref())));
+            if !next.is_read() || eof {
+                return Ok(next);
+            }
+            self.next = next;
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.body.get_ref().len() - self.body.position() as usize
+    }
+
+    fn finish(&mut self) {
+        if let Some(mut cached) = self.storing.take() {
+            trace!("storing {}", self.key);
+            cached.headers.set(ContentLength(cached.body.len() as u64));
+            self.store.0.put(&self.key, cached);
+        }
+    }
+}
+
+/// A response from the cache, passed to a handler without a transport.
+pub struct Replay<H, T> {
+    handler: H,
+    body: io::Cursor<Vec<u8>>,
+    ctrl: http::channel::Receiver<Next>,
+    _marker: PhantomData<T>,
+}
+
+impl<H: Handler<T>, T: Transport> Replay<H, T> {
+    pub fn start(mut handler: H, cached: CachedResponse, age: i64, notifier: rotor::Notifier) -> Option<Replay<H, T>> {
+        let (tx, rx) = http::channel::new(notifier);
+        handler.on_control(Control::new(tx));
+        let mut head = cached.head();
+        head.headers.set_raw("Age", age.to_string());
+        let next = handler.on_response(response::new(head));
+        Replay {
+            handler: handler,
+            body: io::Cursor::new(cached.body),
+            ctrl: rx,
+            _marker: PhantomData,
+        }.advance(next)
+    }
+
+    pub fn wakeup(self) -> Option<Replay<H, T>> {
+        let mut next = None;
+        while let Ok(n) = self.ctrl.try_recv() {
+            next = Some(n);
+        }
+        match next {
+            Some(next) => self.advance(next),
+            None => Some(self),
+        }
+    }
+
+    fn advance(mut self, mut next: Next) -> Option<Replay<H, T>> {
+        loop {
+            if next.is_end() {
+                return None;
+            }
+            if !next.is_read() {
+                // wait to be woken up by the handler's `Control`
+                return Some(self);
+            }
+            let eof = self.body.position() as usize >= self.body.get_ref().len();
+            next = self.handler.on_response_readable(&mut Decoder::cached(&mut self.body, None));
+            if eof && next.is_read() {
+                // there is nothing more to read
+                return None;
+            }
+        }
+    }
+}
+
+/// Responses kept in memory, up to a number of them.
+///
+/// Once full, storing another response drops the one that was used least
+/// recently.
+#[derive(Debug, Clone)]
+pub struct MemoryCache {
+    entries: HashMap<String, (CachedResponse, u64)>,
+    capacity: usize,
+    tick: u64,
+}
+
+impl MemoryCache {
+    /// Create a cache that keeps up to `capacity` responses.
+    pub fn new(capacity: usize) -> MemoryCache {
+        MemoryCache {
+            entries: HashMap::new(),
+            capacity: capacity,
+            tick: 0,
+        }
+    }
+}
+
+impl CacheStore for MemoryCache {
+    fn get(&mut self, url: &str) -> Option<CachedResponse> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(url).map(|entry| {
+            entry.1 = tick;
+            entry.0.clone()
+        })
+    }
+
+    fn put(&mut self, url: &str, response: CachedResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(url) && self.entries.len() >= self.capacity {
+            let lru = self.entries.iter()
+                .min_by_key(|&(_, entry)| entry.1)
+                .map(|(url, _)| url.clone());
+            if let Some(lru) = lru {
+                self.entries.remove(&lru);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(url.to_owned(), (response, self.tick));
+    }
+
+    fn remove(&mut self, url: &str) {
+        self.entries.remove(url);
+    }
+}
+
+/// Responses kept as files in a directory, so that they outlive the
+/// `Client`.
+///
+/// Each response is a file named after a hash of its URL. It is written to
+/// a temporary file first, and then renamed, so that a reader never sees
+/// half of it.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    /// Create a cache in `dir`, which is created once a response is stored.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> DiskCache {
+        DiskCache {
+            dir: dir.into(),
+        }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}", fnv(url)))
+    }
+
+    fn read(&self, url: &str) -> io::Result<CachedResponse> {
+        let mut buf = Vec::new();
+        try!(try!(File::open(self.path(url))).read_to_end(&mut buf));
+        // the file starts with the URL, in case another one has its hash
+        let end = try!(buf.iter().position(|&b| b == b'\n').ok_or_else(invalid));
+        if &buf[..end] != url.as_bytes() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "another URL is stored"));
+        }
+        CachedResponse::read_from(&mut &buf[end + 1..])
+    }
+
+    fn write(&self, url: &str, response: &CachedResponse) -> io::Result<()> {
+        try!(fs::create_dir_all(&self.dir));
+        let path = self.path(url);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(url.as_bytes()));
+            try!(file.write_all(b"\n"));
+            try!(response.write_to(&mut file));
+        }
+        fs::rename(&tmp, &path)
+    }
+}
+
+impl CacheStore for DiskCache {
+    fn get(&mut self, url: &str) -> Option<CachedResponse> {
+        match self.read(url) {
+            Ok(response) => Some(response),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    debug!("error reading cached {}: {:?}", url, e);
+                }
+                None
+            }
+        }
+    }
+
+    fn put(&mut self, url: &str, response: CachedResponse) {
+        if let Err(e) = self.write(url, &response) {
+            debug!("error storing {}: {:?}", url, e);
+        }
+    }
+
+    fn remove(&mut self, url: &str) {
+        let _ = fs::remove_file(self.path(url));
+    }
+}
+
+/// The 64-bit FNV-1a hash of a URL, to name its file.
+fn fnv(url: &str) -> u64 {
+    let mut hash = 0xcbf29ce484222325;
+    for byte in url.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use header::{CacheControl, CacheDirective, Headers, Vary};
+    use http::{MessageHead, RawStatus, ResponseHead};
+    use super::{CacheStore, CachedResponse, DiskCache, MemoryCache, Request, storable};
+
+    fn response(status: u16, headers: &[(&'static str, &'static str)]) -> ResponseHead {
+        let mut head = MessageHead::default();
+        head.subject = RawStatus(status, "".into());
+        for &(name, value) in headers {
+            head.headers.set_raw(name, value);
+        }
+        head
+    }
+
+    fn cached(headers: &[(&'static str, &'static str)], request_time: i64, response_time: i64) -> CachedResponse {
+        CachedResponse::new(&response(200, headers), &Headers::new(), request_time, response_time)
+    }
+
+    #[test]
+    fn test_freshness_lifetime() {
+        assert_eq!(cached(&[("Cache-Control", "max-age=60")], 0, 0).freshness_lifetime(), 60);
+        assert_eq!(cached(&[("Cache-Control", "no-cache, max-age=60")], 0, 0).freshness_lifetime(), 0);
+        assert_eq!(cached(&[
+            ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("Expires", "Sun, 06 Nov 1994 08:59:37 GMT"),
+        ], 0, 0).freshness_lifetime(), 600);
+        assert_eq!(cached(&[("Expires", "0")], 0, 0).freshness_lifetime(), 0);
+        assert_eq!(cached(&[
+            ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("Last-Modified", "Sun, 06 Nov 1994 08:32:57 GMT"),
+        ], 0, 0).freshness_lifetime(), 100);
+        assert_eq!(cached(&[], 0, 0).freshness_lifetime(), 0);
+    }
+
+    #[test]
+    fn test_age() {
+        // 2 seconds on the way, 10 seconds in an upstream cache, and 5
+        // seconds since it was received
+        let response = cached(&[("Age", "10")], 100, 102);
+        assert_eq!(response.age(107), 17);
+        assert_eq!(cached(&[], 100, 100).age(100), 0);
+    }
+
+    #[test]
+    fn test_request_directives() {
+        let fresh = cached(&[], 0, 0);
+        let mut headers = Headers::new();
+        assert!(Request::new(&headers).accepts(&fresh, 10, 60));
+        assert!(!Request::new(&headers).accepts(&fresh, 60, 60));
+
+        headers.set(CacheControl(vec![CacheDirective::MaxAge(5)]));
+        assert!(!Request::new(&headers).accepts(&fresh, 10, 60));
+
+        headers.set(CacheControl(vec![CacheDirective::MaxStale(30)]));
+        assert!(Request::new(&headers).accepts(&fresh, 80, 60));
+        let must = cached(&[("Cache-Control", "must-revalidate")], 0, 0);
+        assert!(!Request::new(&headers).accepts(&must, 80, 60));
+
+        headers.remove::<CacheControl>();
+        headers.set_raw("Pragma", "no-cache");
+        assert!(!Request::new(&headers).accepts(&fresh, 10, 60));
+    }
+
+    #[test]
+    fn test_storable() {
+        let request = Headers::new();
+        assert!(storable(&request, &response(200, &[("Cache-Control", "max-age=60")])));
+        assert!(storable(&request, &response(200, &[("ETag", "\"a\"")])));
+        assert!(storable(&request, &response(200, &[("Cache-Control", "private, max-age=60")])));
+        assert!(!storable(&request, &response(200, &[])));
+        assert!(!storable(&request, &response(200, &[("Cache-Control", "no-store, max-age=60")])));
+        assert!(!storable(&request, &response(200, &[("Cache-Control", "max-age=60"), ("Vary", "*")])));
+        assert!(!storable(&request, &response(206, &[("Cache-Control", "max-age=60")])));
+        assert!(!storable(&request, &response(302, &[("ETag", "\"a\"")])));
+        assert!(storable(&request, &response(302, &[("Cache-Control", "max-age=60")])));
+
+        let mut no_store = Headers::new();
+        no_store.set(CacheControl(vec![CacheDirective::NoStore]));
+        assert!(!storable(&no_store, &response(200, &[("Cache-Control", "max-age=60")])));
+    }
+
+    #[test]
+    fn test_vary() {
+        let mut request = Headers::new();
+        request.set_raw("Accept-Language", "en");
+        let head = response(200, &[("Cache-Control", "max-age=60"), ("Vary", "accept-language")]);
+        let response = CachedResponse::new(&head, &request, 0, 0);
+        assert!(response.headers.has::<Vary>());
+        assert!(response.matches(&request));
+
+        let mut other = Headers::new();
+        other.set_raw("Accept-Language", "de");
+        assert!(!response.matches(&other));
+        assert!(!response.matches(&Headers::new()));
+    }
+
+    #[test]
+    fn test_write_read() {
+        let mut request = Headers::new();
+        request.set_raw("Accept", "text/plain");
+        let head = response(200, &[("Vary", "Accept"), ("Set-Cookie", "a=1"), ("Transfer-Encoding", "chunked")]);
+        let mut response = CachedResponse::new(&head, &request, 1, 2);
+        response.body = b"hello\r\n\r\nworld".to_vec();
+
+        let mut buf = Vec::new();
+        response.write_to(&mut buf).unwrap();
+        let read = CachedResponse::read_from(&mut &buf[..]).unwrap();
+        assert_eq!(read.status(), 200);
+        assert_eq!(read.body(), b"hello\r\n\r\nworld");
+        assert_eq!(read.request_time, 1);
+        assert_eq!(read.response_time, 2);
+        assert!(read.headers().get_raw("Transfer-Encoding").is_none());
+        assert_eq!(read.headers().get_raw("Set-Cookie").unwrap(), "a=1");
+        assert!(read.matches(&request));
+
+        assert!(CachedResponse::read_from(&mut &b"HTTP/1.1 200 OK\r\n\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_memory_cache_lru() {
+        let mut cache = MemoryCache::new(2);
+        cache.put("a", cached(&[], 0, 0));
+        cache.put("b", cached(&[], 0, 0));
+        assert!(cache.get("a").is_some());
+        cache.put("c", cached(&[], 0, 0));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        cache.remove("a");
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = env::temp_dir().join(format!("hyper-cache-test-{}", ::rand::random::<u64>()));
+        let mut cache = DiskCache::new(dir.clone());
+        assert!(cache.get("http://example.domain/").is_none());
+
+        let mut response = cached(&[("ETag", "\"a\"")], 0, 0);
+        response.body = b"hello".to_vec();
+        cache.put("http://example.domain/", response);
+        let read = DiskCache::new(dir.clone()).get("http://example.domain/").unwrap();
+        assert_eq!(read.body(), b"hello");
+        assert_eq!(read.headers().get_raw("ETag").unwrap(), "\"a\"");
+
+        cache.remove("http://example.domain/");
+        assert!(cache.get("http://example.domain/").is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,14 +3,87 @@ use std::hash::Hash;
 use std::fmt;
 use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use rotor::mio::tcp::TcpStream;
+use rotor::{EventSet, Scope, Time};
 use url::Url;
 
-use net::{HttpStream, HttpsStream, Transport, SslClient};
-use super::dns::Dns;
+use http::channel;
+use net::{HttpStream, HttpsStream, Transport, SslClient, ALPN_PROTOCOLS};
+use super::dns::{Dns, Resolve, ThreadResolver};
+use super::eyeballs::{self, Race};
+use super::proxy::{Proxy, Tunnel};
+use super::socks::{Handshake, Socks, Target};
 use super::Registration;
 
+/// The key of a connection opened on another thread, and the stream.
+pub type Connected = ((&'static str, String, u16), io::Result<HttpStream>);
+
+/// Where an `HttpConnector` sends connections to be opened by the client's
+/// loop.
+#[doc(hidden)]
+pub type Dials = channel::Sender<Dial>;
+
+/// A connection being opened as a state of the client's loop, which sends
+/// the stream to the connector once it is open.
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum Dial {
+    /// A race to connect to the first of several addresses.
+    Race(Race),
+    /// A `CONNECT` tunnel through an HTTP proxy.
+    Tunnel(Tunnel),
+    /// A handshake with a SOCKS5 proxy.
+    Socks(Handshake),
+}
+
+impl Dial {
+    /// Start connecting, giving up after `timeout`.
+    ///
+    /// Returns `None` once the connection is handed back.
+    pub fn start<X>(self, scope: &mut Scope<X>, timeout: Duration) -> Option<Dial> {
+        match self {
+            Dial::Race(race) => race.start(scope, timeout).map(Dial::Race),
+            Dial::Tunnel(tunnel) => tunnel.start(scope, timeout).map(Dial::Tunnel),
+            Dial::Socks(handshake) => handshake.start(scope).map(Dial::Socks),
+        }
+    }
+
+    pub fn ready<X>(self, events: EventSet, scope: &mut Scope<X>) -> Option<Dial> {
+        match self {
+            Dial::Race(race) => race.ready(events, scope).map(Dial::Race),
+            Dial::Tunnel(tunnel) => tunnel.ready(events, scope).map(Dial::Tunnel),
+            Dial::Socks(handshake) => handshake.ready(events, scope).map(Dial::Socks),
+        }
+    }
+
+    pub fn timeout<X>(self, scope: &mut Scope<X>) -> Option<Dial> {
+        match self {
+            Dial::Race(race) => race.timeout(scope).map(Dial::Race),
+            Dial::Tunnel(tunnel) => tunnel.timeout(scope).map(Dial::Tunnel),
+            Dial::Socks(handshake) => handshake.timeout(scope).map(Dial::Socks),
+        }
+    }
+
+    /// When to check on the connection again.
+    pub fn deadline(&self) -> Time {
+        match *self {
+            Dial::Race(ref race) => race.deadline(),
+            Dial::Tunnel(ref tunnel) => tunnel.deadline(),
+            Dial::Socks(ref handshake) => handshake.deadline(),
+        }
+    }
+
+    /// Report that there was no room in the loop to connect.
+    pub fn fail_full(self) {
+        match self {
+            Dial::Race(race) => race.fail_full(),
+            Dial::Tunnel(tunnel) => tunnel.fail_full(),
+            Dial::Socks(handshake) => handshake.fail_full(),
+        }
+    }
+}
+
 /// A connector creates a Transport to a remote address..
 pub trait Connect {
     /// Type of Transport to create
@@ -28,16 +101,39 @@ pub trait Connect {
     fn dns_workers(&mut self, usize);
     #[doc(hidden)]
     fn register(&mut self, Registration);
+    #[doc(hidden)]
+    /// Send requests through an HTTP proxy.
+    ///
+    /// Connectors that don't support proxies ignore this.
+    fn proxy(&mut self, _proxy: Proxy) {}
 }
 
 type Scheme = String;
 type Port = u16;
 
 /// A connector for the `http` scheme.
+///
+/// When a hostname resolves to several addresses, they are all tried, as in
+/// "Happy Eyeballs" (RFC 8305). The addresses alternate between IPv6 and
+/// IPv4, and each attempt starts when the one before fails, or after
+/// a short delay. The first connection made is used, and the attempts
+/// still pending are closed. Connecting fails once every attempt failed, or
+/// after the connect timeout of the `Client`.
+///
+/// Hostnames are resolved on a pool of threads, unless another `Resolve`
+/// is set.
 pub struct HttpConnector {
     dns: Option<Dns>,
+    resolver: Option<Box<Resolve>>,
     threads: usize,
-    resolving: HashMap<String, Vec<(&'static str, String, u16)>>,
+    attempt_delay: Duration,
+    /// The keys waiting on each hostname, and the proxy to tunnel through
+    /// when the hostname is its.
+    resolving: HashMap<String, Vec<((&'static str, String, u16), Option<Proxy>)>>,
+    streams: Option<(channel::Sender<Connected>, channel::Receiver<Connected>)>,
+    proxy: Option<Proxy>,
+    socks: Option<Socks>,
+    dials: Option<Dials>,
 }
 
 impl HttpConnector {
@@ -49,14 +145,38 @@ impl HttpConnector {
         self.threads = threads;
         self
     }
+
+    /// Set the resolver of hostnames.
+    ///
+    /// Default is a `ThreadResolver` with the number of `threads`.
+    pub fn resolver<R: Resolve + 'static>(mut self, resolver: R) -> HttpConnector {
+        debug_assert!(self.dns.is_none(), "setting resolver after Dns is created does nothing");
+        self.resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Set how long to wait on a connection attempt before also trying the
+    /// next address.
+    ///
+    /// Default is 250 milliseconds.
+    pub fn attempt_delay(mut self, delay: Duration) -> HttpConnector {
+        self.attempt_delay = delay;
+        self
+    }
 }
 
 impl Default for HttpConnector {
     fn default() -> HttpConnector {
         HttpConnector {
             dns: None,
+            resolver: None,
             threads: 4,
+            attempt_delay: Duration::from_millis(250),
             resolving: HashMap::new(),
+            streams: None,
+            proxy: None,
+            socks: None,
+            dials: None,
         }
     }
 }
@@ -65,7 +185,11 @@ impl fmt::Debug for HttpConnector {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("HttpConnector")
             .field("threads", &self.threads)
+            .field("resolver", &self.resolver.is_some())
+            .field("attempt_delay", &self.attempt_delay)
             .field("resolving", &self.resolving)
+            .field("proxy", &self.proxy)
+            .field("socks", &self.socks)
             .finish()
     }
 }
@@ -79,23 +203,25 @@ impl Connect for HttpConnector {
     }
 
     fn key(&self, url: &Url) -> Option<Self::Key> {
-        if url.scheme() == "http" {
+        if url.scheme() != "http" {
+            return None;
+        }
+        if let Some(ref proxy) = self.proxy {
+            // every request goes to the proxy, so they can share connections
+            Some(("http", proxy.host().to_owned(), proxy.port()))
+        } else {
             Some((
                 "http",
                 url.host_str().expect("http scheme must have host").to_owned(),
                 url.port().unwrap_or(80),
             ))
-        } else {
-            None
         }
     }
 
     fn connect(&mut self, url: &Url) -> io::Result<Self::Key> {
         debug!("Http::connect({:?})", url);
         if let Some(key) = self.key(url) {
-            let host = url.host_str().expect("http scheme must have a host");
-            self.dns.as_ref().expect("dns workers lost").resolve(host);
-            self.resolving.entry(host.to_owned()).or_insert_with(Vec::new).push(key.clone());
+            self.open(key.clone());
             Ok(key)
         } else {
             Err(io::Error::new(io::ErrorKind::InvalidInput, "scheme must be http"))
@@ -103,43 +229,169 @@ impl Connect for HttpConnector {
     }
 
     fn connected(&mut self) -> Option<(Self::Key, io::Result<HttpStream>)> {
-        let (host, addrs) = match self.dns.as_ref().expect("dns workers lost").resolved() {
-            Ok(res) => res,
-            Err(_) => return None
-        };
-        //TODO: try all addrs
-        let addr = addrs.and_then(|mut addrs| Ok(addrs.next().unwrap()));
-        debug!("Http::resolved <- ({:?}, {:?})", host, addr);
-        if let Entry::Occupied(mut entry) = self.resolving.entry(host) {
-            let resolved = entry.get_mut().remove(0);
-            if entry.get().is_empty() {
-                entry.remove();
+        // start connecting to each host resolved
+        while let Ok((host, addrs)) = self.dns.as_ref().expect("dns workers lost").resolved() {
+            let (key, proxy) = match self.resolving.entry(host) {
+                Entry::Occupied(mut entry) => {
+                    let waiting = entry.get_mut().remove(0);
+                    if entry.get().is_empty() {
+                        entry.remove();
+                    }
+                    waiting
+                }
+                Entry::Vacant(entry) => {
+                    trace!("resolved {:?} but not in hashmap?", entry.key());
+                    continue;
+                }
+            };
+            let port = proxy.as_ref().map_or(key.2, |proxy| proxy.port());
+            let addrs = match addrs {
+                Ok(addrs) => eyeballs::interleave(addrs.map(|ip| SocketAddr::new(ip, port)).collect()),
+                Err(e) => return Some((key, Err(e))),
+            };
+            debug!("Http::resolved <- ({:?}, {:?})", key.1, addrs);
+            if addrs.is_empty() {
+                return Some((key, Err(io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))));
             }
-            let port = resolved.2;
-            Some((resolved, addr.and_then(|addr| TcpStream::connect(&SocketAddr::new(addr, port))
-                                                            .map(HttpStream))
-                ))
+            let tx = self.streams.as_ref().expect("streams channel lost").0.clone();
+            let dial = match (proxy, self.socks.as_ref()) {
+                (Some(proxy), _) => Dial::Tunnel(proxy.tunnel(key, addrs, tx)),
+                (None, Some(socks)) => {
+                    let targets = addrs.into_iter().map(Target::Addr).collect();
+                    Dial::Socks(socks.handshake(key, targets, tx))
+                }
+                (None, None) => Dial::Race(Race::new(addrs, self.attempt_delay, key, tx)),
+            };
+            self.dial(dial);
+        }
+        self.streams.as_ref().and_then(|&(_, ref rx)| rx.try_recv().ok())
+    }
+
+    fn register(&mut self, reg: Registration) {
+        let mut resolver = match self.resolver.take() {
+            Some(resolver) => resolver,
+            None => Box::new(ThreadResolver::new(self.threads)),
+        };
+        resolver.register(reg.lookups);
+        self.dns = Some(Dns::new(reg.notify, resolver));
+        self.streams = Some(reg.streams);
+        self.dials = Some(reg.dials);
+    }
+
+    fn proxy(&mut self, proxy: Proxy) {
+        // connections through a SOCKS proxy don't also use an HTTP one
+        if self.socks.is_none() {
+            self.proxy = Some(proxy);
+        }
+    }
+}
+
+impl HttpConnector {
+    /// Start connecting to the host of `key`.
+    fn open(&mut self, key: (&'static str, String, u16)) {
+        let remote_dns = self.socks.as_ref().map_or(false, |socks| socks.remote_dns());
+        if remote_dns {
+            let targets = vec![Target::Domain(key.1.clone(), key.2)];
+            let tx = self.streams.as_ref().expect("streams channel lost").0.clone();
+            let handshake = self.socks.as_ref().expect("socks proxy lost").handshake(key, targets, tx);
+            self.dial(Dial::Socks(handshake));
         } else {
-            trace!("^--  resolved but not in hashmap?");
-            None
+            let host = key.1.clone();
+            self.resolve(host, key, None);
         }
     }
 
+    /// Start tunneling to the host of `key` through the HTTP proxy.
+    fn tunnel(&mut self, key: (&'static str, String, u16), proxy: Proxy) {
+        let host = proxy.host().to_owned();
+        self.resolve(host, key, Some(proxy));
+    }
+
+    /// Have the client's loop open a connection.
+    fn dial(&self, dial: Dial) {
+        // the loop is gone, and so are the requests waiting for this
+        let _ = self.dials.as_ref().expect("dials channel lost").send(dial);
+    }
+
+    fn resolve(&mut self, host: String, key: (&'static str, String, u16), proxy: Option<Proxy>) {
+        // a resolver may answer right away, so the key must be waiting
+        self.resolving.entry(host.clone()).or_insert_with(Vec::new).push((key, proxy));
+        self.dns.as_mut().expect("dns workers lost").resolve(host);
+    }
+}
+
+/// A connector for the `http` scheme, through a SOCKS5 proxy.
+///
+/// With a `socks5h` proxy URL, hostnames are resolved by the proxy. With
+/// `socks5`, they are resolved by the connector, and the proxy is asked for
+/// each address in turn, until it reaches one.
+///
+/// An HTTP proxy set on the `Client` is not used. To also connect to
+/// `https` URLs through the proxy, use `HttpsConnector::socks`.
+#[derive(Debug)]
+pub struct SocksConnector {
+    http: HttpConnector,
+}
+
+impl SocksConnector {
+    /// Create a connector through the proxy at a `socks5` or `socks5h` URL,
+    /// such as `socks5h://127.0.0.1:1080`.
+    ///
+    /// A username and password in the URL are used to authenticate with the
+    /// proxy. The hostname of the proxy is resolved right away.
+    pub fn new(url: &Url) -> ::Result<SocksConnector> {
+        SocksConnector::with_connector(url, HttpConnector::default())
+    }
+
+    /// Create a connector through the proxy, resolving hostnames for
+    /// `socks5` with the resolver of `http`.
+    pub fn with_connector(url: &Url, mut http: HttpConnector) -> ::Result<SocksConnector> {
+        http.socks = Some(try!(Socks::new(url)));
+        Ok(SocksConnector {
+            http: http,
+        })
+    }
+}
+
+impl Connect for SocksConnector {
+    type Output = HttpStream;
+    type Key = (&'static str, String, u16);
+
+    fn dns_workers(&mut self, count: usize) {
+        self.http.dns_workers(count)
+    }
+
+    fn key(&self, url: &Url) -> Option<Self::Key> {
+        self.http.key(url)
+    }
+
+    fn connect(&mut self, url: &Url) -> io::Result<Self::Key> {
+        self.http.connect(url)
+    }
+
+    fn connected(&mut self) -> Option<(Self::Key, io::Result<HttpStream>)> {
+        self.http.connected()
+    }
+
     fn register(&mut self, reg: Registration) {
-        self.dns = Some(Dns::new(reg.notify, self.threads));
+        self.http.register(reg);
     }
 }
 
 /// A connector that can protect HTTP streams using SSL.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct HttpsConnector<S: SslClient> {
     http: HttpConnector,
-    ssl: S
+    ssl: S,
 }
 
 impl<S: SslClient> HttpsConnector<S> {
     /// Create a new connector using the provided SSL implementation.
-    pub fn new(s: S) -> HttpsConnector<S> {
+    ///
+    /// The SSL implementation is set to offer `h2` and `http/1.1` with ALPN.
+    /// If the server picks `h2`, requests on that connection use HTTP/2.
+    pub fn new(mut s: S) -> HttpsConnector<S> {
+        s.set_alpn_protocols(ALPN_PROTOCOLS);
         HttpsConnector {
             http: HttpConnector::default(),
             ssl: s,
@@ -147,6 +399,23 @@ impl<S: SslClient> HttpsConnector<S> {
     }
 }
 
+impl<S: SslClient> HttpsConnector<S> {
+    /// Connect through a SOCKS5 proxy.
+    ///
+    /// TLS is set up with the host at the other end of the tunnel, so the
+    /// proxy cannot read `https` requests.
+    pub fn socks(mut self, socks: SocksConnector) -> HttpsConnector<S> {
+        self.http = socks.http;
+        self
+    }
+}
+
+impl<S: SslClient + Default> Default for HttpsConnector<S> {
+    fn default() -> HttpsConnector<S> {
+        HttpsConnector::new(S::default())
+    }
+}
+
 impl<S: SslClient> Connect for HttpsConnector<S> {
     type Output = HttpsStream<S::Stream>;
     type Key = (&'static str, String, u16);
@@ -156,24 +425,28 @@ impl<S: SslClient> Connect for HttpsConnector<S> {
     }
 
     fn key(&self, url: &Url) -> Option<Self::Key> {
-        let scheme = match url.scheme() {
-            "http" => "http",
-            "https" => "https",
-            _ => return None
-        };
-        Some((
-            scheme,
-            url.host_str().expect("http scheme must have host").to_owned(),
-            url.port_or_known_default().expect("http scheme must have a port"),
-        ))
+        match url.scheme() {
+            "http" => self.http.key(url),
+            "https" => Some((
+                "https",
+                url.host_str().expect("https scheme must have host").to_owned(),
+                url.port_or_known_default().expect("https scheme must have a port"),
+            )),
+            _ => None
+        }
     }
 
     fn connect(&mut self, url: &Url) -> io::Result<Self::Key> {
         debug!("Https::connect({:?})", url);
         if let Some(key) = self.key(url) {
-            let host = url.host_str().expect("http scheme must have a host");
-            self.http.dns.as_ref().expect("dns workers lost").resolve(host);
-            self.http.resolving.entry(host.to_owned()).or_insert_with(Vec::new).push(key.clone());
+            let proxy = if key.0 == "https" { self.http.proxy.clone() } else { None };
+            match proxy {
+                Some(proxy) => {
+                    // TLS is set up once the proxy tunneled to the host
+                    self.http.tunnel(key.clone(), proxy);
+                }
+                None => self.http.open(key.clone()),
+            }
             Ok(key)
         } else {
             Err(io::Error::new(io::ErrorKind::InvalidInput, "scheme must be http or https"))
@@ -201,6 +474,10 @@ impl<S: SslClient> Connect for HttpsConnector<S> {
     fn register(&mut self, reg: Registration) {
         self.http.register(reg);
     }
+
+    fn proxy(&mut self, proxy: Proxy) {
+        self.http.proxy(proxy);
+    }
 }
 
 #[cfg(not(any(feature = "openssl", feature = "security-framework")))]
@@ -0,0 +1,503 @@
+//! Storing cookies between requests, following the storage model of
+//! RFC 6265.
+use std::ascii::AsciiExt;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use time;
+use url::{Host, Url};
+
+use header::CookiePair;
+
+/// A place to keep the cookies a `Client` receives, and to find those it
+/// should send.
+///
+/// `Cookies` keeps them in memory. Implement this to keep them somewhere
+/// else, such as in a database. A `Mutex` of a store in an `Arc` is a store
+/// too, so that the cookies can still be reached while the `Client` uses it.
+pub trait CookieStore: Send {
+    /// Store the cookies a response to `url` set with `Set-Cookie`.
+    fn set_cookies(&mut self, url: &Url, cookies: Vec<CookiePair>);
+
+    /// Get the cookies to send in the `Cookie` header of a request to `url`.
+    fn cookies(&mut self, url: &Url) -> Vec<CookiePair>;
+}
+
+impl<S: CookieStore + ?Sized> CookieStore for Box<S> {
+    fn set_cookies(&mut self, url: &Url, cookies: Vec<CookiePair>) {
+        (**self).set_cookies(url, cookies)
+    }
+
+    fn cookies(&mut self, url: &Url) -> Vec<CookiePair> {
+        (**self).cookies(url)
+    }
+}
+
+impl<S: CookieStore + ?Sized> CookieStore for Arc<Mutex<S>> {
+    fn set_cookies(&mut self, url: &Url, cookies: Vec<CookiePair>) {
+        match self.lock() {
+            Ok(mut store) => store.set_cookies(url, cookies),
+            Err(poisoned) => poisoned.into_inner().set_cookies(url, cookies),
+        }
+    }
+
+    fn cookies(&mut self, url: &Url) -> Vec<CookiePair> {
+        match self.lock() {
+            Ok(mut store) => store.cookies(url),
+            Err(poisoned) => poisoned.into_inner().cookies(url),
+        }
+    }
+}
+
+/// A `CookieStore` shared by every message of a `Client`.
+#[derive(Clone)]
+pub struct Shared(Arc<Mutex<Box<CookieStore>>>);
+
+impl Shared {
+    pub fn new(store: Box<CookieStore>) -> Shared {
+        Shared(Arc::new(Mutex::new(store)))
+    }
+
+    pub fn set_cookies(&mut self, url: &Url, cookies: Vec<CookiePair>) {
+        self.0.set_cookies(url, cookies)
+    }
+
+    pub fn cookies(&mut self, url: &Url) -> Vec<CookiePair> {
+        self.0.cookies(url)
+    }
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("CookieStore")
+    }
+}
+
+/// Domains under which anyone can register a name, so that a cookie may
+/// not be set for all of them.
+///
+/// This is not the whole Public Suffix List, only some well known hosting
+/// suffixes. Second level domains of country codes, such as `co.uk`, are
+/// matched by `GENERIC_SECOND_LEVEL` instead. See `Cookies::public_suffix`.
+static PUBLIC_SUFFIXES: &'static [&'static str] = &[
+    "appspot.com", "azurewebsites.net", "blogspot.com", "cloudapp.net",
+    "cloudfront.net", "elasticbeanstalk.com", "firebaseapp.com",
+    "github.io", "gitlab.io", "herokuapp.com", "netlify.app", "netlify.com",
+    "pages.dev", "s3.amazonaws.com", "vercel.app", "web.app",
+    "workers.dev",
+];
+
+/// Labels that, under a two letter country code, are commonly open for
+/// anyone to register in, as in `co.uk` or `com.au`.
+static GENERIC_SECOND_LEVEL: &'static [&'static str] = &[
+    "ac", "co", "com", "edu", "go", "gob", "gov", "ltd", "me", "mil", "ne",
+    "net", "nom", "or", "org", "plc", "sch",
+];
+
+/// Cookies kept in memory.
+///
+/// A cookie is sent back to the host that set it, and to its subdomains if
+/// it named a `Domain`. It is only sent with requests under its `Path`,
+/// only over `https` if it is `Secure`, and until it expires. A cookie
+/// without `Expires` or `Max-Age` is kept until the `Cookies` are dropped.
+///
+/// A cookie may only name a `Domain` that the request host is in, and that
+/// has at least one label in front of a public suffix, such as `com` or
+/// `co.uk`, since every site under a public suffix would get the cookie.
+///
+/// The public suffixes known here are only an approximation of the Public
+/// Suffix List: top level domains, common second level domains of country
+/// codes, and some hosting suffixes. The protection against cookies shared
+/// across sites is partial, so add the suffixes that matter to an
+/// application with `Cookies::public_suffix`.
+///
+/// The cookies can be saved to and loaded from a file in the Netscape
+/// `cookies.txt` format, as used by curl and wget.
+#[derive(Debug, Default, Clone)]
+pub struct Cookies {
+    cookies: Vec<Stored>,
+    public_suffixes: Vec<String>,
+    next_seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Stored {
+    name: String,
+    value: String,
+    domain: String,
+    path: String,
+    /// Whether the cookie is only sent to `domain` itself, and not its
+    /// subdomains.
+    host_only: bool,
+    secure: bool,
+    http_only: bool,
+    /// Seconds since the epoch, or `None` for a session cookie.
+    expires: Option<i64>,
+    /// Orders cookies by when they were created.
+    seq: u64,
+}
+
+impl Cookies {
+    /// Create an empty store.
+    pub fn new() -> Cookies {
+        Cookies::default()
+    }
+
+    /// Treat a domain as a public suffix, in addition to the top level
+    /// domains and a few well known suffixes such as `co.uk`.
+    pub fn public_suffix(mut self, suffix: &str) -> Cookies {
+        self.public_suffixes.push(suffix.trim_left_matches('.').to_ascii_lowercase());
+        self
+    }
+
+    /// Add the cookies in a Netscape `cookies.txt` file.
+    pub fn read_netscape<R: Read>(&mut self, reader: R) -> io::Result<()> {
+        for line in BufReader::new(reader).lines() {
+            let line = try!(line);
+            let (line, http_only) = if line.starts_with("#HttpOnly_") {
+                (&line["#HttpOnly_".len()..], true)
+            } else {
+                (&line[..], false)
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() != 7 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid cookies.txt line"));
+            }
+            let expires = match fields[4].parse::<i64>() {
+                Ok(0) => None,
+                Ok(secs) => Some(secs),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid cookie expiry")),
+            };
+            let seq = self.seq();
+            self.insert(Stored {
+                name: fields[5].to_owned(),
+                value: fields[6].to_owned(),
+                domain: fields[0].trim_left_matches('.').to_ascii_lowercase(),
+                path: fields[2].to_owned(),
+                host_only: fields[1] != "TRUE",
+                secure: fields[3] == "TRUE",
+                http_only: http_only,
+                expires: expires,
+                seq: seq,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write the cookies that outlive a session as a Netscape `cookies.txt`
+    /// file.
+    pub fn write_netscape<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        try!(writer.write_all(b"# Netscape HTTP Cookie File\n"));
+        let now = now();
+        for cookie in &self.cookies {
+            let expires = match cookie.expires {
+                Some(expires) if expires > now => expires,
+                _ => continue,
+            };
+            try!(write!(writer, "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                        if cookie.http_only { "#HttpOnly_" } else { "" },
+                        if cookie.host_only { "" } else { "." },
+                        cookie.domain,
+                        if cookie.host_only { "FALSE" } else { "TRUE" },
+                        cookie.path,
+                        if cookie.secure { "TRUE" } else { "FALSE" },
+                        expires,
+                        cookie.name,
+                        cookie.value));
+        }
+        Ok(())
+    }
+
+    fn seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    fn is_public_suffix(&self, domain: &str) -> bool {
+        let mut labels = domain.rsplit('.');
+        let tld = labels.next().unwrap_or("");
+        let is_generic_second_level = match (labels.next(), labels.next()) {
+            (Some(label), None) => {
+                tld.len() == 2 && tld.bytes().all(|b| b >= b'a' && b <= b'z') &&
+                    GENERIC_SECOND_LEVEL.contains(&label)
+            }
+            _ => false,
+        };
+        !domain.contains('.') ||
+            is_generic_second_level ||
+            PUBLIC_SUFFIXES.contains(&domain) ||
+            self.public_suffixes.iter().any(|suffix| suffix == domain)
+    }
+
+    /// Replaces a cookie with the same name, domain and path, keeping when
+    /// it was first created.
+    fn insert(&mut self, mut cookie: Stored) {
+        if let Some(pos) = self.cookies.iter().position(|stored| {
+            stored.name == cookie.name && stored.domain == cookie.domain && stored.path == cookie.path
+        }) {
+            cookie.seq = self.cookies.remove(pos).seq;
+        }
+        if cookie.expires.map_or(true, |expires| expires > now()) {
+            self.cookies.push(cookie);
+        }
+    }
+}
+
+impl CookieStore for Cookies {
+    fn set_cookies(&mut self, url: &Url, cookies: Vec<CookiePair>) {
+        let (host, is_ip) = match host(url) {
+            Some(host) => host,
+            None => return,
+        };
+        for cookie in cookies {
+            let expires = match (cookie.max_age, cookie.expires) {
+                (Some(max_age), _) => Some(now().saturating_add(max_age as i64)),
+                (None, Some(tm)) => Some(tm.to_timespec().sec),
+                (None, None) => None,
+            };
+            let domain = cookie.domain.as_ref()
+                .map(|domain| domain.trim_left_matches('.').to_ascii_lowercase())
+                .and_then(|domain| if domain.is_empty() { None } else { Some(domain) });
+            let (domain, host_only) = match domain {
+                Some(domain) => {
+                    if self.is_public_suffix(&domain) && domain != host {
+                        debug!("ignoring cookie {:?} for public suffix {:?}", cookie.name, domain);
+                        continue;
+                    }
+                    if domain == host {
+                        (domain, is_ip || self.is_public_suffix(&host))
+                    } else if !is_ip && domain_match(&host, &domain) {
+                        (domain, false)
+                    } else {
+                        debug!("ignoring cookie {:?} for other domain {:?}", cookie.name, domain);
+                        continue;
+                    }
+                }
+                None => (host.clone(), true),
+            };
+            let path = match cookie.path {
+                Some(ref path) if path.starts_with('/') => path.clone(),
+                _ => default_path(url.path()).to_owned(),
+            };
+            let seq = self.seq();
+            self.insert(Stored {
+                name: cookie.name,
+                value: cookie.value,
+                domain: domain,
+                path: path,
+                host_only: host_only,
+                secure: cookie.secure,
+                http_only: cookie.httponly,
+                expires: expires,
+                seq: seq,
+            });
+        }
+    }
+
+    fn cookies(&mut self, url: &Url) -> Vec<CookiePair> {
+        let now = now();
+        self.cookies.retain(|cookie| cookie.expires.map_or(true, |expires| expires > now));
+        let (host, is_ip) = match host(url) {
+            Some(host) => host,
+            None => return Vec::new(),
+        };
+        let secure = url.scheme() == "https";
+        let mut matching = self.cookies.iter()
+            .filter(|cookie| {
+                if cookie.host_only || is_ip {
+                    cookie.domain == host
+                } else {
+                    domain_match(&host, &cookie.domain)
+                }
+            })
+            .filter(|cookie| path_match(url.path(), &cookie.path))
+            .filter(|cookie| secure || !cookie.secure)
+            .collect::<Vec<_>>();
+        // longer paths first, then older cookies
+        matching.sort_by(|a, b| match b.path.len().cmp(&a.path.len()) {
+            Ordering::Equal => a.seq.cmp(&b.seq),
+            other => other,
+        });
+        matching.into_iter()
+            .map(|cookie| CookiePair::new(cookie.name.clone(), cookie.value.clone()))
+            .collect()
+    }
+}
+
+fn now() -> i64 {
+    time::get_time().sec
+}
+
+/// The lowercased host of a URL, and whether it is an IP address.
+fn host(url: &Url) -> Option<(String, bool)> {
+    match url.host() {
+        Some(Host::Domain(domain)) => Some((domain.to_ascii_lowercase(), false)),
+        Some(Host::Ipv4(ip)) => Some((ip.to_string(), true)),
+        Some(Host::Ipv6(ip)) => Some((format!("[{}]", ip), true)),
+        None => None,
+    }
+}
+
+/// Whether `host` is `domain`, or a subdomain of it.
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain ||
+        (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+/// The directory of a request path, used for cookies without a `Path`.
+fn default_path(path: &str) -> &str {
+    if !path.starts_with('/') {
+        return "/";
+    }
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(pos) => &path[..pos],
+    }
+}
+
+/// Whether a request path is `cookie_path`, or under it.
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path ||
+        (path.starts_with(cookie_path) &&
+         (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use header::CookiePair;
+    use super::{CookieStore, Cookies, default_path, domain_match, path_match};
+
+    fn url(s: &str) -> Url {
+        s.parse().unwrap()
+    }
+
+    fn set(cookies: &mut Cookies, u: &str, set_cookie: &str) {
+        cookies.set_cookies(&url(u), vec![set_cookie.parse().unwrap()]);
+    }
+
+    fn get(cookies: &mut Cookies, u: &str) -> Vec<String> {
+        cookies.cookies(&url(u)).iter().map(|cookie| cookie.pair().to_string()).collect()
+    }
+
+    #[test]
+    fn test_host_only() {
+        let mut cookies = Cookies::new();
+        set(&mut cookies, "http://example.domain/", "a=1");
+        assert_eq!(get(&mut cookies, "http://example.domain/foo"), vec!["a=1"]);
+        assert!(get(&mut cookies, "http://www.example.domain/").is_empty());
+        assert!(get(&mut cookies, "http://other.domain/").is_empty());
+    }
+
+    #[test]
+    fn test_domain() {
+        let mut cookies = Cookies::new();
+        set(&mut cookies, "http://www.example.domain/", "a=1; Domain=.Example.Domain");
+        assert_eq!(get(&mut cookies, "http://example.domain/"), vec!["a=1"]);
+        assert_eq!(get(&mut cookies, "http://api.example.domain/"), vec!["a=1"]);
+        assert!(get(&mut cookies, "http://badexample.domain/").is_empty());
+
+        // a sibling can't be named
+        set(&mut cookies, "http://www.example.domain/", "b=2; Domain=api.example.domain");
+        assert!(get(&mut cookies, "http://api.example.domain/").iter().all(|c| c != "b=2"));
+    }
+
+    #[test]
+    fn test_public_suffix() {
+        let mut cookies = Cookies::new().public_suffix("example.domain");
+        set(&mut cookies, "http://www.example.com/", "a=1; Domain=com");
+        set(&mut cookies, "http://www.example.co.uk/", "b=2; Domain=co.uk");
+        set(&mut cookies, "http://www.example.domain/", "c=3; Domain=example.domain");
+        assert!(get(&mut cookies, "http://other.com/").is_empty());
+        assert!(get(&mut cookies, "http://other.co.uk/").is_empty());
+        assert!(get(&mut cookies, "http://other.example.domain/").is_empty());
+
+        set(&mut cookies, "http://www.example.com.pl/", "d=4; Domain=com.pl");
+        set(&mut cookies, "http://bucket.s3.amazonaws.com/", "e=5; Domain=s3.amazonaws.com");
+        set(&mut cookies, "http://user.github.io/", "f=6; Domain=github.io");
+        assert!(get(&mut cookies, "http://other.com.pl/").is_empty());
+        assert!(get(&mut cookies, "http://other.s3.amazonaws.com/").is_empty());
+        assert!(get(&mut cookies, "http://other.github.io/").is_empty());
+
+        set(&mut cookies, "http://www.example.co.uk/", "g=7; Domain=example.co.uk");
+        assert_eq!(get(&mut cookies, "http://api.example.co.uk/"), vec!["g=7"]);
+    }
+
+    #[test]
+    fn test_path() {
+        let mut cookies = Cookies::new();
+        set(&mut cookies, "http://example.domain/docs/index.html", "a=1");
+        set(&mut cookies, "http://example.domain/", "b=2; Path=/docs/api");
+        set(&mut cookies, "http://example.domain/", "c=3");
+        assert_eq!(get(&mut cookies, "http://example.domain/docs/api/v1"), vec!["b=2", "a=1", "c=3"]);
+        assert_eq!(get(&mut cookies, "http://example.domain/docs"), vec!["a=1", "c=3"]);
+        assert_eq!(get(&mut cookies, "http://example.domain/docsify"), vec!["c=3"]);
+    }
+
+    #[test]
+    fn test_secure() {
+        let mut cookies = Cookies::new();
+        set(&mut cookies, "https://example.domain/", "a=1; Secure");
+        assert!(get(&mut cookies, "http://example.domain/").is_empty());
+        assert_eq!(get(&mut cookies, "https://example.domain/"), vec!["a=1"]);
+    }
+
+    #[test]
+    fn test_replace_and_expire() {
+        let mut cookies = Cookies::new();
+        set(&mut cookies, "http://example.domain/", "a=1");
+        set(&mut cookies, "http://example.domain/", "b=2");
+        set(&mut cookies, "http://example.domain/", "a=3; Max-Age=60");
+        // a keeps its place, since it was created first
+        assert_eq!(get(&mut cookies, "http://example.domain/"), vec!["a=3", "b=2"]);
+        set(&mut cookies, "http://example.domain/", "a=; Max-Age=0");
+        set(&mut cookies, "http://example.domain/", "b=; Expires=Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(get(&mut cookies, "http://example.domain/").is_empty());
+    }
+
+    #[test]
+    fn test_netscape() {
+        let mut cookies = Cookies::new();
+        cookies.set_cookies(&url("https://www.example.domain/"), vec![
+            "a=1; Max-Age=3600; Domain=example.domain; Secure".parse::<CookiePair>().unwrap(),
+            "b=2; Max-Age=3600; Path=/docs; HttpOnly".parse().unwrap(),
+            "session=3".parse().unwrap(),
+        ]);
+        let mut file = Vec::new();
+        cookies.write_netscape(&mut file).unwrap();
+        let file = String::from_utf8(file).unwrap();
+        assert!(!file.contains("session"));
+
+        let mut loaded = Cookies::new();
+        loaded.read_netscape(file.as_bytes()).unwrap();
+        assert_eq!(get(&mut loaded, "https://api.example.domain/"), vec!["a=1"]);
+        assert_eq!(get(&mut loaded, "https://www.example.domain/docs"), vec!["b=2", "a=1"]);
+
+        let mut curl = Cookies::new();
+        curl.read_netscape(&b"# comment\n\n\
+            .example.domain\tTRUE\t/\tFALSE\t0\tc\t3\n\
+            #HttpOnly_example.domain\tFALSE\t/\tFALSE\t0\td\t4\n"[..]).unwrap();
+        assert_eq!(get(&mut curl, "http://www.example.domain/"), vec!["c=3"]);
+        assert_eq!(get(&mut curl, "http://example.domain/"), vec!["c=3", "d=4"]);
+        assert!(curl.read_netscape(&b"bad line\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_matching() {
+        assert!(domain_match("www.example.domain", "example.domain"));
+        assert!(!domain_match("badexample.domain", "example.domain"));
+        assert_eq!(default_path("/docs/index.html"), "/docs");
+        assert_eq!(default_path("/index.html"), "/");
+        assert_eq!(default_path(""), "/");
+        assert!(path_match("/docs/api", "/docs"));
+        assert!(path_match("/docs/api", "/docs/"));
+        assert!(!path_match("/docsify", "/docs"));
+    }
+}
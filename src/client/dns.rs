@@ -1,45 +1,113 @@
+//! Resolving hostnames for a `HttpConnector`.
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::vec;
 
 use ::spmc;
 
 use http::channel;
-
-pub struct Dns {
-    tx: spmc::Sender<String>,
-    rx: channel::Receiver<Answer>,
-}
+use super::stub::Lookups;
 
 pub type Answer = (String, io::Result<IpAddrs>);
 
 pub struct IpAddrs {
-    iter: vec::IntoIter<SocketAddr>,
+    iter: vec::IntoIter<IpAddr>,
 }
 
 impl Iterator for IpAddrs {
     type Item = IpAddr;
     #[inline]
     fn next(&mut self) -> Option<IpAddr> {
-        self.iter.next().map(|addr| addr.ip())
+        self.iter.next()
     }
 }
 
-impl Dns {
-    pub fn new(notify: (channel::Sender<Answer>, channel::Receiver<Answer>), threads: usize) -> Dns {
-        let (tx, rx) = spmc::channel();
-        for _ in 0..threads {
-            work(rx.clone(), notify.0.clone());
+/// Resolves hostnames to IP addresses.
+///
+/// Resolving must not block: the connector calls `resolve` from the client's
+/// event loop. A resolver either answers right away, or hands the
+/// `Resolving` to another thread, which answers once it knows.
+pub trait Resolve: Send {
+    /// Start resolving a hostname.
+    fn resolve(&mut self, resolving: Resolving);
+
+    /// Receive the queue of lookups run by the client's loop.
+    ///
+    /// Resolvers that don't look up in the loop ignore this.
+    #[doc(hidden)]
+    fn register(&mut self, _lookups: Lookups) {}
+}
+
+impl<R: Resolve + ?Sized> Resolve for Box<R> {
+    fn resolve(&mut self, resolving: Resolving) {
+        (**self).resolve(resolving)
+    }
+
+    fn register(&mut self, lookups: Lookups) {
+        (**self).register(lookups)
+    }
+}
+
+/// A hostname being resolved, to be answered with its addresses.
+///
+/// Sending the answer wakes up the client to connect. If a `Resolving` is
+/// dropped without an answer, requests to the hostname time out.
+pub struct Resolving {
+    host: String,
+    tx: channel::Sender<Answer>,
+    caches: Vec<Arc<Mutex<Cache>>>,
+}
+
+impl Resolving {
+    /// The hostname to resolve.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Answer with the addresses of the hostname, or why there are none.
+    pub fn send(self, res: io::Result<Vec<IpAddr>>) {
+        for cache in &self.caches {
+            cache.lock().unwrap().insert(&self.host, &res);
         }
+        let _ = self.tx.send((self.host, res.map(|addrs| IpAddrs { iter: addrs.into_iter() })));
+    }
+}
+
+impl fmt::Debug for Resolving {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Resolving")
+            .field("host", &self.host)
+            .finish()
+    }
+}
+
+/// The resolver of a connector, answering to the client's event loop.
+pub struct Dns {
+    resolver: Box<Resolve>,
+    tx: channel::Sender<Answer>,
+    rx: channel::Receiver<Answer>,
+}
+
+impl Dns {
+    pub fn new(notify: (channel::Sender<Answer>, channel::Receiver<Answer>), resolver: Box<Resolve>) -> Dns {
         Dns {
-            tx: tx,
+            resolver: resolver,
+            tx: notify.0,
             rx: notify.1,
         }
     }
 
-    pub fn resolve<T: Into<String>>(&self, hostname: T) {
-        self.tx.send(hostname.into()).expect("DNS workers all died unexpectedly");
+    pub fn resolve<T: Into<String>>(&mut self, hostname: T) {
+        self.resolver.resolve(Resolving {
+            host: hostname.into(),
+            tx: self.tx.clone(),
+            caches: Vec::new(),
+        });
     }
 
     pub fn resolved(&self) -> Result<Answer, channel::TryRecvError> {
@@ -47,37 +115,56 @@ impl Dns {
     }
 }
 
-fn work(rx: spmc::Receiver<String>, notify: channel::Sender<Answer>) {
+/// Resolves hostnames with the system resolver, on a pool of threads.
+#[derive(Debug)]
+pub struct ThreadResolver {
+    tx: spmc::Sender<Resolving>,
+    threads: usize,
+}
+
+impl ThreadResolver {
+    /// Start a resolver with this many threads.
+    pub fn new(threads: usize) -> ThreadResolver {
+        let (tx, rx) = spmc::channel();
+        for _ in 0..threads {
+            work(rx.clone());
+        }
+        ThreadResolver {
+            tx: tx,
+            threads: threads,
+        }
+    }
+}
+
+impl Resolve for ThreadResolver {
+    fn resolve(&mut self, resolving: Resolving) {
+        self.tx.send(resolving).expect("DNS workers all died unexpectedly");
+    }
+}
+
+fn work(rx: spmc::Receiver<Resolving>) {
     thread::Builder::new().name(String::from("hyper-dns")).spawn(move || {
-        let mut worker = Worker::new(rx, notify);
+        let mut worker = Worker::new(rx);
         let rx = worker.rx.as_ref().expect("Worker lost rx");
-        let notify = worker.notify.as_ref().expect("Worker lost notify");
-        while let Ok(host) = rx.recv() {
-            debug!("resolve {:?}", host);
-            let res = match (&*host, 80).to_socket_addrs().map(|i| IpAddrs{ iter: i }) {
-                Ok(addrs) => (host, Ok(addrs)),
-                Err(e) => (host, Err(e))
-            };
-
-            if let Err(_) = notify.send(res) {
-                break;
-            }
+        while let Ok(resolving) = rx.recv() {
+            debug!("resolve {:?}", resolving.host);
+            let res = (&*resolving.host, 80).to_socket_addrs()
+                .map(|addrs| addrs.map(|addr| addr.ip()).collect());
+            resolving.send(res);
         }
         worker.shutdown = true;
     }).expect("spawn dns thread");
 }
 
 struct Worker {
-    rx: Option<spmc::Receiver<String>>,
-    notify: Option<channel::Sender<Answer>>,
+    rx: Option<spmc::Receiver<Resolving>>,
     shutdown: bool,
 }
 
 impl Worker {
-    fn new(rx: spmc::Receiver<String>, notify: channel::Sender<Answer>) -> Worker {
+    fn new(rx: spmc::Receiver<Resolving>) -> Worker {
         Worker {
             rx: Some(rx),
-            notify: Some(notify),
             shutdown: false,
         }
     }
@@ -87,10 +174,334 @@ impl Drop for Worker {
     fn drop(&mut self) {
         if !self.shutdown {
             trace!("Worker.drop panicked, restarting");
-            work(self.rx.take().expect("Worker lost rx"),
-                self.notify.take().expect("Worker lost notify"));
+            work(self.rx.take().expect("Worker lost rx"));
         } else {
             trace!("Worker.drop shutdown, closing");
         }
     }
 }
+
+/// Resolves hostnames from a fixed map, such as to point them at local
+/// servers in tests.
+///
+/// Other hostnames are resolved with a fallback resolver, if there is one,
+/// and are not found otherwise.
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    fallback: Option<Box<Resolve>>,
+}
+
+impl StaticResolver {
+    /// Create a resolver with no hostnames.
+    pub fn new() -> StaticResolver {
+        StaticResolver {
+            hosts: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    /// Add an address of a hostname.
+    pub fn host<H: Into<String>>(mut self, host: H, addr: IpAddr) -> StaticResolver {
+        self.hosts.entry(host.into().to_lowercase()).or_insert_with(Vec::new).push(addr);
+        self
+    }
+
+    /// Resolve hostnames not in the map with another resolver.
+    ///
+    /// Default is no fallback.
+    pub fn fallback<R: Resolve + 'static>(mut self, resolver: R) -> StaticResolver {
+        self.fallback = Some(Box::new(resolver));
+        self
+    }
+}
+
+impl Default for StaticResolver {
+    fn default() -> StaticResolver {
+        StaticResolver::new()
+    }
+}
+
+impl fmt::Debug for StaticResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticResolver")
+            .field("hosts", &self.hosts)
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+impl Resolve for StaticResolver {
+    fn resolve(&mut self, resolving: Resolving) {
+        let addrs = self.hosts.get(&resolving.host().to_lowercase()).cloned();
+        match (addrs, self.fallback.as_mut()) {
+            (Some(addrs), _) => resolving.send(Ok(addrs)),
+            (None, Some(fallback)) => fallback.resolve(resolving),
+            (None, None) => resolving.send(Err(io::Error::new(io::ErrorKind::NotFound, "unknown host"))),
+        }
+    }
+
+    fn register(&mut self, lookups: Lookups) {
+        if let Some(ref mut fallback) = self.fallback {
+            fallback.register(lookups);
+        }
+    }
+}
+
+/// Remembers the answers of another resolver for a while.
+///
+/// Failures are remembered as well, for a shorter while, so that a host
+/// that does not resolve is not asked about for every request. Once the
+/// cache is full, the hostname used least recently is forgotten.
+#[derive(Debug)]
+pub struct CachingResolver<R> {
+    resolver: R,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl<R: Resolve> CachingResolver<R> {
+    /// Cache the answers of `resolver`.
+    pub fn new(resolver: R) -> CachingResolver<R> {
+        CachingResolver {
+            resolver: resolver,
+            cache: Arc::new(Mutex::new(Cache {
+                entries: HashMap::new(),
+                ttl: Duration::from_secs(60),
+                negative_ttl: Duration::from_secs(5),
+                capacity: 1024,
+                uses: 0,
+            })),
+        }
+    }
+
+    /// Set how long addresses are remembered.
+    ///
+    /// Default is 60 seconds.
+    pub fn ttl(self, val: Duration) -> CachingResolver<R> {
+        self.cache.lock().unwrap().ttl = val;
+        self
+    }
+
+    /// Set how long a failure to resolve is remembered.
+    ///
+    /// Default is 5 seconds.
+    pub fn negative_ttl(self, val: Duration) -> CachingResolver<R> {
+        self.cache.lock().unwrap().negative_ttl = val;
+        self
+    }
+
+    /// Set how many hostnames are remembered.
+    ///
+    /// Default is 1024.
+    pub fn capacity(self, val: usize) -> CachingResolver<R> {
+        self.cache.lock().unwrap().capacity = val;
+        self
+    }
+}
+
+impl<R: Resolve> Resolve for CachingResolver<R> {
+    fn resolve(&mut self, mut resolving: Resolving) {
+        let cached = self.cache.lock().unwrap().get(resolving.host());
+        match cached {
+            Some(res) => {
+                trace!("dns cache hit {:?}", resolving.host());
+                resolving.send(res);
+            }
+            None => {
+                resolving.caches.push(self.cache.clone());
+                self.resolver.resolve(resolving);
+            }
+        }
+    }
+
+    fn register(&mut self, lookups: Lookups) {
+        self.resolver.register(lookups);
+    }
+}
+
+#[derive(Debug)]
+struct Cache {
+    entries: HashMap<String, Cached>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+    /// Counts every use of the cache, to tell which entry was used last.
+    uses: u64,
+}
+
+#[derive(Debug)]
+struct Cached {
+    expires: Instant,
+    used: u64,
+    res: Result<Vec<IpAddr>, (io::ErrorKind, String)>,
+}
+
+impl Cache {
+    fn get(&mut self, host: &str) -> Option<io::Result<Vec<IpAddr>>> {
+        self.uses += 1;
+        let expired = match self.entries.get_mut(host) {
+            Some(cached) => {
+                if cached.expires > Instant::now() {
+                    cached.used = self.uses;
+                    return Some(match cached.res {
+                        Ok(ref addrs) => Ok(addrs.clone()),
+                        Err((kind, ref msg)) => Err(io::Error::new(kind, msg.clone())),
+                    });
+                }
+                true
+            }
+            None => false,
+        };
+        if expired {
+            self.entries.remove(host);
+        }
+        None
+    }
+
+    fn insert(&mut self, host: &str, res: &io::Result<Vec<IpAddr>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.uses += 1;
+        let (ttl, res) = match *res {
+            Ok(ref addrs) => (self.ttl, Ok(addrs.clone())),
+            Err(ref e) => (self.negative_ttl, Err((e.kind(), e.to_string()))),
+        };
+        if !self.entries.contains_key(host) && self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        self.entries.insert(host.to_owned(), Cached {
+            expires: Instant::now() + ttl,
+            used: self.uses,
+            res: res,
+        });
+    }
+
+    /// Forget the expired entries, or else the one used least recently.
+    fn evict(&mut self) {
+        let now = Instant::now();
+        let mut expired = self.entries.iter()
+            .filter(|&(_, cached)| cached.expires <= now)
+            .map(|(host, _)| host.clone())
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            let lru = self.entries.iter()
+                .min_by_key(|&(_, cached)| cached.used)
+                .map(|(host, _)| host.clone());
+            trace!("dns cache full, forgetting {:?}", lru);
+            expired.extend(lru);
+        }
+        for host in &expired {
+            self.entries.remove(host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::IpAddr;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use rotor;
+
+    use http::channel;
+    use super::{Dns, Resolve, Resolving, StaticResolver, CachingResolver};
+
+    /// Counts how often it is asked, answering with 127.0.0.1 or an error.
+    struct Counting(mpsc::Sender<()>, bool);
+
+    impl Resolve for Counting {
+        fn resolve(&mut self, resolving: Resolving) {
+            self.0.send(()).unwrap();
+            if self.1 {
+                resolving.send(Ok(vec!["127.0.0.1".parse().unwrap()]));
+            } else {
+                resolving.send(Err(io::Error::new(io::ErrorKind::Other, "nope")));
+            }
+        }
+    }
+
+    fn start<R: Resolve + 'static>(resolver: R) -> Dns {
+        let mut config = rotor::Config::new();
+        config.slab_capacity(1);
+        let mut notifier = None;
+        {
+            let mut loop_ = rotor::Loop::<rotor::Void>::new(&config).unwrap();
+            loop_.add_machine_with(|scope| {
+                notifier = Some(scope.notifier());
+                rotor::Response::done()
+            }).unwrap();
+        }
+        Dns::new(channel::new(notifier.unwrap()), Box::new(resolver))
+    }
+
+    fn addrs(dns: &Dns) -> io::Result<Vec<IpAddr>> {
+        let (_, res) = dns.resolved().unwrap();
+        res.map(|addrs| addrs.collect())
+    }
+
+    #[test]
+    fn test_static_resolver() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut dns = start(StaticResolver::new().host("Example.Domain", ip));
+        dns.resolve("example.domain");
+        assert_eq!(addrs(&dns).unwrap(), vec![ip]);
+        dns.resolve("other.domain");
+        assert_eq!(addrs(&dns).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let (tx, rx) = mpsc::channel();
+        let mut dns = start(StaticResolver::new().host("example.domain", ip).fallback(Counting(tx, true)));
+        dns.resolve("other.domain");
+        assert_eq!(addrs(&dns).unwrap(), vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_caching_resolver() {
+        let (tx, rx) = mpsc::channel();
+        let mut dns = start(CachingResolver::new(Counting(tx, true)));
+        dns.resolve("example.domain");
+        dns.resolve("example.domain");
+        assert!(addrs(&dns).is_ok());
+        assert!(addrs(&dns).is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_caching_resolver_capacity() {
+        let (tx, rx) = mpsc::channel();
+        let mut dns = start(CachingResolver::new(Counting(tx, true)).capacity(2));
+        dns.resolve("a.domain");
+        dns.resolve("b.domain");
+        // a is used, so b is the least recently used when c is added
+        dns.resolve("a.domain");
+        dns.resolve("c.domain");
+        dns.resolve("a.domain");
+        dns.resolve("b.domain");
+        for _ in 0..6 {
+            assert!(addrs(&dns).is_ok());
+        }
+        // asked for a, b, c, and b again
+        let mut asked = 0;
+        while rx.try_recv().is_ok() {
+            asked += 1;
+        }
+        assert_eq!(asked, 4);
+    }
+
+    #[test]
+    fn test_caching_resolver_expires() {
+        let (tx, rx) = mpsc::channel();
+        let resolver = CachingResolver::new(Counting(tx, false))
+            .negative_ttl(Duration::from_millis(0));
+        let mut dns = start(resolver);
+        dns.resolve("example.domain");
+        dns.resolve("example.domain");
+        assert!(addrs(&dns).is_err());
+        assert!(addrs(&dns).is_err());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,232 @@
+//! Connecting to the first of several addresses that answers, much like
+//! "Happy Eyeballs" (RFC 8305).
+//!
+//! The race is a state of the client's loop. Attempts are started one after
+//! another, each after a short delay or as soon as the one before fails, and
+//! wait on non-blocking sockets together. The first to connect wins, and the
+//! others are closed.
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rotor::{EventSet, PollOpt, Scope, Time};
+use rotor::mio::tcp::TcpStream;
+
+use http::channel;
+use net::HttpStream;
+use super::connect::Connected;
+
+/// Orders addresses to alternate between IPv6 and IPv4, starting with the
+/// family of the first one.
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => is_v6(addr),
+        None => return addrs,
+    };
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs.into_iter()
+        .partition(|addr| is_v6(addr) == first_v6);
+    let mut sorted = Vec::with_capacity(first.len() + second.len());
+    first.reverse();
+    second.reverse();
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => return sorted,
+            (a, b) => {
+                sorted.extend(a);
+                sorted.extend(b);
+            }
+        }
+    }
+}
+
+fn is_v6(addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V6(..) => true,
+        SocketAddr::V4(..) => false,
+    }
+}
+
+/// Connecting to the first of several addresses, as a state of the
+/// client's loop.
+#[doc(hidden)]
+pub struct Race {
+    key: (&'static str, String, u16),
+    addrs: Vec<SocketAddr>,
+    delay: Duration,
+    tx: channel::Sender<Connected>,
+    attempts: Vec<(SocketAddr, TcpStream)>,
+    last_err: Option<io::Error>,
+    next_attempt: Option<Time>,
+    deadline: Option<Time>,
+}
+
+impl Race {
+    /// A race to connect to the first of `addrs` that answers, starting
+    /// another attempt every `delay`.
+    pub fn new(addrs: Vec<SocketAddr>,
+               delay: Duration,
+               key: (&'static str, String, u16),
+               tx: channel::Sender<Connected>) -> Race {
+        Race {
+            key: key,
+            addrs: addrs.into_iter().rev().collect(),
+            delay: delay,
+            tx: tx,
+            attempts: Vec::new(),
+            last_err: None,
+            next_attempt: None,
+            deadline: None,
+        }
+    }
+
+    /// Start the first attempt, giving up on all of them after `timeout`.
+    ///
+    /// Returns `None` once the connection is handed back.
+    pub fn start<X>(mut self, scope: &mut Scope<X>, timeout: Duration) -> Option<Race> {
+        self.deadline = Some(scope.now() + timeout);
+        self.attempt(scope)
+    }
+
+    /// Report that there was no room in the loop to connect.
+    pub fn fail_full(self) {
+        let _ = self.tx.send((self.key, Err(io::Error::new(io::ErrorKind::Other,
+                                                            "too many sockets to connect"))));
+    }
+
+    /// When to start the next attempt, or give up.
+    pub fn deadline(&self) -> Time {
+        let deadline = self.deadline.expect("race not started");
+        match self.next_attempt {
+            Some(next) if next < deadline => next,
+            _ => deadline,
+        }
+    }
+
+    pub fn ready<X>(mut self, _events: EventSet, scope: &mut Scope<X>) -> Option<Race> {
+        // the attempts share this machine, so any of them may be ready
+        let mut failed = false;
+        let mut i = 0;
+        while i < self.attempts.len() {
+            let res = {
+                let stream = &self.attempts[i].1;
+                match stream.take_socket_error() {
+                    Err(e) => Some(Err(e)),
+                    // a socket still connecting has no peer yet
+                    Ok(()) => stream.peer_addr().ok().map(Ok),
+                }
+            };
+            match res {
+                Some(Ok(addr)) => {
+                    let (_, stream) = self.attempts.swap_remove(i);
+                    debug!("connected to {}:{} at {}", self.key.1, self.key.2, addr);
+                    // the loop takes the stream back once it is handed over
+                    let _ = scope.deregister(&stream);
+                    self.close(scope);
+                    let _ = self.tx.send((self.key, Ok(HttpStream(stream))));
+                    return None;
+                }
+                Some(Err(e)) => {
+                    let (addr, stream) = self.attempts.remove(i);
+                    debug!("connect attempt to {} failed: {}", addr, e);
+                    let _ = scope.deregister(&stream);
+                    self.last_err = Some(e);
+                    failed = true;
+                }
+                None => i += 1,
+            }
+        }
+        if failed {
+            // don't wait out the delay once an attempt failed
+            self.attempt(scope)
+        } else {
+            Some(self)
+        }
+    }
+
+    pub fn timeout<X>(mut self, scope: &mut Scope<X>) -> Option<Race> {
+        let now = scope.now();
+        if now >= self.deadline.expect("race not started") {
+            debug!("connect to {}:{} timed out", self.key.1, self.key.2);
+            self.close(scope);
+            let _ = self.tx.send((self.key, Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))));
+            return None;
+        }
+        match self.next_attempt {
+            Some(next) if now >= next => self.attempt(scope),
+            _ => Some(self),
+        }
+    }
+
+    /// Start the next attempt, or report the last error once every attempt
+    /// failed.
+    fn attempt<X>(mut self, scope: &mut Scope<X>) -> Option<Race> {
+        self.next_attempt = None;
+        while let Some(addr) = self.addrs.pop() {
+            trace!("connect attempt to {}", addr);
+            let res = TcpStream::connect(&addr).and_then(|stream| {
+                try!(scope.register(&stream, EventSet::writable(), PollOpt::level()));
+                Ok(stream)
+            });
+            match res {
+                Ok(stream) => {
+                    self.attempts.push((addr, stream));
+                    if !self.addrs.is_empty() {
+                        self.next_attempt = Some(scope.now() + self.delay);
+                    }
+                    return Some(self);
+                }
+                Err(e) => {
+                    debug!("connect attempt to {} failed: {}", addr, e);
+                    self.last_err = Some(e);
+                }
+            }
+        }
+        if !self.attempts.is_empty() {
+            return Some(self);
+        }
+        let err = self.last_err.take().unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+        });
+        let _ = self.tx.send((self.key, Err(err)));
+        None
+    }
+
+    /// Close the attempts still connecting.
+    fn close<X>(&mut self, scope: &mut Scope<X>) {
+        for (_, stream) in self.attempts.drain(..) {
+            let _ = scope.deregister(&stream);
+        }
+    }
+}
+
+impl fmt::Debug for Race {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Race")
+            .field("host", &self.key.1)
+            .field("port", &self.key.2)
+            .field("attempts", &self.attempts.len())
+            .field("remaining", &self.addrs.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::interleave;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave() {
+        assert_eq!(interleave(addrs(&["[::1]:80", "[::2]:80", "[::3]:80", "127.0.0.1:80", "127.0.0.2:80"])),
+                   addrs(&["[::1]:80", "127.0.0.1:80", "[::2]:80", "127.0.0.2:80", "[::3]:80"]));
+        assert_eq!(interleave(addrs(&["127.0.0.1:80", "[::1]:80", "[::2]:80"])),
+                   addrs(&["127.0.0.1:80", "[::1]:80", "[::2]:80"]));
+        assert_eq!(interleave(vec![]), vec![]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Following redirects.
+use header::{Headers, Location};
+use method::Method;
+use url::Url;
+
+/// Which redirect responses a `Client` follows.
+///
+/// A followed redirect is not seen by the `Handler`. Instead, the Handler
+/// is queued again for the URL in the `Location` header, and its
+/// `on_request` is called as for a new request. Afterwards, the method may
+/// be rewritten:
+///
+/// - A `303 See Other` is followed with a `GET`, unless the request was
+///   a `HEAD`.
+/// - A `301 Moved Permanently` or `302 Found` to a `POST` is followed
+///   with a `GET`.
+/// - A `307 Temporary Redirect` or `308 Permanent Redirect` is followed with
+///   the same method, and the Handler writes the same body again.
+///
+/// When the request is rewritten to a `GET`, any body the Handler wants
+/// to write is skipped. When a redirect leads to another origin than the
+/// first request, that is another scheme, host or port, the
+/// `Authorization` and `Cookie` headers are removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedirectPolicy {
+    max: usize,
+    same_origin: bool,
+}
+
+impl RedirectPolicy {
+    /// Follow no redirects, giving every response to the `Handler`.
+    pub fn none() -> RedirectPolicy {
+        RedirectPolicy::limited(0)
+    }
+
+    /// Follow up to `max` redirects for each request. After that, the
+    /// redirect response is given to the `Handler`.
+    pub fn limited(max: usize) -> RedirectPolicy {
+        RedirectPolicy {
+            max: max,
+            same_origin: false,
+        }
+    }
+
+    /// Only follow redirects to the same scheme, host and port.
+    ///
+    /// Default is to follow redirects to any origin.
+    pub fn same_origin(mut self, val: bool) -> RedirectPolicy {
+        self.same_origin = val;
+        self
+    }
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> RedirectPolicy {
+        RedirectPolicy::none()
+    }
+}
+
+/// The state of a request that followed redirects.
+#[derive(Debug, Clone)]
+pub struct Redirected {
+    hops: usize,
+    /// The scheme, host and port of the first request.
+    origin: Origin,
+    method: Option<Method>,
+}
+
+impl Redirected {
+    /// The method replacing the one set by the Handler, if any.
+    pub fn method(&self) -> Option<&Method> {
+        self.method.as_ref()
+    }
+
+    /// Whether credentials must be removed from a request to `url`, because
+    /// it is not to the origin of the first request.
+    pub fn strips_credentials(&self, url: &Url) -> bool {
+        origin(url) != self.origin
+    }
+}
+
+type Origin = (String, Option<String>, Option<u16>);
+
+fn origin(url: &Url) -> Origin {
+    (url.scheme().to_owned(), url.host_str().map(|host| host.to_owned()), url.port_or_known_default())
+}
+
+/// Decides whether to follow a response to a request of `method` to `url`,
+/// returning the URL to follow it to.
+pub fn follow(policy: &RedirectPolicy,
+              prev: Option<&Redirected>,
+              method: &Method,
+              url: &Url,
+              status: u16,
+              headers: &Headers) -> Option<(Url, Redirected)> {
+    let hops = prev.map_or(0, |prev| prev.hops);
+    if hops >= policy.max {
+        return None;
+    }
+    let rewrite = match status {
+        303 => *method != Method::Head,
+        301 | 302 => *method == Method::Post,
+        307 | 308 => false,
+        _ => return None,
+    };
+    let next = match headers.get::<Location>().map(|loc| url.join(loc)) {
+        Some(Ok(next)) => next,
+        Some(Err(e)) => {
+            debug!("redirect with invalid Location: {:?}", e);
+            return None;
+        }
+        None => return None,
+    };
+    match next.scheme() {
+        "http" | "https" => (),
+        _ => return None,
+    }
+    if policy.same_origin && !same_origin(url, &next) {
+        debug!("not following redirect to another origin: {}", next);
+        return None;
+    }
+
+    let redirected = Redirected {
+        hops: hops + 1,
+        origin: match prev {
+            Some(prev) => prev.origin.clone(),
+            None => origin(url),
+        },
+        method: if rewrite {
+            Some(Method::Get)
+        } else {
+            prev.and_then(|prev| prev.method.clone())
+        },
+    };
+    trace!("following {} redirect to {}", status, next);
+    Some((next, redirected))
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    origin(a) == origin(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use header::{Headers, Location};
+    use method::Method;
+    use url::Url;
+    use super::{follow, RedirectPolicy};
+
+    fn location(loc: &str) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(Location(loc.to_owned()));
+        headers
+    }
+
+    #[test]
+    fn test_follow_relative() {
+        let url = Url::parse("http://example.domain/a/b").unwrap();
+        let policy = RedirectPolicy::limited(5);
+        let (next, redirected) = follow(&policy, None, &Method::Get, &url, 302, &location("c?d=e")).unwrap();
+        assert_eq!(next.as_str(), "http://example.domain/a/c?d=e");
+        assert_eq!(redirected.method(), None);
+        assert!(!redirected.strips_credentials(&next));
+
+        assert!(follow(&policy, None, &Method::Get, &url, 302, &Headers::new()).is_none());
+        assert!(follow(&policy, None, &Method::Get, &url, 304, &location("/c")).is_none());
+        assert!(follow(&policy, None, &Method::Get, &url, 302, &location("ftp://example.domain/")).is_none());
+    }
+
+    #[test]
+    fn test_follow_max_hops() {
+        let url = Url::parse("http://example.domain/").unwrap();
+        let policy = RedirectPolicy::limited(2);
+        let (_, first) = follow(&policy, None, &Method::Get, &url, 301, &location("/1")).unwrap();
+        let (_, second) = follow(&policy, Some(&first), &Method::Get, &url, 301, &location("/2")).unwrap();
+        assert!(follow(&policy, Some(&second), &Method::Get, &url, 301, &location("/3")).is_none());
+        assert!(follow(&RedirectPolicy::none(), None, &Method::Get, &url, 301, &location("/1")).is_none());
+    }
+
+    #[test]
+    fn test_follow_method() {
+        let url = Url::parse("http://example.domain/").unwrap();
+        let policy = RedirectPolicy::limited(5);
+        let method = |method: Method, status: u16| {
+            follow(&policy, None, &method, &url, status, &location("/next")).unwrap().1.method().cloned()
+        };
+        assert_eq!(method(Method::Post, 301), Some(Method::Get));
+        assert_eq!(method(Method::Post, 302), Some(Method::Get));
+        assert_eq!(method(Method::Put, 302), None);
+        assert_eq!(method(Method::Put, 303), Some(Method::Get));
+        assert_eq!(method(Method::Head, 303), None);
+        assert_eq!(method(Method::Post, 307), None);
+        assert_eq!(method(Method::Post, 308), None);
+
+        // a rewritten method stays rewritten
+        let (_, first) = follow(&policy, None, &Method::Post, &url, 303, &location("/1")).unwrap();
+        let (_, second) = follow(&policy, Some(&first), &Method::Get, &url, 307, &location("/2")).unwrap();
+        assert_eq!(second.method(), Some(&Method::Get));
+    }
+
+    #[test]
+    fn test_follow_origin() {
+        let url = Url::parse("http://example.domain/").unwrap();
+        let headers = location("https://other.domain/");
+        let (next, redirected) = follow(&RedirectPolicy::limited(5), None, &Method::Get, &url, 302, &headers).unwrap();
+        assert!(redirected.strips_credentials(&next));
+
+        let policy = RedirectPolicy::limited(5).same_origin(true);
+        assert!(follow(&policy, None, &Method::Get, &url, 302, &headers).is_none());
+        assert!(follow(&policy, None, &Method::Get, &url, 302, &location("http://example.domain:8080/")).is_none());
+        assert!(follow(&policy, None, &Method::Get, &url, 302, &location("http://example.domain:80/a")).is_some());
+    }
+
+    #[test]
+    fn test_strips_credentials_on_other_scheme_or_port() {
+        let url = Url::parse("https://example.domain/").unwrap();
+        let policy = RedirectPolicy::limited(5);
+        let (next, redirected) = follow(&policy, None, &Method::Get, &url, 302, &location("http://example.domain/")).unwrap();
+        assert!(redirected.strips_credentials(&next));
+
+        let (next, redirected) = follow(&policy, None, &Method::Get, &url, 302, &location("https://example.domain:8443/")).unwrap();
+        assert!(redirected.strips_credentials(&next));
+
+        let (next, redirected) = follow(&policy, None, &Method::Get, &url, 302, &location("https://example.domain:443/a")).unwrap();
+        assert!(!redirected.strips_credentials(&next));
+
+        // the origin compared is the first request's, not the previous hop's
+        let (next, second) = follow(&policy, Some(&redirected), &Method::Get, &next, 302, &location("http://example.domain/b")).unwrap();
+        assert!(second.strips_credentials(&next));
+        let back = Url::parse("https://example.domain/c").unwrap();
+        assert!(!second.strips_credentials(&back));
+    }
+}
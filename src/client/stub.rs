@@ -0,0 +1,760 @@
+//! A stub resolver, asking nameservers directly from the client's loop.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use rand;
+use rotor::{EventSet, PollOpt, Scope, Time};
+use rotor::mio::tcp::TcpStream;
+use rotor::mio::udp::UdpSocket;
+
+use http::channel;
+use super::dns::{Resolve, Resolving};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION: u16 = 0x0100;
+
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Where a `StubResolver` sends hostnames to be looked up by the client's
+/// loop.
+#[doc(hidden)]
+pub type Lookups = channel::Sender<Query>;
+
+/// Resolves hostnames by asking nameservers itself, without any threads.
+///
+/// The `A` and `AAAA` records of a hostname are queried over UDP, and over
+/// TCP when an answer does not fit in a datagram. Queries are sent from the
+/// loop of the `Client` using the resolver, so that looking up many hosts
+/// at once costs no more than a socket each.
+///
+/// A query not answered within the timeout is sent again, to the next
+/// nameserver, until all attempts are used. Search domains are not used:
+/// hostnames are always looked up as they are.
+///
+/// The resolver only works once registered with a `Client`, through its
+/// `HttpConnector`.
+pub struct StubResolver {
+    nameservers: Vec<SocketAddr>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    timeout: Duration,
+    attempts: usize,
+    lookups: Option<Lookups>,
+}
+
+impl StubResolver {
+    /// Create a resolver with no nameservers and no hosts.
+    pub fn new() -> StubResolver {
+        StubResolver {
+            nameservers: Vec::new(),
+            hosts: HashMap::new(),
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            lookups: None,
+        }
+    }
+
+    /// Create a resolver configured like the system's, from
+    /// `/etc/resolv.conf` and `/etc/hosts`.
+    ///
+    /// As with the system resolver, when no nameservers are configured,
+    /// the one on the local host is used.
+    pub fn system() -> io::Result<StubResolver> {
+        let mut resolver = StubResolver::new();
+        if let Some(conf) = try!(read_file("/etc/resolv.conf")) {
+            resolver = resolver.resolv_conf(&conf);
+        }
+        if resolver.nameservers.is_empty() {
+            resolver = resolver.nameserver(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 53));
+        }
+        if let Some(hosts) = try!(read_file("/etc/hosts")) {
+            resolver = resolver.hosts_file(&hosts);
+        }
+        Ok(resolver)
+    }
+
+    /// Add a nameserver to ask.
+    pub fn nameserver(mut self, addr: SocketAddr) -> StubResolver {
+        self.nameservers.push(addr);
+        self
+    }
+
+    /// Add an address of a hostname, which is then not asked about.
+    pub fn host<H: Into<String>>(mut self, host: H, addr: IpAddr) -> StubResolver {
+        self.hosts.entry(host.into().to_lowercase()).or_insert_with(Vec::new).push(addr);
+        self
+    }
+
+    /// Set how long to wait for an answer before asking again.
+    ///
+    /// Default is 5 seconds.
+    pub fn timeout(mut self, val: Duration) -> StubResolver {
+        self.timeout = val;
+        self
+    }
+
+    /// Set how many times each nameserver is asked.
+    ///
+    /// Default is 2.
+    pub fn attempts(mut self, val: usize) -> StubResolver {
+        self.attempts = val;
+        self
+    }
+
+    /// Add the `nameserver` and `options` lines of a `resolv.conf`.
+    fn resolv_conf(mut self, conf: &str) -> StubResolver {
+        for line in conf.lines() {
+            let mut words = line.split(|c: char| c.is_whitespace()).filter(|w| !w.is_empty());
+            match words.next() {
+                Some("nameserver") => {
+                    if let Some(ip) = words.next().and_then(|ip| ip.parse().ok()) {
+                        self.nameservers.push(SocketAddr::new(ip, 53));
+                    }
+                }
+                Some("options") => {
+                    for option in words {
+                        let mut parts = option.splitn(2, ':');
+                        let (name, val) = (parts.next(), parts.next().and_then(|n| n.parse().ok()));
+                        match (name, val) {
+                            (Some("timeout"), Some(secs)) => self.timeout = Duration::from_secs(secs),
+                            (Some("attempts"), Some(n)) => self.attempts = n as usize,
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        self
+    }
+
+    /// Add the hosts of a `hosts` file.
+    fn hosts_file(mut self, hosts: &str) -> StubResolver {
+        for line in hosts.lines() {
+            let line = line.splitn(2, '#').next().unwrap_or("");
+            let mut words = line.split(|c: char| c.is_whitespace()).filter(|w| !w.is_empty());
+            let ip = match words.next().and_then(|ip| ip.parse().ok()) {
+                Some(ip) => ip,
+                None => continue,
+            };
+            for host in words {
+                self = self.host(host, ip);
+            }
+        }
+        self
+    }
+}
+
+impl Default for StubResolver {
+    fn default() -> StubResolver {
+        StubResolver::new()
+    }
+}
+
+impl fmt::Debug for StubResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StubResolver")
+            .field("nameservers", &self.nameservers)
+            .field("hosts", &self.hosts)
+            .field("timeout", &self.timeout)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+impl Resolve for StubResolver {
+    fn resolve(&mut self, resolving: Resolving) {
+        let found = {
+            let host = resolving.host().trim_left_matches('[').trim_right_matches(']');
+            host.parse().ok().map(|ip| vec![ip])
+                .or_else(|| self.hosts.get(&host.to_lowercase()).cloned())
+        };
+        if let Some(addrs) = found {
+            return resolving.send(Ok(addrs));
+        }
+        if self.nameservers.is_empty() || self.attempts == 0 {
+            return resolving.send(Err(io::Error::new(io::ErrorKind::NotFound, "no nameservers to ask")));
+        }
+        match self.lookups {
+            Some(ref lookups) => {
+                let query = Query {
+                    resolving: resolving,
+                    nameservers: self.nameservers.clone(),
+                    timeout: self.timeout,
+                    attempts: self.attempts,
+                };
+                // the loop is gone, and so are the requests waiting for this
+                let _ = lookups.send(query);
+            }
+            None => {
+                resolving.send(Err(io::Error::new(io::ErrorKind::Other,
+                                                  "stub resolver is not registered with a client")));
+            }
+        }
+    }
+
+    fn register(&mut self, lookups: Lookups) {
+        self.lookups = Some(lookups);
+    }
+}
+
+fn read_file(path: &str) -> io::Result<Option<String>> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            try!(file.read_to_string(&mut contents));
+            Ok(Some(contents))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A hostname for the client's loop to look up.
+#[doc(hidden)]
+pub struct Query {
+    resolving: Resolving,
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+    attempts: usize,
+}
+
+impl Query {
+    /// Answer that the hostname could not be looked up.
+    pub fn fail(self, err: io::Error) {
+        self.resolving.send(Err(err));
+    }
+}
+
+impl fmt::Debug for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Query")
+            .field("host", &self.resolving.host())
+            .field("nameservers", &self.nameservers)
+            .finish()
+    }
+}
+
+/// A hostname being looked up, as a state of the client's loop.
+pub struct Lookup {
+    query: Query,
+    questions: Vec<Question>,
+    socket: Socket,
+    attempt: usize,
+    deadline: Time,
+    not_found: bool,
+}
+
+struct Question {
+    id: u16,
+    qtype: u16,
+    answered: bool,
+    addrs: Vec<IpAddr>,
+}
+
+enum Socket {
+    None,
+    Udp(UdpSocket),
+    Tcp(TcpStream, Vec<u8>, Vec<u8>),
+}
+
+impl Lookup {
+    /// Start asking the first nameserver.
+    ///
+    /// Returns `None` once the hostname is answered.
+    pub fn start<X>(query: Query, scope: &mut Scope<X>) -> Option<Lookup> {
+        let lookup = Lookup {
+            query: query,
+            // AAAA first, to prefer IPv6 as getaddrinfo does
+            questions: vec![TYPE_AAAA, TYPE_A].into_iter().map(|qtype| Question {
+                id: rand::random(),
+                qtype: qtype,
+                answered: false,
+                addrs: Vec::new(),
+            }).collect(),
+            socket: Socket::None,
+            attempt: 0,
+            deadline: scope.now(),
+            not_found: false,
+        };
+        lookup.send(scope)
+    }
+
+    /// When to give up on the current attempt.
+    pub fn deadline(&self) -> Time {
+        self.deadline
+    }
+
+    pub fn ready<X>(mut self, events: EventSet, scope: &mut Scope<X>) -> Option<Lookup> {
+        let server = self.server();
+        let mut replies = Vec::new();
+        let res = match self.socket {
+            Socket::Udp(ref socket) => recv_udp(socket, server, &mut replies),
+            Socket::Tcp(ref mut stream, ref mut out, ref mut buf) => {
+                if events.is_error() || events.is_hup() {
+                    stream.take_socket_error().and_then(|_| {
+                        Err(io::Error::new(io::ErrorKind::ConnectionAborted, "nameserver closed"))
+                    })
+                } else {
+                    exchange_tcp(stream, out, buf, &mut replies)
+                }
+            }
+            Socket::None => Ok(()),
+        };
+        for reply in replies {
+            match self.answer(&reply) {
+                Ok(true) => {
+                    debug!("lookup of {:?} truncated, asking over tcp", self.query.resolving.host());
+                    return self.send_tcp(scope);
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    debug!("lookup of {:?} failed: {}", self.query.resolving.host(), e);
+                    return self.retry(scope);
+                }
+            }
+        }
+        if self.questions.iter().all(|q| q.answered) {
+            return self.finish();
+        }
+        let res = res.and_then(|_| match self.socket {
+            Socket::Tcp(ref stream, ref out, _) => {
+                // once the queries are written, only wait for the replies
+                let interest = if out.is_empty() {
+                    EventSet::readable()
+                } else {
+                    EventSet::readable() | EventSet::writable()
+                };
+                scope.reregister(stream, interest, PollOpt::level())
+            }
+            _ => Ok(()),
+        });
+        match res {
+            Ok(()) => Some(self),
+            Err(e) => {
+                debug!("lookup of {:?} failed: {}", self.query.resolving.host(), e);
+                self.retry(scope)
+            }
+        }
+    }
+
+    pub fn timeout<X>(self, scope: &mut Scope<X>) -> Option<Lookup> {
+        if scope.now() < self.deadline {
+            return Some(self);
+        }
+        debug!("lookup of {:?} timed out, attempt {}", self.query.resolving.host(), self.attempt + 1);
+        self.retry(scope)
+    }
+
+    fn server(&self) -> SocketAddr {
+        self.query.nameservers[self.attempt % self.query.nameservers.len()]
+    }
+
+    /// Ask the nameserver of this attempt, over UDP.
+    fn send<X>(mut self, scope: &mut Scope<X>) -> Option<Lookup> {
+        let server = self.server();
+        let res = self.queries().and_then(|queries| {
+            let local = match server {
+                SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+                SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0),
+            };
+            let socket = try!(UdpSocket::bound(&local));
+            try!(scope.register(&socket, EventSet::readable(), PollOpt::level()));
+            for query in &queries {
+                // a datagram that cannot be sent is as good as lost
+                try!(socket.send_to(query, &server));
+            }
+            Ok(socket)
+        });
+        match res {
+            Ok(socket) => {
+                trace!("lookup of {:?} sent to {}", self.query.resolving.host(), server);
+                self.socket = Socket::Udp(socket);
+                self.deadline = scope.now() + self.query.timeout;
+                Some(self)
+            }
+            Err(e) => {
+                debug!("lookup of {:?} failed to send: {}", self.query.resolving.host(), e);
+                self.retry(scope)
+            }
+        }
+    }
+
+    /// Ask the nameserver of this attempt again, over TCP.
+    fn send_tcp<X>(mut self, scope: &mut Scope<X>) -> Option<Lookup> {
+        let server = self.server();
+        let res = self.queries().and_then(|queries| {
+            let mut out = Vec::new();
+            for query in queries {
+                out.push((query.len() >> 8) as u8);
+                out.push(query.len() as u8);
+                out.extend_from_slice(&query);
+            }
+            let stream = try!(TcpStream::connect(&server));
+            // writable once connected
+            try!(scope.register(&stream, EventSet::writable(), PollOpt::level()));
+            Ok((stream, out))
+        });
+        match res {
+            Ok((stream, out)) => {
+                self.socket = Socket::Tcp(stream, out, Vec::new());
+                self.deadline = scope.now() + self.query.timeout;
+                Some(self)
+            }
+            Err(e) => {
+                debug!("lookup of {:?} failed to connect: {}", self.query.resolving.host(), e);
+                self.retry(scope)
+            }
+        }
+    }
+
+    fn queries(&self) -> io::Result<Vec<Vec<u8>>> {
+        let host = self.query.resolving.host();
+        self.questions.iter()
+            .filter(|q| !q.answered)
+            .map(|q| encode_query(q.id, host, q.qtype))
+            .collect()
+    }
+
+    /// Take in a reply, returning whether it was truncated.
+    fn answer(&mut self, msg: &[u8]) -> io::Result<bool> {
+        let reply = try!(decode_reply(msg));
+        let pos = match self.questions.iter().position(|q| q.id == reply.id && !q.answered) {
+            Some(pos) => pos,
+            None => {
+                trace!("reply to an unknown query {}", reply.id);
+                return Ok(false);
+            }
+        };
+        if reply.truncated {
+            return Ok(true);
+        }
+        match reply.rcode {
+            0 => (),
+            RCODE_NXDOMAIN => self.not_found = true,
+            rcode => return Err(io::Error::new(io::ErrorKind::Other, format!("nameserver error {}", rcode))),
+        }
+        let question = &mut self.questions[pos];
+        question.answered = true;
+        question.addrs = reply.addrs.into_iter().filter(|ip| match (*ip, question.qtype) {
+            (IpAddr::V4(..), TYPE_A) | (IpAddr::V6(..), TYPE_AAAA) => true,
+            _ => false,
+        }).collect();
+        Ok(false)
+    }
+
+    /// Ask the next nameserver, if there are attempts left.
+    fn retry<X>(mut self, scope: &mut Scope<X>) -> Option<Lookup> {
+        self.socket = Socket::None;
+        self.attempt += 1;
+        if self.attempt >= self.query.attempts * self.query.nameservers.len() {
+            if self.questions.iter().any(|q| !q.addrs.is_empty()) {
+                return self.finish();
+            }
+            self.query.fail(io::Error::new(io::ErrorKind::TimedOut, "no nameserver answered"));
+            return None;
+        }
+        self.send(scope)
+    }
+
+    fn finish(self) -> Option<Lookup> {
+        let addrs = self.questions.into_iter()
+            .flat_map(|q| q.addrs.into_iter())
+            .collect::<Vec<_>>();
+        debug!("lookup of {:?} = {:?}", self.query.resolving.host(), addrs);
+        if !addrs.is_empty() {
+            self.query.resolving.send(Ok(addrs));
+        } else if self.not_found {
+            self.query.fail(io::Error::new(io::ErrorKind::NotFound, "host not found"));
+        } else {
+            self.query.fail(io::Error::new(io::ErrorKind::NotFound, "host has no addresses"));
+        }
+        None
+    }
+}
+
+impl fmt::Debug for Lookup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lookup")
+            .field("query", &self.query)
+            .field("attempt", &self.attempt)
+            .finish()
+    }
+}
+
+fn recv_udp(socket: &UdpSocket, server: SocketAddr, replies: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    let mut buf = [0; 512];
+    while let Some((n, from)) = try!(socket.recv_from(&mut buf)) {
+        if from == server {
+            replies.push(buf[..n].to_vec());
+        } else {
+            trace!("ignoring datagram from {}", from);
+        }
+    }
+    Ok(())
+}
+
+fn exchange_tcp(stream: &mut TcpStream,
+                out: &mut Vec<u8>,
+                buf: &mut Vec<u8>,
+                replies: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    while !out.is_empty() {
+        match stream.write(out) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "nameserver closed")),
+            Ok(n) => { out.drain(..n); }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    let mut chunk = [0; 4096];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                take_frames(buf, replies);
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "nameserver closed"));
+            }
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    take_frames(buf, replies);
+    Ok(())
+}
+
+/// Take the messages out of a buffer of length-prefixed ones.
+fn take_frames(buf: &mut Vec<u8>, replies: &mut Vec<Vec<u8>>) {
+    while buf.len() >= 2 {
+        let len = ((buf[0] as usize) << 8) | buf[1] as usize;
+        if buf.len() < 2 + len {
+            break;
+        }
+        replies.push(buf[2..2 + len].to_vec());
+        buf.drain(..2 + len);
+    }
+}
+
+fn encode_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let host = host.trim_right_matches('.');
+    if host.is_empty() || host.len() > 253 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid hostname"));
+    }
+    let mut msg = Vec::with_capacity(18 + host.len());
+    push_u16(&mut msg, id);
+    push_u16(&mut msg, FLAG_RECURSION);
+    push_u16(&mut msg, 1);
+    push_u16(&mut msg, 0);
+    push_u16(&mut msg, 0);
+    push_u16(&mut msg, 0);
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid hostname"));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    push_u16(&mut msg, qtype);
+    push_u16(&mut msg, CLASS_IN);
+    Ok(msg)
+}
+
+fn push_u16(msg: &mut Vec<u8>, val: u16) {
+    msg.push((val >> 8) as u8);
+    msg.push(val as u8);
+}
+
+#[derive(Debug, PartialEq)]
+struct Reply {
+    id: u16,
+    truncated: bool,
+    rcode: u16,
+    addrs: Vec<IpAddr>,
+}
+
+fn decode_reply(msg: &[u8]) -> io::Result<Reply> {
+    let id = try!(read_u16(msg, 0));
+    let flags = try!(read_u16(msg, 2));
+    let questions = try!(read_u16(msg, 4));
+    let answers = try!(read_u16(msg, 6));
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(invalid_reply());
+    }
+    let mut reply = Reply {
+        id: id,
+        truncated: flags & FLAG_TRUNCATED != 0,
+        rcode: flags & 0xf,
+        addrs: Vec::new(),
+    };
+    if reply.truncated {
+        return Ok(reply);
+    }
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = try!(skip_name(msg, pos)) + 4;
+    }
+    for _ in 0..answers {
+        pos = try!(skip_name(msg, pos));
+        let rtype = try!(read_u16(msg, pos));
+        let class = try!(read_u16(msg, pos + 2));
+        let len = try!(read_u16(msg, pos + 8)) as usize;
+        pos += 10;
+        if msg.len() < pos + len {
+            return Err(invalid_reply());
+        }
+        let data = &msg[pos..pos + len];
+        match (rtype, class, len) {
+            (TYPE_A, CLASS_IN, 4) => {
+                reply.addrs.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])));
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let mut segments = [0u16; 8];
+                for (i, segment) in segments.iter_mut().enumerate() {
+                    *segment = ((data[i * 2] as u16) << 8) | data[i * 2 + 1] as u16;
+                }
+                reply.addrs.push(IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                                          segments[4], segments[5], segments[6], segments[7])));
+            }
+            // such as the CNAMEs leading to the addresses
+            _ => (),
+        }
+        pos += len;
+    }
+    Ok(reply)
+}
+
+fn skip_name(msg: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = match msg.get(pos) {
+            Some(&len) => len as usize,
+            None => return Err(invalid_reply()),
+        };
+        if len == 0 {
+            return Ok(pos + 1);
+        } else if len & 0xc0 == 0xc0 {
+            // a pointer ends the name
+            return Ok(pos + 2);
+        }
+        pos += 1 + len;
+    }
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> io::Result<u16> {
+    if msg.len() < pos + 2 {
+        return Err(invalid_reply());
+    }
+    Ok(((msg[pos] as u16) << 8) | msg[pos + 1] as u16)
+}
+
+fn invalid_reply() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid nameserver reply")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::time::Duration;
+
+    use super::{StubResolver, Reply, decode_reply, encode_query, take_frames};
+
+    /// Reply to a query with the addresses as answers.
+    fn reply(query: &[u8], flags: u16, addrs: &[IpAddr]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] = (flags >> 8) as u8;
+        msg[3] = flags as u8;
+        msg[7] = addrs.len() as u8;
+        for addr in addrs {
+            // a pointer to the name in the question
+            msg.extend_from_slice(&[0xc0, 12]);
+            let data = match *addr {
+                IpAddr::V4(ip) => {
+                    msg.extend_from_slice(&[0, 1, 0, 1]);
+                    ip.octets().to_vec()
+                }
+                IpAddr::V6(ip) => {
+                    msg.extend_from_slice(&[0, 28, 0, 1]);
+                    ip.segments().iter().flat_map(|s| vec![(s >> 8) as u8, *s as u8]).collect()
+                }
+            };
+            msg.extend_from_slice(&[0, 0, 0, 60, 0, data.len() as u8]);
+            msg.extend_from_slice(&data);
+        }
+        msg
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "example.domain.", 1).unwrap();
+        assert_eq!(query, b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+                            \x07example\x06domain\x00\x00\x01\x00\x01".to_vec());
+        assert!(encode_query(1, "example..domain", 1).is_err());
+        assert!(encode_query(1, "", 1).is_err());
+    }
+
+    #[test]
+    fn test_decode_reply() {
+        let query = encode_query(7, "example.domain", 1).unwrap();
+        let addrs = vec!["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        assert_eq!(decode_reply(&reply(&query, 0x8180, &addrs)).unwrap(), Reply {
+            id: 7,
+            truncated: false,
+            rcode: 0,
+            addrs: addrs,
+        });
+        assert_eq!(decode_reply(&reply(&query, 0x8183, &[])).unwrap().rcode, 3);
+        assert!(decode_reply(&reply(&query, 0x8380, &[])).unwrap().truncated);
+
+        // not a reply
+        assert!(decode_reply(&query).is_err());
+        // cut short
+        let reply = reply(&query, 0x8180, &["10.0.0.1".parse().unwrap()]);
+        assert!(decode_reply(&reply[..reply.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_take_frames() {
+        let mut buf = b"\x00\x02ab\x00\x03cd".to_vec();
+        let mut replies = Vec::new();
+        take_frames(&mut buf, &mut replies);
+        assert_eq!(replies, vec![b"ab".to_vec()]);
+        assert_eq!(buf, b"\x00\x03cd".to_vec());
+    }
+
+    #[test]
+    fn test_resolv_conf() {
+        let resolver = StubResolver::new().resolv_conf("\
+            # comment\n\
+            nameserver 10.0.0.1\n\
+            nameserver ::1\n\
+            search example.domain\n\
+            options ndots:2 timeout:3 attempts:4\n\
+            ");
+        assert_eq!(resolver.nameservers, vec![
+            "10.0.0.1:53".parse::<SocketAddr>().unwrap(),
+            "[::1]:53".parse::<SocketAddr>().unwrap(),
+        ]);
+        assert_eq!(resolver.timeout, Duration::from_secs(3));
+        assert_eq!(resolver.attempts, 4);
+    }
+
+    #[test]
+    fn test_hosts_file() {
+        let resolver = StubResolver::new().hosts_file("\
+            127.0.0.1\tlocalhost Example.Local # comment\n\
+            ::1 localhost\n\
+            # 10.0.0.1 commented.out\n\
+            ");
+        let localhost: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        assert_eq!(resolver.hosts.get("localhost"), Some(&localhost));
+        assert_eq!(resolver.hosts.get("example.local"), Some(&localhost[..1].to_vec()));
+        assert!(resolver.hosts.get("commented.out").is_none());
+    }
+}
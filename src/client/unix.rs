@@ -0,0 +1,95 @@
+//! Connecting to servers on Unix domain sockets.
+use std::collections::VecDeque;
+use std::io;
+
+use url::Url;
+
+use net::UnixStream;
+use super::Registration;
+use super::connect::Connect;
+
+/// A connector for the `unix` scheme.
+///
+/// The path of a `unix` URL names the socket, followed by a `:` and the
+/// path to request from the server listening on it. A URL of
+/// `unix:///var/run/docker.sock:/v1.24/containers/json` requests
+/// `/v1.24/containers/json` from the socket at `/var/run/docker.sock`.
+/// Without a `:`, `/` is requested.
+#[derive(Debug, Default)]
+pub struct UnixConnector {
+    connected: VecDeque<(String, io::Result<UnixStream>)>,
+}
+
+impl UnixConnector {
+    /// Create a new connector.
+    pub fn new() -> UnixConnector {
+        UnixConnector::default()
+    }
+}
+
+impl Connect for UnixConnector {
+    type Output = UnixStream;
+    type Key = String;
+
+    fn dns_workers(&mut self, _count: usize) {}
+
+    fn key(&self, url: &Url) -> Option<String> {
+        split(url).map(|(socket, _)| socket.to_owned())
+    }
+
+    fn connect(&mut self, url: &Url) -> io::Result<String> {
+        debug!("Unix::connect({:?})", url);
+        match self.key(url) {
+            Some(socket) => {
+                // connecting to a path does not block
+                let res = UnixStream::connect(&socket);
+                self.connected.push_back((socket.clone(), res));
+                Ok(socket)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "scheme must be unix")),
+        }
+    }
+
+    fn connected(&mut self) -> Option<(String, io::Result<UnixStream>)> {
+        self.connected.pop_front()
+    }
+
+    fn register(&mut self, _reg: Registration) {}
+}
+
+/// Splits a `unix` URL into the path of the socket and the path to request.
+pub fn split(url: &Url) -> Option<(&str, &str)> {
+    if url.scheme() != "unix" {
+        return None;
+    }
+    let path = url.path();
+    let (socket, request) = match path.find(':') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => (path, "/"),
+    };
+    if socket.is_empty() {
+        None
+    } else if request.is_empty() {
+        Some((socket, "/"))
+    } else {
+        Some((socket, request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+    use super::split;
+
+    #[test]
+    fn test_split() {
+        let url = Url::parse("unix:///var/run/docker.sock:/v1.24/containers/json?all=1").unwrap();
+        assert_eq!(split(&url), Some(("/var/run/docker.sock", "/v1.24/containers/json")));
+        let url = Url::parse("unix:///var/run/docker.sock").unwrap();
+        assert_eq!(split(&url), Some(("/var/run/docker.sock", "/")));
+        let url = Url::parse("unix:///var/run/docker.sock:").unwrap();
+        assert_eq!(split(&url), Some(("/var/run/docker.sock", "/")));
+        let url = Url::parse("http://example.domain/foo:/bar").unwrap();
+        assert_eq!(split(&url), None);
+    }
+}
@@ -16,11 +16,13 @@ use self::Error::{
     Uri,
     Version,
     Header,
+    Framing,
     Status,
     Timeout,
     Io,
     Ssl,
     TooLarge,
+    UriTooLong,
     Incomplete,
     Utf8
 };
@@ -41,15 +43,21 @@ pub enum Error {
     Version,
     /// An invalid `Header`.
     Header,
+    /// The length of a message body is ambiguous, such as with both
+    /// `Content-Length` and `Transfer-Encoding`.
+    Framing,
     /// A message head is too large to be reasonable.
     TooLarge,
+    /// A request line is too long to be reasonable.
+    UriTooLong,
     /// A message reached EOF, but is not complete.
     Incomplete,
     /// An invalid `Status`, such as `1337 ELITE`.
     Status,
     /// A timeout occurred waiting for an IO event.
     Timeout,
-    /// Event loop is full and cannot process request
+    /// Event loop is full and cannot process request, or too many requests
+    /// are queued for the same host.
     Full,
     /// An `io::Error` that occurred while trying to read or write to a network stream.
     Io(IoError),
@@ -89,7 +97,9 @@ impl StdError for Error {
             Method => "Invalid Method specified",
             Version => "Invalid HTTP version specified",
             Header => "Invalid Header provided",
+            Framing => "Ambiguous message body length",
             TooLarge => "Message head is too large",
+            UriTooLong => "Request URI is too long",
             Status => "Invalid Status provided",
             Incomplete => "Message is incomplete",
             Timeout => "Timeout",
@@ -48,12 +48,19 @@ pub use self::origin::Origin;
 pub use self::pragma::Pragma;
 pub use self::prefer::{Prefer, Preference};
 pub use self::preference_applied::PreferenceApplied;
+pub use self::proxy_authorization::ProxyAuthorization;
 pub use self::range::{Range, ByteRangeSpec};
 pub use self::referer::Referer;
 pub use self::referrer_policy::ReferrerPolicy;
+pub use self::sec_websocket_accept::SecWebSocketAccept;
+pub use self::sec_websocket_key::SecWebSocketKey;
+pub use self::sec_websocket_protocol::SecWebSocketProtocol;
+pub use self::sec_websocket_version::SecWebSocketVersion;
 pub use self::server::Server;
 pub use self::set_cookie::SetCookie;
 pub use self::strict_transport_security::StrictTransportSecurity;
+pub use self::te::Te;
+pub use self::trailer::Trailer;
 pub use self::transfer_encoding::TransferEncoding;
 pub use self::upgrade::{Upgrade, Protocol, ProtocolName};
 pub use self::user_agent::UserAgent;
@@ -411,12 +418,19 @@ mod origin;
 mod pragma;
 mod prefer;
 mod preference_applied;
+mod proxy_authorization;
 mod range;
 mod referer;
 mod referrer_policy;
+mod sec_websocket_accept;
+mod sec_websocket_key;
+mod sec_websocket_protocol;
+mod sec_websocket_version;
 mod server;
 mod set_cookie;
 mod strict_transport_security;
+mod te;
+mod trailer;
 mod transfer_encoding;
 mod upgrade;
 mod user_agent;
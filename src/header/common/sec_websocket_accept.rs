@@ -0,0 +1,27 @@
+header! {
+    /// `Sec-WebSocket-Accept` header, defined in
+    /// [RFC6455](https://tools.ietf.org/html/rfc6455#section-11.3.3)
+    ///
+    /// The `Sec-WebSocket-Accept` header is sent by a server to accept the
+    /// opening handshake of a WebSocket connection. Its value is derived
+    /// from the `Sec-WebSocket-Key` sent by the client, and is computed by
+    /// `hyper::websocket::accept_key`.
+    ///
+    /// # Example values
+    /// * `s3pPLMBiTxaQ9kYGzzhZRbK+xOo=`
+    ///
+    /// # Example
+    /// ```
+    /// use hyper::header::{Headers, SecWebSocketAccept};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(SecWebSocketAccept("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_owned()));
+    /// ```
+    (SecWebSocketAccept, "Sec-WebSocket-Accept") => [String]
+
+    test_sec_websocket_accept {
+        // Testcase from the RFC
+        test_header!(test1, vec![b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo="],
+            Some(SecWebSocketAccept("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_owned())));
+    }
+}
@@ -0,0 +1,27 @@
+header! {
+    /// `Sec-WebSocket-Key` header, defined in
+    /// [RFC6455](https://tools.ietf.org/html/rfc6455#section-11.3.1)
+    ///
+    /// The `Sec-WebSocket-Key` header is sent by a client in the opening
+    /// handshake of a WebSocket connection. It is a base64-encoded random
+    /// 16-byte nonce, which the server proves it received by answering with
+    /// a `Sec-WebSocket-Accept` header.
+    ///
+    /// # Example values
+    /// * `dGhlIHNhbXBsZSBub25jZQ==`
+    ///
+    /// # Example
+    /// ```
+    /// use hyper::header::{Headers, SecWebSocketKey};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(SecWebSocketKey("dGhlIHNhbXBsZSBub25jZQ==".to_owned()));
+    /// ```
+    (SecWebSocketKey, "Sec-WebSocket-Key") => [String]
+
+    test_sec_websocket_key {
+        // Testcase from the RFC
+        test_header!(test1, vec![b"dGhlIHNhbXBsZSBub25jZQ=="],
+            Some(SecWebSocketKey("dGhlIHNhbXBsZSBub25jZQ==".to_owned())));
+    }
+}
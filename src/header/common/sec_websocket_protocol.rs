@@ -0,0 +1,29 @@
+header! {
+    /// `Sec-WebSocket-Protocol` header, defined in
+    /// [RFC6455](https://tools.ietf.org/html/rfc6455#section-11.3.4)
+    ///
+    /// The `Sec-WebSocket-Protocol` header is sent by a client to list the
+    /// subprotocols it can speak over a WebSocket connection, in order of
+    /// preference. A server answers with the single subprotocol it selected.
+    ///
+    /// # ABNF
+    /// ```plain
+    /// Sec-WebSocket-Protocol = 1#token
+    /// ```
+    ///
+    /// # Example values
+    /// * `chat, superchat`
+    ///
+    /// # Example
+    /// ```
+    /// use hyper::header::{Headers, SecWebSocketProtocol};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(SecWebSocketProtocol(vec!["chat".to_owned()]));
+    /// ```
+    (SecWebSocketProtocol, "Sec-WebSocket-Protocol") => (String)+
+
+    test_sec_websocket_protocol {
+        test_header!(test1, vec![b"chat, superchat"]);
+    }
+}
@@ -0,0 +1,26 @@
+header! {
+    /// `Sec-WebSocket-Version` header, defined in
+    /// [RFC6455](https://tools.ietf.org/html/rfc6455#section-11.3.5)
+    ///
+    /// The `Sec-WebSocket-Version` header is sent by a client to indicate
+    /// the version of the WebSocket protocol it wishes to speak. The only
+    /// version defined by RFC6455 is `13`. A server that does not support
+    /// the requested version answers with the versions it does support.
+    ///
+    /// # Example values
+    /// * `13`
+    ///
+    /// # Example
+    /// ```
+    /// use hyper::header::{Headers, SecWebSocketVersion};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(SecWebSocketVersion(13));
+    /// ```
+    (SecWebSocketVersion, "Sec-WebSocket-Version") => [u8]
+
+    test_sec_websocket_version {
+        test_header!(test1, vec![b"13"], Some(SecWebSocketVersion(13)));
+        test_header!(test2, vec![b"thirteen"], None);
+    }
+}
@@ -0,0 +1,76 @@
+use std::ascii::AsciiExt;
+
+use header::{Encoding, QualityItem, qitem};
+
+header! {
+    /// `TE` header, defined in
+    /// [RFC7230](http://tools.ietf.org/html/rfc7230#section-4.3)
+    ///
+    /// The `TE` header field in a request indicates what transfer codings,
+    /// besides chunked, the client is willing to accept in response, and
+    /// whether or not the client is willing to accept trailer fields in a
+    /// chunked transfer coding.
+    ///
+    /// # ABNF
+    /// ```plain
+    /// TE        = #t-codings
+    /// t-codings = "trailers" / ( transfer-coding [ t-ranking ] )
+    /// ```
+    ///
+    /// # Example values
+    /// * `trailers`
+    /// * `trailers, deflate;q=0.5`
+    /// * ``
+    ///
+    /// # Examples
+    /// ```
+    /// use hyper::header::{Headers, Te};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(Te::trailers());
+    /// ```
+    /// ```
+    /// use hyper::header::{Headers, Te, Encoding, QualityItem, Quality, qitem};
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(
+    ///     Te(vec![
+    ///         qitem(Encoding::EncodingExt("trailers".to_owned())),
+    ///         QualityItem::new(Encoding::Deflate, Quality(500)),
+    ///     ])
+    /// );
+    /// ```
+    (Te, "TE") => (QualityItem<Encoding>)*
+
+    test_te {
+        // From the RFC
+        test_header!(test1, vec![b""], Some(Te(vec![])));
+        test_header!(test2, vec![b"trailers"]);
+        test_header!(test3, vec![b"trailers, deflate;q=0.5"]);
+
+        #[test]
+        fn test_accepts_trailers() {
+            assert!(Te::trailers().accepts_trailers());
+            assert!(!Te(vec![qitem(Encoding::Deflate)]).accepts_trailers());
+            let te: Te = Header::parse_header(&b"deflate, Trailers".as_ref().into()).unwrap();
+            assert!(te.accepts_trailers());
+        }
+    }
+}
+
+impl Te {
+    /// Constructor for the most common `TE` value, `trailers`.
+    pub fn trailers() -> Te {
+        Te(vec![qitem(Encoding::EncodingExt("trailers".to_owned()))])
+    }
+
+    /// Whether the `trailers` keyword is present.
+    pub fn accepts_trailers(&self) -> bool {
+        self.0.iter().any(|item| match item.item {
+            Encoding::EncodingExt(ref s) => s.eq_ignore_ascii_case("trailers"),
+            _ => false
+        })
+    }
+}
+
+bench_header!(bench, Te, { vec![b"trailers, deflate;q=0.5".to_vec()] });
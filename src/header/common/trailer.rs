@@ -0,0 +1,48 @@
+use unicase::UniCase;
+
+header! {
+    /// `Trailer` header, defined in
+    /// [RFC7230](http://tools.ietf.org/html/rfc7230#section-4.4)
+    ///
+    /// When a message includes a message body encoded with the chunked
+    /// transfer coding and the sender desires to send metadata in the form
+    /// of trailer fields at the end of the message, the sender should
+    /// generate a `Trailer` header field before the message body to indicate
+    /// which fields will be present in the trailers.
+    ///
+    /// # ABNF
+    /// ```plain
+    /// Trailer = 1#field-name
+    /// ```
+    ///
+    /// # Example values
+    /// * `grpc-status, grpc-message`
+    ///
+    /// # Example
+    /// ```
+    /// # extern crate hyper;
+    /// # extern crate unicase;
+    /// # fn main() {
+    /// // extern crate unicase;
+    ///
+    /// use hyper::header::{Headers, Trailer};
+    /// use unicase::UniCase;
+    ///
+    /// let mut headers = Headers::new();
+    /// headers.set(
+    ///     Trailer(vec![
+    ///         UniCase("grpc-status".to_owned()),
+    ///         UniCase("grpc-message".to_owned()),
+    ///     ])
+    /// );
+    /// # }
+    /// ```
+    (Trailer, "Trailer") => (UniCase<String>)+
+
+    test_trailer {
+        test_header!(test1, vec![b"grpc-status, grpc-message"]);
+        test_header!(test2, vec![b"Digest"]);
+    }
+}
+
+bench_header!(bench, Trailer, { vec![b"grpc-status, grpc-message".to_vec()] });
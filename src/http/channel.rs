@@ -94,3 +94,11 @@ impl<T: Send> Receiver<T> {
         self.rx.try_recv()
     }
 }
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("awake", &self.awake.load(Ordering::Relaxed))
+            .finish()
+    }
+}
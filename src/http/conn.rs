@@ -1,14 +1,17 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fmt;
 use std::hash::Hash;
 use std::io;
 use std::marker::PhantomData;
 use std::mem;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rotor::{self, EventSet, PollOpt, Scope};
 
-use http::{self, h1, Http1Message, Encoder, Decoder, Next, Next_, Reg, Control};
+use header::{Connection, ContentLength, TransferEncoding};
+
+use http::{self, h1, h2, Http1Message, Http2Message, Encoder, Decoder, Next, Next_, Reg, Control};
 use http::channel;
 use http::internal::WriteBuf;
 use http::buffer::Buffer;
@@ -17,6 +20,28 @@ use version::HttpVersion;
 
 const MAX_BUFFER_SIZE: usize = 8192 + 4096 * 100;
 
+/// Max number of times a single HTTP/2 stream's handler is called in a
+/// row, so one busy stream cannot starve the others.
+const MAX_H2_DELIVER: usize = 16;
+
+/// The interim response sent to a request with `Expect: 100-continue`.
+const CONTINUE: &'static [u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// How long a client holds a request body waiting for a `100 Continue`,
+/// before sending it anyway.
+const CONTINUE_TIMEOUT_MS: u64 = 1000;
+
+/// How long a client has to read the response to a message that could not
+/// be read, before the connection is closed anyway.
+const REJECT_TIMEOUT_MS: u64 = 5000;
+
+/// How long the rest of a rejected message is read and discarded, once the
+/// response was written, before the connection is closed anyway.
+const LINGER_TIMEOUT_MS: u64 = 2000;
+
+/// How much of the rest of a rejected message is read and discarded.
+const LINGER_MAX_BYTES: usize = 256 * 1024;
+
 /// This handles a connection, which will have been established over a
 /// Transport (like a socket), and will likely include multiple
 /// `Message`s over HTTP.
@@ -34,6 +59,9 @@ struct ConnInner<K: Key, T: Transport, H: MessageHandler<T>> {
     buf: Buffer,
     ctrl: (channel::Sender<Next>, channel::Receiver<Next>),
     keep_alive_enabled: bool,
+    http2_enabled: bool,
+    /// Whether an HTTP/1 message has been started on this connection before.
+    reused: bool,
     key: K,
     state: State<H, T>,
     transport: T,
@@ -43,6 +71,8 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> fmt::Debug for ConnInner<K, T,
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Conn")
             .field("keep_alive_enabled", &self.keep_alive_enabled)
+            .field("http2_enabled", &self.http2_enabled)
+            .field("reused", &self.reused)
             .field("state", &self.state)
             .field("buf", &self.buf)
             .finish()
@@ -66,6 +96,8 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
             State::Init { interest, .. } => {
                 interest.register()
             }
+            State::Reject(..) => Reg::Write,
+            State::Linger(..) => Reg::Read,
             State::Http1(Http1 { reading: Reading::Closed, writing: Writing::Closed, .. }) => {
                 Reg::Remove
             }
@@ -81,6 +113,7 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
 
                 let write = match *writing {
                     Writing::Head |
+                    Writing::Interim(..) |
                     Writing::Chunk(..) |
                     Writing::Ready(..) => Reg::Write,
                     Writing::Init |
@@ -97,6 +130,15 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                     _ => unreachable!("bad read/write reg combo")
                 }
             }
+            State::Http2(ref http2) => {
+                if http2.is_done() {
+                    Reg::Remove
+                } else if http2.wants_write() {
+                    Reg::ReadWrite
+                } else {
+                    Reg::Read
+                }
+            }
         }
     }
 
@@ -136,7 +178,6 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
             },
             None => {
                 if self.buf.len() >= MAX_BUFFER_SIZE {
-                    //TODO: Handler.on_too_large_error()
                     debug!("MAX_BUFFER_SIZE reached, closing");
                     Err(::Error::TooLarge)
                 } else {
@@ -149,6 +190,11 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
     fn read<F: MessageHandlerFactory<K, T, Output=H>>(&mut self, scope: &mut Scope<F>, state: State<H, T>) -> State<H, T> {
          match state {
             State::Init { interest: Next_::Read, .. } => {
+                if self.transport.negotiated_protocol() == Some(h2::ALPN_ID) {
+                    trace!("negotiated h2 with ALPN");
+                    let timeout = scope.keep_alive_interest().timeout;
+                    return self.read_h2(scope, Http2::new(h2::Connection::server(), HttpVersion::H2, timeout));
+                }
                 let head = match self.parse() {
                     Ok(head) => head,
                     Err(::Error::Io(e)) => match e.kind() {
@@ -159,20 +205,34 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                             return State::Closed;
                         }
                     },
+                    Err(::Error::Version) if self.http2_enabled && h2::is_preface(self.buf.bytes()) => {
+                        trace!("detected http2 connection preface");
+                        let timeout = scope.keep_alive_interest().timeout;
+                        return self.read_h2(scope, Http2::new(h2::Connection::server(), HttpVersion::H2c, timeout));
+                    }
                     Err(e) => {
-                        //TODO: send proper error codes depending on error
                         trace!("parse eror: {:?}", e);
-                        return State::Closed;
+                        return self.reject(scope, &e);
                     }
                 };
-                let mut handler = match scope.create(Seed(&self.key, &self.ctrl.0)) {
+                let mut handler = match scope.create(Seed(&self.key, &self.ctrl.0, self.reused)) {
                     Some(handler) => handler,
                     None => unreachable!()
                 };
+                self.reused = true;
                 match H::Message::decoder(&head) {
                     Ok(decoder) => {
                         trace!("decoder = {:?}", decoder);
+                        // a request with a body would need to be read as
+                        // HTTP/1 first, so those just ignore the Upgrade
+                        if self.http2_enabled && decoder.is_eof() {
+                            if let Some(settings) = <<H as MessageHandler<T>>::Message as Http2Message>::h2c_upgrade(&head) {
+                                return self.upgrade_h2c(scope, handler, head, settings);
+                            }
+                        }
                         let keep_alive = self.keep_alive_enabled && head.should_keep_alive();
+                        let expects_continue = head.expects_continue() && !decoder.is_eof();
+                        let interim = head.version != HttpVersion::Http10;
                         let next = handler.on_incoming(head, &self.transport);
                         trace!("handler.on_incoming() -> {:?}", next);
 
@@ -180,9 +240,16 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                             Next_::Read => self.read(scope, State::Http1(Http1 {
                                 handler: handler,
                                 reading: Reading::Body(decoder),
-                                writing: Writing::Init,
+                                writing: if expects_continue {
+                                    Writing::Interim(Interim::continue_100(false))
+                                } else {
+                                    Writing::Init
+                                },
                                 keep_alive: keep_alive,
                                 timeout: next.timeout,
+                                upgraded: false,
+                                interim: interim,
+                                continuing: Continue::No,
                                 _marker: PhantomData,
                             })),
                             Next_::Write => State::Http1(Http1 {
@@ -199,14 +266,26 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                                 writing: Writing::Head,
                                 keep_alive: keep_alive,
                                 timeout: next.timeout,
+                                upgraded: false,
+                                interim: interim,
+                                // answering before asking for the body
+                                // means the client never sends it
+                                continuing: Continue::No,
                                 _marker: PhantomData,
                             }),
                             Next_::ReadWrite => self.read(scope, State::Http1(Http1 {
                                 handler: handler,
                                 reading: Reading::Body(decoder),
-                                writing: Writing::Head,
+                                writing: if expects_continue {
+                                    Writing::Interim(Interim::continue_100(true))
+                                } else {
+                                    Writing::Head
+                                },
                                 keep_alive: keep_alive,
                                 timeout: next.timeout,
+                                upgraded: false,
+                                interim: interim,
+                                continuing: Continue::No,
                                 _marker: PhantomData,
                             })),
                             Next_::Wait => State::Http1(Http1 {
@@ -215,6 +294,13 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                                 writing: Writing::Init,
                                 keep_alive: keep_alive,
                                 timeout: next.timeout,
+                                upgraded: false,
+                                interim: interim,
+                                continuing: if expects_continue {
+                                    Continue::Owed
+                                } else {
+                                    Continue::No
+                                },
                                 _marker: PhantomData,
                             }),
                             Next_::End |
@@ -223,11 +309,9 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                     },
                     Err(e) => {
                         debug!("error creating decoder: {:?}", e);
-                        //TODO: update state from returned Next
-                        //this would allow a Server to respond with a proper
-                        //4xx code
+                        let state = self.reject(scope, &e);
                         let _ = handler.on_error(e);
-                        State::Closed
+                        state
                     }
                 }
             },
@@ -239,6 +323,18 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                 let next = match http1.reading {
                     Reading::Init => None,
                     Reading::Parse => match self.parse() {
+                        Ok(ref head) if H::Message::is_interim(head) => {
+                            trace!("interim response {:?}", head);
+                            http1.handler.on_interim(head);
+                            if H::Message::is_continue(head) {
+                                match mem::replace(&mut http1.continuing, Continue::No) {
+                                    Continue::Held(next) => Some(next),
+                                    _ => None,
+                                }
+                            } else {
+                                None
+                            }
+                        },
                         Ok(head) => match H::Message::decoder(&head) {
                             Ok(decoder) => {
                                 trace!("decoder = {:?}", decoder);
@@ -247,8 +343,18 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                                 if http1.keep_alive {
                                     http1.keep_alive = head.should_keep_alive();
                                 }
+                                if let Continue::Held(..) = http1.continuing {
+                                    trace!("final response before 100 Continue, dropping request body");
+                                    http1.continuing = Continue::No;
+                                    http1.keep_alive = false;
+                                    http1.writing = Writing::Closed;
+                                }
                                 let next = http1.handler.on_incoming(head, &self.transport);
                                 http1.reading = Reading::Wait(decoder);
+                                if http1.handler.is_upgrade() {
+                                    trace!("response switched protocols");
+                                    http1.upgrade();
+                                }
                                 trace!("handler.on_incoming() -> {:?}", next);
                                 Some(next)
                             },
@@ -263,6 +369,13 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                             io::ErrorKind::Interrupted => None,
                             _ => {
                                 debug!("io error trying to parse {:?}", e);
+                                // closed before a single byte of the response
+                                let err = if e.kind() == io::ErrorKind::UnexpectedEof && self.buf.is_empty() {
+                                    ::Error::Incomplete
+                                } else {
+                                    ::Error::Io(e)
+                                };
+                                let _ = http1.handler.on_error(err);
                                 return State::Closed;
                             }
                         },
@@ -303,6 +416,32 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                     s
                 }
             },
+            State::Http2(http2) => self.read_h2(scope, http2),
+            State::Reject(..) => {
+                trace!("on_readable State::Reject");
+                state
+            }
+            State::Linger(mut discarded) => {
+                trace!("on_readable State::Linger");
+                let mut buf = [0; 4096];
+                loop {
+                    match self.transport.read(&mut buf) {
+                        Ok(0) => return State::Closed,
+                        Ok(n) => {
+                            discarded += n;
+                            if discarded >= LINGER_MAX_BYTES {
+                                debug!("closing rejected connection still sending");
+                                return State::Closed;
+                            }
+                        }
+                        Err(e) => match e.kind() {
+                            io::ErrorKind::WouldBlock => return State::Linger(discarded),
+                            io::ErrorKind::Interrupted => (),
+                            _ => return State::Closed,
+                        }
+                    }
+                }
+            }
             State::Closed => {
                 trace!("on_readable State::Closed");
                 State::Closed
@@ -310,26 +449,119 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
         }
     }
 
-    fn write<F: MessageHandlerFactory<K, T, Output=H>>(&mut self, scope: &mut Scope<F>, mut state: State<H, T>) -> State<H, T> {
+    /// Answers a message that could not be read, if there is a response
+    /// for the error, and closes the connection.
+    fn reject<F: MessageHandlerFactory<K, T, Output=H>>(&self, scope: &Scope<F>, err: &::Error) -> State<H, T> {
+        match H::Message::on_error(err) {
+            Some(mut head) => {
+                debug!("rejecting message: {:?}", err);
+                let body = scope.reject_body(err, &mut head);
+                // the body is sent as is, so it may not be chunked
+                head.headers.remove::<TransferEncoding>();
+                head.headers.set(Connection::close());
+                head.headers.set(ContentLength(body.len() as u64));
+                let mut buf = Vec::new();
+                let _ = H::Message::encode(head, &mut buf);
+                buf.extend_from_slice(&body);
+                State::Reject(WriteBuf {
+                    bytes: buf,
+                    pos: 0,
+                })
+            }
+            None => State::Closed,
+        }
+    }
+
+    fn write<F: MessageHandlerFactory<K, T, Output=H>>(&mut self, scope: &mut Scope<F>, state: State<H, T>) -> State<H, T> {
+        let mut state = match state {
+            State::Http2(http2) => return self.write_h2(scope, http2),
+            State::Reject(mut buf) => {
+                trace!("State::Reject on_writable");
+                match self.transport.write(&buf.bytes[buf.pos..]) {
+                    Ok(n) => {
+                        buf.pos += n;
+                        if buf.pos == buf.bytes.len() {
+                            // closing while the rest of the message is
+                            // unread would reset the connection, and the
+                            // client could lose the response, so read it
+                            // for a while first (RFC 7230, section 6.6).
+                            // Without a shutdown, the client can't tell the
+                            // response ended, and the timeout closes it.
+                            if let Err(e) = self.transport.shutdown_write() {
+                                trace!("shutdown after rejection: {:?}", e);
+                            }
+                            return State::Linger(0);
+                        }
+                    }
+                    Err(e) => match e.kind() {
+                        io::ErrorKind::WouldBlock |
+                        io::ErrorKind::Interrupted => (),
+                        _ => {
+                            debug!("io error writing rejection {:?}", e);
+                            return State::Closed;
+                        }
+                    }
+                }
+                return State::Reject(buf);
+            }
+            state => state,
+        };
         let next = match state {
             State::Init { interest: Next_::Write, .. } => {
                 // this is a Client request, which writes first, so pay
                 // attention to the version written here, which will adjust
                 // our internal state to Http1 or Http2
-                let mut handler = match scope.create(Seed(&self.key, &self.ctrl.0)) {
+                let mut handler = match scope.create(Seed(&self.key, &self.ctrl.0, self.reused)) {
                     Some(handler) => handler,
                     None => {
                         trace!("could not create handler {:?}", self.key);
                         return State::Closed;
                     }
                 };
+                self.reused = true;
                 let mut head = http::MessageHead::default();
                 let mut interest = handler.on_outgoing(&mut head);
+                if self.transport.negotiated_protocol() == Some(h2::ALPN_ID) {
+                    // the server picked HTTP/2 during the TLS handshake,
+                    // no matter what version the handler asked for
+                    head.version = HttpVersion::H2;
+                }
+                if head.version == HttpVersion::H2 || head.version == HttpVersion::H2c {
+                    // The handler was given the Control of this connection,
+                    // so it now belongs to the first stream. The connection
+                    // gets a new one, used to ask it to open more streams.
+                    let conn_ctrl = channel::share(&self.ctrl.0);
+                    let stream_ctrl = mem::replace(&mut self.ctrl, conn_ctrl);
+                    let mut http2 = Http2::new(h2::Connection::client(), head.version, None);
+                    let mut stream = Stream::new(handler, http2.conn.open_stream(), stream_ctrl);
+                    let (fields, len) = <<H as MessageHandler<T>>::Message as Http2Message>::encode_fields(head);
+                    http2.conn.send_headers(&mut stream.h2, fields, len);
+                    stream.writing = StreamWriting::Wait;
+                    if stream.update(&mut http2.conn, interest) {
+                        http2.streams.insert(stream.h2.id(), stream);
+                    }
+                    return self.write_h2(scope, http2);
+                }
                 if head.version == HttpVersion::Http11 {
                     let mut buf = Vec::new();
                     let keep_alive = self.keep_alive_enabled && head.should_keep_alive();
+                    let expects_continue = head.expects_continue();
                     let mut encoder = H::Message::encode(head, &mut buf);
+                    let mut continuing = Continue::No;
                     let writing = match interest.interest {
+                        // only the head goes out, and the body waits for
+                        // the server to ask for it
+                        Next_::Write |
+                        Next_::ReadWrite if expects_continue && !encoder.is_eof() => {
+                            trace!("holding request body until 100 Continue");
+                            continuing = Continue::Held(interest);
+                            interest = Next::read().timeout(Duration::from_millis(CONTINUE_TIMEOUT_MS));
+                            Writing::Chunk(Chunk {
+                                buf: Cow::Owned(buf),
+                                pos: 0,
+                                next: (encoder, interest.clone())
+                            })
+                        },
                         // user wants to write some data right away
                         // try to write the headers and the first chunk
                         // together, so they are in the same packet
@@ -354,6 +586,9 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                         handler: handler,
                         keep_alive: keep_alive,
                         timeout: interest.timeout,
+                        upgraded: false,
+                        interim: true,
+                        continuing: continuing,
                         _marker: PhantomData,
                     })
                 }
@@ -363,8 +598,15 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                 trace!("Conn.on_writable State::{:?}", state);
                 None
             }
-            State::Http1(Http1 { ref mut handler, ref mut writing, ref mut keep_alive, .. }) => {
-                match *writing {
+            State::Http2(..) => unreachable!("http2 handled above"),
+            State::Reject(..) => unreachable!("rejection handled above"),
+            State::Linger(..) => {
+                trace!("on_writable State::Linger");
+                None
+            }
+            State::Http1(ref mut http1) => {
+                let Http1 { ref mut handler, ref mut reading, ref mut writing, ref mut keep_alive, ref mut upgraded, interim, ref mut continuing, .. } = *http1;
+                let next = match *writing {
                     Writing::Init => {
                         trace!("Conn.on_writable Http1::Writing::Init");
                         None
@@ -372,35 +614,75 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                     Writing::Head => {
                         let mut head = http::MessageHead::default();
                         let mut interest = handler.on_outgoing(&mut head);
-                        // if the request wants to close, server cannot stop it
-                        if *keep_alive {
-                            // if the request wants to stay alive, then it depends
-                            // on the server to agree
-                            *keep_alive = head.should_keep_alive();
-                        }
-                        let mut buf = Vec::new();
-                        let mut encoder = <<H as MessageHandler<T>>::Message as Http1Message>::encode(head, &mut buf);
-                        *writing = match interest.interest {
-                            // user wants to write some data right away
-                            // try to write the headers and the first chunk
-                            // together, so they are in the same packet
-                            Next_::Write |
-                            Next_::ReadWrite => {
-                                encoder.prefix(WriteBuf {
-                                    bytes: buf,
-                                    pos: 0
-                                });
-                                interest = handler.on_encode(&mut Encoder::h1(&mut encoder, &mut self.transport));
-                                Writing::Ready(encoder)
-                            },
-                            _ => Writing::Chunk(Chunk {
+                        if handler.is_interim() {
+                            trace!("response head is informational");
+                            let mut buf = Vec::new();
+                            if interim {
+                                <<H as MessageHandler<T>>::Message as Http1Message>::encode_interim(head, &mut buf);
+                            } else {
+                                debug!("dropping informational head for an HTTP/1.0 request");
+                            }
+                            *writing = Writing::Interim(Interim {
                                 buf: Cow::Owned(buf),
                                 pos: 0,
-                                next: (encoder, interest.clone())
-                            })
-                        };
+                                head: false,
+                            });
+                        } else {
+                            // if the request wants to close, server cannot stop it
+                            if *keep_alive {
+                                // if the request wants to stay alive, then it depends
+                                // on the server to agree
+                                *keep_alive = head.should_keep_alive();
+                            }
+                            let mut buf = Vec::new();
+                            let mut encoder = if handler.is_upgrade() {
+                                trace!("response switches protocols");
+                                *keep_alive = false;
+                                *upgraded = true;
+                                *reading = Reading::Wait(h1::Decoder::eof());
+                                <<H as MessageHandler<T>>::Message as Http1Message>::encode_upgrade(head, &mut buf)
+                            } else {
+                                <<H as MessageHandler<T>>::Message as Http1Message>::encode(head, &mut buf)
+                            };
+                            *writing = match interest.interest {
+                                // user wants to write some data right away
+                                // try to write the headers and the first chunk
+                                // together, so they are in the same packet
+                                Next_::Write |
+                                Next_::ReadWrite => {
+                                    encoder.prefix(WriteBuf {
+                                        bytes: buf,
+                                        pos: 0
+                                    });
+                                    interest = handler.on_encode(&mut Encoder::h1(&mut encoder, &mut self.transport));
+                                    Writing::Ready(encoder)
+                                },
+                                _ => Writing::Chunk(Chunk {
+                                    buf: Cow::Owned(buf),
+                                    pos: 0,
+                                    next: (encoder, interest.clone())
+                                })
+                            };
+                        }
                         Some(interest)
                     },
+                    Writing::Interim(ref mut interim) => {
+                        trace!("Http1.Interim on_writable");
+                        match self.transport.write(&interim.buf.as_ref()[interim.pos..]) {
+                            Ok(n) => {
+                                interim.pos += n;
+                                trace!("Http1.Interim wrote={}, done={}", n, interim.is_written());
+                                None
+                            },
+                            Err(e) => match e.kind() {
+                                io::ErrorKind::WouldBlock |
+                                io::ErrorKind::Interrupted => None,
+                                _ => {
+                                    Some(handler.on_error(e.into()))
+                                }
+                            }
+                        }
+                    },
                     Writing::Chunk(ref mut chunk) => {
                         trace!("Http1.Chunk on_writable");
                         match self.transport.write(&chunk.buf.as_ref()[chunk.pos..]) {
@@ -438,7 +720,26 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
                         trace!("on_writable Http1::Writing::Closed");
                         None
                     }
+                };
+                let written = match *writing {
+                    Writing::Interim(ref interim) if interim.is_written() => Some(interim.head),
+                    _ => None,
+                };
+                if let Some(head) = written {
+                    *writing = if head {
+                        Writing::Head
+                    } else {
+                        Writing::Init
+                    };
+                    // the 100 Continue waited for the head before it
+                    if let Reading::Body(..) = *reading {
+                        if let Continue::Owed = *continuing {
+                            *continuing = Continue::No;
+                            *writing = Writing::Interim(Interim::continue_100(head));
+                        }
+                    }
                 }
+                next
             },
             State::Closed => {
                 trace!("on_writable State::Closed");
@@ -455,6 +756,11 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
     fn can_read_more(&self, was_init: bool) -> bool {
         match self.state {
             State::Init { .. } => !was_init && !self.buf.is_empty(),
+            // frames are all parsed out of the buffer on each read, so any
+            // bytes left are an incomplete frame
+            State::Http2(..) => false,
+            // the rest of a rejected message is never parsed
+            State::Reject(..) | State::Linger(..) => false,
             _ => !self.buf.is_empty()
         }
     }
@@ -464,7 +770,24 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
         trace!("on_error state = {:?}", self.state);
         let next = match self.state {
             State::Init { .. } => Next::remove(),
-            State::Http1(ref mut http1) => http1.handler.on_error(err),
+            State::Http1(ref mut http1) => match (err, mem::replace(&mut http1.continuing, Continue::No)) {
+                (::Error::Timeout, Continue::Held(next)) => {
+                    trace!("no 100 Continue in time, sending request body");
+                    next
+                },
+                (err, continuing) => {
+                    http1.continuing = continuing;
+                    http1.handler.on_error(err)
+                }
+            },
+            State::Http2(ref mut http2) => {
+                if http2.on_error(err) {
+                    return;
+                }
+                Next::remove()
+            }
+            State::Reject(..) |
+            State::Linger(..) |
             State::Closed => Next::remove(),
         };
         self.state.update(next, factory);
@@ -489,11 +812,389 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> ConnInner<K, T, H> {
     fn on_remove(self) {
         debug!("on_remove");
         match self.state {
-            State::Init { .. } | State::Closed => (),
-            State::Http1(http1) => http1.handler.on_remove(self.transport),
+            State::Init { .. } | State::Reject(..) | State::Linger(..) | State::Closed => (),
+            State::Http1(http1) => {
+                if http1.upgraded {
+                    let buf = self.buf.bytes().to_vec();
+                    http1.handler.on_upgrade(http::Upgraded::new(self.transport, buf));
+                } else {
+                    http1.handler.on_remove(self.transport);
+                }
+            }
+            // streams don't own the transport, so their handlers are dropped
+            State::Http2(..) => (),
+        }
+    }
+
+    /// Switches to HTTP/2 after an `Upgrade: h2c` request, which becomes
+    /// stream 1.
+    fn upgrade_h2c<F>(&mut self, scope: &mut Scope<F>, mut handler: H, mut head: http::MessageHead<<<H as MessageHandler<T>>::Message as Http1Message>::Incoming>, settings: Vec<(u16, u32)>) -> State<H, T>
+    where F: MessageHandlerFactory<K, T, Output=H> {
+        trace!("upgrading to h2c, settings={:?}", settings);
+        let conn = match h2::Connection::server_upgrade(&settings) {
+            Ok(conn) => conn,
+            Err(reason) => {
+                debug!("invalid HTTP2-Settings: {:?}", reason);
+                let _ = handler.on_error(::Error::Header);
+                return State::Closed;
+            }
+        };
+        let timeout = scope.keep_alive_interest().timeout;
+        let mut http2 = Http2::new(conn, HttpVersion::H2c, timeout);
+        let mut h2_stream = match http2.conn.accept_stream(1, 0) {
+            Ok(stream) => stream,
+            Err(reason) => unreachable!("stream 1 is always acceptable: {:?}", reason),
+        };
+        // the request was complete as HTTP/1
+        h2_stream.recv_eos();
+
+        // the handler was given the Control of this connection, which now
+        // belongs to stream 1
+        let conn_ctrl = channel::share(&self.ctrl.0);
+        let stream_ctrl = mem::replace(&mut self.ctrl, conn_ctrl);
+        head.version = HttpVersion::H2c;
+        let next = handler.on_incoming(head, &self.transport);
+        trace!("h2 stream 1 handler.on_incoming() -> {:?}", next);
+        let mut stream = Stream::new(handler, h2_stream, stream_ctrl);
+        stream.head_received = true;
+        stream.reading = StreamReading::Wait;
+        if stream.update(&mut http2.conn, next) {
+            http2.streams.insert(1, stream);
+        }
+        self.read_h2(scope, http2)
+    }
+
+    fn read_h2<F>(&mut self, scope: &mut Scope<F>, mut http2: Http2<H, T>) -> State<H, T>
+    where F: MessageHandlerFactory<K, T, Output=H> {
+        match self.buf.read_from(&mut self.transport) {
+            Ok(0) => {
+                trace!("h2 read eof");
+                http2.abort(io::ErrorKind::UnexpectedEof, "connection closed");
+                return State::Closed;
+            }
+            Ok(_) => (),
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock |
+                io::ErrorKind::Interrupted => (),
+                _ => {
+                    debug!("io error trying to read h2 frames {:?}", e);
+                    http2.abort(e.kind(), "connection error");
+                    return State::Closed;
+                }
+            }
+        }
+
+        if http2.conn.is_awaiting_preface() {
+            match http2.conn.read_preface(self.buf.bytes()) {
+                Ok(0) => return State::Http2(http2),
+                Ok(n) => self.buf.consume(n),
+                Err(reason) => return self.h2_conn_error(http2, reason),
+            }
+        }
+
+        loop {
+            let (frame, len) = match h2::frame::parse(self.buf.bytes(), http2.conn.max_recv_frame_size()) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => break,
+                Err(reason) => return self.h2_conn_error(http2, reason),
+            };
+            self.buf.consume(len);
+            if let Err(reason) = self.h2_frame(scope, &mut http2, frame) {
+                return self.h2_conn_error(http2, reason);
+            }
+        }
+
+        self.h2_deliver(&mut http2);
+        self.h2_flush(http2)
+    }
+
+    fn h2_frame<F>(&mut self, scope: &mut Scope<F>, http2: &mut Http2<H, T>, frame: h2::Frame) -> Result<(), h2::Reason>
+    where F: MessageHandlerFactory<K, T, Output=H> {
+        use http::h2::{Frame, Reason};
+
+        if http2.conn.is_expecting_continuation() {
+            if let Frame::Continuation { .. } = frame {} else {
+                debug!("expected CONTINUATION, received {:?}", frame);
+                return Err(Reason::ProtocolError);
+            }
+        }
+
+        match frame {
+            Frame::Settings { ack: true, .. } => trace!("h2 settings acknowledged"),
+            Frame::Settings { ack: false, params } => {
+                let delta = try!(http2.conn.recv_settings(&params));
+                if delta != 0 {
+                    for stream in http2.streams.values_mut() {
+                        stream.h2.adjust_send_window(delta);
+                    }
+                }
+            }
+            Frame::Ping { ack: false, payload } => http2.conn.recv_ping(&payload),
+            Frame::Ping { ack: true, .. } => (),
+            Frame::GoAway { last_stream_id, reason } => {
+                debug!("h2 GOAWAY received, last_stream_id={}, reason={:?}", last_stream_id, reason);
+                http2.conn.recv_go_away();
+                // streams above last_stream_id were never processed by the peer
+                let refused = http2.streams.keys()
+                    .filter(|id| **id > last_stream_id)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for id in refused {
+                    http2.remove_stream(id, Reason::RefusedStream.into_io().into());
+                }
+            }
+            Frame::WindowUpdate { stream_id: 0, increment } => {
+                if increment == 0 {
+                    debug!("h2 connection WINDOW_UPDATE with an increment of 0");
+                    return Err(Reason::ProtocolError);
+                }
+                try!(http2.conn.recv_window_update(increment));
+            }
+            Frame::WindowUpdate { stream_id, increment } => {
+                let err = match http2.streams.get_mut(&stream_id) {
+                    Some(_) if increment == 0 => Some(Reason::ProtocolError),
+                    Some(stream) => stream.h2.recv_window_update(increment).err(),
+                    None => None,
+                };
+                if let Some(reason) = err {
+                    http2.reset_stream(stream_id, reason);
+                }
+            }
+            Frame::RstStream { stream_id, reason } => {
+                debug!("h2 stream {} reset by peer: {:?}", stream_id, reason);
+                http2.remove_stream(stream_id, reason.into_io().into());
+            }
+            Frame::Data { stream_id, data, flow_len, end_stream } => {
+                try!(http2.conn.recv_data(flow_len));
+                let err = match http2.streams.get_mut(&stream_id) {
+                    Some(stream) => stream.h2.recv_data(&data, flow_len, end_stream).err(),
+                    None => {
+                        if http2.conn.is_idle_stream(stream_id) {
+                            return Err(Reason::ProtocolError);
+                        }
+                        Some(Reason::StreamClosed)
+                    }
+                };
+                if let Some(reason) = err {
+                    http2.reset_stream(stream_id, reason);
+                }
+            }
+            Frame::Headers { stream_id, block, end_stream, end_headers } => {
+                if end_headers {
+                    try!(self.h2_headers(scope, http2, stream_id, block, end_stream));
+                } else {
+                    try!(http2.conn.start_continuation(stream_id, block, end_stream));
+                }
+            }
+            Frame::Continuation { stream_id, block, end_headers } => {
+                if let Some((block, end_stream)) = try!(http2.conn.recv_continuation(stream_id, &block, end_headers)) {
+                    try!(self.h2_headers(scope, http2, stream_id, block, end_stream));
+                }
+            }
+            // server push is disabled in our SETTINGS
+            Frame::PushPromise { .. } => return Err(Reason::ProtocolError),
+            Frame::Priority { .. } |
+            Frame::Unknown => (),
+        }
+        Ok(())
+    }
+
+    fn h2_headers<F>(&mut self, scope: &mut Scope<F>, http2: &mut Http2<H, T>, id: h2::StreamId, block: Vec<u8>, end_stream: bool) -> Result<(), h2::Reason>
+    where F: MessageHandlerFactory<K, T, Output=H> {
+        use http::h2::Reason;
+
+        // the block must always be decoded, to keep the HPACK state in sync
+        let fields = try!(http2.conn.decode_headers(&block));
+
+        if http2.streams.contains_key(&id) {
+            let mut reset = None;
+            {
+                let stream = http2.streams.get_mut(&id).unwrap();
+                if !stream.head_received {
+                    match <<H as MessageHandler<T>>::Message as Http2Message>::decode_fields(fields) {
+                        Ok(ref head) if <<H as MessageHandler<T>>::Message as Http2Message>::is_informational(head) => {
+                            trace!("h2 stream {} informational head", id);
+                            stream.handler.on_interim(head);
+                            return Ok(());
+                        }
+                        Ok(head) => {
+                            stream.head_received = true;
+                            stream.pending_head = Some(head);
+                        }
+                        Err(e) => {
+                            debug!("h2 stream {} invalid head: {:?}", id, e);
+                            reset = Some((Reason::ProtocolError, e));
+                        }
+                    }
+                } else if !end_stream {
+                    debug!("h2 stream {} trailers without END_STREAM", id);
+                    reset = Some((Reason::ProtocolError, ::Error::Header));
+                } else if let Err(e) = stream.h2.recv_trailers(fields) {
+                    debug!("h2 stream {} invalid trailers: {:?}", id, e);
+                    reset = Some((Reason::ProtocolError, e));
+                }
+                if end_stream {
+                    stream.h2.recv_eos();
+                }
+            }
+            if let Some((reason, err)) = reset {
+                http2.conn.send_reset(id, reason);
+                http2.remove_stream(id, err);
+            }
+            return Ok(());
+        }
+
+        if http2.conn.is_client() || !http2.conn.is_idle_stream(id) {
+            if http2.conn.is_idle_stream(id) {
+                return Err(Reason::ProtocolError);
+            }
+            http2.conn.send_reset(id, Reason::StreamClosed);
+            return Ok(());
+        }
+
+        let mut h2_stream = match http2.conn.accept_stream(id, http2.streams.len()) {
+            Ok(stream) => stream,
+            Err(Reason::RefusedStream) => {
+                debug!("h2 stream {} refused", id);
+                http2.conn.send_reset(id, Reason::RefusedStream);
+                return Ok(());
+            }
+            Err(reason) => return Err(reason),
+        };
+        if end_stream {
+            h2_stream.recv_eos();
+        }
+        let head = match <<H as MessageHandler<T>>::Message as Http2Message>::decode_fields(fields) {
+            Ok(head) => head,
+            Err(e) => {
+                debug!("h2 stream {} invalid head: {:?}", id, e);
+                http2.conn.send_reset(id, Reason::ProtocolError);
+                return Ok(());
+            }
+        };
+        let ctrl = channel::share(&self.ctrl.0);
+        let mut handler = match scope.create(Seed(&self.key, &ctrl.0, false)) {
+            Some(handler) => handler,
+            None => {
+                http2.conn.send_reset(id, Reason::RefusedStream);
+                return Ok(());
+            }
+        };
+        let next = handler.on_incoming(head, &self.transport);
+        trace!("h2 stream {} handler.on_incoming() -> {:?}", id, next);
+        let mut stream = Stream::new(handler, h2_stream, ctrl);
+        stream.head_received = true;
+        stream.reading = StreamReading::Wait;
+        if stream.update(&mut http2.conn, next) {
+            http2.streams.insert(id, stream);
+        }
+        Ok(())
+    }
+
+    /// Calls `on_incoming` and `on_decode` of streams that have something
+    /// for their handler.
+    fn h2_deliver(&mut self, http2: &mut Http2<H, T>) {
+        let Http2 { ref mut conn, ref mut streams, .. } = *http2;
+        let mut done = Vec::new();
+        for (id, stream) in streams.iter_mut() {
+            for _ in 0..MAX_H2_DELIVER {
+                let next = match stream.reading {
+                    StreamReading::Head if stream.pending_head.is_some() => {
+                        let head = stream.pending_head.take().unwrap();
+                        stream.reading = StreamReading::Wait;
+                        stream.handler.on_incoming(head, &self.transport)
+                    }
+                    StreamReading::Body if stream.h2.is_readable() => {
+                        stream.handler.on_decode(&mut Decoder::h2(&mut stream.h2, &self.transport))
+                    }
+                    _ => break,
+                };
+                trace!("h2 stream {} handler -> {:?}", id, next);
+                if !stream.update(conn, next) {
+                    done.push(*id);
+                    break;
+                }
+            }
+            conn.release_capacity(Some(&mut stream.h2));
+        }
+        for id in done {
+            streams.remove(&id);
+        }
+        conn.release_capacity(None);
+    }
+
+    fn write_h2<F>(&mut self, scope: &mut Scope<F>, mut http2: Http2<H, T>) -> State<H, T>
+    where F: MessageHandlerFactory<K, T, Output=H> {
+        if http2.opening {
+            while http2.conn.stream_capacity(http2.streams.len()) > 0 {
+                let ctrl = channel::share(&self.ctrl.0);
+                let handler = match scope.create(Seed(&self.key, &ctrl.0, false)) {
+                    Some(handler) => handler,
+                    None => break,
+                };
+                let mut stream = Stream::new(handler, http2.conn.open_stream(), ctrl);
+                trace!("h2 opened stream {}", stream.h2.id());
+                stream.writing = StreamWriting::Head;
+                http2.streams.insert(stream.h2.id(), stream);
+            }
+            http2.opening = false;
+        }
+
+        {
+            let Http2 { ref mut conn, ref mut streams, version, .. } = http2;
+            let mut done = Vec::new();
+            for (id, stream) in streams.iter_mut() {
+                let next = match stream.writing {
+                    StreamWriting::Head => {
+                        let mut head = http::MessageHead::default();
+                        head.version = version;
+                        let next = stream.handler.on_outgoing(&mut head);
+                        let (fields, len) = <<H as MessageHandler<T>>::Message as Http2Message>::encode_fields(head);
+                        if stream.handler.is_interim() {
+                            // the final head still follows
+                            conn.send_headers(&mut stream.h2, fields, None);
+                            stream.writing = StreamWriting::Init;
+                        } else {
+                            conn.send_headers(&mut stream.h2, fields, len);
+                            stream.writing = StreamWriting::Wait;
+                        }
+                        next
+                    }
+                    StreamWriting::Ready if conn.can_send(&stream.h2) => {
+                        stream.handler.on_encode(&mut Encoder::h2(&mut stream.h2, &mut *conn, &mut self.transport))
+                    }
+                    _ => continue,
+                };
+                trace!("h2 stream {} handler -> {:?}", id, next);
+                if !stream.update(conn, next) {
+                    done.push(*id);
+                }
+            }
+            for id in done {
+                streams.remove(&id);
+            }
         }
+
+        self.h2_deliver(&mut http2);
+        self.h2_flush(http2)
+    }
+
+    fn h2_flush(&mut self, mut http2: Http2<H, T>) -> State<H, T> {
+        if let Err(e) = http2.conn.flush(&mut self.transport) {
+            debug!("io error trying to write h2 frames {:?}", e);
+            http2.abort(e.kind(), "connection error");
+            return State::Closed;
+        }
+        State::Http2(http2)
     }
 
+    fn h2_conn_error(&mut self, mut http2: Http2<H, T>, reason: h2::Reason) -> State<H, T> {
+        debug!("h2 connection error: {:?}", reason);
+        http2.conn.send_go_away(reason);
+        let _ = http2.conn.flush(&mut self.transport);
+        http2.abort(io::ErrorKind::ConnectionAborted, "http2 connection error");
+        State::Closed
+    }
 }
 
 pub enum ReadyResult<C> {
@@ -507,6 +1208,8 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> Conn<K, T, H> {
             buf: Buffer::new(),
             ctrl: channel::new(notify),
             keep_alive_enabled: true,
+            http2_enabled: false,
+            reused: false,
             key: key,
             state: State::Init {
                 interest: next.interest,
@@ -521,6 +1224,13 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> Conn<K, T, H> {
         self
     }
 
+    /// Whether an incoming connection may switch to HTTP/2, from either the
+    /// connection preface or an `Upgrade: h2c` request.
+    pub fn http2(mut self, val: bool) -> Conn<K, T, H> {
+        self.0.http2_enabled = val;
+        self
+    }
+
     pub fn ready<F>(
         mut self,
         events: EventSet,
@@ -617,6 +1327,9 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> Conn<K, T, H> {
             trace!("woke up with {:?}", next);
             self.0.state.update(next, &**scope);
         }
+        if let State::Http2(ref mut http2) = self.0.state {
+            http2.wakeup_streams();
+        }
 
         let mut conn = Some(self);
         loop {
@@ -654,11 +1367,22 @@ impl<K: Key, T: Transport, H: MessageHandler<T>> Conn<K, T, H> {
         }
     }
 
+    /// How many more messages could be started on this connection now.
+    pub fn capacity(&self) -> usize {
+        match self.0.state {
+            State::Init { interest: Next_::Wait, .. } => 1,
+            State::Http2(ref http2) => http2.conn.stream_capacity(http2.streams.len()),
+            _ => 0
+        }
+    }
+
     pub fn is_idle(&self) -> bool {
-        if let State::Init { interest: Next_::Wait, .. } = self.0.state {
-            true
-        } else {
-            false
+        match self.0.state {
+            State::Init { interest: Next_::Wait, .. } => true,
+            State::Http2(ref http2) => {
+                http2.conn.is_client() && !http2.conn.is_going_away() && http2.streams.is_empty()
+            }
+            _ => false
         }
     }
 }
@@ -677,7 +1401,14 @@ enum State<H: MessageHandler<T>, T: Transport> {
     /// when we've identified a certain message, we must always parse frame
     /// head to determine if the incoming frame is part of a current message,
     /// or a new one. This also means we could have multiple messages at once.
-    //Http2 {},
+    Http2(Http2<H, T>),
+    /// A message could not be read, and its response is written before
+    /// the connection is closed.
+    Reject(WriteBuf<Vec<u8>>),
+    /// The response to a rejected message was written, and the rest of
+    /// the message is read and discarded, counting the bytes, until the
+    /// client closes the connection.
+    Linger(usize),
     Closed,
 }
 
@@ -687,6 +1418,9 @@ impl<H: MessageHandler<T>, T: Transport> State<H, T> {
         match *self {
             State::Init { timeout, .. } => timeout,
             State::Http1(ref http1) => http1.timeout,
+            State::Http2(ref http2) => http2.timeout(),
+            State::Reject(..) => Some(Duration::from_millis(REJECT_TIMEOUT_MS)),
+            State::Linger(..) => Some(Duration::from_millis(LINGER_TIMEOUT_MS)),
             State::Closed => None,
         }
     }
@@ -702,6 +1436,16 @@ impl<H: MessageHandler<T>, T: Transport> fmt::Debug for State<H, T> {
             State::Http1(ref h1) => f.debug_tuple("Http1")
                 .field(h1)
                 .finish(),
+            State::Http2(ref h2) => f.debug_tuple("Http2")
+                .field(h2)
+                .finish(),
+            State::Reject(ref buf) => f.debug_struct("Reject")
+                .field("pos", &buf.pos)
+                .field("len", &buf.bytes.len())
+                .finish(),
+            State::Linger(discarded) => f.debug_tuple("Linger")
+                .field(&discarded)
+                .finish(),
             State::Closed => f.write_str("Closed")
         }
     }
@@ -718,6 +1462,10 @@ impl<H: MessageHandler<T>, T: Transport> State<H, T> {
             match (state, next.interest) {
                 (_, Next_::Remove) |
                 (State::Closed, _) => return, // Keep State::Closed.
+                (state @ State::Reject(..), _) |
+                (state @ State::Linger(..), _) => {
+                    mem::replace(self, state);
+                }
                 (State::Init { .. }, e) => {
                     mem::replace(self,
                                  State::Init {
@@ -775,6 +1523,14 @@ impl<H: MessageHandler<T>, T: Transport> State<H, T> {
                             };
 
                             trace!("(reading, writing) -> {:?}", (&reading, &writing));
+                            if http1.upgraded {
+                                // keep the handler around, so on_remove can
+                                // hand it the transport
+                                http1.reading = Reading::Closed;
+                                http1.writing = writing;
+                                mem::replace(self, State::Http1(http1));
+                                return;
+                            }
                             match (reading, writing) {
                                 (Reading::KeepAlive, Writing::KeepAlive) => {
                                     let next = factory.keep_alive_interest();
@@ -830,11 +1586,19 @@ impl<H: MessageHandler<T>, T: Transport> State<H, T> {
                                 }
                                 same => same,
                             };
+                            http1.send_continue();
                         }
                         Next_::Write => {
+                            if let Continue::Owed = http1.continuing {
+                                http1.continuing = Continue::No;
+                            }
                             http1.writing = match http1.writing {
                                 Writing::Wait(encoder) => Writing::Ready(encoder),
                                 Writing::Init => Writing::Head,
+                                Writing::Interim(mut interim) => {
+                                    interim.head = true;
+                                    Writing::Interim(interim)
+                                }
                                 Writing::Chunk(chunk) => {
                                     if chunk.is_written() {
                                         Writing::Ready(chunk.next.0)
@@ -869,6 +1633,10 @@ impl<H: MessageHandler<T>, T: Transport> State<H, T> {
                             http1.writing = match http1.writing {
                                 Writing::Wait(encoder) => Writing::Ready(encoder),
                                 Writing::Init => Writing::Head,
+                                Writing::Interim(mut interim) => {
+                                    interim.head = true;
+                                    Writing::Interim(interim)
+                                }
                                 Writing::Chunk(chunk) => {
                                     if chunk.is_written() {
                                         Writing::Ready(chunk.next.0)
@@ -878,6 +1646,7 @@ impl<H: MessageHandler<T>, T: Transport> State<H, T> {
                                 }
                                 same => same,
                             };
+                            http1.send_continue();
                         }
                         Next_::Wait => {
                             http1.reading = match http1.reading {
@@ -901,6 +1670,16 @@ impl<H: MessageHandler<T>, T: Transport> State<H, T> {
                     http1.timeout = timeout;
                     mem::replace(self, State::Http1(http1));
                 }
+                (State::Http2(mut http2), next_) => {
+                    // Nexts for streams come through their own Control, so
+                    // on the connection a write means to open more streams.
+                    match next_ {
+                        Next_::Write |
+                        Next_::ReadWrite => http2.opening = true,
+                        _ => trace!("Http2 ignoring connection {:?}", next_),
+                    }
+                    mem::replace(self, State::Http2(http2));
+                }
             };
         }
 }
@@ -913,6 +1692,13 @@ struct Http1<H, T> {
     writing: Writing,
     keep_alive: bool,
     timeout: Option<Duration>,
+    /// The message switched protocols, so reading and writing pass bytes
+    /// through as is, and ending hands the transport to the handler.
+    upgraded: bool,
+    /// Whether informational heads may be written, which an HTTP/1.0
+    /// client would not understand (RFC 7231, section 6.2).
+    interim: bool,
+    continuing: Continue,
     _marker: PhantomData<T>,
 }
 
@@ -923,10 +1709,59 @@ impl<H, T> fmt::Debug for Http1<H, T> {
             .field("writing", &self.writing)
             .field("keep_alive", &self.keep_alive)
             .field("timeout", &self.timeout)
+            .field("upgraded", &self.upgraded)
+            .field("interim", &self.interim)
+            .field("continuing", &self.continuing)
             .finish()
     }
 }
 
+impl<H, T> Http1<H, T> {
+    /// Switches a client connection to pass bytes through, after a response
+    /// that switched protocols.
+    fn upgrade(&mut self) {
+        self.keep_alive = false;
+        self.upgraded = true;
+        self.reading = Reading::Wait(h1::Decoder::eof());
+        self.writing = match mem::replace(&mut self.writing, Writing::Closed) {
+            // the request is still being written
+            Writing::Chunk(chunk) => Writing::Chunk(chunk),
+            _ => Writing::Wait(h1::Encoder::eof()),
+        };
+    }
+
+    /// Starts writing the `100 Continue` a server owes, now that the handler
+    /// wants the request body. Once the response head is under way, the
+    /// client gets that instead.
+    fn send_continue(&mut self) {
+        if let Continue::Owed = self.continuing {
+            let head = match self.writing {
+                Writing::Init => false,
+                Writing::Head => true,
+                // sent once the informational head being written is done
+                Writing::Interim(..) => return,
+                _ => {
+                    self.continuing = Continue::No;
+                    return;
+                }
+            };
+            self.continuing = Continue::No;
+            self.writing = Writing::Interim(Interim::continue_100(head));
+        }
+    }
+}
+
+/// Where a message is in an `Expect: 100-continue` exchange.
+#[derive(Debug)]
+enum Continue {
+    No,
+    /// The server has not yet asked the client for the request body.
+    Owed,
+    /// The client is holding the request body until the server answers,
+    /// and then carries on with the handler's interest.
+    Held(Next),
+}
+
 #[derive(Debug)]
 enum Reading {
     Init,
@@ -941,6 +1776,7 @@ enum Reading {
 enum Writing {
     Init,
     Head,
+    Interim(Interim),
     Chunk(Chunk) ,
     Ready(h1::Encoder),
     Wait(h1::Encoder),
@@ -948,6 +1784,30 @@ enum Writing {
     Closed
 }
 
+/// An informational head, such as `100 Continue`, written before the next
+/// head of the same message.
+#[derive(Debug)]
+struct Interim {
+    buf: Cow<'static, [u8]>,
+    pos: usize,
+    /// The handler asked to write the next head once this one is done.
+    head: bool,
+}
+
+impl Interim {
+    fn continue_100(head: bool) -> Interim {
+        Interim {
+            buf: Cow::Borrowed(CONTINUE),
+            pos: 0,
+            head: head,
+        }
+    }
+
+    fn is_written(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
 #[derive(Debug)]
 struct Chunk {
     buf: Cow<'static, [u8]>,
@@ -961,18 +1821,314 @@ impl Chunk {
     }
 }
 
+struct Http2<H: MessageHandler<T>, T: Transport> {
+    conn: h2::Connection,
+    streams: HashMap<h2::StreamId, Stream<H, T>>,
+    version: HttpVersion,
+    /// The connection was asked to open streams for queued messages.
+    opening: bool,
+    /// Timeout while there are no streams.
+    idle_timeout: Option<Duration>,
+}
+
+impl<H: MessageHandler<T>, T: Transport> fmt::Debug for Http2<H, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Http2")
+            .field("conn", &self.conn)
+            .field("streams", &self.streams)
+            .field("version", &self.version)
+            .field("opening", &self.opening)
+            .finish()
+    }
+}
+
+impl<H: MessageHandler<T>, T: Transport> Http2<H, T> {
+    fn new(conn: h2::Connection, version: HttpVersion, idle_timeout: Option<Duration>) -> Http2<H, T> {
+        Http2 {
+            conn: conn,
+            streams: HashMap::new(),
+            version: version,
+            opening: false,
+            idle_timeout: idle_timeout,
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        if self.streams.is_empty() {
+            return self.idle_timeout;
+        }
+        let now = Instant::now();
+        self.streams.values().filter_map(|stream| stream.deadline).min().map(|deadline| {
+            if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        self.conn.is_going_away() && self.streams.is_empty() && !self.conn.has_pending_write()
+    }
+
+    fn wants_write(&self) -> bool {
+        if self.conn.has_pending_write() {
+            return true;
+        }
+        if self.opening && self.conn.stream_capacity(self.streams.len()) > 0 {
+            return true;
+        }
+        self.streams.values().any(|stream| match stream.writing {
+            StreamWriting::Head => true,
+            StreamWriting::Ready => self.conn.can_send(&stream.h2),
+            _ => false,
+        })
+    }
+
+    /// Removes a stream, telling its handler why.
+    fn remove_stream(&mut self, id: h2::StreamId, err: ::Error) {
+        if let Some(mut stream) = self.streams.remove(&id) {
+            let _ = stream.handler.on_error(err);
+        }
+    }
+
+    /// Sends a `RST_STREAM` and removes the stream.
+    fn reset_stream(&mut self, id: h2::StreamId, reason: h2::Reason) {
+        debug!("h2 stream {} reset: {:?}", id, reason);
+        self.conn.send_reset(id, reason);
+        self.remove_stream(id, reason.into_io().into());
+    }
+
+    /// Tells every stream the connection is gone.
+    fn abort(&mut self, kind: io::ErrorKind, msg: &str) {
+        for (_, mut stream) in self.streams.drain() {
+            let _ = stream.handler.on_error(io::Error::new(kind, msg).into());
+        }
+    }
+
+    /// Returns true if the connection can be kept after the error.
+    fn on_error(&mut self, err: ::Error) -> bool {
+        match err {
+            ::Error::Timeout => {
+                if self.streams.is_empty() {
+                    debug!("h2 connection idle timeout");
+                    self.conn.send_go_away(h2::Reason::NoError);
+                    return true;
+                }
+                // only the streams whose own deadline passed time out
+                let now = Instant::now();
+                let Http2 { ref mut conn, ref mut streams, .. } = *self;
+                let mut done = Vec::new();
+                for (id, stream) in streams.iter_mut() {
+                    if stream.deadline.map_or(false, |deadline| deadline <= now) {
+                        let next = stream.handler.on_error(::Error::Timeout);
+                        if !stream.update(conn, next) {
+                            done.push(*id);
+                        }
+                    }
+                }
+                for id in done {
+                    streams.remove(&id);
+                }
+                true
+            }
+            ::Error::Io(e) => {
+                self.abort(e.kind(), "connection error");
+                false
+            }
+            _ => {
+                self.abort(io::ErrorKind::Other, "connection error");
+                false
+            }
+        }
+    }
+
+    fn wakeup_streams(&mut self) {
+        let Http2 { ref mut conn, ref mut streams, .. } = *self;
+        let mut done = Vec::new();
+        for (id, stream) in streams.iter_mut() {
+            while let Ok(next) = stream.ctrl.1.try_recv() {
+                trace!("h2 stream {} woke up with {:?}", id, next);
+                if !stream.update(conn, next) {
+                    done.push(*id);
+                    break;
+                }
+            }
+        }
+        for id in done {
+            streams.remove(&id);
+        }
+    }
+}
+
+/// A single HTTP/2 stream, which is a message exchange with its own
+/// `MessageHandler`.
+struct Stream<H: MessageHandler<T>, T: Transport> {
+    handler: H,
+    h2: h2::Stream,
+    reading: StreamReading,
+    writing: StreamWriting,
+    head_received: bool,
+    pending_head: Option<http::MessageHead<<<H as MessageHandler<T>>::Message as Http1Message>::Incoming>>,
+    ctrl: (channel::Sender<Next>, channel::Receiver<Next>),
+    /// When the handler's timeout for its current `Next` runs out.
+    deadline: Option<Instant>,
+}
+
+impl<H: MessageHandler<T>, T: Transport> fmt::Debug for Stream<H, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stream")
+            .field("h2", &self.h2)
+            .field("reading", &self.reading)
+            .field("writing", &self.writing)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+impl<H: MessageHandler<T>, T: Transport> Stream<H, T> {
+    fn new(handler: H, h2: h2::Stream, ctrl: (channel::Sender<Next>, channel::Receiver<Next>)) -> Stream<H, T> {
+        Stream {
+            handler: handler,
+            h2: h2,
+            reading: StreamReading::Init,
+            writing: StreamWriting::Init,
+            head_received: false,
+            pending_head: None,
+            ctrl: ctrl,
+            deadline: None,
+        }
+    }
+
+    /// Applies the `Next` of the handler. Returns false once the stream
+    /// is finished, and should be removed.
+    fn update(&mut self, conn: &mut h2::Connection, next: Next) -> bool {
+        self.deadline = next.timeout.map(|timeout| Instant::now() + timeout);
+        if self.h2.is_closing() {
+            conn.send_eos(&mut self.h2);
+        }
+        let (read, write) = match next.interest {
+            Next_::Read => (true, false),
+            Next_::Write => (false, true),
+            Next_::ReadWrite => (true, true),
+            Next_::Wait => (false, false),
+            Next_::End => {
+                self.end(conn);
+                return false;
+            }
+            Next_::Remove => {
+                conn.send_reset(self.h2.id(), h2::Reason::Cancel);
+                return false;
+            }
+        };
+
+        self.reading = match self.reading {
+            StreamReading::Init |
+            StreamReading::Head => if read {
+                StreamReading::Head
+            } else {
+                StreamReading::Init
+            },
+            StreamReading::Wait |
+            StreamReading::Body => if read {
+                StreamReading::Body
+            } else if self.h2.is_recv_eof() {
+                StreamReading::Closed
+            } else {
+                StreamReading::Wait
+            },
+            StreamReading::Closed => StreamReading::Closed,
+        };
+        self.writing = match self.writing {
+            StreamWriting::Init |
+            StreamWriting::Head => if write {
+                StreamWriting::Head
+            } else {
+                StreamWriting::Init
+            },
+            StreamWriting::Wait |
+            StreamWriting::Ready => if write {
+                StreamWriting::Ready
+            } else if self.h2.is_send_eof() {
+                StreamWriting::Closed
+            } else {
+                StreamWriting::Wait
+            },
+            StreamWriting::Closed => StreamWriting::Closed,
+        };
+
+        match (&self.reading, &self.writing) {
+            (&StreamReading::Closed, &StreamWriting::Closed) => false,
+            _ => true
+        }
+    }
+
+    fn end(&mut self, conn: &mut h2::Connection) {
+        let id = self.h2.id();
+        if !self.h2.is_send_eof() {
+            if !self.h2.is_headers_sent() {
+                conn.send_reset(id, h2::Reason::Cancel);
+                return;
+            }
+            conn.send_eos(&mut self.h2);
+        }
+        if !self.h2.is_recv_eof() {
+            // a server has sent a complete response, and doesn't need the
+            // rest of the request body
+            let reason = if conn.is_client() {
+                h2::Reason::Cancel
+            } else {
+                h2::Reason::NoError
+            };
+            conn.send_reset(id, reason);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum StreamReading {
+    /// Waiting for a message head, but the handler isn't interested yet.
+    Init,
+    /// The handler wants the message head.
+    Head,
+    Body,
+    Wait,
+    Closed,
+}
+
+#[derive(Debug)]
+enum StreamWriting {
+    Init,
+    Head,
+    Ready,
+    Wait,
+    Closed,
+}
+
 pub trait MessageHandler<T: Transport> {
-    type Message: Http1Message;
+    type Message: Http2Message;
     fn on_incoming(&mut self, head: http::MessageHead<<Self::Message as Http1Message>::Incoming>, transport: &T) -> Next;
     fn on_outgoing(&mut self, head: &mut http::MessageHead<<Self::Message as Http1Message>::Outgoing>) -> Next;
     fn on_decode(&mut self, &mut http::Decoder<T>) -> Next;
     fn on_encode(&mut self, &mut http::Encoder<T>) -> Next;
     fn on_error(&mut self, err: ::Error) -> Next;
 
+    /// Whether the message exchange so far switched protocols, with a
+    /// `101 Switching Protocols`, or a successful response to `CONNECT`.
+    fn is_upgrade(&self) -> bool;
+
+    /// Receives an informational head, that the final head still follows.
+    fn on_interim(&mut self, head: &http::MessageHead<<Self::Message as Http1Message>::Incoming>);
+
+    /// Whether the head last given to `on_outgoing` is informational, so
+    /// that another head follows it.
+    fn is_interim(&self) -> bool;
+
     fn on_remove(self, T) where Self: Sized;
+    fn on_upgrade(self, http::Upgraded<T>) where Self: Sized;
 }
 
-pub struct Seed<'a, K: Key + 'a>(&'a K, &'a channel::Sender<Next>);
+pub struct Seed<'a, K: Key + 'a>(&'a K, &'a channel::Sender<Next>, bool);
 
 impl<'a, K: Key + 'a> Seed<'a, K> {
     pub fn control(&self) -> Control {
@@ -984,6 +2140,12 @@ impl<'a, K: Key + 'a> Seed<'a, K> {
     pub fn key(&self) -> &K {
         self.0
     }
+
+    /// Whether the connection already carried an HTTP/1 message before this
+    /// one, such as a kept-alive connection taken from the pool.
+    pub fn is_reused(&self) -> bool {
+        self.2
+    }
 }
 
 
@@ -993,6 +2155,12 @@ pub trait MessageHandlerFactory<K: Key, T: Transport> {
     fn create(&mut self, seed: Seed<K>) -> Option<Self::Output>;
 
     fn keep_alive_interest(&self) -> Next;
+
+    /// The body of the response to a message that could not be read. Its
+    /// head, with the status for the error, can be changed as well.
+    fn reject_body(&self, _err: &::Error, _head: &mut http::MessageHead<<<Self::Output as MessageHandler<T>>::Message as Http1Message>::Outgoing>) -> Vec<u8> {
+        Vec::new()
+    }
 }
 
 pub trait Key: Eq + Hash + Clone + fmt::Debug {}
@@ -0,0 +1,545 @@
+//! Decoding and encoding the content coding of a body, such as `gzip`.
+//!
+//! Decoding is driven by reads: each read decodes what it can from the bytes
+//! already received, and only then reads more from the transfer decoder. So
+//! a `WouldBlock` from the transport leaves the state untouched, to continue
+//! on the next read.
+//!
+//! Encoding compresses each write right away into a buffer, which is then
+//! written with the transfer encoder.
+use std::fmt;
+use std::io;
+
+use brotli_decompressor::{BrotliDecompressStream, BrotliResult, BrotliState, HeapAlloc, HuffmanCode};
+use flate2::{Compress, Compression, Decompress, Flush, Status};
+
+use header::Encoding;
+
+/// How many bytes are read from the transfer decoder at once.
+const INPUT_SIZE: usize = 8192;
+
+pub struct ContentDecoder {
+    coding: Coding,
+    input: Vec<u8>,
+    pos: usize,
+    input_eof: bool,
+    done: bool,
+}
+
+enum Coding {
+    Gzip(Gzip),
+    /// The zlib wrapper is detected once the first bytes arrive, since some
+    /// servers send raw deflate instead.
+    Deflate(Option<Decompress>),
+    Brotli(Box<BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>>),
+}
+
+enum Gzip {
+    Header { members: usize },
+    Body { inflate: Decompress, crc: Crc, members: usize },
+    Trailer { crc: u32, size: u32, members: usize },
+}
+
+/// Progress made by one decoding step: bytes of input consumed, and bytes of
+/// output produced.
+struct Progress(usize, usize);
+
+impl ContentDecoder {
+    /// Creates a decoder for a content coding, if it is supported.
+    pub fn new(encoding: &Encoding) -> Option<ContentDecoder> {
+        let coding = match *encoding {
+            Encoding::Gzip => Coding::Gzip(Gzip::Header { members: 0 }),
+            Encoding::Deflate => Coding::Deflate(None),
+            Encoding::EncodingExt(ref ext) if ext == "br" => {
+                Coding::Brotli(Box::new(BrotliState::new(HeapAlloc::<u8>::new(0),
+                                                         HeapAlloc::<u32>::new(0),
+                                                         HeapAlloc::<HuffmanCode>::new(HuffmanCode::default()))))
+            }
+            _ => return None,
+        };
+        Some(ContentDecoder {
+            coding: coding,
+            input: Vec::new(),
+            pos: 0,
+            input_eof: false,
+            done: false,
+        })
+    }
+
+    /// Reads decoded content into `buf`, reading the encoded content with
+    /// `src` when more is needed.
+    pub fn read<F>(&mut self, buf: &mut [u8], mut src: F) -> io::Result<usize>
+    where F: FnMut(&mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.done {
+                return Ok(0);
+            }
+            let Progress(consumed, produced) = try!(self.step(buf));
+            self.pos += consumed;
+            if produced > 0 {
+                return Ok(produced);
+            }
+            if consumed > 0 || self.done {
+                continue;
+            }
+
+            // no progress without more input
+            if self.input_eof {
+                if self.pos == self.input.len() && self.coding.is_complete() {
+                    self.done = true;
+                    return Ok(0);
+                }
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "encoded content ended early"));
+            }
+            self.input.drain(..self.pos);
+            self.pos = 0;
+            let start = self.input.len();
+            self.input.resize(start + INPUT_SIZE, 0);
+            match src(&mut self.input[start..]) {
+                Ok(n) => {
+                    self.input.truncate(start + n);
+                    if n == 0 {
+                        self.input_eof = true;
+                    }
+                }
+                Err(e) => {
+                    self.input.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn step(&mut self, buf: &mut [u8]) -> io::Result<Progress> {
+        let input = &self.input[self.pos..];
+        match self.coding {
+            Coding::Gzip(ref mut gzip) => gzip.step(input, buf),
+            Coding::Deflate(ref mut inflate) => {
+                if inflate.is_none() {
+                    if input.len() < 2 && !self.input_eof {
+                        return Ok(Progress(0, 0));
+                    }
+                    *inflate = Some(Decompress::new(is_zlib_header(input)));
+                }
+                let inflate = inflate.as_mut().unwrap();
+                let (consumed, produced, status) = try!(inflate_step(inflate, input, buf));
+                if status == Status::StreamEnd {
+                    self.done = true;
+                }
+                Ok(Progress(consumed, produced))
+            }
+            Coding::Brotli(ref mut state) => {
+                let mut available_in = input.len();
+                let mut input_offset = 0;
+                let mut available_out = buf.len();
+                let mut output_offset = 0;
+                let mut total_out = 0;
+                let res = BrotliDecompressStream(&mut available_in, &mut input_offset, input,
+                                                 &mut available_out, &mut output_offset, buf,
+                                                 &mut total_out, state);
+                match res {
+                    BrotliResult::ResultSuccess => self.done = true,
+                    BrotliResult::NeedsMoreInput | BrotliResult::NeedsMoreOutput => (),
+                    BrotliResult::ResultFailure => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid brotli content"));
+                    }
+                }
+                Ok(Progress(input_offset, output_offset))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ContentDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let coding = match self.coding {
+            Coding::Gzip(..) => "gzip",
+            Coding::Deflate(..) => "deflate",
+            Coding::Brotli(..) => "br",
+        };
+        f.debug_struct("ContentDecoder")
+            .field("coding", &coding)
+            .field("buffered", &(self.input.len() - self.pos))
+            .field("input_eof", &self.input_eof)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl Coding {
+    /// Whether running out of input here is a proper end of the content.
+    fn is_complete(&self) -> bool {
+        match *self {
+            Coding::Gzip(Gzip::Header { members }) => members > 0,
+            _ => false,
+        }
+    }
+}
+
+impl Gzip {
+    fn step(&mut self, input: &[u8], buf: &mut [u8]) -> io::Result<Progress> {
+        let (progress, next) = match *self {
+            Gzip::Header { members } => {
+                match try!(gzip_header_len(input)) {
+                    Some(len) => {
+                        let body = Gzip::Body {
+                            inflate: Decompress::new(false),
+                            crc: Crc::new(),
+                            members: members,
+                        };
+                        (Progress(len, 0), Some(body))
+                    }
+                    None => (Progress(0, 0), None),
+                }
+            }
+            Gzip::Body { ref mut inflate, ref mut crc, members } => {
+                let (consumed, produced, status) = try!(inflate_step(inflate, input, buf));
+                crc.update(&buf[..produced]);
+                let next = if status == Status::StreamEnd {
+                    Some(Gzip::Trailer {
+                        crc: crc.sum(),
+                        size: crc.amount(),
+                        members: members,
+                    })
+                } else {
+                    None
+                };
+                (Progress(consumed, produced), next)
+            }
+            Gzip::Trailer { crc, size, members } => {
+                if input.len() < 8 {
+                    (Progress(0, 0), None)
+                } else {
+                    if crc != le_u32(&input[..4]) || size != le_u32(&input[4..8]) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "gzip checksum mismatch"));
+                    }
+                    // another member may follow
+                    (Progress(8, 0), Some(Gzip::Header { members: members + 1 }))
+                }
+            }
+        };
+        if let Some(next) = next {
+            *self = next;
+        }
+        Ok(progress)
+    }
+}
+
+/// Compresses a body for a content coding.
+pub struct ContentEncoder {
+    deflate: Compress,
+    /// The CRC of the content, for the gzip trailer.
+    crc: Option<Crc>,
+    started: bool,
+}
+
+impl ContentEncoder {
+    /// Creates an encoder for a content coding, if it is supported.
+    ///
+    /// Supported are `gzip` and `deflate`.
+    pub fn new(encoding: &Encoding) -> Option<ContentEncoder> {
+        match *encoding {
+            Encoding::Gzip => Some(ContentEncoder {
+                deflate: Compress::new(Compression::Default, false),
+                crc: Some(Crc::new()),
+                started: false,
+            }),
+            Encoding::Deflate => Some(ContentEncoder {
+                deflate: Compress::new(Compression::Default, true),
+                crc: None,
+                started: false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Compresses `data`, appending the output to `out`.
+    ///
+    /// The output is flushed, so that all of `data` can be decoded from it
+    /// without waiting for the rest of the body.
+    pub fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.start(out);
+        if let Some(ref mut crc) = self.crc {
+            crc.update(data);
+        }
+        self.compress(data, out, Flush::Sync);
+    }
+
+    /// Ends the compressed stream, appending the output to `out`.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        self.start(out);
+        self.compress(&[], out, Flush::Finish);
+        if let Some(ref crc) = self.crc {
+            for &n in &[crc.sum(), crc.amount()] {
+                out.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+            }
+        }
+    }
+
+    fn start(&mut self, out: &mut Vec<u8>) {
+        if !self.started && self.crc.is_some() {
+            // the gzip header: no flags, no modification time, unknown OS
+            out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+        }
+        self.started = true;
+    }
+
+    fn compress(&mut self, mut data: &[u8], out: &mut Vec<u8>, flush: Flush) {
+        loop {
+            out.reserve(data.len() / 2 + 64);
+            let before_in = self.deflate.total_in();
+            let status = self.deflate.compress_vec(data, out, flush);
+            data = &data[(self.deflate.total_in() - before_in) as usize..];
+            // the output filling all the room means there may be more
+            if status == Status::StreamEnd || (data.is_empty() && out.len() < out.capacity()) {
+                return;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ContentEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ContentEncoder")
+            .field("coding", &if self.crc.is_some() { "gzip" } else { "deflate" })
+            .field("total_in", &self.deflate.total_in())
+            .finish()
+    }
+}
+
+fn inflate_step(inflate: &mut Decompress, input: &[u8], buf: &mut [u8]) -> io::Result<(usize, usize, Status)> {
+    let (before_in, before_out) = (inflate.total_in(), inflate.total_out());
+    let status = match inflate.decompress(input, buf, Flush::None) {
+        Ok(status) => status,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    };
+    Ok(((inflate.total_in() - before_in) as usize, (inflate.total_out() - before_out) as usize, status))
+}
+
+/// Whether content starts with a zlib header (RFC 1950), rather than being
+/// raw deflate.
+fn is_zlib_header(input: &[u8]) -> bool {
+    input.len() >= 2 &&
+        input[0] & 0x0F == 8 &&
+        ((input[0] as u16) << 8 | input[1] as u16) % 31 == 0
+}
+
+/// The length of the gzip member header (RFC 1952) at the start of `input`,
+/// if all of it is there.
+fn gzip_header_len(input: &[u8]) -> io::Result<Option<usize>> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if input.len() < 10 {
+        return Ok(None);
+    }
+    if input[0] != 0x1f || input[1] != 0x8b || input[2] != 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid gzip header"));
+    }
+    let flags = input[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        if input.len() < pos + 2 {
+            return Ok(None);
+        }
+        pos += 2 + (input[pos] as usize | (input[pos + 1] as usize) << 8);
+    }
+    for &flag in &[FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            if pos >= input.len() {
+                return Ok(None);
+            }
+            match input[pos..].iter().position(|&b| b == 0) {
+                Some(end) => pos += end + 1,
+                None => return Ok(None),
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    if input.len() < pos {
+        Ok(None)
+    } else {
+        Ok(Some(pos))
+    }
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+/// The CRC-32 and size of decoded gzip content.
+struct Crc {
+    table: [u32; 256],
+    crc: u32,
+    amount: u32,
+}
+
+impl Crc {
+    fn new() -> Crc {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        Crc {
+            table: table,
+            crc: 0,
+            amount: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let mut c = !self.crc;
+        for &b in data {
+            c = self.table[((c ^ b as u32) & 0xFF) as usize] ^ (c >> 8);
+        }
+        self.crc = !c;
+        self.amount = self.amount.wrapping_add(data.len() as u32);
+    }
+
+    fn sum(&self) -> u32 {
+        self.crc
+    }
+
+    fn amount(&self) -> u32 {
+        self.amount
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+
+    use flate2::Compression;
+    use flate2::write::{GzEncoder, ZlibEncoder, DeflateEncoder};
+
+    use header::Encoding;
+    use super::{ContentDecoder, ContentEncoder, Crc};
+
+    /// Decodes `encoded`, with the source blocking every `block_every` bytes
+    /// and reading with a small buffer.
+    fn decode(encoding: Encoding, encoded: &[u8], block_every: usize) -> io::Result<Vec<u8>> {
+        let mut decoder = ContentDecoder::new(&encoding).unwrap();
+        let mut src = encoded;
+        let mut blocked = false;
+        let mut out = Vec::new();
+        loop {
+            let mut buf = [0; 7];
+            let res = decoder.read(&mut buf, |raw| {
+                if blocked {
+                    blocked = false;
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+                }
+                blocked = true;
+                let n = ::std::cmp::min(block_every, raw.len());
+                (&mut src).take(n as u64).read(raw)
+            });
+            match res {
+                Ok(0) => return Ok(out),
+                Ok(n) => out.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    const CONTENT: &'static [u8] = b"{\"json\": [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17]}";
+
+    #[test]
+    fn test_gzip() {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::Default);
+        enc.write_all(CONTENT).unwrap();
+        let gz = enc.finish().unwrap();
+        for &block_every in &[1, 3, 100] {
+            assert_eq!(decode(Encoding::Gzip, &gz, block_every).unwrap(), CONTENT);
+        }
+
+        // concatenated members
+        let mut twice = gz.clone();
+        twice.extend_from_slice(&gz);
+        let mut expected = CONTENT.to_vec();
+        expected.extend_from_slice(CONTENT);
+        assert_eq!(decode(Encoding::Gzip, &twice, 5).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_gzip_corrupt() {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::Default);
+        enc.write_all(CONTENT).unwrap();
+        let mut gz = enc.finish().unwrap();
+
+        let truncated = gz.len() - 4;
+        assert_eq!(decode(Encoding::Gzip, &gz[..truncated], 10).unwrap_err().kind(),
+                   io::ErrorKind::UnexpectedEof);
+
+        let crc = gz.len() - 8;
+        gz[crc] ^= 0xff;
+        assert_eq!(decode(Encoding::Gzip, &gz, 10).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        assert!(decode(Encoding::Gzip, b"not gzip at all", 10).is_err());
+    }
+
+    #[test]
+    fn test_deflate() {
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::Default);
+        enc.write_all(CONTENT).unwrap();
+        let zlib = enc.finish().unwrap();
+        assert_eq!(decode(Encoding::Deflate, &zlib, 1).unwrap(), CONTENT);
+
+        let mut enc = DeflateEncoder::new(Vec::new(), Compression::Default);
+        enc.write_all(CONTENT).unwrap();
+        let raw = enc.finish().unwrap();
+        assert_eq!(decode(Encoding::Deflate, &raw, 4).unwrap(), CONTENT);
+    }
+
+    #[test]
+    fn test_brotli() {
+        // "hello" compressed with brotli
+        let br = [0x0b, 0x02, 0x80, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x03];
+        assert_eq!(decode(Encoding::EncodingExt("br".to_owned()), &br, 2).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert!(ContentDecoder::new(&Encoding::Compress).is_none());
+        assert!(ContentDecoder::new(&Encoding::EncodingExt("zstd".to_owned())).is_none());
+        assert!(ContentEncoder::new(&Encoding::EncodingExt("br".to_owned())).is_none());
+    }
+
+    #[test]
+    fn test_encode() {
+        for encoding in vec![Encoding::Gzip, Encoding::Deflate] {
+            let mut encoder = ContentEncoder::new(&encoding).unwrap();
+            let mut encoded = Vec::new();
+            encoder.encode(b"", &mut encoded);
+            encoder.encode(&CONTENT[..10], &mut encoded);
+            // what was written so far can be decoded already
+            let mut partial = ContentDecoder::new(&encoding).unwrap();
+            let mut src = &encoded[..];
+            let mut buf = [0; 64];
+            assert_eq!(partial.read(&mut buf, |raw| src.read(raw)).unwrap(), 10);
+
+            encoder.encode(&CONTENT[10..], &mut encoded);
+            encoder.finish(&mut encoded);
+            assert_eq!(decode(encoding, &encoded, 3).unwrap(), CONTENT);
+        }
+    }
+
+    #[test]
+    fn test_crc() {
+        let mut crc = Crc::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.sum(), 0xCBF43926);
+        assert_eq!(crc.amount(), 9);
+    }
+}
@@ -1,28 +1,62 @@
 use std::{cmp, usize};
 use std::io::{self, Read};
 
+use httparse;
+
+use header::Headers;
+use http::content::ContentDecoder;
+use http::strip_trailers;
 use self::Kind::{Length, Chunked, Eof};
 
+/// The most bytes of trailer fields read after the last chunk.
+const MAX_TRAILER_SIZE: usize = 8192;
+/// The most trailer fields read after the last chunk.
+const MAX_TRAILERS: usize = 32;
+
 /// Decoders to handle different Transfer-Encodings.
 ///
 /// If a message body does not include a Transfer-Encoding, it *should*
 /// include a Content-Length header.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Decoder {
     kind: Kind,
+    content: Option<ContentDecoder>,
 }
 
 impl Decoder {
     pub fn length(x: u64) -> Decoder {
-        Decoder { kind: Kind::Length(x) }
+        Decoder::new(Kind::Length(x))
     }
 
     pub fn chunked() -> Decoder {
-        Decoder { kind: Kind::Chunked(ChunkedState::Size, 0) }
+        Decoder::new(Kind::Chunked(ChunkedState::Size, 0, Trailers::new()))
     }
 
     pub fn eof() -> Decoder {
-        Decoder { kind: Kind::Eof(false) }
+        Decoder::new(Kind::Eof(false))
+    }
+
+    fn new(kind: Kind) -> Decoder {
+        Decoder {
+            kind: kind,
+            content: None,
+        }
+    }
+
+    /// Decodes the content coding of the body as well, from now on.
+    pub fn decode_content(&mut self, content: ContentDecoder) {
+        self.content = Some(content);
+    }
+
+    /// The trailer fields sent after the last chunk.
+    ///
+    /// This is `None` until the body was read to its end, and for bodies
+    /// that are not chunked.
+    pub fn trailers(&self) -> Option<&Headers> {
+        match self.kind {
+            Chunked(_, _, ref trailers) => trailers.headers.as_ref(),
+            _ => None,
+        }
     }
 }
 
@@ -31,7 +65,7 @@ enum Kind {
     /// A Reader used when a Content-Length header is passed with a positive integer.
     Length(u64),
     /// A Reader used when Transfer-Encoding is `chunked`.
-    Chunked(ChunkedState, u64),
+    Chunked(ChunkedState, u64, Trailers),
     /// A Reader used for responses that don't indicate a length or chunked.
     ///
     /// Note: This should only used for `Response`s. It is illegal for a
@@ -58,15 +92,36 @@ enum ChunkedState {
     Body,
     BodyCr,
     BodyLf,
+    Trailer,
     End,
 }
 
+/// The trailer section following the last chunk.
+#[derive(Debug, Clone)]
+struct Trailers {
+    raw: Vec<u8>,
+    headers: Option<Headers>,
+}
+
+impl Trailers {
+    fn new() -> Trailers {
+        Trailers {
+            raw: Vec::new(),
+            headers: None,
+        }
+    }
+}
+
 impl Decoder {
+    /// Whether the body was read from the transport to its end.
+    ///
+    /// Decoded content may still be left to read, but the connection
+    /// is done with this message.
     pub fn is_eof(&self) -> bool {
         trace!("is_eof? {:?}", self);
         match self.kind {
             Length(0) |
-            Chunked(ChunkedState::End, _) |
+            Chunked(ChunkedState::End, _, _) |
             Eof(true) => true,
             _ => false,
         }
@@ -75,7 +130,19 @@ impl Decoder {
 
 impl Decoder {
     pub fn decode<R: Read>(&mut self, body: &mut R, buf: &mut [u8]) -> io::Result<usize> {
-        match self.kind {
+        match self.content {
+            Some(ref mut content) => {
+                let kind = &mut self.kind;
+                content.read(buf, |raw| kind.decode(body, raw))
+            }
+            None => self.kind.decode(body, buf),
+        }
+    }
+}
+
+impl Kind {
+    fn decode<R: Read>(&mut self, body: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
             Length(ref mut remaining) => {
                 trace!("Sized read, remaining={:?}", remaining);
                 if *remaining == 0 {
@@ -94,11 +161,11 @@ impl Decoder {
                     Ok(num as usize)
                 }
             }
-            Chunked(ref mut state, ref mut size) => {
+            Chunked(ref mut state, ref mut size, ref mut trailers) => {
                 loop {
                     let mut read = 0;
                     // advances the chunked state
-                    *state = try!(state.step(body, size, buf, &mut read));
+                    *state = try!(state.step(body, size, trailers, buf, &mut read));
                     if *state == ChunkedState::End {
                         trace!("end of chunked");
                         return Ok(0);
@@ -136,6 +203,7 @@ impl ChunkedState {
     fn step<R: Read>(&self,
                      body: &mut R,
                      size: &mut u64,
+                     trailers: &mut Trailers,
                      buf: &mut [u8],
                      read: &mut usize)
                      -> io::Result<ChunkedState> {
@@ -148,6 +216,7 @@ impl ChunkedState {
             Body => try!(ChunkedState::read_body(body, size, buf, read)),
             BodyCr => try!(ChunkedState::read_body_cr(body)),
             BodyLf => try!(ChunkedState::read_body_lf(body)),
+            Trailer => try!(ChunkedState::read_trailer(body, trailers)),
             End => ChunkedState::End,
         })
     }
@@ -201,7 +270,7 @@ impl ChunkedState {
         trace!("Chunk size is {:?}", size);
         match byte!(rdr) {
             b'\n' if *size > 0 => Ok(ChunkedState::Body),
-            b'\n' if *size == 0 => Ok(ChunkedState::End),
+            b'\n' if *size == 0 => Ok(ChunkedState::Trailer),
             _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid chunk size LF")),
         }
     }
@@ -250,6 +319,34 @@ impl ChunkedState {
             _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid chunk body LF")),
         }
     }
+    fn read_trailer<R: Read>(rdr: &mut R, trailers: &mut Trailers) -> io::Result<ChunkedState> {
+        trace!("read_trailer");
+        // the trailer section ends with an empty line, which is all of it
+        // when there are no fields
+        while trailers.raw != b"\r\n" && !trailers.raw.ends_with(b"\r\n\r\n") {
+            if trailers.raw.len() >= MAX_TRAILER_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Trailer fields too large"));
+            }
+            trailers.raw.push(byte!(rdr));
+        }
+
+        let mut headers = {
+            let mut fields = [httparse::EMPTY_HEADER; MAX_TRAILERS];
+            match httparse::parse_headers(&trailers.raw, &mut fields) {
+                Ok(httparse::Status::Complete((_, fields))) => {
+                    try!(Headers::from_raw(fields).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "Invalid trailer fields")
+                    }))
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                               "Invalid trailer fields")),
+            }
+        };
+        strip_trailers(&mut headers);
+        trailers.raw = Vec::new();
+        trailers.headers = Some(headers);
+        Ok(ChunkedState::End)
+    }
 }
 
 #[cfg(test)]
@@ -258,7 +355,7 @@ mod tests {
     use std::io;
     use std::io::Write;
     use super::Decoder;
-    use super::ChunkedState;
+    use super::{ChunkedState, Trailers};
     use mock::Async;
 
     #[test]
@@ -269,14 +366,15 @@ mod tests {
             let mut state = ChunkedState::Size;
             let mut rdr = &mut s.as_bytes();
             let mut size = 0;
+            let mut trailers = Trailers::new();
             let mut count = 0;
             loop {
                 let mut buf = [0u8; 10];
-                let result = state.step(&mut rdr, &mut size, &mut buf, &mut count);
+                let result = state.step(&mut rdr, &mut size, &mut trailers, &mut buf, &mut count);
                 let desc = format!("read_size failed for {:?}", s);
                 state = result.expect(desc.as_str());
                 trace!("State {:?}", state);
-                if state == ChunkedState::Body || state == ChunkedState::End {
+                if state == ChunkedState::Body || state == ChunkedState::Trailer {
                     break;
                 }
             }
@@ -287,10 +385,11 @@ mod tests {
             let mut state = ChunkedState::Size;
             let mut rdr = &mut s.as_bytes();
             let mut size = 0;
+            let mut trailers = Trailers::new();
             let mut count = 0;
             loop {
                 let mut buf = [0u8; 10];
-                let result = state.step(&mut rdr, &mut size, &mut buf, &mut count);
+                let result = state.step(&mut rdr, &mut size, &mut trailers, &mut buf, &mut count);
                 state = match result {
                     Ok(s) => s,
                     Err(e) => {
@@ -300,7 +399,7 @@ mod tests {
                     }
                 };
                 trace!("State {:?}", state);
-                if state == ChunkedState::Body || state == ChunkedState::End {
+                if state == ChunkedState::Body || state == ChunkedState::Trailer {
                     panic!(format!("Was Ok. Expected Err for {:?}", s));
                 }
             }
@@ -364,7 +463,7 @@ mod tests {
 
     #[test]
     fn test_read_chunked_single_read() {
-        let content = b"10\r\n1234567890abcdef\r\n0\r\n";
+        let content = b"10\r\n1234567890abcdef\r\n0\r\n\r\n";
         let mut mock_buf = io::Cursor::new(content);
         let mut buf = [0u8; 16];
         let count = Decoder::chunked().decode(&mut mock_buf, &mut buf).expect("decode");
@@ -375,7 +474,7 @@ mod tests {
 
     #[test]
     fn test_read_chunked_after_eof() {
-        let content = b"10\r\n1234567890abcdef\r\n0\r\n";
+        let content = b"10\r\n1234567890abcdef\r\n0\r\n\r\n";
         let mut mock_buf = io::Cursor::new(content);
         let mut buf = [0u8; 50];
         let mut decoder = Decoder::chunked();
@@ -395,6 +494,54 @@ mod tests {
         assert_eq!(0, count);
     }
 
+    #[test]
+    fn test_read_chunked_trailers() {
+        let content = b"3\r\nfoo\r\n0\r\nGrpc-Status: 0\r\nContent-Length: 5\r\n\r\n";
+        let mut mock_buf = io::Cursor::new(&content[..]);
+        let mut buf = [0u8; 10];
+        let mut decoder = Decoder::chunked();
+
+        assert_eq!(decoder.decode(&mut mock_buf, &mut buf).unwrap(), 3);
+        assert!(decoder.trailers().is_none());
+        assert_eq!(decoder.decode(&mut mock_buf, &mut buf).unwrap(), 0);
+        assert!(decoder.is_eof());
+
+        let trailers = decoder.trailers().expect("trailers");
+        assert_eq!(trailers.get_raw("Grpc-Status").unwrap(), "0");
+        // framing fields are not allowed in trailers
+        assert!(trailers.get_raw("Content-Length").is_none());
+        assert_eq!(mock_buf.position() as usize, content.len());
+    }
+
+    #[test]
+    fn test_read_chunked_no_trailers() {
+        let mut bytes = &b"0\r\n\r\n"[..];
+        let mut buf = [0u8; 10];
+        let mut decoder = Decoder::chunked();
+        assert_eq!(decoder.decode(&mut bytes, &mut buf).unwrap(), 0);
+        assert_eq!(decoder.trailers().unwrap().len(), 0);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_read_chunked_trailers_too_large() {
+        let mut content = b"0\r\nX-Big: ".to_vec();
+        content.extend(vec![b'a'; super::MAX_TRAILER_SIZE]);
+        content.extend(b"\r\n\r\n");
+        let mut bytes = &content[..];
+        let mut buf = [0u8; 10];
+        let e = Decoder::chunked().decode(&mut bytes, &mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_read_chunked_trailers_invalid() {
+        let mut bytes = &b"0\r\nno colon\r\n\r\n"[..];
+        let mut buf = [0u8; 10];
+        let e = Decoder::chunked().decode(&mut bytes, &mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
     // perform an async read using a custom buffer size and causing a blocking
     // read at the specified byte
     fn read_async(mut decoder: Decoder,
@@ -426,11 +573,11 @@ mod tests {
     // iterate over the different ways that this async read could go.
     // tests every combination of buffer size that is passed in, with a blocking
     // read at each byte along the content - The shotgun approach
-    fn all_async_cases(content: &str, expected: &str, decoder: Decoder) {
+    fn all_async_cases<F: Fn() -> Decoder>(content: &str, expected: &str, decoder: F) {
         let content_len = content.len();
         for block_at in 0..content_len {
             for read_buffer_size in 1..content_len {
-                let actual = read_async(decoder.clone(),
+                let actual = read_async(decoder(),
                                         content.as_bytes(),
                                         block_at,
                                         read_buffer_size);
@@ -446,20 +593,26 @@ mod tests {
     #[test]
     fn test_read_length_async() {
         let content = "foobar";
-        all_async_cases(content, content, Decoder::length(content.len() as u64));
+        all_async_cases(content, content, || Decoder::length(content.len() as u64));
     }
 
     #[test]
     fn test_read_chunked_async() {
-        let content = "3\r\nfoo\r\n3\r\nbar\r\n0\r\n";
+        let content = "3\r\nfoo\r\n3\r\nbar\r\n0\r\n\r\n";
         let expected = "foobar";
-        all_async_cases(content, expected, Decoder::chunked());
+        all_async_cases(content, expected, Decoder::chunked);
+    }
+
+    #[test]
+    fn test_read_chunked_trailers_async() {
+        let content = "3\r\nfoo\r\n0\r\nDigest: sha-256=abc\r\n\r\n";
+        all_async_cases(content, "foo", Decoder::chunked);
     }
 
     #[test]
     fn test_read_eof_async() {
         let content = "foobar";
-        all_async_cases(content, content, Decoder::eof());
+        all_async_cases(content, content, Decoder::eof);
     }
 
 }
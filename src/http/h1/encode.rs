@@ -2,14 +2,42 @@ use std::borrow::Cow;
 use std::cmp;
 use std::io::{self, Write};
 
+use header::Headers;
+use http::content::ContentEncoder;
+use http::strip_trailers;
 use http::internal::{AtomicWrite, WriteBuf};
 
 /// Encoders to handle different Transfer-Encodings.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Encoder {
     kind: Kind,
     prefix: Prefix,
     is_closed: bool,
+    content: Option<Content>,
+    trailers: Option<Headers>,
+}
+
+/// The content coding of the body, with compressed bytes not yet written.
+#[derive(Debug)]
+struct Content {
+    encoder: ContentEncoder,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Content {
+    fn flush<W: AtomicWrite>(&mut self, kind: &mut Kind, prefix: &mut Prefix, w: &mut W) -> io::Result<()> {
+        while self.pos < self.buf.len() {
+            let n = try!(kind.encode(w, prefix, &self.buf[self.pos..]));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "body is longer than its length"));
+            }
+            self.pos += n;
+        }
+        self.buf.clear();
+        self.pos = 0;
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -20,6 +48,10 @@ enum Kind {
     ///
     /// Enforces that the body is not longer than the Content-Length header.
     Length(u64),
+    /// An Encoder for a connection that switched protocols.
+    ///
+    /// Bytes are written as is, until the connection is closed.
+    Eof,
 }
 
 impl Encoder {
@@ -28,6 +60,8 @@ impl Encoder {
             kind: Kind::Chunked(Chunked::Init),
             prefix: Prefix(None),
             is_closed: false,
+            content: None,
+            trailers: None,
         }
     }
 
@@ -36,6 +70,18 @@ impl Encoder {
             kind: Kind::Length(len),
             prefix: Prefix(None),
             is_closed: false,
+            content: None,
+            trailers: None,
+        }
+    }
+
+    pub fn eof() -> Encoder {
+        Encoder {
+            kind: Kind::Eof,
+            prefix: Prefix(None),
+            is_closed: false,
+            content: None,
+            trailers: None,
         }
     }
 
@@ -43,6 +89,29 @@ impl Encoder {
         self.prefix.0 = Some(prefix);
     }
 
+    /// Compresses the body with a content coding as well, from now on.
+    pub fn encode_content(&mut self, content: ContentEncoder) {
+        self.content = Some(Content {
+            encoder: content,
+            buf: Vec::new(),
+            pos: 0,
+        });
+    }
+
+    /// Sends trailer fields after the last chunk.
+    ///
+    /// Returns `false` if the body is not chunked, and cannot carry them.
+    pub fn set_trailers(&mut self, mut trailers: Headers) -> bool {
+        match self.kind {
+            Kind::Chunked(_) => {
+                strip_trailers(&mut trailers);
+                self.trailers = Some(trailers);
+                true
+            }
+            _ => false,
+        }
+    }
+
     pub fn is_eof(&self) -> bool {
         if self.prefix.0.is_some() {
             return false;
@@ -63,13 +132,16 @@ impl Encoder {
         self.is_closed = true;
     }
 
-    pub fn finish(self) -> Option<WriteBuf<Cow<'static, [u8]>>> {
+    pub fn finish(mut self) -> Option<WriteBuf<Cow<'static, [u8]>>> {
+        if let Some(content) = self.content.take() {
+            return Some(self.finish_content(content));
+        }
         let trailer = self.trailer();
         let buf = self.prefix.0;
 
         match (buf, trailer) {
             (Some(mut buf), Some(trailer)) => {
-                buf.bytes.extend_from_slice(trailer);
+                buf.bytes.extend_from_slice(&trailer);
                 Some(WriteBuf {
                     bytes: Cow::Owned(buf.bytes),
                     pos: buf.pos,
@@ -81,7 +153,7 @@ impl Encoder {
             }),
             (None, Some(trailer)) => {
                 Some(WriteBuf {
-                    bytes: Cow::Borrowed(trailer),
+                    bytes: trailer,
                     pos: 0,
                 })
             },
@@ -89,31 +161,90 @@ impl Encoder {
         }
     }
 
-    fn trailer(&self) -> Option<&'static [u8]> {
+    /// Ends the compressed body, returning everything left to write.
+    fn finish_content(self, mut content: Content) -> WriteBuf<Cow<'static, [u8]>> {
+        let mut bytes = match self.prefix.0 {
+            Some(buf) => buf.bytes[buf.pos..].to_vec(),
+            None => Vec::new(),
+        };
+        let mut last = Vec::new();
+        content.encoder.finish(&mut last);
+        let pending = &content.buf[content.pos..];
+        match self.kind {
+            Kind::Chunked(ref chunked) => {
+                chunked.rest(pending, &mut bytes);
+                Chunked::Init.rest(&last, &mut bytes);
+                bytes.extend_from_slice(&self.last_chunk());
+            }
+            Kind::Length(remaining) => {
+                let start = bytes.len();
+                bytes.extend_from_slice(pending);
+                bytes.extend_from_slice(&last);
+                bytes.truncate(start + remaining as usize);
+            }
+            Kind::Eof => {
+                bytes.extend_from_slice(pending);
+                bytes.extend_from_slice(&last);
+            }
+        }
+        WriteBuf {
+            bytes: Cow::Owned(bytes),
+            pos: 0,
+        }
+    }
+
+    fn trailer(&self) -> Option<Cow<'static, [u8]>> {
         match self.kind {
             Kind::Chunked(Chunked::Init) => {
-                Some(b"0\r\n\r\n")
+                Some(self.last_chunk())
             }
             _ => None
         }
     }
 
+    /// The last chunk, followed by the trailer fields, if any.
+    fn last_chunk(&self) -> Cow<'static, [u8]> {
+        match self.trailers {
+            Some(ref trailers) => Cow::Owned(format!("0\r\n{}\r\n", trailers).into_bytes()),
+            None => Cow::Borrowed(b"0\r\n\r\n"),
+        }
+    }
+
     pub fn encode<W: AtomicWrite>(&mut self, w: &mut W, msg: &[u8]) -> io::Result<usize> {
-        match self.kind {
+        match self.content {
+            Some(ref mut content) => {
+                // what was compressed before goes out first
+                try!(content.flush(&mut self.kind, &mut self.prefix, w));
+                content.encoder.encode(msg, &mut content.buf);
+                match content.flush(&mut self.kind, &mut self.prefix, w) {
+                    Ok(()) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(e),
+                }
+                Ok(msg.len())
+            }
+            None => self.kind.encode(w, &mut self.prefix, msg),
+        }
+    }
+}
+
+impl Kind {
+    fn encode<W: AtomicWrite>(&mut self, w: &mut W, prefix: &mut Prefix, msg: &[u8]) -> io::Result<usize> {
+        match *self {
             Kind::Chunked(ref mut chunked) => {
-                chunked.encode(w, &mut self.prefix, msg)
+                chunked.encode(w, prefix, msg)
             },
             Kind::Length(ref mut remaining) => {
                 let mut n = {
                     let max = cmp::min(*remaining as usize, msg.len());
                     let slice = &msg[..max];
 
-                    let prefix = self.prefix.0.as_ref().map(|buf| &buf.bytes[buf.pos..]).unwrap_or(b"");
+                    let prefix = prefix.0.as_ref().map(|buf| &buf.bytes[buf.pos..]).unwrap_or(b"");
 
                     try!(w.write_atomic(&[prefix, slice]))
                 };
 
-                n = self.prefix.update(n);
+                n = prefix.update(n);
                 if n == 0 {
                     return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
                 }
@@ -121,6 +252,18 @@ impl Encoder {
                 *remaining -= n as u64;
                 Ok(n)
             },
+            Kind::Eof => {
+                let n = {
+                    let prefix = prefix.0.as_ref().map(|buf| &buf.bytes[buf.pos..]).unwrap_or(b"");
+                    try!(w.write_atomic(&[prefix, msg]))
+                };
+
+                let n = prefix.update(n);
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+                }
+                Ok(n)
+            },
         }
     }
 }
@@ -138,6 +281,43 @@ enum Chunked {
 }
 
 impl Chunked {
+    /// Appends what is left to write of the chunk of `msg`, or of a new
+    /// chunk if none was started.
+    fn rest(&self, msg: &[u8], dst: &mut Vec<u8>) {
+        match *self {
+            Chunked::Init => {
+                if !msg.is_empty() {
+                    let _ = write!(dst, "{:X}\r\n", msg.len());
+                    dst.extend_from_slice(msg);
+                    dst.extend_from_slice(b"\r\n");
+                }
+            }
+            Chunked::Size(ref size) => {
+                dst.extend_from_slice(&size.bytes[size.pos.into() .. size.len.into()]);
+                dst.extend_from_slice(b"\r\n");
+                dst.extend_from_slice(msg);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Chunked::SizeCr => {
+                dst.extend_from_slice(b"\r\n");
+                dst.extend_from_slice(msg);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Chunked::SizeLf => {
+                dst.extend_from_slice(b"\n");
+                dst.extend_from_slice(msg);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Chunked::Body(pos) => {
+                dst.extend_from_slice(&msg[pos..]);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Chunked::BodyCr => dst.extend_from_slice(b"\r\n"),
+            Chunked::BodyLf => dst.extend_from_slice(b"\n"),
+            Chunked::End => (),
+        }
+    }
+
     fn encode<W: AtomicWrite>(&mut self, w: &mut W, prefix: &mut Prefix, msg: &[u8]) -> io::Result<usize> {
         match *self {
             Chunked::Init => {
@@ -371,6 +551,103 @@ mod tests {
         assert_eq!(&dst[..], &b"7\r\nfoo bar\r\nD\r\nbaz quux herp\r\n0\r\n\r\n"[..]);
     }
 
+    #[test]
+    fn test_chunked_trailers() {
+        use header::{ContentLength, Headers};
+
+        let mut dst = Buf::new();
+        let mut encoder = Encoder::chunked();
+        let mut trailers = Headers::new();
+        trailers.set_raw("Grpc-Status", "0");
+        trailers.set(ContentLength(3));
+        assert!(encoder.set_trailers(trailers));
+        assert_eq!(3, encoder.encode(&mut dst, b"foo").unwrap());
+        let rest = encoder.finish().unwrap();
+        let mut body = dst[..].to_vec();
+        body.extend_from_slice(&rest.bytes[rest.pos..]);
+        assert_eq!(&body[..], &b"3\r\nfoo\r\n0\r\nGrpc-Status: 0\r\n\r\n"[..]);
+    }
+
+    #[test]
+    fn test_length_no_trailers() {
+        use header::Headers;
+
+        let mut encoder = Encoder::length(3);
+        let mut trailers = Headers::new();
+        trailers.set_raw("Grpc-Status", "0");
+        assert!(!encoder.set_trailers(trailers));
+    }
+
+    fn decode_gzip_chunked(body: &[u8]) -> Vec<u8> {
+        use header::Encoding;
+        use http::content::ContentDecoder;
+        use http::h1::Decoder;
+
+        let mut decoder = Decoder::chunked();
+        decoder.decode_content(ContentDecoder::new(&Encoding::Gzip).unwrap());
+        let mut src = body;
+        let mut out = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            let n = decoder.decode(&mut src, &mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert!(decoder.is_eof());
+        out
+    }
+
+    #[test]
+    fn test_chunked_encode_content() {
+        use header::Encoding;
+        use http::content::ContentEncoder;
+
+        let mut dst = Buf::new();
+        let mut encoder = Encoder::chunked();
+        encoder.encode_content(ContentEncoder::new(&Encoding::Gzip).unwrap());
+        assert_eq!(7, encoder.encode(&mut dst, b"foo bar").unwrap());
+        assert_eq!(4, encoder.encode(&mut dst, b" baz").unwrap());
+        let rest = encoder.finish().unwrap();
+        let mut body = dst[..].to_vec();
+        body.extend_from_slice(&rest.bytes[rest.pos..]);
+        assert!(body.ends_with(b"\r\n0\r\n\r\n"));
+        assert_eq!(decode_gzip_chunked(&body), b"foo bar baz");
+    }
+
+    #[test]
+    fn test_chunked_encode_content_async() {
+        use std::io;
+        use header::Encoding;
+        use http::content::ContentEncoder;
+
+        let mut dst = Async::new(Buf::new(), 5);
+        let mut encoder = Encoder::chunked();
+        encoder.encode_content(ContentEncoder::new(&Encoding::Gzip).unwrap());
+        // compressed, but only partly written
+        assert_eq!(7, encoder.encode(&mut dst, b"foo bar").unwrap());
+        assert_eq!(encoder.encode(&mut dst, b" baz").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        dst.block_in(3);
+        assert_eq!(encoder.encode(&mut dst, b" baz").unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        dst.block_in(1000);
+        assert_eq!(4, encoder.encode(&mut dst, b" baz").unwrap());
+        let rest = encoder.finish().unwrap();
+        let mut body = dst[..].to_vec();
+        body.extend_from_slice(&rest.bytes[rest.pos..]);
+        assert_eq!(decode_gzip_chunked(&body), b"foo bar baz");
+
+        // finishing in the middle of a chunk completes it
+        let mut dst = Async::new(Buf::new(), 5);
+        let mut encoder = Encoder::chunked();
+        encoder.encode_content(ContentEncoder::new(&Encoding::Gzip).unwrap());
+        assert_eq!(7, encoder.encode(&mut dst, b"foo bar").unwrap());
+        let rest = encoder.finish().unwrap();
+        let mut body = dst[..].to_vec();
+        body.extend_from_slice(&rest.bytes[rest.pos..]);
+        assert_eq!(decode_gzip_chunked(&body), b"foo bar");
+    }
+
     #[test]
     fn test_sized_encode() {
         let mut dst = Buf::new();
@@ -380,4 +657,15 @@ mod tests {
 
         assert_eq!(dst, b"foo barb");
     }
+
+    #[test]
+    fn test_eof_encode() {
+        let mut dst = Buf::new();
+        let mut encoder = Encoder::eof();
+        encoder.encode(&mut dst, b"foo bar").unwrap();
+        encoder.encode(&mut dst, b"baz").unwrap();
+        assert!(!encoder.is_eof());
+
+        assert_eq!(dst, b"foo barbaz");
+    }
 }
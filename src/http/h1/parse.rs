@@ -1,5 +1,7 @@
+use std::ascii::AsciiExt;
 use std::borrow::Cow;
 use std::fmt::{self, Write};
+use std::str;
 
 use httparse;
 
@@ -11,6 +13,8 @@ use status::StatusCode;
 use version::HttpVersion::{Http10, Http11};
 
 const MAX_HEADERS: usize = 100;
+/// The longest request line read, well before the whole head is too large.
+const MAX_REQUEST_LINE: usize = 65536;
 const AVERAGE_HEADER_SIZE: usize = 30; // totally scientific
 
 pub fn parse<T: Http1Message<Incoming=I>, I>(buf: &[u8]) -> ParseResult<I> {
@@ -34,6 +38,7 @@ impl Http1Message for ServerMessage {
         Ok(match try!(req.parse(buf)) {
             httparse::Status::Complete(len) => {
                 trace!("Request.parse Complete({})", len);
+                try!(check_head(&buf[..len]));
                 Some((MessageHead {
                     version: if req.version.unwrap() == 1 { Http11 } else { Http10 },
                     subject: RequestLine(
@@ -43,22 +48,47 @@ impl Http1Message for ServerMessage {
                     headers: try!(Headers::from_raw(req.headers))
                 }, len))
             },
-            httparse::Status::Partial => None
+            httparse::Status::Partial => {
+                if buf.len() > MAX_REQUEST_LINE && !buf.contains(&b'\n') {
+                    debug!("request line longer than {} bytes", MAX_REQUEST_LINE);
+                    return Err(::Error::UriTooLong);
+                }
+                None
+            }
         })
     }
 
     fn decoder(head: &MessageHead<Self::Incoming>) -> ::Result<Decoder> {
-        use ::header;
-        if let Some(&header::ContentLength(len)) = head.headers.get() {
-            Ok(Decoder::length(len))
-        } else if head.headers.has::<header::TransferEncoding>() {
-            //TODO: check for Transfer-Encoding: chunked
-            Ok(Decoder::chunked())
-        } else {
-            Ok(Decoder::length(0))
+        // According to https://tools.ietf.org/html/rfc7230#section-3.3.3
+        // 3. Both Transfer-Encoding and Content-Length is an error.
+        //    Transfer-Encoding with chunked last has a chunked body,
+        //    otherwise the length cannot be determined, which is an error.
+        // 4. Multiple or invalid Content-Length is an error.
+        // 5. Content-Length header has a sized body.
+        // 6. Otherwise a request has no body.
+        match (try!(transfer_encoding(&head.headers)), try!(content_length(&head.headers, false))) {
+            (Some(_), Some(_)) => {
+                debug!("request has both Transfer-Encoding and Content-Length");
+                Err(::Error::Framing)
+            }
+            (Some(true), None) => Ok(Decoder::chunked()),
+            (Some(false), None) => {
+                debug!("request Transfer-Encoding does not end with chunked");
+                Err(::Error::Framing)
+            }
+            (None, Some(len)) => Ok(Decoder::length(len)),
+            (None, None) => Ok(Decoder::length(0)),
         }
     }
 
+    fn is_continue(_head: &MessageHead<Self::Incoming>) -> bool {
+        false
+    }
+
+    fn is_interim(_head: &MessageHead<Self::Incoming>) -> bool {
+        false
+    }
+
 
     fn encode(mut head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>) -> Encoder {
         use ::header;
@@ -103,6 +133,45 @@ impl Http1Message for ServerMessage {
         }
         body
     }
+
+    fn encode_upgrade(mut head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>) -> Encoder {
+        use ::header;
+        trace!("writing upgrade head: {:?}", head);
+
+        if !head.headers.has::<header::Date>() {
+            head.headers.set(header::Date(header::HttpDate(::time::now_utc())));
+        }
+
+        let init_cap = 30 + head.headers.len() * AVERAGE_HEADER_SIZE;
+        dst.reserve(init_cap);
+        let _ = write!(FastWrite(dst), "{} {}\r\n{}\r\n", head.version, head.subject, head.headers);
+        Encoder::eof()
+    }
+
+    fn encode_interim(head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>) {
+        trace!("writing interim head: {:?}", head);
+
+        let init_cap = 30 + head.headers.len() * AVERAGE_HEADER_SIZE;
+        dst.reserve(init_cap);
+        let _ = write!(FastWrite(dst), "{} {}\r\n{}\r\n", head.version, head.subject, head.headers);
+    }
+
+    fn on_error(err: &::Error) -> Option<MessageHead<Self::Outgoing>> {
+        let status = match *err {
+            // the client went away, or is too slow
+            ::Error::Io(..) |
+            ::Error::Timeout => return None,
+            ::Error::UriTooLong => StatusCode::UriTooLong,
+            ::Error::TooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ::Error::Version => StatusCode::HttpVersionNotSupported,
+            _ => StatusCode::BadRequest,
+        };
+        Some(MessageHead {
+            version: Http11,
+            subject: status,
+            headers: Headers::new(),
+        })
+    }
 }
 
 impl Http1Message for ClientMessage {
@@ -132,36 +201,46 @@ impl Http1Message for ClientMessage {
     }
 
     fn decoder(inc: &MessageHead<Self::Incoming>) -> ::Result<Decoder> {
-        use ::header;
         // According to https://tools.ietf.org/html/rfc7230#section-3.3.3
         // 1. HEAD reponses, and Status 1xx, 204, and 304 cannot have a body.
         // 2. Status 2xx to a CONNECT cannot have a body.
         //
         // First two steps taken care of before this method.
         //
-        // 3. Transfer-Encoding: chunked has a chunked body.
-        // 4. If multiple differing Content-Length headers or invalid, close connection.
+        // 3. Transfer-Encoding: chunked has a chunked body. Along with
+        //    Content-Length, it could be response splitting, so is an error.
+        // 4. If multiple differing Content-Length values or invalid, close
+        //    connection. Repeating the same value is accepted.
         // 5. Content-Length header has a sized body.
         // 6. Not Client.
         // 7. Read till EOF.
-        if let Some(&header::TransferEncoding(ref codings)) = inc.headers.get() {
-            if codings.last() == Some(&header::Encoding::Chunked) {
-                Ok(Decoder::chunked())
-            } else {
+        match (try!(transfer_encoding(&inc.headers)), try!(content_length(&inc.headers, true))) {
+            (Some(_), Some(_)) => {
+                debug!("response has both Transfer-Encoding and Content-Length");
+                Err(::Error::Framing)
+            }
+            (Some(true), None) => Ok(Decoder::chunked()),
+            (Some(false), None) => {
                 trace!("not chuncked. read till eof");
                 Ok(Decoder::eof())
             }
-        } else if let Some(&header::ContentLength(len)) = inc.headers.get() {
-            Ok(Decoder::length(len))
-        } else if inc.headers.has::<header::ContentLength>() {
-            trace!("illegal Content-Length: {:?}", inc.headers.get_raw("Content-Length"));
-            Err(::Error::Header)
-        } else {
-            trace!("neither Transfer-Encoding nor Content-Length");
-            Ok(Decoder::eof())
+            (None, Some(len)) => Ok(Decoder::length(len)),
+            (None, None) => {
+                trace!("neither Transfer-Encoding nor Content-Length");
+                Ok(Decoder::eof())
+            }
         }
     }
 
+    fn is_continue(inc: &MessageHead<Self::Incoming>) -> bool {
+        inc.subject.0 == 100
+    }
+
+    fn is_interim(inc: &MessageHead<Self::Incoming>) -> bool {
+        // 101 is the final response, to a different protocol
+        inc.subject.0 >= 100 && inc.subject.0 < 200 && inc.subject.0 != 101
+    }
+
     fn encode(mut head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>) -> Encoder {
         trace!("writing head: {:?}", head);
 
@@ -203,6 +282,120 @@ impl Http1Message for ClientMessage {
 
         body
     }
+
+    fn encode_upgrade(head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>) -> Encoder {
+        trace!("writing upgrade head: {:?}", head);
+
+        let init_cap = 30 + head.headers.len() * AVERAGE_HEADER_SIZE;
+        dst.reserve(init_cap);
+        let _ = write!(FastWrite(dst), "{} {}\r\n{}\r\n", head.subject, head.version, head.headers);
+        Encoder::eof()
+    }
+
+    fn encode_interim(_head: MessageHead<Self::Outgoing>, _dst: &mut Vec<u8>) {
+        unreachable!("requests have no informational heads")
+    }
+
+    fn on_error(_err: &::Error) -> Option<MessageHead<Self::Outgoing>> {
+        None
+    }
+}
+
+/// Whether `Transfer-Encoding` ends with `chunked`, if it was sent.
+fn transfer_encoding(headers: &Headers) -> ::Result<Option<bool>> {
+    let raw = match headers.get_raw("Transfer-Encoding") {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let mut codings = Vec::new();
+    for line in raw.iter() {
+        let line = try!(str::from_utf8(line));
+        codings.extend(line.split(',').map(str::trim).filter(|coding| !coding.is_empty()));
+    }
+    let chunked = codings.iter().filter(|coding| coding.eq_ignore_ascii_case("chunked")).count();
+    match codings.last() {
+        None => Err(::Error::Header),
+        // chunked must not be applied more than once
+        Some(last) if last.eq_ignore_ascii_case("chunked") && chunked == 1 => Ok(Some(true)),
+        Some(_) if chunked == 0 => Ok(Some(false)),
+        Some(_) => {
+            debug!("chunked is not the only final Transfer-Encoding: {:?}", codings);
+            Err(::Error::Framing)
+        }
+    }
+}
+
+/// The value of `Content-Length`, if it was sent.
+///
+/// Unless `repeated` is allowed, the field may not be repeated, even with
+/// the same value, unlike the typed `ContentLength` header. Responses allow
+/// it, as a recipient may accept identical values (RFC 7230, section
+/// 3.3.2), and servers and proxies do send them.
+fn content_length(headers: &Headers, repeated: bool) -> ::Result<Option<u64>> {
+    let raw = match headers.get_raw("Content-Length") {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    if raw.one().is_none() && !repeated {
+        debug!("multiple Content-Length fields");
+        return Err(::Error::Framing);
+    }
+    let mut len = None;
+    for line in raw.iter() {
+        for value in try!(str::from_utf8(line)).split(',') {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|b| b'0' <= b && b <= b'9') {
+                debug!("illegal Content-Length: {:?}", value);
+                return Err(if line.contains(&b',') {
+                    ::Error::Framing
+                } else {
+                    ::Error::Header
+                });
+            }
+            let value: u64 = try!(value.parse().map_err(|_| ::Error::Header));
+            if len.map_or(false, |len| len != value) || (len.is_some() && !repeated) {
+                debug!("differing Content-Length values");
+                return Err(::Error::Framing);
+            }
+            len = Some(value);
+        }
+    }
+    Ok(len)
+}
+
+/// Rejects what other parsers might read differently than httparse, which
+/// a proxy in front could be fooled with to smuggle a request: bare LF line
+/// endings, obs-fold line continuations, and whitespace before a colon.
+fn check_head(head: &[u8]) -> ::Result<()> {
+    for (i, b) in head.iter().enumerate() {
+        if *b == b'\n' && (i == 0 || head[i - 1] != b'\r') {
+            debug!("bare LF in message head");
+            return Err(::Error::Header);
+        }
+    }
+    // the request line itself was checked by httparse, after any empty lines
+    let fields = head.split(|b| *b == b'\n')
+        .map(|line| &line[..line.len().saturating_sub(1)])
+        .skip_while(|line| line.is_empty())
+        .skip(1);
+    for line in fields {
+        if line.is_empty() {
+            break;
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            debug!("obs-fold in message head");
+            return Err(::Error::Header);
+        }
+        let name = match line.iter().position(|b| *b == b':') {
+            Some(colon) => &line[..colon],
+            None => return Err(::Error::Header),
+        };
+        if name.iter().any(|b| *b == b' ' || *b == b'\t') {
+            debug!("whitespace before colon in message head");
+            return Err(::Error::Header);
+        }
+    }
+    Ok(())
 }
 
 struct FastWrite<'a>(&'a mut Vec<u8>);
@@ -252,6 +445,155 @@ mod tests {
         assert_eq!(res.subject.1, "Howdy");
     }
 
+    #[test]
+    fn test_encode_interim() {
+        use header::Headers;
+        use http::{Http1Message, MessageHead, ServerMessage};
+        use status::StatusCode;
+        use version::HttpVersion;
+
+        let mut headers = Headers::new();
+        headers.set_raw("Link", "</style.css>; rel=preload; as=style");
+        let head = MessageHead {
+            version: HttpVersion::Http11,
+            subject: StatusCode::EarlyHints,
+            headers: headers,
+        };
+        let mut dst = Vec::new();
+        ServerMessage::encode_interim(head, &mut dst);
+        assert_eq!(::std::str::from_utf8(&dst).unwrap(),
+                   "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style\r\n\r\n");
+    }
+
+    #[test]
+    fn test_is_interim() {
+        let raw = b"HTTP/1.1 103 Early Hints\r\n\r\n";
+        let (res, _) = parse::<http::ClientMessage, _>(raw).unwrap().unwrap();
+        assert!(<http::ClientMessage as http::Http1Message>::is_interim(&res));
+
+        let raw = b"HTTP/1.1 101 Switching Protocols\r\n\r\n";
+        let (res, _) = parse::<http::ClientMessage, _>(raw).unwrap().unwrap();
+        assert!(!<http::ClientMessage as http::Http1Message>::is_interim(&res));
+    }
+
+    #[test]
+    fn test_parse_request_strict() {
+        let invalid: &[&[u8]] = &[
+            b"GET / HTTP/1.1\nHost: hyper.rs\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: hyper.rs\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : hyper.rs\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: hyper.rs\r\nX-Folded: a\r\n b\r\n\r\n",
+        ];
+        for raw in invalid {
+            assert!(parse::<http::ServerMessage, _>(raw).is_err(), "{:?}", ::std::str::from_utf8(raw));
+        }
+    }
+
+    fn request_framing(raw: &[u8]) -> ::Result<()> {
+        let (req, _) = try!(parse::<http::ServerMessage, _>(raw)).expect("complete head");
+        <http::ServerMessage as http::Http1Message>::decoder(&req).map(|_| ())
+    }
+
+    #[test]
+    fn test_parse_request_line_too_long() {
+        use error::Error;
+
+        let mut raw = b"GET /".to_vec();
+        raw.extend(vec![b'a'; super::MAX_REQUEST_LINE]);
+        match parse::<http::ServerMessage, _>(&raw) {
+            Err(Error::UriTooLong) => (),
+            other => panic!("{:?}", other),
+        }
+
+        // a long head is fine, as long as the request line ended
+        let mut raw = b"GET / HTTP/1.1\r\nX-Long: ".to_vec();
+        raw.extend(vec![b'a'; super::MAX_REQUEST_LINE]);
+        assert!(parse::<http::ServerMessage, _>(&raw).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_server_on_error() {
+        use error::Error;
+        use http::{Http1Message, ServerMessage};
+        use status::StatusCode;
+
+        fn status(err: Error) -> Option<StatusCode> {
+            ServerMessage::on_error(&err).map(|head| head.subject)
+        }
+
+        assert_eq!(status(Error::Header), Some(StatusCode::BadRequest));
+        assert_eq!(status(Error::Framing), Some(StatusCode::BadRequest));
+        assert_eq!(status(Error::UriTooLong), Some(StatusCode::UriTooLong));
+        assert_eq!(status(Error::TooLarge), Some(StatusCode::RequestHeaderFieldsTooLarge));
+        assert_eq!(status(Error::Version), Some(StatusCode::HttpVersionNotSupported));
+        assert_eq!(status(Error::Timeout), None);
+    }
+
+    #[test]
+    fn test_request_framing() {
+        use error::Error;
+
+        assert!(request_framing(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n").is_ok());
+        assert!(request_framing(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n").is_ok());
+        assert!(request_framing(b"GET / HTTP/1.1\r\n\r\n").is_ok());
+
+        let ambiguous: &[&[u8]] = &[
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ];
+        for raw in ambiguous {
+            match request_framing(raw) {
+                Err(Error::Framing) => (),
+                other => panic!("{:?} for {:?}", other, ::std::str::from_utf8(raw)),
+            }
+        }
+
+        match request_framing(b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n") {
+            Err(Error::Header) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_response_framing() {
+        use error::Error;
+        use http::{ClientMessage, Http1Message};
+        use http::h1::Decoder;
+
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n";
+        let (res, _) = parse::<ClientMessage, _>(raw).unwrap().unwrap();
+        assert!(ClientMessage::decoder(&res).is_ok());
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let (res, _) = parse::<ClientMessage, _>(raw).unwrap().unwrap();
+        match ClientMessage::decoder(&res) {
+            Err(Error::Framing) => (),
+            other => panic!("{:?}", other),
+        }
+
+        // the same length repeated is accepted in a response
+        let repeated: &[&[u8]] = &[
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5, 5\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+        ];
+        for raw in repeated {
+            let (res, _) = parse::<ClientMessage, _>(raw).unwrap().unwrap();
+            let decoder = ClientMessage::decoder(&res).unwrap();
+            assert_eq!(format!("{:?}", decoder), format!("{:?}", Decoder::length(5)));
+        }
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5, 6\r\n\r\n";
+        let (res, _) = parse::<ClientMessage, _>(raw).unwrap().unwrap();
+        match ClientMessage::decoder(&res) {
+            Err(Error::Framing) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
     #[cfg(feature = "nightly")]
     use test::Bencher;
 
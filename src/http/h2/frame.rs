@@ -0,0 +1,574 @@
+//! HTTP/2 frame parsing and encoding, as defined in
+//! [RFC7540 Section 4](https://tools.ietf.org/html/rfc7540#section-4).
+use std::fmt;
+
+use super::Reason;
+
+pub const HEAD_LEN: usize = 9;
+
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+pub const MAX_MAX_FRAME_SIZE: u32 = 16_777_215;
+pub const DEFAULT_WINDOW_SIZE: u32 = 65_535;
+pub const MAX_WINDOW_SIZE: u32 = 0x7fff_ffff;
+
+mod kind {
+    pub const DATA: u8 = 0x0;
+    pub const HEADERS: u8 = 0x1;
+    pub const PRIORITY: u8 = 0x2;
+    pub const RST_STREAM: u8 = 0x3;
+    pub const SETTINGS: u8 = 0x4;
+    pub const PUSH_PROMISE: u8 = 0x5;
+    pub const PING: u8 = 0x6;
+    pub const GOAWAY: u8 = 0x7;
+    pub const WINDOW_UPDATE: u8 = 0x8;
+    pub const CONTINUATION: u8 = 0x9;
+}
+
+mod flag {
+    pub const END_STREAM: u8 = 0x1;
+    pub const ACK: u8 = 0x1;
+    pub const END_HEADERS: u8 = 0x4;
+    pub const PADDED: u8 = 0x8;
+    pub const PRIORITY: u8 = 0x20;
+}
+
+/// Identifiers of the `SETTINGS` parameters.
+pub mod setting {
+    pub const HEADER_TABLE_SIZE: u16 = 0x1;
+    pub const ENABLE_PUSH: u16 = 0x2;
+    pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+    pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+    pub const MAX_FRAME_SIZE: u16 = 0x5;
+    pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+}
+
+pub type StreamId = u32;
+
+/// The 9 octet header that begins every frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Head {
+    pub length: u32,
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: StreamId,
+}
+
+impl Head {
+    pub fn parse(buf: &[u8]) -> Head {
+        debug_assert!(buf.len() >= HEAD_LEN);
+        Head {
+            length: (buf[0] as u32) << 16 | (buf[1] as u32) << 8 | buf[2] as u32,
+            kind: buf[3],
+            flags: buf[4],
+            stream_id: read_u32(&buf[5..9]) & MAX_WINDOW_SIZE,
+        }
+    }
+
+    pub fn encode(&self, dst: &mut Vec<u8>) {
+        dst.push((self.length >> 16) as u8);
+        dst.push((self.length >> 8) as u8);
+        dst.push(self.length as u8);
+        dst.push(self.kind);
+        dst.push(self.flags);
+        push_u32(dst, self.stream_id & MAX_WINDOW_SIZE);
+    }
+}
+
+/// A parsed HTTP/2 frame.
+#[derive(PartialEq)]
+pub enum Frame {
+    Data {
+        stream_id: StreamId,
+        data: Vec<u8>,
+        /// The full length of the frame payload, including padding, which
+        /// counts against flow control.
+        flow_len: u32,
+        end_stream: bool,
+    },
+    Headers {
+        stream_id: StreamId,
+        block: Vec<u8>,
+        end_stream: bool,
+        end_headers: bool,
+    },
+    Priority {
+        stream_id: StreamId,
+    },
+    RstStream {
+        stream_id: StreamId,
+        reason: Reason,
+    },
+    Settings {
+        ack: bool,
+        params: Vec<(u16, u32)>,
+    },
+    PushPromise {
+        stream_id: StreamId,
+    },
+    Ping {
+        ack: bool,
+        payload: [u8; 8],
+    },
+    GoAway {
+        last_stream_id: StreamId,
+        reason: Reason,
+    },
+    WindowUpdate {
+        stream_id: StreamId,
+        increment: u32,
+    },
+    Continuation {
+        stream_id: StreamId,
+        block: Vec<u8>,
+        end_headers: bool,
+    },
+    Unknown,
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Frame::Data { stream_id, ref data, end_stream, .. } => f.debug_struct("Data")
+                .field("stream_id", &stream_id)
+                .field("len", &data.len())
+                .field("end_stream", &end_stream)
+                .finish(),
+            Frame::Headers { stream_id, ref block, end_stream, end_headers } => f.debug_struct("Headers")
+                .field("stream_id", &stream_id)
+                .field("len", &block.len())
+                .field("end_stream", &end_stream)
+                .field("end_headers", &end_headers)
+                .finish(),
+            Frame::Priority { stream_id } => f.debug_struct("Priority")
+                .field("stream_id", &stream_id)
+                .finish(),
+            Frame::RstStream { stream_id, reason } => f.debug_struct("RstStream")
+                .field("stream_id", &stream_id)
+                .field("reason", &reason)
+                .finish(),
+            Frame::Settings { ack, ref params } => f.debug_struct("Settings")
+                .field("ack", &ack)
+                .field("params", params)
+                .finish(),
+            Frame::PushPromise { stream_id } => f.debug_struct("PushPromise")
+                .field("stream_id", &stream_id)
+                .finish(),
+            Frame::Ping { ack, .. } => f.debug_struct("Ping")
+                .field("ack", &ack)
+                .finish(),
+            Frame::GoAway { last_stream_id, reason } => f.debug_struct("GoAway")
+                .field("last_stream_id", &last_stream_id)
+                .field("reason", &reason)
+                .finish(),
+            Frame::WindowUpdate { stream_id, increment } => f.debug_struct("WindowUpdate")
+                .field("stream_id", &stream_id)
+                .field("increment", &increment)
+                .finish(),
+            Frame::Continuation { stream_id, ref block, end_headers } => f.debug_struct("Continuation")
+                .field("stream_id", &stream_id)
+                .field("len", &block.len())
+                .field("end_headers", &end_headers)
+                .finish(),
+            Frame::Unknown => f.write_str("Unknown"),
+        }
+    }
+}
+
+/// Try to parse a single frame from the front of `buf`.
+///
+/// Returns `Ok(None)` if there are not yet enough bytes for a full frame.
+/// On success, the number of bytes consumed is returned with the frame.
+pub fn parse(buf: &[u8], max_frame_size: u32) -> Result<Option<(Frame, usize)>, Reason> {
+    if buf.len() < HEAD_LEN {
+        return Ok(None);
+    }
+    let head = Head::parse(buf);
+    if head.length > max_frame_size {
+        debug!("frame too large: {} > {}", head.length, max_frame_size);
+        return Err(Reason::FrameSizeError);
+    }
+    let total = HEAD_LEN + head.length as usize;
+    if buf.len() < total {
+        return Ok(None);
+    }
+    let payload = &buf[HEAD_LEN..total];
+    let frame = try!(parse_payload(head, payload));
+    trace!("parsed frame {:?}", frame);
+    Ok(Some((frame, total)))
+}
+
+fn parse_payload(head: Head, payload: &[u8]) -> Result<Frame, Reason> {
+    let id = head.stream_id;
+    match head.kind {
+        kind::DATA => {
+            if id == 0 {
+                return Err(Reason::ProtocolError);
+            }
+            let data = try!(strip_padding(head.flags, payload));
+            Ok(Frame::Data {
+                stream_id: id,
+                data: data.to_vec(),
+                flow_len: head.length,
+                end_stream: head.flags & flag::END_STREAM != 0,
+            })
+        }
+        kind::HEADERS => {
+            if id == 0 {
+                return Err(Reason::ProtocolError);
+            }
+            let mut block = try!(strip_padding(head.flags, payload));
+            if head.flags & flag::PRIORITY != 0 {
+                if block.len() < 5 {
+                    return Err(Reason::FrameSizeError);
+                }
+                block = &block[5..];
+            }
+            Ok(Frame::Headers {
+                stream_id: id,
+                block: block.to_vec(),
+                end_stream: head.flags & flag::END_STREAM != 0,
+                end_headers: head.flags & flag::END_HEADERS != 0,
+            })
+        }
+        kind::PRIORITY => {
+            if id == 0 {
+                return Err(Reason::ProtocolError);
+            }
+            if payload.len() != 5 {
+                return Err(Reason::FrameSizeError);
+            }
+            Ok(Frame::Priority { stream_id: id })
+        }
+        kind::RST_STREAM => {
+            if id == 0 {
+                return Err(Reason::ProtocolError);
+            }
+            if payload.len() != 4 {
+                return Err(Reason::FrameSizeError);
+            }
+            Ok(Frame::RstStream {
+                stream_id: id,
+                reason: Reason::from_u32(read_u32(payload)),
+            })
+        }
+        kind::SETTINGS => {
+            if id != 0 {
+                return Err(Reason::ProtocolError);
+            }
+            let ack = head.flags & flag::ACK != 0;
+            if ack && !payload.is_empty() {
+                return Err(Reason::FrameSizeError);
+            }
+            if payload.len() % 6 != 0 {
+                return Err(Reason::FrameSizeError);
+            }
+            let params = payload.chunks(6).map(|chunk| {
+                ((chunk[0] as u16) << 8 | chunk[1] as u16, read_u32(&chunk[2..]))
+            }).collect();
+            Ok(Frame::Settings {
+                ack: ack,
+                params: params,
+            })
+        }
+        kind::PUSH_PROMISE => Ok(Frame::PushPromise { stream_id: id }),
+        kind::PING => {
+            if id != 0 {
+                return Err(Reason::ProtocolError);
+            }
+            if payload.len() != 8 {
+                return Err(Reason::FrameSizeError);
+            }
+            let mut data = [0; 8];
+            data.copy_from_slice(payload);
+            Ok(Frame::Ping {
+                ack: head.flags & flag::ACK != 0,
+                payload: data,
+            })
+        }
+        kind::GOAWAY => {
+            if id != 0 {
+                return Err(Reason::ProtocolError);
+            }
+            if payload.len() < 8 {
+                return Err(Reason::FrameSizeError);
+            }
+            Ok(Frame::GoAway {
+                last_stream_id: read_u32(payload) & MAX_WINDOW_SIZE,
+                reason: Reason::from_u32(read_u32(&payload[4..])),
+            })
+        }
+        kind::WINDOW_UPDATE => {
+            if payload.len() != 4 {
+                return Err(Reason::FrameSizeError);
+            }
+            Ok(Frame::WindowUpdate {
+                stream_id: id,
+                increment: read_u32(payload) & MAX_WINDOW_SIZE,
+            })
+        }
+        kind::CONTINUATION => {
+            if id == 0 {
+                return Err(Reason::ProtocolError);
+            }
+            Ok(Frame::Continuation {
+                stream_id: id,
+                block: payload.to_vec(),
+                end_headers: head.flags & flag::END_HEADERS != 0,
+            })
+        }
+        // Implementations MUST ignore and discard any frame that has a type
+        // that is unknown.
+        _ => Ok(Frame::Unknown),
+    }
+}
+
+fn strip_padding(flags: u8, payload: &[u8]) -> Result<&[u8], Reason> {
+    if flags & flag::PADDED == 0 {
+        return Ok(payload);
+    }
+    if payload.is_empty() {
+        return Err(Reason::ProtocolError);
+    }
+    let pad = payload[0] as usize;
+    if pad >= payload.len() {
+        return Err(Reason::ProtocolError);
+    }
+    Ok(&payload[1..payload.len() - pad])
+}
+
+pub fn encode_data(dst: &mut Vec<u8>, stream_id: StreamId, data: &[u8], end_stream: bool) {
+    Head {
+        length: data.len() as u32,
+        kind: kind::DATA,
+        flags: if end_stream { flag::END_STREAM } else { 0 },
+        stream_id: stream_id,
+    }.encode(dst);
+    dst.extend_from_slice(data);
+}
+
+/// Encodes a header block, splitting it into `CONTINUATION` frames if it
+/// is larger than `max_frame_size`.
+pub fn encode_headers(dst: &mut Vec<u8>, stream_id: StreamId, block: &[u8], end_stream: bool, max_frame_size: usize) {
+    let mut chunks = block.chunks(max_frame_size);
+    let first = chunks.next().unwrap_or(&[]);
+    let mut flags = if end_stream { flag::END_STREAM } else { 0 };
+    if first.len() == block.len() {
+        flags |= flag::END_HEADERS;
+    }
+    Head {
+        length: first.len() as u32,
+        kind: kind::HEADERS,
+        flags: flags,
+        stream_id: stream_id,
+    }.encode(dst);
+    dst.extend_from_slice(first);
+
+    let mut written = first.len();
+    for chunk in chunks {
+        written += chunk.len();
+        Head {
+            length: chunk.len() as u32,
+            kind: kind::CONTINUATION,
+            flags: if written == block.len() { flag::END_HEADERS } else { 0 },
+            stream_id: stream_id,
+        }.encode(dst);
+        dst.extend_from_slice(chunk);
+    }
+}
+
+pub fn encode_rst_stream(dst: &mut Vec<u8>, stream_id: StreamId, reason: Reason) {
+    Head {
+        length: 4,
+        kind: kind::RST_STREAM,
+        flags: 0,
+        stream_id: stream_id,
+    }.encode(dst);
+    push_u32(dst, reason.to_u32());
+}
+
+pub fn encode_settings(dst: &mut Vec<u8>, params: &[(u16, u32)]) {
+    Head {
+        length: (params.len() * 6) as u32,
+        kind: kind::SETTINGS,
+        flags: 0,
+        stream_id: 0,
+    }.encode(dst);
+    for &(id, val) in params {
+        dst.push((id >> 8) as u8);
+        dst.push(id as u8);
+        push_u32(dst, val);
+    }
+}
+
+pub fn encode_settings_ack(dst: &mut Vec<u8>) {
+    Head {
+        length: 0,
+        kind: kind::SETTINGS,
+        flags: flag::ACK,
+        stream_id: 0,
+    }.encode(dst);
+}
+
+pub fn encode_ping_ack(dst: &mut Vec<u8>, payload: &[u8; 8]) {
+    Head {
+        length: 8,
+        kind: kind::PING,
+        flags: flag::ACK,
+        stream_id: 0,
+    }.encode(dst);
+    dst.extend_from_slice(payload);
+}
+
+pub fn encode_goaway(dst: &mut Vec<u8>, last_stream_id: StreamId, reason: Reason) {
+    Head {
+        length: 8,
+        kind: kind::GOAWAY,
+        flags: 0,
+        stream_id: 0,
+    }.encode(dst);
+    push_u32(dst, last_stream_id & MAX_WINDOW_SIZE);
+    push_u32(dst, reason.to_u32());
+}
+
+pub fn encode_window_update(dst: &mut Vec<u8>, stream_id: StreamId, increment: u32) {
+    Head {
+        length: 4,
+        kind: kind::WINDOW_UPDATE,
+        flags: 0,
+        stream_id: stream_id,
+    }.encode(dst);
+    push_u32(dst, increment & MAX_WINDOW_SIZE);
+}
+
+/// Parses the payload of a `SETTINGS` frame from its base64url encoding,
+/// as used in the `HTTP2-Settings` header.
+pub fn decode_settings_payload(payload: &[u8]) -> Option<Vec<(u16, u32)>> {
+    if payload.len() % 6 != 0 {
+        return None;
+    }
+    Some(payload.chunks(6).map(|chunk| {
+        ((chunk[0] as u16) << 8 | chunk[1] as u16, read_u32(&chunk[2..]))
+    }).collect())
+}
+
+#[inline]
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+#[inline]
+fn push_u32(dst: &mut Vec<u8>, val: u32) {
+    dst.push((val >> 24) as u8);
+    dst.push((val >> 16) as u8);
+    dst.push((val >> 8) as u8);
+    dst.push(val as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::h2::Reason;
+
+    #[test]
+    fn test_head_roundtrip() {
+        let head = Head {
+            length: 0x12345,
+            kind: 0x1,
+            flags: 0x5,
+            stream_id: 7,
+        };
+        let mut buf = Vec::new();
+        head.encode(&mut buf);
+        assert_eq!(buf.len(), HEAD_LEN);
+        assert_eq!(Head::parse(&buf), head);
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        let mut buf = Vec::new();
+        encode_data(&mut buf, 1, b"hello", true);
+        assert_eq!(parse(&buf[..5], DEFAULT_MAX_FRAME_SIZE), Ok(None));
+        assert_eq!(parse(&buf[..buf.len() - 1], DEFAULT_MAX_FRAME_SIZE), Ok(None));
+        let (frame, len) = parse(&buf, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(frame, Frame::Data {
+            stream_id: 1,
+            data: b"hello".to_vec(),
+            flow_len: 5,
+            end_stream: true,
+        });
+    }
+
+    #[test]
+    fn test_parse_padded_data() {
+        let buf = [0, 0, 6, 0, 0x8, 0, 0, 0, 3, 2, b'h', b'i', b'!', 0, 0];
+        let (frame, _) = parse(&buf, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(frame, Frame::Data {
+            stream_id: 3,
+            data: b"hi!".to_vec(),
+            flow_len: 6,
+            end_stream: false,
+        });
+    }
+
+    #[test]
+    fn test_parse_too_large() {
+        let mut buf = Vec::new();
+        encode_data(&mut buf, 1, &[0; 20], false);
+        assert_eq!(parse(&buf, 10), Err(Reason::FrameSizeError));
+    }
+
+    #[test]
+    fn test_settings_roundtrip() {
+        let mut buf = Vec::new();
+        encode_settings(&mut buf, &[(setting::MAX_CONCURRENT_STREAMS, 100), (setting::ENABLE_PUSH, 0)]);
+        let (frame, _) = parse(&buf, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(frame, Frame::Settings {
+            ack: false,
+            params: vec![(setting::MAX_CONCURRENT_STREAMS, 100), (setting::ENABLE_PUSH, 0)],
+        });
+    }
+
+    #[test]
+    fn test_settings_with_stream_id_is_error() {
+        let mut buf = Vec::new();
+        Head { length: 0, kind: 0x4, flags: 0, stream_id: 1 }.encode(&mut buf);
+        assert_eq!(parse(&buf, DEFAULT_MAX_FRAME_SIZE), Err(Reason::ProtocolError));
+    }
+
+    #[test]
+    fn test_headers_continuation() {
+        let block = [7u8; 25];
+        let mut buf = Vec::new();
+        encode_headers(&mut buf, 5, &block, true, 10);
+
+        let (first, n1) = parse(&buf, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(first, Frame::Headers {
+            stream_id: 5,
+            block: vec![7; 10],
+            end_stream: true,
+            end_headers: false,
+        });
+        let (second, n2) = parse(&buf[n1..], DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(second, Frame::Continuation {
+            stream_id: 5,
+            block: vec![7; 10],
+            end_headers: false,
+        });
+        let (third, n3) = parse(&buf[n1 + n2..], DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap();
+        assert_eq!(third, Frame::Continuation {
+            stream_id: 5,
+            block: vec![7; 5],
+            end_headers: true,
+        });
+        assert_eq!(n1 + n2 + n3, buf.len());
+    }
+
+    #[test]
+    fn test_unknown_frame_ignored() {
+        let mut buf = Vec::new();
+        Head { length: 2, kind: 0xfa, flags: 0, stream_id: 0 }.encode(&mut buf);
+        buf.extend_from_slice(b"hi");
+        assert_eq!(parse(&buf, DEFAULT_MAX_FRAME_SIZE).unwrap().unwrap(), (Frame::Unknown, 11));
+    }
+}
@@ -1,810 +1,1254 @@
-//! Adapts the `solicit`-provided HTTP/2 implementation into the `HttpMessage` API.
-
-use std::io::{self, Write, Read, Cursor};
-use std::net::Shutdown;
+//! HTTP/2 connection and stream state.
+//!
+//! This contains the protocol pieces of HTTP/2 that don't depend on the
+//! `MessageHandler`s: framing, HPACK, settings and flow control. The
+//! `http::Conn` drives these from the event loop, and maps each stream onto
+//! its own `MessageHandler`.
 use std::ascii::AsciiExt;
-use std::mem;
-use std::time::Duration;
-
-use http::{
-    Protocol,
-    HttpMessage,
-    RequestHead,
-    ResponseHead,
-    RawStatus,
-};
-use net::{NetworkStream, NetworkConnector};
-use net::{HttpConnector, HttpStream};
-use url::Position as UrlPosition;
-use header::Headers;
-
-use header;
-use version;
-
-use solicit::http::Header as Http2Header;
-use solicit::http::HttpScheme;
-use solicit::http::HttpError as Http2Error;
-use solicit::http::transport::TransportStream;
-use solicit::http::client::{ClientStream, HttpConnect, HttpConnectError, write_preface};
-use solicit::client::SimpleClient;
+use std::borrow::Cow;
+use std::cmp;
+use std::fmt;
+use std::io::{self, Write};
 
+use hpack;
 use httparse;
+use serialize::base64::FromBase64;
+
+use header::{self, Headers, ProtocolName};
+use http::content::ContentDecoder;
+use http::{MessageHead, RawStatus, RequestLine, Http2Message, ServerMessage, ClientMessage, strip_trailers};
+use method::Method;
+use status::StatusCode;
+use uri::RequestUri;
+use version::HttpVersion;
+
+pub use self::frame::{Frame, StreamId};
+
+pub mod frame;
+
+/// The connection preface every HTTP/2 client must send first.
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The ALPN protocol id for HTTP/2 over TLS.
+pub const ALPN_ID: &'static [u8] = b"h2";
+
+/// Max amount of bytes queued to be written before `Encoder`s are told to
+/// wait.
+const MAX_WRITE_BUF: usize = frame::DEFAULT_MAX_FRAME_SIZE as usize * 4;
+
+/// Streams allowed concurrently when acting as a server, and assumed to be
+/// allowed by a peer that hasn't sent `SETTINGS_MAX_CONCURRENT_STREAMS`.
+/// This is the minimum recommended by RFC 7540, instead of the unlimited
+/// initial value.
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// The largest header list we accept, advertised as
+/// `SETTINGS_MAX_HEADER_LIST_SIZE`. A header block is never larger than the
+/// list it encodes, so a block split over `CONTINUATION` frames is also
+/// limited to this size.
+const MAX_HEADER_LIST_SIZE: u32 = 64 * 1024;
+
+/// Whether the bytes received so far could be the start of the client
+/// preface.
+pub fn is_preface(bytes: &[u8]) -> bool {
+    let n = cmp::min(bytes.len(), PREFACE.len());
+    n > 0 && bytes[..n] == PREFACE[..n]
+}
 
-/// A trait alias representing all types that are both `NetworkStream` and `Clone`.
-pub trait CloneableStream: NetworkStream + Clone {}
-impl<S: NetworkStream + Clone> CloneableStream for S {}
-
-/// A newtype wrapping any `CloneableStream` in order to provide an implementation of a
-/// `TransportSream` trait for all types that are a `CloneableStream`.
-#[derive(Clone)]
-struct Http2Stream<S: CloneableStream>(S);
+/// Error codes used in `RST_STREAM` and `GOAWAY` frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    NoError,
+    ProtocolError,
+    InternalError,
+    FlowControlError,
+    SettingsTimeout,
+    StreamClosed,
+    FrameSizeError,
+    RefusedStream,
+    Cancel,
+    CompressionError,
+    ConnectError,
+    EnhanceYourCalm,
+    InadequateSecurity,
+    Http11Required,
+    Other(u32),
+}
 
-impl<S> Write for Http2Stream<S> where S: CloneableStream {
-    #[inline]
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+impl Reason {
+    pub fn from_u32(val: u32) -> Reason {
+        match val {
+            0x0 => Reason::NoError,
+            0x1 => Reason::ProtocolError,
+            0x2 => Reason::InternalError,
+            0x3 => Reason::FlowControlError,
+            0x4 => Reason::SettingsTimeout,
+            0x5 => Reason::StreamClosed,
+            0x6 => Reason::FrameSizeError,
+            0x7 => Reason::RefusedStream,
+            0x8 => Reason::Cancel,
+            0x9 => Reason::CompressionError,
+            0xa => Reason::ConnectError,
+            0xb => Reason::EnhanceYourCalm,
+            0xc => Reason::InadequateSecurity,
+            0xd => Reason::Http11Required,
+            other => Reason::Other(other),
+        }
     }
-    #[inline]
-    fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+
+    pub fn to_u32(&self) -> u32 {
+        match *self {
+            Reason::NoError => 0x0,
+            Reason::ProtocolError => 0x1,
+            Reason::InternalError => 0x2,
+            Reason::FlowControlError => 0x3,
+            Reason::SettingsTimeout => 0x4,
+            Reason::StreamClosed => 0x5,
+            Reason::FrameSizeError => 0x6,
+            Reason::RefusedStream => 0x7,
+            Reason::Cancel => 0x8,
+            Reason::CompressionError => 0x9,
+            Reason::ConnectError => 0xa,
+            Reason::EnhanceYourCalm => 0xb,
+            Reason::InadequateSecurity => 0xc,
+            Reason::Http11Required => 0xd,
+            Reason::Other(other) => other,
+        }
     }
-}
 
-impl<S> Read for Http2Stream<S> where S: CloneableStream {
-    #[inline]
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+    /// Convert into an `io::Error` that can be handed to a `MessageHandler`.
+    pub fn into_io(self) -> io::Error {
+        let kind = match self {
+            Reason::Cancel |
+            Reason::RefusedStream => io::ErrorKind::ConnectionAborted,
+            _ => io::ErrorKind::ConnectionReset,
+        };
+        io::Error::new(kind, format!("http2 stream error: {:?}", self))
     }
 }
 
-impl<S> TransportStream for Http2Stream<S> where S: CloneableStream {
-    fn try_split(&self) -> Result<Http2Stream<S>, io::Error> {
-        Ok(self.clone())
-    }
+/// The `SETTINGS` of one side of a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub header_table_size: u32,
+    pub enable_push: bool,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: u32,
+}
 
-    fn close(&mut self) -> Result<(), io::Error> {
-        self.0.close(Shutdown::Both)
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            header_table_size: 4096,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: frame::DEFAULT_WINDOW_SIZE,
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+        }
     }
 }
 
-/// A helper struct that implements the `HttpConnect` trait from the `solicit` crate.
-///
-/// This is used by the `Http2Protocol` when it needs to create a new `SimpleClient`.
-struct Http2Connector<S> where S: CloneableStream {
-    stream: S,
-    scheme: HttpScheme,
-    host: String,
+/// The connection-wide state of an HTTP/2 connection.
+pub struct Connection {
+    is_client: bool,
+    awaiting_preface: bool,
+    local: Settings,
+    remote: Settings,
+    encoder: hpack::Encoder<'static>,
+    decoder: hpack::Decoder<'static>,
+    send_window: i64,
+    recv_window: i64,
+    unreleased: u32,
+    next_stream_id: StreamId,
+    last_remote_stream_id: StreamId,
+    continuation: Option<(StreamId, Vec<u8>, bool)>,
+    going_away: bool,
+    write_buf: Vec<u8>,
+    write_pos: usize,
 }
 
-#[derive(Debug)]
-struct Http2ConnectError(io::Error);
-
-impl ::std::fmt::Display for Http2ConnectError {
-    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(fmt, "HTTP/2 connect error: {}", (self as &::std::error::Error).description())
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("h2::Connection")
+            .field("is_client", &self.is_client)
+            .field("local", &self.local)
+            .field("remote", &self.remote)
+            .field("send_window", &self.send_window)
+            .field("recv_window", &self.recv_window)
+            .field("going_away", &self.going_away)
+            .field("pending_write", &(self.write_buf.len() - self.write_pos))
+            .finish()
     }
 }
 
-impl ::std::error::Error for Http2ConnectError {
-    fn description(&self) -> &str {
-        self.0.description()
+impl Connection {
+    /// Creates a client connection, queueing the preface and our `SETTINGS`.
+    pub fn client() -> Connection {
+        let mut conn = Connection::new(true);
+        conn.local.enable_push = false;
+        conn.write_buf.extend_from_slice(PREFACE);
+        frame::encode_settings(&mut conn.write_buf, &[
+            (frame::setting::ENABLE_PUSH, 0),
+            (frame::setting::MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE),
+        ]);
+        conn
     }
 
-    fn cause(&self) -> Option<&::std::error::Error> {
-        self.0.cause()
+    /// Creates a server connection, which expects the client preface to be
+    /// read before any frames.
+    pub fn server() -> Connection {
+        Connection::server_with_prefix(b"")
     }
-}
 
-impl HttpConnectError for Http2ConnectError {}
+    /// Creates a server connection upgraded from an HTTP/1.1 request with
+    /// `Upgrade: h2c`.
+    ///
+    /// The `101 Switching Protocols` response is queued before our
+    /// `SETTINGS`, and the settings from the `HTTP2-Settings` header are
+    /// applied without an `ACK`, as the 101 acknowledges them.
+    pub fn server_upgrade(settings: &[(u16, u32)]) -> Result<Connection, Reason> {
+        let mut conn = Connection::server_with_prefix(
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n");
+        try!(conn.apply_settings(settings));
+        Ok(conn)
+    }
 
-impl From<io::Error> for Http2ConnectError {
-    fn from(e: io::Error) -> Http2ConnectError { Http2ConnectError(e) }
-}
+    fn server_with_prefix(prefix: &[u8]) -> Connection {
+        let mut conn = Connection::new(false);
+        conn.awaiting_preface = true;
+        conn.local.max_concurrent_streams = Some(MAX_CONCURRENT_STREAMS);
+        conn.write_buf.extend_from_slice(prefix);
+        frame::encode_settings(&mut conn.write_buf, &[
+            (frame::setting::MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (frame::setting::MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE),
+        ]);
+        conn
+    }
 
-impl<S> HttpConnect for Http2Connector<S> where S: CloneableStream {
-    /// The type of the underlying transport stream that the `HttpConnection`s
-    /// produced by this `HttpConnect` implementation will be based on.
-    type Stream = Http2Stream<S>;
-    /// The type of the error that can be produced by trying to establish the
-    /// connection (i.e. calling the `connect` method).
-    type Err = Http2ConnectError;
+    fn new(is_client: bool) -> Connection {
+        Connection {
+            is_client: is_client,
+            awaiting_preface: false,
+            local: Settings::default(),
+            remote: Settings::default(),
+            encoder: hpack::Encoder::new(),
+            decoder: hpack::Decoder::new(),
+            send_window: frame::DEFAULT_WINDOW_SIZE as i64,
+            recv_window: frame::DEFAULT_WINDOW_SIZE as i64,
+            unreleased: 0,
+            next_stream_id: if is_client { 1 } else { 2 },
+            last_remote_stream_id: 0,
+            continuation: None,
+            going_away: false,
+            write_buf: Vec::new(),
+            write_pos: 0,
+        }
+    }
 
-    /// Establishes a network connection that can be used by HTTP/2 connections.
-    fn connect(mut self) -> Result<ClientStream<Self::Stream>, Self::Err> {
-        try!(write_preface(&mut self.stream));
-        Ok(ClientStream(Http2Stream(self.stream), self.scheme, self.host))
+    #[inline]
+    pub fn is_client(&self) -> bool {
+        self.is_client
     }
-}
 
-/// The `Protocol` implementation that provides HTTP/2 messages (i.e. `Http2Message`).
-pub struct Http2Protocol<C, S> where C: NetworkConnector<Stream=S> + Send + 'static,
-                                 S: NetworkStream + Send + Clone {
-    connector: C,
-}
+    #[inline]
+    pub fn is_going_away(&self) -> bool {
+        self.going_away
+    }
 
-impl<C, S> Http2Protocol<C, S> where C: NetworkConnector<Stream=S> + Send + 'static,
-                                     S: NetworkStream + Send + Clone {
-    /// Create a new `Http2Protocol` that will use the given `NetworkConnector` to establish TCP
-    /// connections to the server.
-    pub fn with_connector(connector: C) -> Http2Protocol<C, S> {
-        Http2Protocol {
-            connector: connector,
-        }
+    /// The largest frame this side is willing to receive.
+    #[inline]
+    pub fn max_recv_frame_size(&self) -> u32 {
+        self.local.max_frame_size
     }
 
-    /// A private helper method that creates a new `SimpleClient` that will use the given
-    /// `NetworkStream` to communicate to the remote host.
-    fn new_client(&self, stream: S, host: String, scheme: HttpScheme)
-            -> ::Result<SimpleClient<Http2Stream<S>>> {
-        Ok(try!(SimpleClient::with_connector(Http2Connector {
-            stream: stream,
-            scheme: scheme,
-            host: host,
-        })))
+    /// Consumes the client preface from the front of `buf`.
+    ///
+    /// Returns the number of bytes consumed, which is `0` if more bytes are
+    /// needed.
+    pub fn read_preface(&mut self, buf: &[u8]) -> Result<usize, Reason> {
+        if !self.awaiting_preface {
+            return Ok(0);
+        }
+        let n = cmp::min(buf.len(), PREFACE.len());
+        if buf[..n] != PREFACE[..n] {
+            debug!("invalid http2 preface");
+            return Err(Reason::ProtocolError);
+        }
+        if n < PREFACE.len() {
+            return Ok(0);
+        }
+        self.awaiting_preface = false;
+        Ok(n)
     }
-}
 
-impl<C, S> Protocol for Http2Protocol<C, S> where C: NetworkConnector<Stream=S> + Send + 'static,
-                                                  S: NetworkStream + Send + Clone {
-    fn new_message(&self, host: &str, port: u16, scheme: &str) -> ::Result<Box<HttpMessage>> {
-        let stream = try!(self.connector.connect(host, port, scheme)).into();
+    #[inline]
+    pub fn is_awaiting_preface(&self) -> bool {
+        self.awaiting_preface
+    }
 
-        let scheme = match scheme {
-            "http" => HttpScheme::Http,
-            "https" => HttpScheme::Https,
-            _ => return Err(From::from(Http2Error::from(
-                        io::Error::new(io::ErrorKind::Other, "Invalid scheme")))),
-        };
-        let client = try!(self.new_client(stream, host.into(), scheme));
+    /// Applies the peer's `SETTINGS`, queueing the `ACK`.
+    ///
+    /// Returns the change to the initial stream window size, which needs to
+    /// be applied to all open streams.
+    pub fn recv_settings(&mut self, params: &[(u16, u32)]) -> Result<i64, Reason> {
+        let delta = try!(self.apply_settings(params));
+        frame::encode_settings_ack(&mut self.write_buf);
+        Ok(delta)
+    }
 
-        Ok(Box::new(Http2Message::with_client(client)))
+    fn apply_settings(&mut self, params: &[(u16, u32)]) -> Result<i64, Reason> {
+        use self::frame::setting;
+        let mut delta = 0;
+        for &(id, val) in params {
+            match id {
+                setting::HEADER_TABLE_SIZE => self.remote.header_table_size = val,
+                setting::ENABLE_PUSH => {
+                    if val > 1 {
+                        return Err(Reason::ProtocolError);
+                    }
+                    self.remote.enable_push = val == 1;
+                }
+                setting::MAX_CONCURRENT_STREAMS => self.remote.max_concurrent_streams = Some(val),
+                setting::INITIAL_WINDOW_SIZE => {
+                    if val > frame::MAX_WINDOW_SIZE {
+                        return Err(Reason::FlowControlError);
+                    }
+                    delta += val as i64 - self.remote.initial_window_size as i64;
+                    self.remote.initial_window_size = val;
+                }
+                setting::MAX_FRAME_SIZE => {
+                    if val < frame::DEFAULT_MAX_FRAME_SIZE || val > frame::MAX_MAX_FRAME_SIZE {
+                        return Err(Reason::ProtocolError);
+                    }
+                    self.remote.max_frame_size = val;
+                }
+                setting::MAX_HEADER_LIST_SIZE => (),
+                // unknown settings MUST be ignored
+                _ => (),
+            }
+        }
+        Ok(delta)
     }
-}
 
-/// Represents an HTTP/2 request, described by a `RequestHead` and the body of the request.
-/// A convenience struct only in use by the `Http2Message`.
-#[derive(Clone, Debug)]
-struct Http2Request {
-    head: RequestHead,
-    body: Vec<u8>,
-}
+    pub fn recv_ping(&mut self, payload: &[u8; 8]) {
+        frame::encode_ping_ack(&mut self.write_buf, payload);
+    }
 
-/// Represents an HTTP/2 response.
-/// A convenience struct only in use by the `Http2Message`.
-#[derive(Clone, Debug)]
-struct Http2Response {
-    body: Cursor<Vec<u8>>,
-}
+    pub fn recv_window_update(&mut self, increment: u32) -> Result<(), Reason> {
+        self.send_window += increment as i64;
+        if self.send_window > frame::MAX_WINDOW_SIZE as i64 {
+            return Err(Reason::FlowControlError);
+        }
+        Ok(())
+    }
 
-/// The enum tracks the state of the `Http2Message`.
-enum MessageState {
-    /// State corresponding to no message being set to outgoing yet.
-    Idle,
-    /// State corresponding to an outgoing message being written out.
-    Writing(Http2Request),
-    /// State corresponding to an incoming message being read.
-    Reading(Http2Response),
-}
+    pub fn recv_go_away(&mut self) {
+        self.going_away = true;
+    }
 
-impl MessageState {
-    fn take_request(&mut self) -> Option<Http2Request> {
-        match *self {
-            MessageState::Idle | MessageState::Reading(_) => return None,
-            MessageState::Writing(_) => {},
+    /// Records the start of a header block that continues in
+    /// `CONTINUATION` frames.
+    pub fn start_continuation(&mut self, id: StreamId, block: Vec<u8>, end_stream: bool) -> Result<(), Reason> {
+        if self.continuation.is_some() {
+            return Err(Reason::ProtocolError);
+        }
+        if block.len() > MAX_HEADER_LIST_SIZE as usize {
+            return Err(Reason::EnhanceYourCalm);
         }
-        let old = mem::replace(self, MessageState::Idle);
+        self.continuation = Some((id, block, end_stream));
+        Ok(())
+    }
 
-        match old {
-            // These states are effectively unreachable since we already know the state
-            MessageState::Idle | MessageState::Reading(_) => None,
-            MessageState::Writing(req) => Some(req),
+    /// Appends a `CONTINUATION` fragment, returning the whole header block
+    /// and its `END_STREAM` flag once it is complete.
+    ///
+    /// A block growing past `MAX_HEADER_LIST_SIZE` is a connection error,
+    /// so that endless fragments can't take up all memory.
+    pub fn recv_continuation(&mut self, id: StreamId, block: &[u8], end_headers: bool) -> Result<Option<(Vec<u8>, bool)>, Reason> {
+        match self.continuation {
+            Some((cont_id, ref mut buf, _)) if cont_id == id => {
+                if buf.len() + block.len() > MAX_HEADER_LIST_SIZE as usize {
+                    debug!("header block is larger than {} bytes", MAX_HEADER_LIST_SIZE);
+                    return Err(Reason::EnhanceYourCalm);
+                }
+                buf.extend_from_slice(block)
+            }
+            _ => return Err(Reason::ProtocolError),
+        }
+        if end_headers {
+            Ok(self.continuation.take().map(|(_, buf, end_stream)| (buf, end_stream)))
+        } else {
+            Ok(None)
         }
     }
-}
 
-/// An implementation of the `HttpMessage` trait for HTTP/2.
-///
-/// Relies on the `solicit::http::SimpleClient` for HTTP/2 communication. Adapts both outgoing and
-/// incoming messages to the API that `hyper` expects in order to be able to use the message in
-/// the `hyper::client` module.
-pub struct Http2Message<S> where S: CloneableStream {
-    client: SimpleClient<Http2Stream<S>>,
-    state: MessageState,
-}
+    /// Whether a header block is partially received. Any frame other than
+    /// `CONTINUATION` is a connection error in that case.
+    #[inline]
+    pub fn is_expecting_continuation(&self) -> bool {
+        self.continuation.is_some()
+    }
 
-impl<S> ::std::fmt::Debug for Http2Message<S> where S: CloneableStream {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-        write!(f, "<Http2Message>")
+    pub fn decode_headers(&mut self, block: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Reason> {
+        self.decoder.decode(block).map_err(|e| {
+            debug!("hpack decode error: {:?}", e);
+            Reason::CompressionError
+        })
     }
-}
 
-impl<S> Http2Message<S> where S: CloneableStream {
-    /// Helper method that creates a new completely fresh `Http2Message`, which will use the given
-    /// `SimpleClient` for its HTTP/2 communication.
-    fn with_client(client: SimpleClient<Http2Stream<S>>) -> Http2Message<S> {
-        Http2Message {
-            client: client,
-            state: MessageState::Idle,
+    /// Checks a new stream opened by the peer.
+    pub fn accept_stream(&mut self, id: StreamId, active: usize) -> Result<Stream, Reason> {
+        // streams initiated by a client use odd ids
+        if self.is_client || id % 2 == 0 || id <= self.last_remote_stream_id {
+            return Err(Reason::ProtocolError);
         }
+        self.last_remote_stream_id = id;
+        if self.going_away {
+            return Err(Reason::RefusedStream);
+        }
+        if let Some(max) = self.local.max_concurrent_streams {
+            if active >= max as usize {
+                return Err(Reason::RefusedStream);
+            }
+        }
+        Ok(Stream::new(id, &self.local, &self.remote))
     }
-}
 
-impl<S> Write for Http2Message<S> where S: CloneableStream {
-    #[inline]
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if let MessageState::Writing(ref mut req) = self.state {
-            req.body.write(buf)
+    /// Whether the peer has closed this id by opening a higher one, or if
+    /// it's an id this side would open.
+    pub fn is_idle_stream(&self, id: StreamId) -> bool {
+        let is_local = (id % 2 == 1) == self.is_client;
+        if is_local {
+            id >= self.next_stream_id
         } else {
-            Err(io::Error::new(io::ErrorKind::Other,
-                               "Not in a writable state"))
+            id > self.last_remote_stream_id
         }
     }
-    #[inline]
-    fn flush(&mut self) -> io::Result<()> {
-        if let MessageState::Writing(ref mut req) = self.state {
-            req.body.flush()
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other,
-                               "Not in a writable state"))
+
+    /// How many more locally initiated streams may be opened, given the
+    /// number of streams currently active.
+    pub fn stream_capacity(&self, active: usize) -> usize {
+        if self.going_away || !self.is_client {
+            return 0;
         }
+        let max = self.remote.max_concurrent_streams.unwrap_or(MAX_CONCURRENT_STREAMS);
+        (max as usize).saturating_sub(active)
     }
-}
 
-impl<S> Read for Http2Message<S> where S: CloneableStream {
-    #[inline]
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if let MessageState::Reading(ref mut res) = self.state {
-            res.body.read(buf)
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other,
-                               "Not in a readable state"))
+    /// Opens a new locally initiated stream.
+    pub fn open_stream(&mut self) -> Stream {
+        let id = self.next_stream_id;
+        self.next_stream_id += 2;
+        Stream::new(id, &self.local, &self.remote)
+    }
+
+    /// Queues the `HEADERS` (and `CONTINUATION`s) for a message head.
+    pub fn send_headers(&mut self, stream: &mut Stream, fields: Vec<(Vec<u8>, Vec<u8>)>, body_len: Option<u64>) {
+        let block = self.encoder.encode(fields.iter().map(|&(ref n, ref v)| (&n[..], &v[..])));
+        let end_stream = body_len == Some(0);
+        frame::encode_headers(&mut self.write_buf, stream.id, &block, end_stream, self.remote.max_frame_size as usize);
+        stream.headers_sent = true;
+        stream.send_remaining = body_len;
+        if end_stream {
+            stream.sent_eos = true;
         }
     }
-}
 
-/// A helper function that prepares the headers that should be sent in an HTTP/2 message.
-///
-/// Adapts the `Headers` into a list of octet string pairs.
-fn prepare_headers(mut headers: Headers) -> Vec<Http2Header> {
-    if headers.remove::<header::Connection>() {
-        warn!("The `Connection` header is not valid for an HTTP/2 connection.");
+    /// Queues a `DATA` frame with as much of `data` as flow control allows.
+    pub fn send_data(&mut self, stream: &mut Stream, data: &[u8]) -> io::Result<usize> {
+        if stream.sent_eos || stream.closing {
+            return Ok(0);
+        }
+        if self.write_buf.len() - self.write_pos >= MAX_WRITE_BUF {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "http2 write buffer full"));
+        }
+        let mut n = data.len();
+        if let Some(remaining) = stream.send_remaining {
+            n = cmp::min(n as u64, remaining) as usize;
+        }
+        n = cmp::min(n, self.remote.max_frame_size as usize);
+        let window = cmp::min(self.send_window, stream.send_window);
+        if window <= 0 {
+            trace!("stream {} blocked by flow control", stream.id);
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "http2 flow control"));
+        }
+        n = cmp::min(n as i64, window) as usize;
+
+        let end_stream = match stream.send_remaining {
+            Some(ref mut remaining) => {
+                *remaining -= n as u64;
+                // trailers end the stream instead, once the body is closed
+                *remaining == 0 && stream.send_trailers.is_none()
+            }
+            None => false,
+        };
+        frame::encode_data(&mut self.write_buf, stream.id, &data[..n], end_stream);
+        self.send_window -= n as i64;
+        stream.send_window -= n as i64;
+        if end_stream {
+            stream.sent_eos = true;
+        }
+        Ok(n)
     }
-    let mut http2_headers: Vec<_> = headers.iter().filter_map(|h| {
-        if h.is::<header::SetCookie>() {
-            None
-        } else {
-            // HTTP/2 header names MUST be lowercase.
-            Some((h.name().to_ascii_lowercase().into_bytes(), h.value_string().into_bytes()))
+
+    /// Ends the outgoing side of a stream, if not already done.
+    ///
+    /// Trailers set on the stream are sent as the last `HEADERS`.
+    pub fn send_eos(&mut self, stream: &mut Stream) {
+        if !stream.sent_eos {
+            match stream.send_trailers.take() {
+                Some(trailers) => {
+                    let mut fields = Vec::new();
+                    push_headers(&mut fields, &trailers);
+                    let block = self.encoder.encode(fields.iter().map(|&(ref n, ref v)| (&n[..], &v[..])));
+                    frame::encode_headers(&mut self.write_buf, stream.id, &block, true, self.remote.max_frame_size as usize);
+                }
+                None => frame::encode_data(&mut self.write_buf, stream.id, &[], true),
+            }
+            stream.sent_eos = true;
         }
-    }).collect();
+    }
+
+    pub fn send_reset(&mut self, id: StreamId, reason: Reason) {
+        frame::encode_rst_stream(&mut self.write_buf, id, reason);
+    }
 
-    // Now separately add the cookies, as `hyper` considers `Set-Cookie` to be only a single
-    // header, even in the face of multiple cookies being set.
-    if let Some(set_cookie) = headers.get::<header::SetCookie>() {
-        for cookie in set_cookie.iter() {
-            http2_headers.push((b"set-cookie".to_vec(), cookie.to_string().into_bytes()));
+    pub fn send_go_away(&mut self, reason: Reason) {
+        if !self.going_away {
+            let last = self.last_remote_stream_id;
+            frame::encode_goaway(&mut self.write_buf, last, reason);
+            self.going_away = true;
         }
     }
 
-    http2_headers
-}
+    /// Accounts a received `DATA` frame against the connection window.
+    pub fn recv_data(&mut self, flow_len: u32) -> Result<(), Reason> {
+        self.recv_window -= flow_len as i64;
+        if self.recv_window < 0 {
+            return Err(Reason::FlowControlError);
+        }
+        self.unreleased += flow_len;
+        Ok(())
+    }
 
-/// A helper function that prepares the body for sending in an HTTP/2 request.
-#[inline]
-fn prepare_body(body: Vec<u8>) -> Option<Vec<u8>> {
-    if body.is_empty() {
-        None
-    } else {
-        Some(body)
+    /// Gives back window for bytes that have been consumed.
+    ///
+    /// `WINDOW_UPDATE`s are only sent once half a window has been consumed,
+    /// to keep from sending one for every read.
+    pub fn release_capacity(&mut self, stream: Option<&mut Stream>) {
+        let threshold = self.local.initial_window_size / 2;
+        if let Some(stream) = stream {
+            if stream.unreleased >= threshold && !stream.recv_eos {
+                frame::encode_window_update(&mut self.write_buf, stream.id, stream.unreleased);
+                stream.recv_window += stream.unreleased as i64;
+                stream.unreleased = 0;
+            }
+        }
+        if self.unreleased >= threshold {
+            frame::encode_window_update(&mut self.write_buf, 0, self.unreleased);
+            self.recv_window += self.unreleased as i64;
+            self.unreleased = 0;
+        }
     }
-}
 
-/// Parses a set of HTTP/2 headers into a `hyper::header::Headers` struct.
-fn parse_headers(http2_headers: Vec<Http2Header>) -> ::Result<Headers> {
-    // Adapt the header name from `Vec<u8>` to `String`, without making any copies.
-    let mut headers = Vec::new();
-    for (name, value) in http2_headers.into_iter() {
-        let name = match String::from_utf8(name) {
-            Ok(name) => name,
-            Err(_) => return Err(From::from(Http2Error::MalformedResponse)),
-        };
-        headers.push((name, value));
+    /// Whether a stream could send `DATA` right now.
+    pub fn can_send(&self, stream: &Stream) -> bool {
+        !stream.sent_eos &&
+            self.send_window > 0 &&
+            stream.send_window > 0 &&
+            self.write_buf.len() - self.write_pos < MAX_WRITE_BUF
     }
 
-    let mut raw_headers = Vec::new();
-    for &(ref name, ref value) in &headers {
-        raw_headers.push(httparse::Header { name: &name, value: &value });
+    #[inline]
+    pub fn has_pending_write(&self) -> bool {
+        self.write_pos < self.write_buf.len()
     }
 
-    Headers::from_raw(&raw_headers)
+    /// Writes queued frames to the transport.
+    pub fn flush<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        while self.write_pos < self.write_buf.len() {
+            match w.write(&self.write_buf[self.write_pos..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "http2 write zero")),
+                Ok(n) => self.write_pos += n,
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+        }
+        if self.write_pos == self.write_buf.len() {
+            self.write_buf.clear();
+            self.write_pos = 0;
+        }
+        Ok(())
+    }
 }
 
-/// Parses the response, as returned by `solicit`, into a `ResponseHead` and the full response
-/// body.
-///
-/// Returns them as a two-tuple.
-fn parse_response(response: ::solicit::http::Response) -> ::Result<(ResponseHead, Vec<u8>)> {
-    let status = try!(response.status_code());
-    let headers = try!(parse_headers(response.headers));
-    Ok((ResponseHead {
-        headers: headers,
-        raw_status: RawStatus(status, "".into()),
-        version: version::HttpVersion::Http20,
-    }, response.body))
+/// The protocol state of a single HTTP/2 stream.
+#[derive(Debug)]
+pub struct Stream {
+    id: StreamId,
+    recv_buf: Vec<u8>,
+    recv_pos: usize,
+    recv_eos: bool,
+    recv_window: i64,
+    content: Option<ContentDecoder>,
+    unreleased: u32,
+    send_window: i64,
+    send_remaining: Option<u64>,
+    headers_sent: bool,
+    sent_eos: bool,
+    closing: bool,
+    recv_trailers: Option<Headers>,
+    send_trailers: Option<Headers>,
 }
 
-impl<S> HttpMessage for Http2Message<S> where S: CloneableStream {
-    fn set_outgoing(&mut self, head: RequestHead) -> ::Result<RequestHead> {
-        match self.state {
-            MessageState::Writing(_) | MessageState::Reading(_) => {
-                return Err(From::from(Http2Error::from(
-                            io::Error::new(io::ErrorKind::Other,
-                                           "An outoging has already been set"))));
-            },
-            MessageState::Idle => {},
-        };
-        self.state = MessageState::Writing(Http2Request {
-            head: head.clone(),
-            body: Vec::new(),
-        });
+impl Stream {
+    fn new(id: StreamId, local: &Settings, remote: &Settings) -> Stream {
+        Stream {
+            id: id,
+            recv_buf: Vec::new(),
+            recv_pos: 0,
+            recv_eos: false,
+            recv_window: local.initial_window_size as i64,
+            content: None,
+            unreleased: 0,
+            send_window: remote.initial_window_size as i64,
+            send_remaining: None,
+            headers_sent: false,
+            sent_eos: false,
+            closing: false,
+            recv_trailers: None,
+            send_trailers: None,
+        }
+    }
 
-        Ok(head)
+    #[inline]
+    pub fn id(&self) -> StreamId {
+        self.id
     }
 
-    fn get_incoming(&mut self) -> ::Result<ResponseHead> {
-        // Prepare the request so that it can be passed off to the HTTP/2 client.
-        let request = match self.state.take_request() {
-            None => {
-                return Err(From::from(Http2Error::from(
-                            io::Error::new(io::ErrorKind::Other,
-                                           "No request in progress"))));
-            },
-            Some(req) => req,
-        };
-        let (RequestHead { headers, method, url }, body) = (request.head, request.body);
+    /// Buffers the payload of a `DATA` frame for the `Decoder`.
+    pub fn recv_data(&mut self, data: &[u8], flow_len: u32, end_stream: bool) -> Result<(), Reason> {
+        if self.recv_eos {
+            return Err(Reason::StreamClosed);
+        }
+        self.recv_window -= flow_len as i64;
+        if self.recv_window < 0 {
+            return Err(Reason::FlowControlError);
+        }
+        // padding is released right away, since it never reaches the Decoder
+        self.unreleased += flow_len - data.len() as u32;
+        self.recv_buf.extend_from_slice(data);
+        if end_stream {
+            self.recv_eos = true;
+        }
+        Ok(())
+    }
+
+    pub fn recv_eos(&mut self) {
+        self.recv_eos = true;
+    }
 
-        let method = method.as_ref().as_bytes();
-        let path = url[UrlPosition::BeforePath..UrlPosition::AfterQuery].as_bytes();
-        let extra_headers = prepare_headers(headers);
-        let body = prepare_body(body);
+    /// Keeps the fields of a `HEADERS` frame that ended the stream after
+    /// the body.
+    pub fn recv_trailers(&mut self, fields: Vec<(Vec<u8>, Vec<u8>)>) -> ::Result<()> {
+        let (pseudo, mut trailers) = try!(split_fields(fields));
+        if !pseudo.is_empty() {
+            // pseudo-headers are only allowed in a head
+            return Err(::Error::Header);
+        }
+        strip_trailers(&mut trailers);
+        self.recv_trailers = Some(trailers);
+        self.recv_eos = true;
+        Ok(())
+    }
 
-        // Finally, everything is ready and we issue the request.
-        let stream_id = try!(self.client.request(method, &path, &extra_headers, body));
+    /// The trailer fields received after the body, once it was read to
+    /// its end.
+    pub fn trailers(&self) -> Option<&Headers> {
+        if self.is_recv_eof() {
+            self.recv_trailers.as_ref()
+        } else {
+            None
+        }
+    }
 
-        // Wait for the response
-        let resp = try!(self.client.get_response(stream_id));
+    /// Sends trailer fields when the stream ends. Returns `false` if it
+    /// already ended.
+    pub fn set_trailers(&mut self, mut trailers: Headers) -> bool {
+        if self.sent_eos {
+            return false;
+        }
+        strip_trailers(&mut trailers);
+        self.send_trailers = Some(trailers);
+        true
+    }
 
-        // Now that the response is back, adapt it to the structs that hyper expects/provides.
-        let (head, body) = try!(parse_response(resp));
+    pub fn recv_window_update(&mut self, increment: u32) -> Result<(), Reason> {
+        self.send_window += increment as i64;
+        if self.send_window > frame::MAX_WINDOW_SIZE as i64 {
+            return Err(Reason::FlowControlError);
+        }
+        Ok(())
+    }
 
-        // For now, since `solicit` has already read the full response, we just wrap the body into
-        // a `Cursor` to allow for the public interface to support `io::Read`.
-        let body = Cursor::new(body);
+    /// Applies a change in `SETTINGS_INITIAL_WINDOW_SIZE`.
+    pub fn adjust_send_window(&mut self, delta: i64) {
+        self.send_window += delta;
+    }
 
-        // The body is saved so that it can be read out from the message.
-        self.state = MessageState::Reading(Http2Response {
-            body: body,
-        });
+    /// Decodes the content coding of the body as well, from now on.
+    pub fn decode_content(&mut self, content: ContentDecoder) {
+        self.content = Some(content);
+    }
 
-        Ok(head)
+    /// Read buffered body bytes.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.content.take() {
+            Some(mut content) => {
+                let res = content.read(buf, |raw| self.read_raw(raw));
+                self.content = Some(content);
+                res
+            }
+            None => self.read_raw(buf),
+        }
     }
 
-    fn has_body(&self) -> bool {
-        true
+    fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.recv_buf.len() - self.recv_pos;
+        if available == 0 {
+            if self.recv_eos {
+                return Ok(0);
+            }
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "http2 stream waiting for data"));
+        }
+        let n = cmp::min(available, buf.len());
+        buf[..n].copy_from_slice(&self.recv_buf[self.recv_pos..self.recv_pos + n]);
+        self.recv_pos += n;
+        self.unreleased += n as u32;
+        if self.recv_pos == self.recv_buf.len() {
+            self.recv_buf.clear();
+            self.recv_pos = 0;
+        }
+        Ok(n)
     }
 
+    /// Whether the `Decoder` has something to report, either data or eof.
     #[inline]
-    fn set_read_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
-        Ok(())
+    pub fn is_readable(&self) -> bool {
+        self.recv_pos < self.recv_buf.len() || self.recv_eos
     }
 
     #[inline]
-    fn set_write_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
-        Ok(())
+    pub fn is_recv_eof(&self) -> bool {
+        self.recv_eos && self.recv_pos == self.recv_buf.len()
     }
 
     #[inline]
-    fn close_connection(&mut self) -> ::Result<()> {
-        Ok(())
+    pub fn is_headers_sent(&self) -> bool {
+        self.headers_sent
+    }
+
+    #[inline]
+    pub fn is_send_eof(&self) -> bool {
+        self.sent_eos
+    }
+
+    /// User has called `encoder.close()` in a `Handler`.
+    #[inline]
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    #[inline]
+    pub fn is_closing(&self) -> bool {
+        self.closing
     }
 }
 
-/// A convenience method that creates a default `Http2Protocol` that uses a `net::HttpConnector`
-/// (which produces an `HttpStream` for the underlying transport layer).
-#[inline]
-pub fn new_protocol() -> Http2Protocol<HttpConnector, HttpStream> {
-    Http2Protocol::with_connector(HttpConnector)
+/// Header fields that are specific to a HTTP/1 connection, and MUST NOT be
+/// sent in HTTP/2.
+fn is_connection_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case("connection") ||
+        name.eq_ignore_ascii_case("keep-alive") ||
+        name.eq_ignore_ascii_case("proxy-connection") ||
+        name.eq_ignore_ascii_case("transfer-encoding") ||
+        name.eq_ignore_ascii_case("upgrade") ||
+        name.eq_ignore_ascii_case("host")
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{Http2Protocol, prepare_headers, parse_headers, parse_response};
+fn push_headers(fields: &mut Vec<(Vec<u8>, Vec<u8>)>, headers: &Headers) {
+    for h in headers.iter() {
+        if is_connection_header(h.name()) {
+            continue;
+        }
+        if h.is::<header::SetCookie>() {
+            // hyper considers `Set-Cookie` a single header, but each cookie
+            // needs its own field.
+            if let Some(set_cookie) = h.value::<header::SetCookie>() {
+                for cookie in set_cookie.iter() {
+                    fields.push((b"set-cookie".to_vec(), cookie.to_string().into_bytes()));
+                }
+            }
+            continue;
+        }
+        // HTTP/2 header names MUST be lowercase.
+        fields.push((h.name().to_ascii_lowercase().into_bytes(), h.value_string().into_bytes()));
+    }
+}
 
-    use std::io::{Read};
+/// Splits the pseudo-header fields from the regular headers.
+fn split_fields(fields: Vec<(Vec<u8>, Vec<u8>)>) -> ::Result<(Vec<(Vec<u8>, Vec<u8>)>, Headers)> {
+    let mut pseudo = Vec::new();
+    let mut regular = Vec::new();
+    for (name, value) in fields {
+        if name.first() == Some(&b':') {
+            if !regular.is_empty() {
+                // pseudo-headers MUST come before regular fields
+                return Err(::Error::Header);
+            }
+            pseudo.push((name, value));
+        } else {
+            if name.iter().any(|b| b'A' <= *b && *b <= b'Z') {
+                return Err(::Error::Header);
+            }
+            regular.push((try!(String::from_utf8(name)), value));
+        }
+    }
+    let raw = regular.iter().map(|&(ref name, ref value)| httparse::Header {
+        name: name,
+        value: value,
+    }).collect::<Vec<_>>();
+    let headers = try!(Headers::from_raw(&raw));
+    Ok((pseudo, headers))
+}
 
-    use mock::{MockHttp2Connector, MockStream};
-    use http::{RequestHead, ResponseHead, Protocol};
+impl Http2Message for ServerMessage {
+    fn is_informational(_head: &MessageHead<RequestLine>) -> bool {
+        false
+    }
 
-    use header::Headers;
-    use header;
-    use url::Url;
-    use method;
-    use cookie;
-    use version;
+    fn h2c_upgrade(head: &MessageHead<RequestLine>) -> Option<Vec<(u16, u32)>> {
+        if head.version != HttpVersion::Http11 {
+            return None;
+        }
+        match head.headers.get::<header::Upgrade>() {
+            Some(upgrade) if upgrade.iter().any(|p| p.name == ProtocolName::H2c) => (),
+            _ => return None,
+        }
+        // the header is the base64url encoded payload of a SETTINGS frame
+        let settings = head.headers.get_raw("HTTP2-Settings")
+            .and_then(|raw| raw.one())
+            .and_then(|val| val.from_base64().ok())
+            .and_then(|payload| frame::decode_settings_payload(&payload));
+        if settings.is_none() {
+            debug!("Upgrade: h2c without valid HTTP2-Settings, ignoring");
+        }
+        settings
+    }
 
-    use solicit::http::connection::{HttpFrame, ReceiveFrame};
+    fn decode_fields(fields: Vec<(Vec<u8>, Vec<u8>)>) -> ::Result<MessageHead<RequestLine>> {
+        let (pseudo, mut headers) = try!(split_fields(fields));
+        let mut method = None;
+        let mut path = None;
+        let mut scheme = None;
+        let mut authority = None;
+        for (name, value) in pseudo {
+            let value = try!(String::from_utf8(value));
+            match &name[..] {
+                b":method" => method = Some(try!(value.parse::<Method>())),
+                b":path" => path = Some(value),
+                b":scheme" => scheme = Some(value),
+                b":authority" => authority = Some(value),
+                _ => return Err(::Error::Header),
+            }
+        }
+        let method = try!(method.ok_or(::Error::Method));
+        let version = if scheme.as_ref().map(|s| &s[..]) == Some("https") {
+            HttpVersion::H2
+        } else {
+            HttpVersion::H2c
+        };
+        // a CONNECT names only the authority, while every other request
+        // must have a scheme and path (RFC 7540, Section 8.1.2.3)
+        let uri = match (&method, scheme, path, authority) {
+            (&Method::Connect, None, None, Some(authority)) => RequestUri::Authority(authority),
+            (&Method::Connect, _, _, _) => return Err(::Error::Header),
+            (_, Some(_), Some(path), authority) => {
+                if let Some(authority) = authority {
+                    if !headers.has::<header::Host>() {
+                        headers.set_raw("Host", authority);
+                    }
+                }
+                try!(path.parse())
+            }
+            _ => return Err(::Error::Header),
+        };
+        Ok(MessageHead {
+            version: version,
+            subject: RequestLine(method, uri),
+            headers: headers,
+        })
+    }
 
-    /// Tests that the `Http2Message` correctly reads a response with no body.
-    #[test]
-    fn test_http2_response_no_body() {
-        let mut mock_connector = MockHttp2Connector::new();
-        mock_connector.new_response_stream(b"200", &Headers::new(), None);
-        let protocol = Http2Protocol::with_connector(mock_connector);
-
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
-        message.set_outgoing(RequestHead {
-            headers: Headers::new(),
-            method: method::Method::Get,
-            url: Url::parse("http://127.0.0.1/hello").unwrap(),
-        }).unwrap();
-        let resp = message.get_incoming().unwrap();
-
-        assert_eq!(resp.raw_status.0, 200);
-        let mut body = Vec::new();
-        message.read_to_end(&mut body).unwrap();
-        assert_eq!(body.len(), 0);
-    }
-
-    /// Tests that the `Http2Message` correctly reads a response with a body.
-    #[test]
-    fn test_http2_response_with_body() {
-        let mut mock_connector = MockHttp2Connector::new();
-        mock_connector.new_response_stream(b"200", &Headers::new(), Some(vec![1, 2, 3]));
-        let protocol = Http2Protocol::with_connector(mock_connector);
-
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
-        message.set_outgoing(RequestHead {
-            headers: Headers::new(),
-            method: method::Method::Get,
-            url: Url::parse("http://127.0.0.1/hello").unwrap(),
-        }).unwrap();
-        let resp = message.get_incoming().unwrap();
-
-        assert_eq!(resp.raw_status.0, 200);
-        let mut body = Vec::new();
-        message.read_to_end(&mut body).unwrap();
-        assert_eq!(vec![1, 2, 3], body);
-    }
-
-    /// Tests that the `Http2Message` correctly reads a response with an empty body.
-    #[test]
-    fn test_http2_response_empty_body() {
-        let mut mock_connector = MockHttp2Connector::new();
-        mock_connector.new_response_stream(b"200", &Headers::new(), Some(vec![]));
-        let protocol = Http2Protocol::with_connector(mock_connector);
-
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
-        message.set_outgoing(RequestHead {
-            headers: Headers::new(),
-            method: method::Method::Get,
-            url: Url::parse("http://127.0.0.1/hello").unwrap(),
-        }).unwrap();
-        let resp = message.get_incoming().unwrap();
-
-        assert_eq!(resp.raw_status.0, 200);
-        let mut body = Vec::new();
-        message.read_to_end(&mut body).unwrap();
-        assert_eq!(Vec::<u8>::new(), body);
-    }
-
-    /// Tests that the `Http2Message` correctly parses out the headers into the `ResponseHead`.
-    #[test]
-    fn test_http2_response_headers() {
-        let mut mock_connector = MockHttp2Connector::new();
-        let mut headers = Headers::new();
-        headers.set(header::ContentLength(3));
-        headers.set(header::ETag(header::EntityTag::new(true, "tag".into())));
-        mock_connector.new_response_stream(b"200", &headers, Some(vec![1, 2, 3]));
-        let protocol = Http2Protocol::with_connector(mock_connector);
-
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
-        message.set_outgoing(RequestHead {
-            headers: Headers::new(),
-            method: method::Method::Get,
-            url: Url::parse("http://127.0.0.1/hello").unwrap(),
-        }).unwrap();
-        let resp = message.get_incoming().unwrap();
-
-        assert_eq!(resp.raw_status.0, 200);
-        assert!(resp.headers.has::<header::ContentLength>());
-        let &header::ContentLength(len) = resp.headers.get::<header::ContentLength>().unwrap();
-        assert_eq!(3, len);
-        assert!(resp.headers.has::<header::ETag>());
-        let &header::ETag(ref tag) = resp.headers.get::<header::ETag>().unwrap();
-        assert_eq!(tag.tag(), "tag");
-    }
-
-    /// Tests that an error is returned when the `Http2Message` is not in a readable state.
-    #[test]
-    fn test_http2_message_not_readable() {
-        let mut mock_connector = MockHttp2Connector::new();
-        mock_connector.new_response_stream(b"200", &Headers::new(), None);
-        let protocol = Http2Protocol::with_connector(mock_connector);
+    fn encode_fields(mut head: MessageHead<StatusCode>) -> (Vec<(Vec<u8>, Vec<u8>)>, Option<u64>) {
+        trace!("encoding h2 head: {:?}", head);
+        if !head.headers.has::<header::Date>() {
+            head.headers.set(header::Date(header::HttpDate(::time::now_utc())));
+        }
+        let body_len = head.headers.get::<header::ContentLength>().map(|len| **len);
+        let mut fields = Vec::with_capacity(head.headers.len() + 1);
+        fields.push((b":status".to_vec(), head.subject.to_u16().to_string().into_bytes()));
+        push_headers(&mut fields, &head.headers);
+        (fields, body_len)
+    }
+}
 
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
+impl Http2Message for ClientMessage {
+    fn is_informational(head: &MessageHead<RawStatus>) -> bool {
+        head.subject.0 >= 100 && head.subject.0 < 200
+    }
 
-        // No outgoing set yet, so nothing can be read at this point.
-        assert!(message.read(&mut [0; 5]).is_err());
+    fn h2c_upgrade(_head: &MessageHead<RawStatus>) -> Option<Vec<(u16, u32)>> {
+        None
     }
 
-    /// Tests that an error is returned when the `Http2Message` is not in a writable state.
-    #[test]
-    fn test_http2_message_not_writable() {
-        let mut mock_connector = MockHttp2Connector::new();
-        mock_connector.new_response_stream(b"200", &Headers::new(), None);
-        let protocol = Http2Protocol::with_connector(mock_connector);
-
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
-        message.set_outgoing(RequestHead {
-            headers: Headers::new(),
-            method: method::Method::Get,
-            url: Url::parse("http://127.0.0.1/hello").unwrap(),
-        }).unwrap();
-        let _ = message.get_incoming().unwrap();
-        // Writes are invalid now
-        assert!(message.write(&[1]).is_err());
-    }
-
-    /// Asserts that the given stream contains the full expected client preface: the preface bytes,
-    /// settings frame, and settings ack frame.
-    fn assert_client_preface(server_stream: &mut MockStream) {
-        // Skip client preface
-        server_stream.read(&mut [0; 24]).unwrap();
-        // The first frame are the settings
-        assert!(match server_stream.recv_frame().unwrap() {
-            HttpFrame::SettingsFrame(_) => true,
-            _ => false,
-        });
-        // Now the ACK to the server's settings.
-        assert!(match server_stream.recv_frame().unwrap() {
-            HttpFrame::SettingsFrame(_) => true,
-            _ => false,
-        });
+    fn decode_fields(fields: Vec<(Vec<u8>, Vec<u8>)>) -> ::Result<MessageHead<RawStatus>> {
+        let (pseudo, headers) = try!(split_fields(fields));
+        let mut status = None;
+        for (name, value) in pseudo {
+            match &name[..] {
+                b":status" => {
+                    let value = try!(::std::str::from_utf8(&value));
+                    status = Some(try!(value.parse::<u16>().map_err(|_| ::Error::Status)));
+                }
+                _ => return Err(::Error::Header),
+            }
+        }
+        let code = try!(status.ok_or(::Error::Status));
+        let reason = StatusCode::from_u16(code).canonical_reason().unwrap_or("");
+        Ok(MessageHead {
+            version: HttpVersion::H2,
+            subject: RawStatus(code, Cow::Borrowed(reason)),
+            headers: headers,
+        })
     }
 
-    /// Tests that sending a request with no body works correctly.
-    #[test]
-    fn test_http2_request_no_body() {
-        let mut mock_connector = MockHttp2Connector::new();
-        let stream = mock_connector.new_response_stream(b"200", &Headers::new(), Some(vec![]));
-        let protocol = Http2Protocol::with_connector(mock_connector);
-
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
-        message.set_outgoing(RequestHead {
-            headers: Headers::new(),
-            method: method::Method::Get,
-            url: Url::parse("http://127.0.0.1/hello").unwrap(),
-        }).unwrap();
-        let _ = message.get_incoming().unwrap();
-
-        let stream = stream.inner.lock().unwrap();
-        assert!(stream.write.len() > 0);
-        // The output stream of the client side gets flipped so that we can read the stream from
-        // the server's end.
-        let mut server_stream = MockStream::with_input(&stream.write);
-        assert_client_preface(&mut server_stream);
-        let frame = server_stream.recv_frame().unwrap();
-        assert!(match frame {
-            HttpFrame::HeadersFrame(ref frame) => frame.is_end_of_stream(),
-            _ => false,
+    fn encode_fields(mut head: MessageHead<RequestLine>) -> (Vec<(Vec<u8>, Vec<u8>)>, Option<u64>) {
+        trace!("encoding h2 head: {:?}", head);
+        let RequestLine(method, uri) = head.subject;
+        let body_len = match head.headers.get::<header::ContentLength>() {
+            Some(len) => Some(**len),
+            None => match method {
+                Method::Head | Method::Get | Method::Connect => Some(0),
+                _ => None,
+            }
+        };
+        let authority = head.headers.remove::<header::Host>().map(|host| match host.port {
+            Some(port) => format!("{}:{}", host.hostname, port),
+            None => host.hostname,
         });
+        let scheme = if head.version == HttpVersion::H2 { "https" } else { "http" };
+
+        let mut fields = Vec::with_capacity(head.headers.len() + 4);
+        fields.push((b":method".to_vec(), method.as_ref().as_bytes().to_vec()));
+        match uri {
+            RequestUri::Authority(authority) => {
+                fields.push((b":authority".to_vec(), authority.into_bytes()));
+            }
+            uri => {
+                let path = match uri {
+                    RequestUri::AbsolutePath { path, query: Some(query) } => format!("{}?{}", path, query),
+                    RequestUri::AbsolutePath { path, query: None } => path,
+                    RequestUri::AbsoluteUri(url) => url[::url::Position::BeforePath..::url::Position::AfterQuery].to_owned(),
+                    RequestUri::Star => "*".to_owned(),
+                    RequestUri::Authority(..) => unreachable!("matched above"),
+                };
+                fields.push((b":scheme".to_vec(), scheme.as_bytes().to_vec()));
+                fields.push((b":path".to_vec(), path.into_bytes()));
+                if let Some(authority) = authority {
+                    fields.push((b":authority".to_vec(), authority.into_bytes()));
+                }
+            }
+        }
+        push_headers(&mut fields, &head.headers);
+        (fields, body_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use header::{Headers, ContentLength, CookiePair, Host, SetCookie};
+    use http::{MessageHead, RequestLine, Http2Message, ServerMessage, ClientMessage};
+    use method::Method;
+    use status::StatusCode;
+    use uri::RequestUri;
+    use version::HttpVersion;
+
+    use super::{Connection, Frame, Reason, PREFACE};
+    use super::frame;
+
+    fn field(name: &str, value: &str) -> (Vec<u8>, Vec<u8>) {
+        (name.as_bytes().to_vec(), value.as_bytes().to_vec())
     }
 
-    /// Tests that sending a request with a body works correctly.
     #[test]
-    fn test_http2_request_with_body() {
-        let mut mock_connector = MockHttp2Connector::new();
-        let stream = mock_connector.new_response_stream(b"200", &Headers::new(), None);
-        let protocol = Http2Protocol::with_connector(mock_connector);
-
-        let mut message = protocol.new_message("127.0.0.1", 1337, "http").unwrap();
-        message.set_outgoing(RequestHead {
-            headers: Headers::new(),
-            method: method::Method::Get,
-            url: Url::parse("http://127.0.0.1/hello").unwrap(),
-        }).unwrap();
-        // Write a few things to the request in multiple writes.
-        message.write(&[1]).unwrap();
-        message.write(&[2, 3]).unwrap();
-        let _ = message.get_incoming().unwrap();
-
-        let stream = stream.inner.lock().unwrap();
-        assert!(stream.write.len() > 0);
-        // The output stream of the client side gets flipped so that we can read the stream from
-        // the server's end.
-        let mut server_stream = MockStream::with_input(&stream.write);
-        assert_client_preface(&mut server_stream);
-        let frame = server_stream.recv_frame().unwrap();
-        assert!(match frame {
-            HttpFrame::HeadersFrame(ref frame) => !frame.is_end_of_stream(),
-            _ => false,
-        });
-        assert!(match server_stream.recv_frame().unwrap() {
-            HttpFrame::DataFrame(ref frame) => frame.data == vec![1, 2, 3],
-            _ => false,
+    fn test_server_decode_request() {
+        let head = ServerMessage::decode_fields(vec![
+            field(":method", "POST"),
+            field(":scheme", "http"),
+            field(":path", "/echo?a=b"),
+            field(":authority", "hyper.rs"),
+            field("content-length", "5"),
+        ]).unwrap();
+        assert_eq!(head.version, HttpVersion::H2c);
+        assert_eq!(head.subject.0, Method::Post);
+        assert_eq!(head.subject.1, RequestUri::AbsolutePath {
+            path: "/echo".to_owned(),
+            query: Some("a=b".to_owned()),
         });
+        assert_eq!(head.headers.get::<ContentLength>(), Some(&ContentLength(5)));
+        assert_eq!(head.headers.get::<Host>().map(|h| &h.hostname[..]), Some("hyper.rs"));
     }
 
-    /// Tests that headers are correctly prepared when they include a `Set-Cookie` header.
     #[test]
-    fn test_http2_prepare_headers_with_set_cookie() {
-        let cookies = header::SetCookie(vec![
-            cookie::Cookie::new("foo".to_owned(), "bar".to_owned()),
-            cookie::Cookie::new("baz".to_owned(), "quux".to_owned())
-        ]);
-        let mut headers = Headers::new();
-        headers.set(cookies);
-
-        let h2headers = prepare_headers(headers);
-
-        assert_eq!(vec![
-            (b"set-cookie".to_vec(), b"foo=bar".to_vec()),
-            (b"set-cookie".to_vec(), b"baz=quux".to_vec()),
-        ], h2headers);
+    fn test_server_decode_requires_scheme_and_path() {
+        assert!(ServerMessage::decode_fields(vec![
+            field(":method", "GET"),
+            field(":path", "/"),
+        ]).is_err());
+        assert!(ServerMessage::decode_fields(vec![
+            field(":method", "GET"),
+            field(":scheme", "https"),
+            field(":authority", "hyper.rs"),
+        ]).is_err());
+
+        let head = ServerMessage::decode_fields(vec![
+            field(":method", "CONNECT"),
+            field(":authority", "hyper.rs:443"),
+        ]).unwrap();
+        assert_eq!(head.subject.1, RequestUri::Authority("hyper.rs:443".to_owned()));
+        assert!(ServerMessage::decode_fields(vec![
+            field(":method", "CONNECT"),
+            field(":scheme", "https"),
+            field(":path", "/"),
+            field(":authority", "hyper.rs:443"),
+        ]).is_err());
     }
 
-    /// Tests that headers are correctly prepared when they include a `Cookie` header.
     #[test]
-    fn test_http2_prepapre_headers_with_cookie() {
-        let cookies = header::Cookie(vec![
-            cookie::Cookie::new("foo".to_owned(), "bar".to_owned()),
-            cookie::Cookie::new("baz".to_owned(), "quux".to_owned())
-        ]);
-        let mut headers = Headers::new();
-        headers.set(cookies);
-
-        let h2headers = prepare_headers(headers);
+    fn test_server_decode_rejects_late_pseudo() {
+        assert!(ServerMessage::decode_fields(vec![
+            field(":method", "GET"),
+            field("accept", "*/*"),
+            field(":path", "/"),
+        ]).is_err());
+    }
 
-        assert_eq!(vec![
-            (b"cookie".to_vec(), b"foo=bar; baz=quux".to_vec()),
-        ], h2headers);
+    #[test]
+    fn test_server_decode_rejects_uppercase() {
+        assert!(ServerMessage::decode_fields(vec![
+            field(":method", "GET"),
+            field(":path", "/"),
+            field("Accept", "*/*"),
+        ]).is_err());
     }
 
-    /// Tests that HTTP/2 headers are correctly prepared.
     #[test]
-    fn test_http2_prepare_headers() {
+    fn test_server_encode_response() {
         let mut headers = Headers::new();
-        headers.set(header::ContentLength(3));
-        let expected = vec![
-            (b"content-length".to_vec(), b"3".to_vec()),
-        ];
-
-        assert_eq!(expected, prepare_headers(headers));
+        headers.set(ContentLength(3));
+        headers.set(::header::Connection::close());
+        headers.set(SetCookie(vec![
+            CookiePair::new("a".to_owned(), "1".to_owned()),
+            CookiePair::new("b".to_owned(), "2".to_owned()),
+        ]));
+        let (fields, len) = ServerMessage::encode_fields(MessageHead {
+            version: HttpVersion::H2,
+            subject: StatusCode::NotFound,
+            headers: headers,
+        });
+        assert_eq!(len, Some(3));
+        assert_eq!(fields[0], field(":status", "404"));
+        assert!(fields.contains(&field("content-length", "3")));
+        assert!(fields.contains(&field("set-cookie", "a=1")));
+        assert!(fields.contains(&field("set-cookie", "b=2")));
+        assert!(!fields.iter().any(|&(ref n, _)| n == b"connection"));
     }
 
-    /// Tests that the headers of a response are correctly parsed when they include a `Set-Cookie`
-    /// header.
     #[test]
-    fn test_http2_parse_headers_with_set_cookie() {
-        let h2headers = vec![
-            (b"set-cookie".to_vec(), b"foo=bar".to_vec()),
-            (b"set-cookie".to_vec(), b"baz=quux".to_vec()),
-        ];
-        let expected = header::SetCookie(vec![
-            cookie::Cookie::new("foo".to_owned(), "bar".to_owned()),
-            cookie::Cookie::new("baz".to_owned(), "quux".to_owned())
+    fn test_client_encode_request() {
+        let mut headers = Headers::new();
+        headers.set(Host {
+            hostname: "hyper.rs".to_owned(),
+            port: Some(8080),
+        });
+        let (fields, len) = ClientMessage::encode_fields(MessageHead {
+            version: HttpVersion::H2c,
+            subject: RequestLine(Method::Get, RequestUri::AbsolutePath {
+                path: "/".to_owned(),
+                query: None,
+            }),
+            headers: headers,
+        });
+        assert_eq!(len, Some(0));
+        assert_eq!(fields, vec![
+            field(":method", "GET"),
+            field(":scheme", "http"),
+            field(":path", "/"),
+            field(":authority", "hyper.rs:8080"),
         ]);
-
-        let headers = parse_headers(h2headers).unwrap();
-
-        assert!(headers.has::<header::SetCookie>());
-        let set_cookie = headers.get::<header::SetCookie>().unwrap();
-        assert_eq!(expected, *set_cookie);
     }
 
-    /// Tests that parsing HTTP/2 headers with `Cookie` headers works correctly.
     #[test]
-    fn test_http2_parse_headers_with_cookie() {
-        let expected = header::Cookie(vec![
-            cookie::Cookie::new("foo".to_owned(), "bar".to_owned()),
-            cookie::Cookie::new("baz".to_owned(), "quux".to_owned())
-        ]);
-        // HTTP/2 allows the `Cookie` header to be split into multiple ones to facilitate better
-        // compression.
-        let h2headers = vec![
-            (b"cookie".to_vec(), b"foo=bar".to_vec()),
-            (b"cookie".to_vec(), b"baz=quux".to_vec()),
-        ];
-
-        let headers = parse_headers(h2headers).unwrap();
-
-        assert!(headers.has::<header::Cookie>());
-        assert_eq!(*headers.get::<header::Cookie>().unwrap(), expected);
+    fn test_client_decode_response() {
+        let head = ClientMessage::decode_fields(vec![
+            field(":status", "204"),
+            field("server", "test"),
+        ]).unwrap();
+        assert_eq!(head.subject.0, 204);
+        assert_eq!(head.subject.1, "No Content");
+        assert!(ClientMessage::decode_fields(vec![field("server", "test")]).is_err());
     }
 
-    /// Tests that the headers of a response are correctly parsed.
     #[test]
-    fn test_http2_parse_headers() {
-        let h2headers = vec![
-            (b":status".to_vec(), b"200".to_vec()),
-            (b"content-length".to_vec(), b"3".to_vec()),
-        ];
-
-        let headers = parse_headers(h2headers).unwrap();
-
-        assert!(headers.has::<header::ContentLength>());
-        let &header::ContentLength(len) = headers.get::<header::ContentLength>().unwrap();
-        assert_eq!(3, len);
+    fn test_server_h2c_upgrade() {
+        use header::{Upgrade, Protocol, ProtocolName};
+
+        let mut head = ServerMessage::decode_fields(vec![
+            field(":method", "GET"),
+            field(":scheme", "http"),
+            field(":path", "/"),
+        ]).unwrap();
+        head.version = HttpVersion::Http11;
+        assert_eq!(ServerMessage::h2c_upgrade(&head), None);
+
+        head.headers.set(Upgrade(vec![Protocol::new(ProtocolName::H2c, None)]));
+        assert_eq!(ServerMessage::h2c_upgrade(&head), None);
+
+        // MAX_CONCURRENT_STREAMS=100, INITIAL_WINDOW_SIZE=65535
+        head.headers.set_raw("HTTP2-Settings", "AAMAAABkAAQAAP__");
+        assert_eq!(ServerMessage::h2c_upgrade(&head), Some(vec![
+            (frame::setting::MAX_CONCURRENT_STREAMS, 100),
+            (frame::setting::INITIAL_WINDOW_SIZE, 65535),
+        ]));
     }
 
-    /// Tests that if a header name is not a valid utf8 byte sequence, an error is returned.
     #[test]
-    fn test_http2_parse_headers_invalid_name() {
-        let h2headers = vec![
-            (vec![0xfe], vec![]),
-        ];
+    fn test_server_upgrade_queues_101() {
+        let mut conn = Connection::server_upgrade(&[]).unwrap();
+        assert!(conn.is_awaiting_preface());
+        let mut out = Vec::new();
+        conn.flush(&mut out).unwrap();
+        assert!(out.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    }
 
-        assert!(parse_headers(h2headers).is_err());
+    #[test]
+    fn test_server_preface() {
+        let mut conn = Connection::server();
+        assert!(conn.is_awaiting_preface());
+        assert_eq!(conn.read_preface(&PREFACE[..10]), Ok(0));
+        assert_eq!(conn.read_preface(b"GET / HTTP/1.1\r\n"), Err(Reason::ProtocolError));
+        assert_eq!(conn.read_preface(PREFACE), Ok(PREFACE.len()));
+        assert!(!conn.is_awaiting_preface());
     }
 
-    /// Tests that a response with no pseudo-header for status is considered invalid.
     #[test]
-    fn test_http2_parse_response_no_status_code() {
-        let response = ::solicit::http::Response {
-            body: Vec::new(),
-            headers: vec![
-                (b"content-length".to_vec(), b"3".to_vec()),
-            ],
-            stream_id: 1,
-        };
+    fn test_client_preface_and_settings_queued() {
+        let mut conn = Connection::client();
+        let mut out = Vec::new();
+        conn.flush(&mut out).unwrap();
+        assert!(out.starts_with(PREFACE));
+        match frame::parse(&out[PREFACE.len()..], frame::DEFAULT_MAX_FRAME_SIZE) {
+            Ok(Some((Frame::Settings { ack: false, .. }, _))) => (),
+            other => panic!("expected settings, got {:?}", other),
+        }
+        assert!(!conn.has_pending_write());
+    }
 
-        assert!(parse_response(response).is_err());
+    #[test]
+    fn test_flow_control_blocks_data() {
+        let mut conn = Connection::client();
+        let mut stream = conn.open_stream();
+        assert_eq!(stream.id(), 1);
+        conn.send_headers(&mut stream, vec![field(":method", "POST")], None);
+        stream.adjust_send_window(-(frame::DEFAULT_WINDOW_SIZE as i64) + 3);
+
+        assert_eq!(conn.send_data(&mut stream, b"hello").unwrap(), 3);
+        let e = conn.send_data(&mut stream, b"lo").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+        stream.recv_window_update(10).unwrap();
+        assert_eq!(conn.send_data(&mut stream, b"lo").unwrap(), 2);
     }
 
-    /// Tests that an HTTP/2 response gets correctly parsed into a body and response head, when
-    /// the body is empty.
     #[test]
-    fn test_http2_parse_response_no_body() {
-        let response = ::solicit::http::Response {
-            body: Vec::new(),
-            headers: vec![
-                (b":status".to_vec(), b"200".to_vec()),
-                (b"content-length".to_vec(), b"0".to_vec()),
-            ],
-            stream_id: 1,
-        };
+    fn test_send_data_respects_content_length() {
+        let mut conn = Connection::client();
+        let mut stream = conn.open_stream();
+        conn.send_headers(&mut stream, vec![field(":method", "POST")], Some(4));
+        assert_eq!(conn.send_data(&mut stream, b"foo bar").unwrap(), 4);
+        assert!(stream.is_send_eof());
+        assert_eq!(conn.send_data(&mut stream, b"baz").unwrap(), 0);
+    }
 
-        let (head, body) = parse_response(response).unwrap();
+    #[test]
+    fn test_stream_read() {
+        let mut conn = Connection::server();
+        let mut stream = conn.accept_stream(1, 0).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        stream.recv_data(b"hello", 5, true).unwrap();
+        assert_eq!(stream.read(&mut buf[..3]).unwrap(), 3);
+        assert_eq!(&buf[..3], b"hel");
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.is_recv_eof());
+    }
 
-        assert_eq!(body, vec![]);
-        let ResponseHead { headers, raw_status, version } = head;
-        assert_eq!(raw_status.0, 200);
-        assert_eq!(raw_status.1, "");
-        assert!(headers.has::<header::ContentLength>());
-        assert_eq!(version, version::HttpVersion::Http20);
+    #[test]
+    fn test_stream_capacity() {
+        let mut conn = Connection::client();
+        assert_eq!(conn.stream_capacity(0), 100);
+        conn.recv_settings(&[(frame::setting::MAX_CONCURRENT_STREAMS, 2)]).unwrap();
+        assert_eq!(conn.stream_capacity(1), 1);
+        assert_eq!(conn.stream_capacity(3), 0);
+        conn.recv_go_away();
+        assert_eq!(conn.stream_capacity(0), 0);
+
+        assert_eq!(Connection::server().stream_capacity(0), 0);
     }
 
-    /// Tests that an HTTP/2 response gets correctly parsed into a body and response head, when
-    /// the body is not empty.
     #[test]
-    fn test_http2_parse_response_with_body() {
-        let expected_body = vec![1, 2, 3];
-        let response = ::solicit::http::Response {
-            body: expected_body.clone(),
-            headers: vec![
-                (b":status".to_vec(), b"200".to_vec()),
-                (b"content-length".to_vec(), b"3".to_vec()),
-            ],
-            stream_id: 1,
-        };
+    fn test_accept_stream_ids() {
+        let mut conn = Connection::server();
+        assert_eq!(conn.accept_stream(2, 0).unwrap_err(), Reason::ProtocolError);
+        assert!(conn.accept_stream(3, 0).is_ok());
+        assert_eq!(conn.accept_stream(1, 0).unwrap_err(), Reason::ProtocolError);
+        assert_eq!(conn.accept_stream(5, 100).unwrap_err(), Reason::RefusedStream);
+    }
 
-        let (head, body) = parse_response(response).unwrap();
+    #[test]
+    fn test_continuation_limit() {
+        let mut conn = Connection::server();
+        let fragment = vec![0; frame::DEFAULT_MAX_FRAME_SIZE as usize];
+        conn.start_continuation(1, fragment.clone(), true).unwrap();
+        let mut received = fragment.len();
+        while received + fragment.len() <= MAX_HEADER_LIST_SIZE as usize {
+            assert_eq!(conn.recv_continuation(1, &fragment, false), Ok(None));
+            received += fragment.len();
+        }
+        assert_eq!(conn.recv_continuation(1, &fragment, false), Err(Reason::EnhanceYourCalm));
 
-        assert_eq!(body, expected_body);
-        let ResponseHead { headers, raw_status, version } = head;
-        assert_eq!(raw_status.0, 200);
-        assert_eq!(raw_status.1, "");
-        assert!(headers.has::<header::ContentLength>());
-        assert_eq!(version, version::HttpVersion::Http20);
+        let mut conn = Connection::server();
+        conn.start_continuation(1, vec![0; 8], false).unwrap();
+        assert_eq!(conn.recv_continuation(3, &[0], true), Err(Reason::ProtocolError));
+        assert_eq!(conn.recv_continuation(1, &[0], true), Ok(Some((vec![0; 9], false))));
+        assert!(!conn.is_expecting_continuation());
     }
 }
@@ -4,9 +4,9 @@ use std::fmt;
 use std::io::{self, Read, Write};
 use std::time::Duration;
 
-use header::Connection;
+use header::{Connection, Expect};
 use header::ConnectionOption::{KeepAlive, Close};
-use header::Headers;
+use header::{Encoding, Headers};
 use method::Method;
 use net::Transport;
 use status::StatusCode;
@@ -22,8 +22,9 @@ pub use self::conn::{Conn, MessageHandler, MessageHandlerFactory, Seed, Key, Rea
 mod buffer;
 pub mod channel;
 mod conn;
+mod content;
 mod h1;
-//mod h2;
+mod h2;
 
 /// Wraps a `Transport` to provide HTTP decoding when reading.
 #[derive(Debug)]
@@ -36,6 +37,8 @@ pub struct Encoder<'a, T: Transport + 'a>(EncoderImpl<'a, T>);
 #[derive(Debug)]
 enum DecoderImpl<'a, T: Read + 'a> {
     H1(&'a mut h1::Decoder, Trans<'a, T>),
+    H2(&'a mut h2::Stream, &'a T),
+    Cached(&'a mut io::Cursor<Vec<u8>>, Option<&'a T>),
 }
 
 #[derive(Debug)]
@@ -65,6 +68,7 @@ impl<'a, T: Read + 'a> Read for Trans<'a, T> {
 #[derive(Debug)]
 enum EncoderImpl<'a, T: Transport + 'a> {
     H1(&'a mut h1::Encoder, &'a mut T),
+    H2(&'a mut h2::Stream, &'a mut h2::Connection, &'a mut T),
 }
 
 impl<'a, T: Read> Decoder<'a, T> {
@@ -72,6 +76,16 @@ impl<'a, T: Read> Decoder<'a, T> {
         Decoder(DecoderImpl::H1(decoder, transport))
     }
 
+    fn h2(stream: &'a mut h2::Stream, transport: &'a T) -> Decoder<'a, T> {
+        Decoder(DecoderImpl::H2(stream, transport))
+    }
+
+    /// A body that was already read, such as one stored by the client cache.
+    #[doc(hidden)]
+    pub fn cached(body: &'a mut io::Cursor<Vec<u8>>, transport: Option<&'a T>) -> Decoder<'a, T> {
+        Decoder(DecoderImpl::Cached(body, transport))
+    }
+
     /// Read from the `Transport`.
     #[inline]
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
@@ -79,6 +93,8 @@ impl<'a, T: Read> Decoder<'a, T> {
             DecoderImpl::H1(ref mut decoder, ref mut transport) => {
                 decoder.decode(transport, buf)
             }
+            DecoderImpl::H2(ref mut stream, _) => stream.read(buf),
+            DecoderImpl::Cached(ref mut body, _) => body.read(buf),
         }
     }
 
@@ -98,10 +114,63 @@ impl<'a, T: Read> Decoder<'a, T> {
         }
     }
 
+    /// Decode the content coding of the body, in addition to its framing.
+    ///
+    /// The supported codings are `gzip`, `deflate` and `br`. This should be
+    /// called before the body is first read, and returns `false` if the
+    /// coding is not supported, in which case reading is unchanged.
+    pub fn decode_content(&mut self, coding: &Encoding) -> bool {
+        if let DecoderImpl::Cached(..) = self.0 {
+            // a cached body was stored decoded
+            return false;
+        }
+        let content = match content::ContentDecoder::new(coding) {
+            Some(content) => content,
+            None => return false,
+        };
+        match self.0 {
+            DecoderImpl::H1(ref mut decoder, _) => decoder.decode_content(content),
+            DecoderImpl::H2(ref mut stream, _) => stream.decode_content(content),
+            DecoderImpl::Cached(..) => unreachable!("cached bodies are not decoded"),
+        }
+        true
+    }
+
+    /// The trailer fields sent after the body.
+    ///
+    /// This is `None` until the body was read to its end, or if the body
+    /// could not carry trailers, such as an HTTP/1 body that is not
+    /// chunked. Fields that are only allowed in a head are left out.
+    pub fn trailers(&self) -> Option<&Headers> {
+        match self.0 {
+            DecoderImpl::H1(ref decoder, _) => decoder.trailers(),
+            DecoderImpl::H2(ref stream, _) => stream.trailers(),
+            DecoderImpl::Cached(..) => None,
+        }
+    }
+
+    /// Whether the body is replayed by the client cache, rather than read
+    /// from the transport.
+    pub fn is_cached(&self) -> bool {
+        match self.0 {
+            DecoderImpl::Cached(..) => true,
+            _ => false,
+        }
+    }
+
     /// Get a reference to the transport.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the client cache answered the request without a
+    /// transport. Check `is_cached` first in a handler that uses a cache.
     pub fn get_ref(&self) -> &T {
         match self.0 {
-            DecoderImpl::H1(_, ref transport) => transport.get_ref()
+            DecoderImpl::H1(_, ref transport) => transport.get_ref(),
+            DecoderImpl::H2(_, transport) => transport,
+            DecoderImpl::Cached(_, transport) => {
+                transport.expect("cached response without a transport")
+            }
         }
     }
 }
@@ -111,6 +180,10 @@ impl<'a, T: Transport> Encoder<'a, T> {
         Encoder(EncoderImpl::H1(encoder, transport))
     }
 
+    fn h2(stream: &'a mut h2::Stream, conn: &'a mut h2::Connection, transport: &'a mut T) -> Encoder<'a, T> {
+        Encoder(EncoderImpl::H2(stream, conn, transport))
+    }
+
     /// Write to the `Transport`.
     #[inline]
     pub fn write(&mut self, data: &[u8]) -> io::Result<usize> {
@@ -125,6 +198,11 @@ impl<'a, T: Transport> Encoder<'a, T> {
                     encoder.encode(*transport, data)
                 }
             }
+            EncoderImpl::H2(ref mut stream, ref mut conn, ref mut transport) => {
+                let n = try!(conn.send_data(stream, data));
+                try!(conn.flush(*transport));
+                Ok(n)
+            }
         }
     }
 
@@ -144,22 +222,65 @@ impl<'a, T: Transport> Encoder<'a, T> {
         }
     }
 
+    /// Compress the body with a content coding, in addition to its framing.
+    ///
+    /// The supported codings are `gzip` and `deflate`. This should be called
+    /// before the body is first written, and the `Content-Encoding` header
+    /// set to match. Each write is compressed and flushed right away, and
+    /// `close()` ends the compressed stream. Returns `false` if the coding
+    /// is not supported, or this is an HTTP/2 stream, in which case writing
+    /// is unchanged.
+    pub fn encode_content(&mut self, coding: &Encoding) -> bool {
+        match self.0 {
+            EncoderImpl::H1(ref mut encoder, _) => {
+                match content::ContentEncoder::new(coding) {
+                    Some(content) => {
+                        encoder.encode_content(content);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            EncoderImpl::H2(..) => false,
+        }
+    }
+
+    /// Send trailer fields after the body.
+    ///
+    /// This should be called before the body is closed, and for HTTP/2
+    /// before its last byte is written. An HTTP/1 body can only carry
+    /// trailers with `Transfer-Encoding: chunked`; otherwise this returns
+    /// `false` and the trailers are not sent. Fields that are only allowed
+    /// in a head, like `Content-Length`, are left out.
+    ///
+    /// A server should only send trailers the client needs if the request
+    /// had a `TE: trailers` header.
+    pub fn set_trailers(&mut self, trailers: Headers) -> bool {
+        match self.0 {
+            EncoderImpl::H1(ref mut encoder, _) => encoder.set_trailers(trailers),
+            EncoderImpl::H2(ref mut stream, _, _) => stream.set_trailers(trailers),
+        }
+    }
+
     /// Closes an encoder, signaling that no more writing will occur.
     ///
     /// This is needed for encodings that don't know the length of the content
     /// beforehand. Most common instance would be usage of
     /// `Transfer-Enciding: chunked`. You would call `close()` to signal
-    /// the `Encoder` should write the end chunk, or `0\r\n\r\n`.
+    /// the `Encoder` should write the end chunk, or `0\r\n\r\n`, along with
+    /// any trailers.
     pub fn close(&mut self) {
         match self.0 {
-            EncoderImpl::H1(ref mut encoder, _) => encoder.close()
+            EncoderImpl::H1(ref mut encoder, _) => encoder.close(),
+            EncoderImpl::H2(ref mut stream, _, _) => stream.close(),
         }
     }
 
     /// Get a reference to the transport.
     pub fn get_ref(&self) -> &T {
         match self.0 {
-            EncoderImpl::H1(_, ref transport) => &*transport
+            EncoderImpl::H1(_, ref transport) => &*transport,
+            EncoderImpl::H2(_, _, ref transport) => &*transport,
         }
     }
 }
@@ -183,10 +304,89 @@ impl<'a, T: Transport> Write for Encoder<'a, T> {
             EncoderImpl::H1(_, ref mut transport) => {
                 transport.flush()
             }
+            EncoderImpl::H2(_, ref mut conn, ref mut transport) => {
+                try!(conn.flush(*transport));
+                transport.flush()
+            }
         }
     }
 }
 
+/// A connection taken over after switching protocols.
+///
+/// Reading returns the bytes hyper had already read past the HTTP message
+/// first, and then continues with the `Transport`.
+pub struct Upgraded<T> {
+    transport: T,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<T> Upgraded<T> {
+    fn new(transport: T, buf: Vec<u8>) -> Upgraded<T> {
+        Upgraded {
+            transport: transport,
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    /// Bytes read from the transport that were not yet consumed.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Get a reference to the transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Get a mutable reference to the transport.
+    ///
+    /// Reading from it directly skips any `buffered()` bytes.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Unwraps into the transport and the bytes not yet consumed.
+    pub fn into_parts(mut self) -> (T, Vec<u8>) {
+        self.buf.drain(..self.pos);
+        (self.transport, self.buf)
+    }
+}
+
+impl<T> fmt::Debug for Upgraded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Upgraded")
+            .field("buffered", &self.buffered().len())
+            .finish()
+    }
+}
+
+impl<T: Read> Read for Upgraded<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buf.len() {
+            let n = try!((&self.buf[self.pos..]).read(buf));
+            self.pos += n;
+            Ok(n)
+        } else {
+            self.transport.read(buf)
+        }
+    }
+}
+
+impl<T: Write> Write for Upgraded<T> {
+    #[inline]
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.transport.write(data)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
+
 /// Because privacy rules. Reasons.
 /// https://github.com/rust-lang/rust/issues/30905
 mod internal {
@@ -250,6 +450,13 @@ impl<S> MessageHead<S> {
     pub fn should_keep_alive(&self) -> bool {
         should_keep_alive(self.version, &self.headers)
     }
+
+    /// Whether the body of this message waits for a `100 Continue`.
+    ///
+    /// HTTP/1.0 messages cannot ask for one.
+    pub fn expects_continue(&self) -> bool {
+        self.version == Http11 && self.headers.get::<Expect>() == Some(&Expect::Continue)
+    }
 }
 
 /// The raw status code and reason-phrase.
@@ -302,6 +509,27 @@ pub fn should_keep_alive(version: HttpVersion, headers: &Headers) -> bool {
     ret
 }
 
+/// Fields that frame, route or describe the payload of a message, which
+/// are not allowed in trailers.
+const NOT_TRAILERS: &'static [&'static str] = &[
+    "Connection",
+    "Content-Encoding",
+    "Content-Length",
+    "Content-Range",
+    "Content-Type",
+    "Host",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+];
+
+/// Removes the fields that may only be sent in a head from trailers.
+fn strip_trailers(trailers: &mut Headers) {
+    for name in NOT_TRAILERS {
+        trailers.remove_raw(name);
+    }
+}
+
 pub type ParseResult<T> = ::Result<Option<(MessageHead<T>, usize)>>;
 
 pub fn parse<T: Http1Message<Incoming=I>, I>(rdr: &[u8]) -> ParseResult<I> {
@@ -325,7 +553,28 @@ pub trait Http1Message {
     type Outgoing: Default;
     fn parse(bytes: &[u8]) -> ParseResult<Self::Incoming>;
     fn decoder(head: &MessageHead<Self::Incoming>) -> ::Result<h1::Decoder>;
+    /// Whether an incoming head is an interim `100 Continue`.
+    fn is_continue(head: &MessageHead<Self::Incoming>) -> bool;
+    /// Whether an incoming head is informational, with the final head still
+    /// to follow.
+    fn is_interim(head: &MessageHead<Self::Incoming>) -> bool;
     fn encode(head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>) -> h1::Encoder;
+    /// Encodes the head of a message that switches protocols, which has no
+    /// body framing. Everything written after it is passed through as is.
+    fn encode_upgrade(head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>) -> h1::Encoder;
+    /// Encodes an informational head, which has no body, and is followed by
+    /// another head.
+    fn encode_interim(head: MessageHead<Self::Outgoing>, dst: &mut Vec<u8>);
+    /// The head of a response to an incoming message that could not be
+    /// read, to send before closing the connection, if any.
+    fn on_error(err: &::Error) -> Option<MessageHead<Self::Outgoing>>;
+}
+
+pub trait Http2Message: Http1Message {
+    fn is_informational(head: &MessageHead<Self::Incoming>) -> bool;
+    fn h2c_upgrade(head: &MessageHead<Self::Incoming>) -> Option<Vec<(u16, u32)>>;
+    fn decode_fields(fields: Vec<(Vec<u8>, Vec<u8>)>) -> ::Result<MessageHead<Self::Incoming>>;
+    fn encode_fields(head: MessageHead<Self::Outgoing>) -> (Vec<(Vec<u8>, Vec<u8>)>, Option<u64>);
 }
 
 /// Used to signal desired events when working with asynchronous IO.
@@ -375,6 +624,13 @@ pub struct Control {
 }
 
 impl Control {
+    #[doc(hidden)]
+    pub fn new(tx: self::channel::Sender<Next>) -> Control {
+        Control {
+            tx: tx,
+        }
+    }
+
     /// Wakeup a waiting socket to listen for a certain event.
     pub fn ready(&self, next: Next) -> Result<(), ControlError> {
         //TODO: assert!( next.interest != Next_::Wait ) ?
@@ -448,6 +704,24 @@ impl Next {
         self.timeout = Some(dur);
         self
     }
+
+    /// Whether this signals the desire to read.
+    #[doc(hidden)]
+    pub fn is_read(&self) -> bool {
+        match self.interest {
+            Next_::Read | Next_::ReadWrite => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this signals the end of the message.
+    #[doc(hidden)]
+    pub fn is_end(&self) -> bool {
+        match self.interest {
+            Next_::End | Next_::Remove => true,
+            _ => false,
+        }
+    }
 }
 
 impl Next_ {
@@ -478,3 +752,34 @@ fn test_should_keep_alive() {
     assert!(should_keep_alive(Http10, &headers));
     assert!(should_keep_alive(Http11, &headers));
 }
+
+#[test]
+fn test_upgraded_reads_buffered_first() {
+    let mut upgraded = Upgraded::new(&b"world"[..], b"hello ".to_vec());
+    let mut buf = [0; 3];
+    upgraded.read_exact(&mut buf).unwrap();
+    assert_eq!(upgraded.buffered(), b"lo ");
+
+    let mut rest = String::new();
+    upgraded.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "lo world");
+
+    let (_, buffered) = Upgraded::new(&b""[..], b"abc".to_vec()).into_parts();
+    assert_eq!(buffered, b"abc");
+}
+
+#[test]
+fn test_cached_decoder() {
+    let mut body = io::Cursor::new(b"hello".to_vec());
+    let transport: &[u8] = b"";
+    {
+        let mut decoder = Decoder::cached(&mut body, Some(&transport));
+        assert!(decoder.is_cached());
+        assert_eq!(decoder.get_ref(), &transport);
+        let mut buf = [0; 8];
+        assert_eq!(decoder.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+    let decoder = Decoder::<&[u8]>::cached(&mut body, None);
+    assert!(decoder.is_cached());
+}
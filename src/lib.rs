@@ -29,6 +29,10 @@ extern crate serde;
 extern crate cookie;
 extern crate unicase;
 extern crate httparse;
+extern crate hpack;
+extern crate flate2;
+extern crate brotli_decompressor;
+extern crate rand;
 extern crate rotor;
 extern crate spmc;
 extern crate vecio;
@@ -50,7 +54,7 @@ pub use url::Url;
 pub use client::Client;
 pub use error::{Result, Error};
 pub use header::Headers;
-pub use http::{Next, Encoder, Decoder, Control, ControlError};
+pub use http::{Next, Encoder, Decoder, Control, ControlError, Upgraded};
 pub use method::Method::{self, Get, Head, Post, Delete};
 pub use net::{HttpStream, Transport};
 pub use status::StatusCode::{self, Ok, BadRequest, NotFound};
@@ -79,6 +83,7 @@ pub mod server;
 pub mod status;
 pub mod uri;
 pub mod version;
+pub mod websocket;
 
 /// Re-exporting the mime crate, for convenience.
 pub mod mime {
@@ -1,10 +1,14 @@
 //! A collection of traits abstracting over Listeners and Streams.
 use std::io::{self, Read, Write};
-use std::net::{SocketAddr};
+use std::net::{Shutdown, SocketAddr};
 use std::option;
+#[cfg(unix)]
+use std::path::Path;
 
 use rotor::mio::tcp::{TcpStream, TcpListener};
 use rotor::mio::{Selector, Token, Evented, EventSet, PollOpt, TryAccept};
+#[cfg(unix)]
+use rotor::mio::unix::{UnixStream as MioUnixStream, UnixListener as MioUnixListener};
 
 #[cfg(feature = "openssl")]
 pub use self::openssl::{Openssl, OpensslStream};
@@ -26,6 +30,22 @@ pub trait Transport: Read + Write + Evented + ::vecio::Writev {
     fn blocked(&self) -> Option<Blocked> {
         None
     }
+
+    /// Returns the application protocol negotiated with ALPN, if any.
+    ///
+    /// Transports without TLS, or whose TLS implementation does not support
+    /// ALPN, always return `None`.
+    fn negotiated_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Shuts down writing, so the peer reads the end of the stream, while
+    /// reading from it still works.
+    ///
+    /// By default, this is not supported and returns an error.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "shutdown not supported"))
+    }
 }
 
 /// A trait representing a socket transport that can be used in a Client or Server.
@@ -42,6 +62,22 @@ pub trait Transport: Read + Write + Evented {
     fn blocked(&self) -> Option<Blocked> {
         None
     }
+
+    /// Returns the application protocol negotiated with ALPN, if any.
+    ///
+    /// Transports without TLS, or whose TLS implementation does not support
+    /// ALPN, always return `None`.
+    fn negotiated_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Shuts down writing, so the peer reads the end of the stream, while
+    /// reading from it still works.
+    ///
+    /// By default, this is not supported and returns an error.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "shutdown not supported"))
+    }
 }
 
 /// Declares when a transport is blocked from any further action, until the
@@ -63,6 +99,12 @@ pub trait Accept: Evented {
     fn accept(&self) -> io::Result<Option<Self::Output>>;
     /// Return the local `SocketAddr` of this listener.
     fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// Whether this listener has a local `SocketAddr` at all.
+    ///
+    /// By default, this is true.
+    fn has_local_addr(&self) -> bool {
+        true
+    }
 }
 
 /// An alias to `mio::tcp::TcpStream`.
@@ -73,6 +115,10 @@ impl Transport for HttpStream {
     fn take_socket_error(&mut self) -> io::Result<()> {
         self.0.take_socket_error()
     }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.0.shutdown(Shutdown::Write)
+    }
 }
 
 impl Read for HttpStream {
@@ -178,6 +224,165 @@ impl IntoIterator for HttpListener {
     }
 }
 
+/// An alias to `mio::unix::UnixStream`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixStream(pub MioUnixStream);
+
+#[cfg(unix)]
+impl UnixStream {
+    /// Connect to the Unix domain socket at a path.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+        MioUnixStream::connect(path.as_ref()).map(UnixStream)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        // connecting to a socket path either fails right away or succeeds,
+        // so there is no pending error to take
+        Ok(())
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        use std::os::unix::net::UnixStream as StdUnixStream;
+
+        // the socket is only borrowed, so it must not be closed by the
+        // std stream being dropped
+        let stream = unsafe { StdUnixStream::from_raw_fd(self.0.as_raw_fd()) };
+        let res = stream.shutdown(Shutdown::Write);
+        ::std::mem::forget(stream);
+        res
+    }
+}
+
+#[cfg(unix)]
+impl Read for UnixStream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl Write for UnixStream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(unix)]
+impl Evented for UnixStream {
+    #[inline]
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    #[inline]
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    #[inline]
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
+
+#[cfg(unix)]
+impl ::vecio::Writev for UnixStream {
+    #[inline]
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        use ::vecio::Rawv;
+        self.0.writev(bufs)
+    }
+}
+
+/// An alias to `mio::unix::UnixListener`.
+///
+/// A Unix domain socket has a path instead of a `SocketAddr`, so its
+/// `Accept::local_addr` is always an error, and a `Server` listening on
+/// one does not list it in `Listening::addrs`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixListener(pub MioUnixListener);
+
+#[cfg(unix)]
+impl UnixListener {
+    /// Bind to a socket path.
+    ///
+    /// The path must not exist yet.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        MioUnixListener::bind(path.as_ref()).map(UnixListener)
+    }
+
+    /// Try to duplicate the underlying listening socket.
+    pub fn try_clone(&self) -> io::Result<UnixListener> {
+        self.0.try_clone().map(UnixListener)
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    type Output = UnixStream;
+
+    #[inline]
+    fn accept(&self) -> io::Result<Option<UnixStream>> {
+        TryAccept::accept(&self.0).map(|ok| ok.map(UnixStream))
+    }
+
+    #[inline]
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "unix sockets have no socket address"))
+    }
+
+    #[inline]
+    fn has_local_addr(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(unix)]
+impl Evented for UnixListener {
+    #[inline]
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    #[inline]
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    #[inline]
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
+
+#[cfg(unix)]
+impl IntoIterator for UnixListener {
+    type Item = Self;
+    type IntoIter = option::IntoIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Some(self).into_iter()
+    }
+}
+
+/// The protocols offered with ALPN by `HttpsConnector` and `HttpsListener`,
+/// in order of preference.
+#[doc(hidden)]
+pub const ALPN_PROTOCOLS: &'static [&'static [u8]] = &[b"h2", b"http/1.1"];
+
 /// Deprecated
 ///
 /// Use `SslClient` and `SslServer` instead.
@@ -188,6 +393,10 @@ pub trait Ssl {
     fn wrap_client(&self, stream: HttpStream, host: &str) -> ::Result<Self::Stream>;
     /// Wrap a server stream with SSL.
     fn wrap_server(&self, stream: HttpStream) -> ::Result<Self::Stream>;
+    /// Set the application protocols to offer with ALPN.
+    ///
+    /// Implementations without ALPN support ignore this.
+    fn set_alpn_protocols(&mut self, _protocols: &[&[u8]]) {}
 }
 
 /// An abstraction to allow any SSL implementation to be used with client-side `HttpsStream`s.
@@ -196,6 +405,12 @@ pub trait SslClient {
     type Stream: Transport;
     /// Wrap a client stream with SSL.
     fn wrap_client(&self, stream: HttpStream, host: &str) -> ::Result<Self::Stream>;
+    /// Set the application protocols to offer with ALPN, in order of preference.
+    ///
+    /// The protocol picked by the server is reported by
+    /// `Transport::negotiated_protocol` on the wrapped stream.
+    /// Implementations without ALPN support ignore this.
+    fn set_alpn_protocols(&mut self, _protocols: &[&[u8]]) {}
 }
 
 /// An abstraction to allow any SSL implementation to be used with server-side `HttpsStream`s.
@@ -204,6 +419,12 @@ pub trait SslServer {
     type Stream: Transport;
     /// Wrap a server stream with SSL.
     fn wrap_server(&self, stream: HttpStream) -> ::Result<Self::Stream>;
+    /// Set the application protocols to accept with ALPN, in order of preference.
+    ///
+    /// The protocol picked for a client is reported by
+    /// `Transport::negotiated_protocol` on the wrapped stream.
+    /// Implementations without ALPN support ignore this.
+    fn set_alpn_protocols(&mut self, _protocols: &[&[u8]]) {}
 }
 
 impl<S: Ssl> SslClient for S {
@@ -212,6 +433,10 @@ impl<S: Ssl> SslClient for S {
     fn wrap_client(&self, stream: HttpStream, host: &str) -> ::Result<Self::Stream> {
         Ssl::wrap_client(self, stream, host)
     }
+
+    fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) {
+        Ssl::set_alpn_protocols(self, protocols)
+    }
 }
 
 impl<S: Ssl> SslServer for S {
@@ -220,6 +445,10 @@ impl<S: Ssl> SslServer for S {
     fn wrap_server(&self, stream: HttpStream) -> ::Result<Self::Stream> {
         Ssl::wrap_server(self, stream)
     }
+
+    fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) {
+        Ssl::set_alpn_protocols(self, protocols)
+    }
 }
 
 /// A stream over the HTTP protocol, possibly protected by TLS.
@@ -332,6 +561,22 @@ impl<S: Transport> Transport for HttpsStream<S> {
             HttpsStream::Https(ref s) => s.blocked(),
         }
     }
+
+    #[inline]
+    fn negotiated_protocol(&self) -> Option<&[u8]> {
+        match *self {
+            HttpsStream::Http(ref s) => s.negotiated_protocol(),
+            HttpsStream::Https(ref s) => s.negotiated_protocol(),
+        }
+    }
+
+    #[inline]
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        match *self {
+            HttpsStream::Http(ref mut s) => s.shutdown_write(),
+            HttpsStream::Https(ref mut s) => s.shutdown_write(),
+        }
+    }
 }
 
 /// An `HttpListener` over SSL.
@@ -343,16 +588,17 @@ pub struct HttpsListener<S: SslServer> {
 
 impl<S: SslServer> HttpsListener<S> {
     /// Start listening to an address over HTTPS.
+    ///
+    /// The `ssl` implementation is set to accept `h2` and `http/1.1` with
+    /// ALPN, so that clients supporting it will be served over HTTP/2.
     #[inline]
     pub fn new(addr: &SocketAddr, ssl: S) -> io::Result<HttpsListener<S>> {
-        TcpListener::bind(addr).map(|l| HttpsListener {
-            listener: l,
-            ssl: ssl
-        })
+        TcpListener::bind(addr).map(|l| HttpsListener::with_listener(l, ssl))
     }
 
     /// Construct an `HttpsListener` from a bound `TcpListener`.
-    pub fn with_listener(listener: TcpListener, ssl: S) -> HttpsListener<S> {
+    pub fn with_listener(listener: TcpListener, mut ssl: S) -> HttpsListener<S> {
+        ssl.set_alpn_protocols(ALPN_PROTOCOLS);
         HttpsListener {
             listener: listener,
             ssl: ssl
@@ -491,6 +737,11 @@ mod openssl {
     impl super::SslClient for OpensslClient {
         type Stream = OpensslStream<HttpStream>;
 
+        #[cfg(feature = "alpn")]
+        fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) {
+            self.0.set_alpn_protocols(protocols);
+        }
+
         #[cfg(not(windows))]
         fn wrap_client(&self, stream: HttpStream, host: &str) -> ::Result<Self::Stream> {
             let mut ssl = try!(Ssl::new(&self.0));
@@ -540,6 +791,11 @@ mod openssl {
     impl super::Ssl for Openssl {
         type Stream = OpensslStream<HttpStream>;
 
+        #[cfg(feature = "alpn")]
+        fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) {
+            self.context.set_alpn_protocols(protocols);
+        }
+
         fn wrap_client(&self, stream: HttpStream, host: &str) -> ::Result<Self::Stream> {
             let ssl = try!(Ssl::new(&self.context));
             try!(ssl.set_hostname(host));
@@ -636,6 +892,11 @@ mod openssl {
         fn take_socket_error(&mut self) -> io::Result<()> {
             self.stream.get_mut().take_socket_error()
         }
+
+        #[cfg(feature = "alpn")]
+        fn negotiated_protocol(&self) -> Option<&[u8]> {
+            self.stream.ssl().selected_alpn_protocol()
+        }
     }
 }
 
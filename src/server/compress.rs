@@ -0,0 +1,260 @@
+//! Compressing response bodies.
+use mime::{Mime, TopLevel, SubLevel};
+use unicase::UniCase;
+
+use header::{CacheControl, CacheDirective, ContentEncoding, ContentLength, ContentType,
+             ETag, Encoding, Headers, QualityItem, Vary};
+use status::StatusCode;
+
+/// Which responses a `Server` compresses.
+///
+/// A response is compressed when the request's `Accept-Encoding` allows
+/// `gzip` or `deflate`, its `Content-Type` is one of the allowed types, and
+/// its `Content-Length` is unknown or at least the minimum size. The
+/// `Handler` writes the body as usual. The `Content-Encoding` is set, the
+/// `Content-Length` is removed so that the body is sent chunked, a strong
+/// `ETag` is made weak, since it no longer names the same bytes, and
+/// `Accept-Encoding` is added to the `Vary` header.
+///
+/// Responses that already have a `Content-Encoding`, or a `Cache-Control`
+/// of `no-transform`, are left alone. So are HTTP/2 responses, responses
+/// to HTTP/1.0 requests, which can't be chunked, and responses to `HEAD`,
+/// which keep their `Content-Length`.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u64,
+    content_types: Vec<Mime>,
+}
+
+impl Compression {
+    /// Compress responses with the default settings.
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            content_types: vec![
+                Mime(TopLevel::Text, SubLevel::Star, vec![]),
+                Mime(TopLevel::Application, SubLevel::Json, vec![]),
+                Mime(TopLevel::Application, SubLevel::Javascript, vec![]),
+                Mime(TopLevel::Application, SubLevel::Xml, vec![]),
+                Mime(TopLevel::Image, SubLevel::Ext("svg+xml".to_owned()), vec![]),
+            ],
+        }
+    }
+
+    /// Set the smallest `Content-Length` worth compressing.
+    ///
+    /// Default is 1024 bytes.
+    pub fn min_size(mut self, val: u64) -> Compression {
+        self.min_size = val;
+        self
+    }
+
+    /// Set the content types that are compressed.
+    ///
+    /// A `*` subtype allows all subtypes, such as `text/*`. Parameters
+    /// are ignored.
+    ///
+    /// Default is `text/*`, `application/json`, `application/javascript`,
+    /// `application/xml` and `image/svg+xml`.
+    pub fn content_types(mut self, val: Vec<Mime>) -> Compression {
+        self.content_types = val;
+        self
+    }
+
+    /// Whether a response with these headers is worth compressing.
+    fn allows(&self, status: &StatusCode, headers: &Headers) -> bool {
+        match status.to_u16() {
+            100...199 | 204 | 206 | 304 => return false,
+            _ => (),
+        }
+        if headers.has::<ContentEncoding>() {
+            return false;
+        }
+        if let Some(&CacheControl(ref directives)) = headers.get() {
+            if directives.contains(&CacheDirective::NoTransform) {
+                return false;
+            }
+        }
+        if let Some(&ContentLength(len)) = headers.get() {
+            if len < self.min_size {
+                return false;
+            }
+        }
+        match headers.get::<ContentType>() {
+            Some(&ContentType(Mime(ref top, ref sub, _))) => {
+                self.content_types.iter().any(|&Mime(ref allowed_top, ref allowed_sub, _)| {
+                    (*allowed_top == TopLevel::Star || allowed_top == top) &&
+                        (*allowed_sub == SubLevel::Star || allowed_sub == sub)
+                })
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+/// Picks the coding with the highest quality in an `Accept-Encoding`.
+///
+/// Ties go to `gzip`.
+pub fn negotiate(accept: &[QualityItem<Encoding>]) -> Option<Encoding> {
+    let any = Encoding::EncodingExt("*".to_owned());
+    let quality = |coding: &Encoding| {
+        accept.iter().find(|qi| qi.item == *coding)
+            .or_else(|| accept.iter().find(|qi| qi.item == any))
+            .map_or(0, |qi| qi.quality.0)
+    };
+    let mut best = None;
+    let mut best_quality = 0;
+    for coding in vec![Encoding::Gzip, Encoding::Deflate] {
+        let q = quality(&coding);
+        if q > best_quality {
+            best = Some(coding);
+            best_quality = q;
+        }
+    }
+    best
+}
+
+/// Prepares the headers of a response for compression with `coding`.
+///
+/// Returns the coding to compress the body with, if it should be.
+pub fn prepare(compression: &Compression,
+               coding: Option<Encoding>,
+               status: &StatusCode,
+               headers: &mut Headers) -> Option<Encoding> {
+    if !compression.allows(status, headers) {
+        return None;
+    }
+    // the response depends on Accept-Encoding, even when not compressed
+    let vary = match headers.get::<Vary>() {
+        Some(&Vary::Any) => None,
+        Some(&Vary::Items(ref items)) => {
+            let accept_encoding = UniCase("Accept-Encoding".to_owned());
+            if items.contains(&accept_encoding) {
+                None
+            } else {
+                let mut items = items.clone();
+                items.push(accept_encoding);
+                Some(Vary::Items(items))
+            }
+        }
+        None => Some(Vary::Items(vec![UniCase("Accept-Encoding".to_owned())])),
+    };
+    if let Some(vary) = vary {
+        headers.set(vary);
+    }
+    if let Some(ref coding) = coding {
+        headers.set(ContentEncoding(vec![coding.clone()]));
+        headers.remove::<ContentLength>();
+        // the compressed body may not share a strong validator with the
+        // identity body (RFC 7232, section 2.1)
+        if let Some(&mut ETag(ref mut tag)) = headers.get_mut::<ETag>() {
+            tag.weak = true;
+        }
+    }
+    coding
+}
+
+#[cfg(test)]
+mod tests {
+    use header::{AcceptEncoding, CacheControl, CacheDirective, ContentEncoding, ContentLength,
+                 ContentType, ETag, Encoding, EntityTag, Header, Headers, Vary};
+    use status::StatusCode;
+    use super::{Compression, negotiate, prepare};
+
+    fn negotiated(accept: &str) -> Option<Encoding> {
+        let accept = AcceptEncoding::parse_header(&accept.into()).unwrap();
+        negotiate(&accept.0)
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiated("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(negotiated("gzip;q=0, deflate;q=0.1"), Some(Encoding::Deflate));
+        assert_eq!(negotiated("*;q=0.3, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(negotiated("br, identity"), None);
+        assert_eq!(negotiated("*;q=0"), None);
+    }
+
+    fn html(len: Option<u64>) -> Headers {
+        let mut headers = Headers::new();
+        headers.set(ContentType::html());
+        if let Some(len) = len {
+            headers.set(ContentLength(len));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_prepare() {
+        let compression = Compression::new();
+        let mut headers = html(Some(4096));
+        assert_eq!(prepare(&compression, Some(Encoding::Gzip), &StatusCode::Ok, &mut headers),
+                   Some(Encoding::Gzip));
+        assert_eq!(headers.get(), Some(&ContentEncoding(vec![Encoding::Gzip])));
+        assert_eq!(headers.get::<ContentLength>(), None);
+        assert_eq!(headers.get_raw("Vary").unwrap(), "Accept-Encoding");
+
+        // not accepted, but it could have been
+        let mut headers = html(None);
+        assert_eq!(prepare(&compression, None, &StatusCode::Ok, &mut headers), None);
+        assert!(headers.has::<Vary>());
+        assert!(!headers.has::<ContentEncoding>());
+
+        let mut headers = html(None);
+        headers.set_raw("Vary", "cookie");
+        prepare(&compression, Some(Encoding::Gzip), &StatusCode::Ok, &mut headers);
+        assert_eq!(headers.get_raw("Vary").unwrap(), "cookie, Accept-Encoding");
+    }
+
+    #[test]
+    fn test_prepare_weakens_etag() {
+        let compression = Compression::new();
+        let mut headers = html(None);
+        headers.set(ETag(EntityTag::strong("abc".to_owned())));
+        prepare(&compression, Some(Encoding::Gzip), &StatusCode::Ok, &mut headers);
+        assert_eq!(headers.get(), Some(&ETag(EntityTag::weak("abc".to_owned()))));
+
+        // left alone when the body is not compressed
+        let mut headers = html(None);
+        headers.set(ETag(EntityTag::strong("abc".to_owned())));
+        prepare(&compression, None, &StatusCode::Ok, &mut headers);
+        assert_eq!(headers.get(), Some(&ETag(EntityTag::strong("abc".to_owned()))));
+    }
+
+    #[test]
+    fn test_prepare_skips() {
+        let compression = Compression::new().min_size(100);
+        let skips = |status: StatusCode, headers: &mut Headers| {
+            prepare(&compression, Some(Encoding::Gzip), &status, headers).is_none()
+        };
+        assert!(skips(StatusCode::Ok, &mut html(Some(99))));
+        assert!(!skips(StatusCode::Ok, &mut html(Some(100))));
+        assert!(skips(StatusCode::NoContent, &mut html(None)));
+        assert!(skips(StatusCode::NotModified, &mut html(None)));
+        assert!(skips(StatusCode::PartialContent, &mut html(None)));
+        assert!(skips(StatusCode::Ok, &mut Headers::new()));
+
+        let mut png = Headers::new();
+        png.set(ContentType::png());
+        assert!(skips(StatusCode::Ok, &mut png));
+
+        let mut encoded = html(None);
+        encoded.set(ContentEncoding(vec![Encoding::Deflate]));
+        assert!(skips(StatusCode::Ok, &mut encoded));
+
+        let mut no_transform = html(None);
+        no_transform.set(CacheControl(vec![CacheDirective::NoTransform]));
+        assert!(skips(StatusCode::Ok, &mut no_transform));
+
+        let mut json = Headers::new();
+        json.set(ContentType::json());
+        assert!(!skips(StatusCode::Ok, &mut json));
+    }
+}
@@ -1,10 +1,16 @@
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 
+use header::{AcceptEncoding, Encoding};
 use http::{self, Next};
+use method::Method;
 use net::Transport;
+use status::StatusCode;
+use version::HttpVersion;
 
 use super::{Handler, request, response};
+use super::compress::{self, Compression};
 
 /// A `MessageHandler` for a Server.
 ///
@@ -13,13 +19,25 @@ use super::{Handler, request, response};
 /// would expect in a Server Handler.
 pub struct Message<H: Handler<T>, T: Transport> {
     handler: H,
+    connect: bool,
+    upgrade: bool,
+    interim: bool,
+    compression: Option<Arc<Compression>>,
+    /// The coding the request accepts, and then the one the body is
+    /// compressed with.
+    coding: Option<Encoding>,
     _marker: PhantomData<T>
 }
 
 impl<H: Handler<T>, T: Transport> Message<H, T> {
-    pub fn new(handler: H) -> Message<H, T> {
+    pub fn new(handler: H, compression: Option<Arc<Compression>>) -> Message<H, T> {
         Message {
             handler: handler,
+            connect: false,
+            upgrade: false,
+            interim: false,
+            compression: compression,
+            coding: None,
             _marker: PhantomData,
         }
     }
@@ -30,6 +48,20 @@ impl<H: Handler<T>, T: Transport> http::MessageHandler<T> for Message<H, T> {
 
     fn on_incoming(&mut self, head: http::RequestHead, transport: &T) -> Next {
         trace!("on_incoming {:?}", head);
+        self.connect = head.subject.0 == Method::Connect;
+        match head.version {
+            HttpVersion::Http11 => (),
+            // a compressed body is sent chunked, which an HTTP/1.0 client
+            // can't decode, and an Encoder of an HTTP/2 stream cannot
+            // compress
+            _ => self.compression = None,
+        }
+        // the Content-Length of a HEAD response is the handler's, which
+        // a compressed body would not match (RFC 7231, section 4.3.2)
+        if self.compression.is_some() && head.subject.0 != Method::Head {
+            self.coding = head.headers.get::<AcceptEncoding>()
+                .and_then(|&AcceptEncoding(ref accept)| compress::negotiate(accept));
+        }
         let req = request::new(head, transport);
         self.handler.on_request(req)
     }
@@ -38,12 +70,31 @@ impl<H: Handler<T>, T: Transport> http::MessageHandler<T> for Message<H, T> {
         self.handler.on_request_readable(transport)
     }
 
-    fn on_outgoing(&mut self, head: &mut http::MessageHead<::status::StatusCode>) -> Next {
-        let mut res = response::new(head);
-        self.handler.on_response(&mut res)
+    fn on_outgoing(&mut self, head: &mut http::MessageHead<StatusCode>) -> Next {
+        let next = {
+            let mut res = response::new(head);
+            self.handler.on_response(&mut res)
+        };
+        self.interim = head.subject.is_informational() &&
+            head.subject != StatusCode::SwitchingProtocols;
+        if self.interim {
+            return next;
+        }
+        self.upgrade = head.subject == StatusCode::SwitchingProtocols ||
+            (self.connect && head.subject.is_success());
+        self.coding = match self.compression {
+            Some(ref compression) if !self.upgrade => {
+                compress::prepare(compression, self.coding.take(), &head.subject, &mut head.headers)
+            }
+            _ => None,
+        };
+        next
     }
 
     fn on_encode(&mut self, transport: &mut http::Encoder<T>) -> Next {
+        if let Some(coding) = self.coding.take() {
+            transport.encode_content(&coding);
+        }
         self.handler.on_response_writable(transport)
     }
 
@@ -51,7 +102,23 @@ impl<H: Handler<T>, T: Transport> http::MessageHandler<T> for Message<H, T> {
         self.handler.on_error(error)
     }
 
+    fn is_upgrade(&self) -> bool {
+        self.upgrade
+    }
+
+    fn on_interim(&mut self, _head: &http::RequestHead) {
+        unreachable!("requests have no informational heads")
+    }
+
+    fn is_interim(&self) -> bool {
+        self.interim
+    }
+
     fn on_remove(self, transport: T) {
         self.handler.on_remove(transport);
     }
+
+    fn on_upgrade(self, upgraded: http::Upgraded<T>) {
+        self.handler.on_upgrade(upgraded);
+    }
 }
@@ -4,6 +4,8 @@
 //! them off to a `Handler`.
 use std::fmt;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
@@ -11,15 +13,19 @@ use std::time::Duration;
 use rotor::mio::{EventSet, PollOpt};
 use rotor::{self, Scope};
 
+pub use self::compress::Compression;
 pub use self::request::Request;
 pub use self::response::Response;
 
 use http::{self, Next, ReadyResult};
 
 pub use net::{Accept, HttpListener, HttpsListener};
+#[cfg(unix)]
+pub use net::UnixListener;
 use net::{SslServer, Transport};
 
 
+mod compress;
 mod request;
 mod response;
 mod message;
@@ -41,8 +47,19 @@ pub struct Server<A> {
     lead_listener: A,
     other_listeners: Vec<A>,
     keep_alive: bool,
+    http2: bool,
     idle_timeout: Option<Duration>,
     max_sockets: usize,
+    compression: Option<Compression>,
+    error_page: Option<ErrorPage>,
+}
+
+struct ErrorPage(Box<Fn(&::Error, &mut Response) -> Vec<u8> + Send + Sync>);
+
+impl fmt::Debug for ErrorPage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("ErrorPage")
+    }
 }
 
 impl<A: Accept> Server<A> {
@@ -58,8 +75,11 @@ impl<A: Accept> Server<A> {
             lead_listener: lead_listener,
             other_listeners: other_listeners,
             keep_alive: true,
+            http2: false,
             idle_timeout: Some(Duration::from_secs(10)),
             max_sockets: 4096,
+            compression: None,
+            error_page: None,
         }
     }
 
@@ -71,6 +91,22 @@ impl<A: Accept> Server<A> {
         self
     }
 
+    /// Enables or disables HTTP/2 on plaintext connections.
+    ///
+    /// When enabled, a connection that starts with the HTTP/2 connection
+    /// preface (prior knowledge), or an HTTP/1.1 request with
+    /// `Upgrade: h2c`, is served as HTTP/2, with each stream getting its
+    /// own `Handler`.
+    ///
+    /// This does not apply to `HttpsListener` connections, which use
+    /// whichever protocol was negotiated with ALPN.
+    ///
+    /// Default is false.
+    pub fn http2(mut self, val: bool) -> Server<A> {
+        self.http2 = val;
+        self
+    }
+
     /// Sets how long an idle connection will be kept before closing.
     ///
     /// Default is 10 seconds.
@@ -86,6 +122,33 @@ impl<A: Accept> Server<A> {
         self.max_sockets = val;
         self
     }
+
+    /// Compresses response bodies the client accepts compressed.
+    ///
+    /// See `Compression` for which responses are compressed.
+    ///
+    /// Default is no compression.
+    pub fn compression(mut self, val: Compression) -> Server<A> {
+        self.compression = Some(val);
+        self
+    }
+
+    /// Sets the body of the responses written for requests that could not
+    /// be read.
+    ///
+    /// Such a request is answered with a `400 Bad Request`, `414 URI Too
+    /// Long`, `431 Request Header Fields Too Large` or `505 HTTP Version Not
+    /// Supported`, without calling the `Handler`, and the connection is
+    /// closed afterwards. The function gets the error and the `Response`,
+    /// whose status and headers (such as `Content-Type`) can be changed,
+    /// and returns the body.
+    ///
+    /// Default is an empty body.
+    pub fn error_page<F>(mut self, page: F) -> Server<A>
+    where F: Fn(&::Error, &mut Response) -> Vec<u8> + Send + Sync + 'static {
+        self.error_page = Some(ErrorPage(Box::new(page)));
+        self
+    }
 }
 
 impl Server<HttpListener> { //<H: HandlerFactory<<HttpListener as Accept>::Output>> Server<HttpListener, H> {
@@ -100,6 +163,17 @@ impl Server<HttpListener> { //<H: HandlerFactory<<HttpListener as Accept>::Outpu
 }
 
 
+#[cfg(unix)]
+impl Server<UnixListener> {
+    /// Creates a new HTTP server config listening on a Unix domain socket
+    /// at the provided path.
+    pub fn unix<P: AsRef<Path>>(path: P) -> ::Result<Server<UnixListener>> {
+        UnixListener::bind(path)
+            .map(Server::new)
+            .map_err(From::from)
+    }
+}
+
 impl<S: SslServer> Server<HttpsListener<S>> {
     /// Creates a new server config that will handle `HttpStream`s over SSL.
     ///
@@ -122,7 +196,9 @@ impl<A: Accept> Server<A> {
         config.slab_capacity(self.max_sockets);
         config.mio().notify_capacity(self.max_sockets);
         let keep_alive = self.keep_alive;
+        let http2 = self.http2;
         let idle_timeout = self.idle_timeout;
+        let compression = self.compression.map(Arc::new);
         let mut loop_ = rotor::Loop::new(&config).unwrap();
 
         let mut addrs = Vec::with_capacity(1 + self.other_listeners.len());
@@ -132,7 +208,9 @@ impl<A: Accept> Server<A> {
         {
             let notifier = &mut notifier;
             let listener = self.lead_listener;
-            addrs.push(try!(listener.local_addr()));
+            if listener.has_local_addr() {
+                addrs.push(try!(listener.local_addr()));
+            }
             let shutdown_rx = shutdown.clone();
             loop_.add_machine_with(move |scope| {
                 *notifier = Some(scope.notifier());
@@ -144,7 +222,9 @@ impl<A: Accept> Server<A> {
 
         // Add the other listeners.
         for listener in self.other_listeners {
-            addrs.push(try!(listener.local_addr()));
+            if listener.has_local_addr() {
+                addrs.push(try!(listener.local_addr()));
+            }
             let shutdown_rx = shutdown.clone();
             loop_.add_machine_with(move |scope| {
                 rotor_try!(scope.register(&listener, EventSet::readable(), PollOpt::level()));
@@ -161,6 +241,9 @@ impl<A: Accept> Server<A> {
                 factory: factory,
                 idle_timeout: idle_timeout,
                 keep_alive: keep_alive,
+                http2: http2,
+                compression: compression,
+                error_page: self.error_page,
             }))
         };
         Ok((listening, server))
@@ -189,13 +272,16 @@ struct Context<F> {
     factory: F,
     idle_timeout: Option<Duration>,
     keep_alive: bool,
+    http2: bool,
+    compression: Option<Arc<Compression>>,
+    error_page: Option<ErrorPage>,
 }
 
 impl<F: HandlerFactory<T>, T: Transport> http::MessageHandlerFactory<(), T> for Context<F> {
     type Output = message::Message<F::Output, T>;
 
     fn create(&mut self, seed: http::Seed<()>) -> Option<Self::Output> {
-        Some(message::Message::new(self.factory.create(seed.control())))
+        Some(message::Message::new(self.factory.create(seed.control()), self.compression.clone()))
     }
 
     fn keep_alive_interest(&self) -> Next {
@@ -205,6 +291,13 @@ impl<F: HandlerFactory<T>, T: Transport> http::MessageHandlerFactory<(), T> for
             Next::read()
         }
     }
+
+    fn reject_body(&self, err: &::Error, head: &mut http::MessageHead<StatusCode>) -> Vec<u8> {
+        match self.error_page {
+            Some(ErrorPage(ref page)) => page(err, &mut response::new(head)),
+            None => Vec::new(),
+        }
+    }
 }
 
 enum ServerFsm<A, H>
@@ -228,6 +321,7 @@ where A: Accept,
             ServerFsm::Conn(
                 http::Conn::new((), seed, Next::read(), scope.notifier())
                     .keep_alive(scope.keep_alive)
+                    .http2(scope.http2)
             )
         )
     }
@@ -356,6 +450,9 @@ impl fmt::Display for Listening {
 
 impl Listening {
     /// The addresses this server is listening on.
+    ///
+    /// Listeners without a socket address, such as a `UnixListener`, are
+    /// not included.
     pub fn addrs(&self) -> &[SocketAddr] {
         &self.addrs
     }
@@ -373,10 +470,22 @@ impl Listening {
 /// Each event handler returns its desired `Next` action.
 pub trait Handler<T: Transport> {
     /// This event occurs first, triggering when a `Request` has been parsed.
+    ///
+    /// If the request has `Expect: 100-continue`, a `100 Continue` is sent
+    /// the first time this handler signals `Next::read()`. Signaling
+    /// `Next::write()` first instead answers with a final status, without the
+    /// client ever sending the body, and the connection is closed after it.
     fn on_request(&mut self, request: Request<T>) -> Next;
     /// This event occurs each time the `Request` is ready to be read from.
     fn on_request_readable(&mut self, request: &mut http::Decoder<T>) -> Next;
     /// This event occurs after the first time this handled signals `Next::write()`.
+    ///
+    /// Setting an informational status, other than `101 Switching
+    /// Protocols`, sends just this head, such as a `103 Early Hints` with
+    /// `Link` headers. The returned `Next` then works as it does from
+    /// `on_request`, and this event occurs again for the next head once the
+    /// handler signals `Next::write()`. An HTTP/1.0 client would not
+    /// understand such a head, so it is not sent to one.
     fn on_response(&mut self, response: &mut Response) -> Next;
     /// This event occurs each time the `Response` is ready to be written to.
     fn on_response_writable(&mut self, response: &mut http::Encoder<T>) -> Next;
@@ -393,6 +502,21 @@ pub trait Handler<T: Transport> {
     fn on_remove(self, _transport: T) where Self: Sized {
         debug!("default Handler.on_remove");
     }
+
+    /// This event occurs when the `Response` switched protocols, and this
+    /// Handler then signaled `Next::end()`.
+    ///
+    /// A response switches protocols when its status is `101 Switching
+    /// Protocols`, or when it is a `2xx` to a `CONNECT` request. After the
+    /// head is written, the `Decoder` and `Encoder` no longer apply any HTTP
+    /// framing, so the new protocol can be spoken with `Next` as well. Ending
+    /// the message hands over the `Transport`, together with any bytes hyper
+    /// had already read from it, so it can be driven elsewhere.
+    ///
+    /// By default, this calls `on_remove` with the transport.
+    fn on_upgrade(self, upgraded: http::Upgraded<T>) where Self: Sized {
+        self.on_remove(upgraded.into_parts().0);
+    }
 }
 
 
@@ -39,6 +39,9 @@ pub enum StatusCode {
     /// 102 Processing
     /// [[RFC2518](https://tools.ietf.org/html/rfc2518)]
     Processing,
+    /// 103 Early Hints
+    /// [[RFC8297](https://tools.ietf.org/html/rfc8297)]
+    EarlyHints,
 
     /// 200 OK
     /// [[RFC7231, Section 6.3.1](https://tools.ietf.org/html/rfc7231#section-6.3.1)]
@@ -235,6 +238,7 @@ impl StatusCode {
             100 => StatusCode::Continue,
             101 => StatusCode::SwitchingProtocols,
             102 => StatusCode::Processing,
+            103 => StatusCode::EarlyHints,
             200 => StatusCode::Ok,
             201 => StatusCode::Created,
             202 => StatusCode::Accepted,
@@ -302,6 +306,7 @@ impl StatusCode {
             StatusCode::Continue => 100,
             StatusCode::SwitchingProtocols => 101,
             StatusCode::Processing => 102,
+            StatusCode::EarlyHints => 103,
             StatusCode::Ok => 200,
             StatusCode::Created => 201,
             StatusCode::Accepted => 202,
@@ -378,6 +383,7 @@ impl StatusCode {
             StatusCode::Continue => Some("Continue"),
             StatusCode::SwitchingProtocols => Some("Switching Protocols"),
             StatusCode::Processing => Some("Processing"),
+            StatusCode::EarlyHints => Some("Early Hints"),
 
             StatusCode::Ok => Some("OK"),
             StatusCode::Created => Some("Created"),
@@ -683,6 +689,7 @@ mod tests {
         validate(100, Continue, Continue, Some("Continue"));
         validate(101, SwitchingProtocols, Continue, Some("Switching Protocols"));
         validate(102, Processing, Continue, Some("Processing"));
+        validate(103, EarlyHints, Continue, Some("Early Hints"));
 
         validate(200, Ok, Ok, Some("OK"));
         validate(201, Created, Ok, Some("Created"));
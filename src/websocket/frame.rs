@@ -0,0 +1,319 @@
+//! WebSocket frames, as defined in RFC 6455 section 5.
+
+/// Status codes used in close frames.
+pub mod close_code {
+    /// The purpose of the connection has been fulfilled.
+    pub const NORMAL: u16 = 1000;
+    /// An endpoint is going away, such as a server shutting down.
+    pub const GOING_AWAY: u16 = 1001;
+    /// An endpoint received a frame violating the protocol.
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    /// An endpoint received a type of data it cannot accept.
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    /// No status code was present in a close frame. Never sent.
+    pub const NO_STATUS: u16 = 1005;
+    /// The connection was closed without a close frame. Never sent.
+    pub const ABNORMAL: u16 = 1006;
+    /// An endpoint received data inconsistent with the type of the message,
+    /// such as invalid UTF-8 in a text message.
+    pub const INVALID_DATA: u16 = 1007;
+    /// An endpoint received a message violating its policy.
+    pub const POLICY_VIOLATION: u16 = 1008;
+    /// An endpoint received a message too big to process.
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    /// A client expected the server to negotiate an extension.
+    pub const MISSING_EXTENSION: u16 = 1010;
+    /// A server encountered an unexpected condition.
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    /// Whether a code may be received in a close frame.
+    pub fn is_valid(code: u16) -> bool {
+        match code {
+            1000...1003 | 1007...1011 | 3000...4999 => true,
+            _ => false,
+        }
+    }
+}
+
+/// The opcode of a frame, which tells how to interpret its payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    /// Continues a fragmented message.
+    Continuation,
+    /// Starts a text message.
+    Text,
+    /// Starts a binary message.
+    Binary,
+    /// Starts the close handshake.
+    Close,
+    /// Asks for a `Pong`.
+    Ping,
+    /// Answers a `Ping`.
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(b: u8) -> Option<OpCode> {
+        match b {
+            0x0 => Some(OpCode::Continuation),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn to_u8(&self) -> u8 {
+        match *self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    /// Whether this is a control frame, which may be sent in between the
+    /// frames of a fragmented message.
+    pub fn is_control(&self) -> bool {
+        match *self {
+            OpCode::Close | OpCode::Ping | OpCode::Pong => true,
+            _ => false,
+        }
+    }
+}
+
+/// A single WebSocket frame.
+///
+/// The payload is always kept unmasked. When a `mask` is set, it is applied
+/// while encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Whether this is the final frame of a message.
+    pub fin: bool,
+    /// How the payload is interpreted.
+    pub opcode: OpCode,
+    /// The masking key, which every frame sent by a client must have.
+    pub mask: Option<[u8; 4]>,
+    /// The unmasked payload.
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Creates a final, unmasked frame.
+    pub fn new(opcode: OpCode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            opcode: opcode,
+            mask: None,
+            payload: payload,
+        }
+    }
+
+    /// Parses a frame from the start of `buf`.
+    ///
+    /// Returns `Ok(None)` when `buf` does not yet hold a full frame, or the
+    /// frame and how many bytes of `buf` it used. Frames violating the
+    /// protocol, or with a payload longer than `max_payload`, are an error
+    /// with the close code to fail the connection with.
+    pub fn parse(buf: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, u16> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        if buf[0] & 0x70 != 0 {
+            debug!("websocket frame with reserved bits set, but no extension");
+            return Err(close_code::PROTOCOL_ERROR);
+        }
+        let opcode = match OpCode::from_u8(buf[0] & 0x0F) {
+            Some(opcode) => opcode,
+            None => {
+                debug!("websocket frame with unknown opcode {:#x}", buf[0] & 0x0F);
+                return Err(close_code::PROTOCOL_ERROR);
+            }
+        };
+        let masked = buf[1] & 0x80 != 0;
+
+        let mut pos = 2;
+        let len = match buf[1] & 0x7F {
+            126 => {
+                if buf.len() < pos + 2 {
+                    return Ok(None);
+                }
+                let len = (buf[2] as u64) << 8 | buf[3] as u64;
+                pos += 2;
+                len
+            }
+            127 => {
+                if buf.len() < pos + 8 {
+                    return Ok(None);
+                }
+                let len = buf[2..10].iter().fold(0u64, |len, &b| len << 8 | b as u64);
+                if len >> 63 != 0 {
+                    return Err(close_code::PROTOCOL_ERROR);
+                }
+                pos += 8;
+                len
+            }
+            len => len as u64,
+        };
+
+        if opcode.is_control() && (!fin || len > 125) {
+            debug!("websocket control frame fragmented or too long");
+            return Err(close_code::PROTOCOL_ERROR);
+        }
+        if len > max_payload as u64 {
+            debug!("websocket frame payload too long: {}", len);
+            return Err(close_code::MESSAGE_TOO_BIG);
+        }
+        let len = len as usize;
+
+        let mask = if masked {
+            if buf.len() < pos + 4 {
+                return Ok(None);
+            }
+            let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+            pos += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        if buf.len() < pos + len {
+            return Ok(None);
+        }
+        let mut payload = buf[pos..pos + len].to_vec();
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Some((Frame {
+            fin: fin,
+            opcode: opcode,
+            mask: mask,
+            payload: payload,
+        }, pos + len)))
+    }
+
+    /// Encodes this frame onto the end of `dst`.
+    pub fn encode(&self, dst: &mut Vec<u8>) {
+        let first = if self.fin { 0x80 } else { 0 } | self.opcode.to_u8();
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        let len = self.payload.len();
+
+        dst.reserve(14 + len);
+        dst.push(first);
+        if len < 126 {
+            dst.push(mask_bit | len as u8);
+        } else if len <= 0xFFFF {
+            dst.push(mask_bit | 126);
+            dst.push((len >> 8) as u8);
+            dst.push(len as u8);
+        } else {
+            dst.push(mask_bit | 127);
+            for i in (0..8).rev() {
+                dst.push((len as u64 >> (i * 8)) as u8);
+            }
+        }
+
+        match self.mask {
+            Some(mask) => {
+                dst.extend_from_slice(&mask);
+                let start = dst.len();
+                dst.extend_from_slice(&self.payload);
+                apply_mask(&mut dst[start..], mask);
+            }
+            None => dst.extend_from_slice(&self.payload),
+        }
+    }
+}
+
+/// Masks or unmasks a payload with a masking key, in place.
+pub fn apply_mask(buf: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, OpCode, close_code};
+
+    #[test]
+    fn test_parse_unmasked_text() {
+        // from RFC 6455 section 5.7
+        let raw = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let (frame, len) = Frame::parse(&raw, 1024).unwrap().unwrap();
+        assert_eq!(len, raw.len());
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.mask, None);
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[test]
+    fn test_parse_masked_text() {
+        let raw = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let (frame, _) = Frame::parse(&raw, 1024).unwrap().unwrap();
+        assert_eq!(frame.mask, Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[test]
+    fn test_parse_fragments() {
+        let first = [0x01, 0x03, 0x48, 0x65, 0x6c];
+        let (frame, _) = Frame::parse(&first, 1024).unwrap().unwrap();
+        assert!(!frame.fin);
+        assert_eq!(frame.opcode, OpCode::Text);
+
+        let last = [0x80, 0x02, 0x6c, 0x6f];
+        let (frame, _) = Frame::parse(&last, 1024).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OpCode::Continuation);
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        let raw = [0x81, 0x05, 0x48, 0x65];
+        assert_eq!(Frame::parse(&raw, 1024), Ok(None));
+        assert_eq!(Frame::parse(&raw[..1], 1024), Ok(None));
+        assert_eq!(Frame::parse(&[0x82, 0x7E, 0x01], 1024), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        // reserved bits
+        assert_eq!(Frame::parse(&[0xC1, 0x00], 1024), Err(close_code::PROTOCOL_ERROR));
+        // unknown opcode
+        assert_eq!(Frame::parse(&[0x83, 0x00], 1024), Err(close_code::PROTOCOL_ERROR));
+        // fragmented ping
+        assert_eq!(Frame::parse(&[0x09, 0x00], 1024), Err(close_code::PROTOCOL_ERROR));
+        // control frame over 125 bytes
+        assert_eq!(Frame::parse(&[0x89, 0x7E, 0x00, 0x7E], 1024), Err(close_code::PROTOCOL_ERROR));
+        // over the limit
+        assert_eq!(Frame::parse(&[0x82, 0x7E, 0x01, 0x00], 255), Err(close_code::MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        for &len in &[0, 125, 126, 65535, 65536] {
+            let mut frame = Frame::new(OpCode::Binary, vec![7; len]);
+            frame.mask = Some([1, 2, 3, 4]);
+            let mut buf = Vec::new();
+            frame.encode(&mut buf);
+            let (parsed, n) = Frame::parse(&buf, 1 << 20).unwrap().unwrap();
+            assert_eq!(n, buf.len());
+            assert_eq!(parsed, frame);
+        }
+    }
+
+    #[test]
+    fn test_encode_unmasked() {
+        let mut buf = Vec::new();
+        Frame::new(OpCode::Pong, b"Hello".to_vec()).encode(&mut buf);
+        assert_eq!(buf, [0x8a, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+    }
+}
@@ -0,0 +1,692 @@
+//! WebSocket support, as defined in RFC 6455.
+//!
+//! A WebSocket starts as an HTTP/1.1 request asking to upgrade the
+//! connection. A server `Handler` checks the request with `accept`, and
+//! answers with a `101 Switching Protocols` response. From then on, the
+//! `Decoder` and `Encoder` of the connection carry WebSocket frames, which a
+//! `WebSocket` reads and writes, telling which `Next` to return.
+//!
+//! ```no_run
+//! use hyper::{Decoder, Encoder, Next, StatusCode};
+//! use hyper::header::SecWebSocketAccept;
+//! use hyper::net::HttpStream;
+//! use hyper::server::{Handler, Request, Response};
+//! use hyper::websocket::{self, Message, WebSocket};
+//!
+//! struct Echo {
+//!     accept: Option<SecWebSocketAccept>,
+//!     ws: WebSocket,
+//! }
+//!
+//! impl Handler<HttpStream> for Echo {
+//!     fn on_request(&mut self, req: Request<HttpStream>) -> Next {
+//!         self.accept = websocket::accept(req.method(), req.headers()).ok();
+//!         Next::write()
+//!     }
+//!
+//!     fn on_request_readable(&mut self, decoder: &mut Decoder<HttpStream>) -> Next {
+//!         loop {
+//!             match self.ws.read(decoder) {
+//!                 Ok(Some(Message::Text(text))) => {
+//!                     let _ = self.ws.send(Message::Text(text));
+//!                 }
+//!                 Ok(Some(_)) => (),
+//!                 Ok(None) | Err(_) => return self.ws.next(),
+//!             }
+//!         }
+//!     }
+//!
+//!     fn on_response(&mut self, res: &mut Response) -> Next {
+//!         match self.accept.take() {
+//!             Some(accept) => {
+//!                 res.set_status(StatusCode::SwitchingProtocols);
+//!                 websocket::set_response_headers(res.headers_mut(), accept);
+//!                 Next::read()
+//!             }
+//!             None => {
+//!                 res.set_status(StatusCode::BadRequest);
+//!                 Next::end()
+//!             }
+//!         }
+//!     }
+//!
+//!     fn on_response_writable(&mut self, encoder: &mut Encoder<HttpStream>) -> Next {
+//!         match self.ws.write(encoder) {
+//!             Ok(()) => self.ws.next(),
+//!             Err(_) => Next::end(),
+//!         }
+//!     }
+//! }
+//! ```
+use std::ascii::AsciiExt;
+use std::io::{self, Read, Write};
+
+use rand;
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
+use unicase::UniCase;
+
+use header::{Headers, Connection, ConnectionOption, Upgrade, Protocol, ProtocolName,
+             SecWebSocketAccept, SecWebSocketKey, SecWebSocketVersion};
+use http::Next;
+use method::Method;
+use status::StatusCode;
+
+pub use self::frame::{Frame, OpCode, close_code};
+
+pub mod frame;
+mod sha1;
+
+/// The only version of the protocol, sent in `Sec-WebSocket-Version`.
+pub const VERSION: u8 = 13;
+
+/// Appended to a `Sec-WebSocket-Key` to compute the `Sec-WebSocket-Accept`.
+const GUID: &'static [u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Default for `WebSocket::max_message_size`.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default for `WebSocket::fragment_size`.
+const DEFAULT_FRAGMENT_SIZE: usize = 64 * 1024;
+
+/// Computes the `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &[u8]) -> String {
+    let mut data = Vec::with_capacity(key.len() + GUID.len());
+    data.extend_from_slice(key);
+    data.extend_from_slice(GUID);
+    sha1::digest(&data).to_base64(STANDARD)
+}
+
+/// Whether the headers ask to upgrade the connection to a WebSocket.
+pub fn is_upgrade(headers: &Headers) -> bool {
+    let upgrade = match headers.get::<Upgrade>() {
+        Some(&Upgrade(ref protocols)) => {
+            protocols.iter().any(|p| p.name == ProtocolName::WebSocket)
+        }
+        None => false,
+    };
+    let connection = match headers.get::<Connection>() {
+        Some(&Connection(ref options)) => options.iter().any(|opt| match *opt {
+            ConnectionOption::ConnectionHeader(ref name) => name.eq_ignore_ascii_case("upgrade"),
+            _ => false,
+        }),
+        None => false,
+    };
+    upgrade && connection
+}
+
+/// Checks the opening handshake of a request, returning the
+/// `Sec-WebSocket-Accept` to answer it with.
+///
+/// The request must be a `GET` asking to upgrade to `websocket`, with
+/// `Sec-WebSocket-Version: 13` and a `Sec-WebSocket-Key` of 16 bytes. If it
+/// is not, the server should respond with a `400 Bad Request`, including a
+/// `Sec-WebSocket-Version` header of the supported version.
+pub fn accept(method: &Method, headers: &Headers) -> ::Result<SecWebSocketAccept> {
+    if *method != Method::Get || !is_upgrade(headers) {
+        debug!("not a websocket upgrade request");
+        return Err(::Error::Header);
+    }
+    match headers.get::<SecWebSocketVersion>() {
+        Some(&SecWebSocketVersion(VERSION)) => (),
+        other => {
+            debug!("unsupported websocket version: {:?}", other);
+            return Err(::Error::Header);
+        }
+    }
+    let key = match headers.get::<SecWebSocketKey>() {
+        Some(key) => key,
+        None => {
+            debug!("websocket request without Sec-WebSocket-Key");
+            return Err(::Error::Header);
+        }
+    };
+    match key.0.from_base64() {
+        Ok(ref nonce) if nonce.len() == 16 => (),
+        _ => {
+            debug!("invalid Sec-WebSocket-Key: {:?}", key.0);
+            return Err(::Error::Header);
+        }
+    }
+    Ok(SecWebSocketAccept(accept_key(key.0.as_bytes())))
+}
+
+/// Sets the headers of a `101 Switching Protocols` response accepting
+/// a WebSocket.
+pub fn set_response_headers(headers: &mut Headers, accept: SecWebSocketAccept) {
+    headers.set(Upgrade(vec![Protocol::new(ProtocolName::WebSocket, None)]));
+    headers.set(Connection(vec![ConnectionOption::ConnectionHeader(UniCase("Upgrade".to_owned()))]));
+    headers.set(accept);
+}
+
+/// Sets the headers of a request opening a WebSocket, returning the new
+/// random key to verify the response with.
+pub fn set_request_headers(headers: &mut Headers) -> SecWebSocketKey {
+    let mut nonce = [0u8; 16];
+    for b in nonce.iter_mut() {
+        *b = rand::random();
+    }
+    let key = SecWebSocketKey(nonce.to_base64(STANDARD));
+    headers.set(Upgrade(vec![Protocol::new(ProtocolName::WebSocket, None)]));
+    headers.set(Connection(vec![ConnectionOption::ConnectionHeader(UniCase("Upgrade".to_owned()))]));
+    headers.set(SecWebSocketVersion(VERSION));
+    headers.set(key.clone());
+    key
+}
+
+/// Checks that a response accepted the WebSocket opened with `key`.
+pub fn verify_response(status: &StatusCode, headers: &Headers, key: &SecWebSocketKey) -> ::Result<()> {
+    if *status != StatusCode::SwitchingProtocols {
+        debug!("websocket not accepted, status = {}", status);
+        return Err(::Error::Status);
+    }
+    if !is_upgrade(headers) {
+        debug!("websocket response missing upgrade headers");
+        return Err(::Error::Header);
+    }
+    match headers.get::<SecWebSocketAccept>() {
+        Some(accept) if accept.0 == accept_key(key.0.as_bytes()) => Ok(()),
+        other => {
+            debug!("websocket response with wrong Sec-WebSocket-Accept: {:?}", other);
+            Err(::Error::Header)
+        }
+    }
+}
+
+/// A complete WebSocket message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// A UTF-8 text message.
+    Text(String),
+    /// A binary message.
+    Binary(Vec<u8>),
+    /// A ping. Reading one already queued the `Pong` answer.
+    Ping(Vec<u8>),
+    /// A pong.
+    Pong(Vec<u8>),
+    /// A close frame, with its status code and reason, if any. Reading one
+    /// already queued the answering close frame.
+    Close(Option<(u16, String)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Server,
+    Client,
+}
+
+/// The state of one end of a WebSocket connection.
+///
+/// Bytes are read from a `Decoder`, and written to an `Encoder`, of a
+/// connection that switched protocols. Neither ever blocks: `read` returns
+/// `Ok(None)` when no full message is available yet, and `write` keeps
+/// anything it could not write for the next time. After each event, `next`
+/// tells what the `Handler` should wait for.
+#[derive(Debug)]
+pub struct WebSocket {
+    role: Role,
+    max_message_size: usize,
+    fragment_size: usize,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    message: Option<(OpCode, Vec<u8>)>,
+    sent_close: bool,
+    received_close: bool,
+}
+
+impl WebSocket {
+    /// Creates the server end of a WebSocket.
+    pub fn server() -> WebSocket {
+        WebSocket::new(Role::Server)
+    }
+
+    /// Creates the client end of a WebSocket, which masks every frame it sends.
+    pub fn client() -> WebSocket {
+        WebSocket::new(Role::Client)
+    }
+
+    fn new(role: Role) -> WebSocket {
+        WebSocket {
+            role: role,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            message: None,
+            sent_close: false,
+            received_close: false,
+        }
+    }
+
+    /// Sets the largest message that will be received. Bigger messages fail
+    /// the connection with `close_code::MESSAGE_TOO_BIG`.
+    ///
+    /// Default is 16MB.
+    pub fn max_message_size(mut self, size: usize) -> WebSocket {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Sets the largest payload of a frame that will be sent. Bigger messages
+    /// are sent in fragments. A size of 0 sends every message in a single
+    /// frame.
+    ///
+    /// Default is 64KB.
+    pub fn fragment_size(mut self, size: usize) -> WebSocket {
+        self.fragment_size = size;
+        self
+    }
+
+    /// Reads the next complete message.
+    ///
+    /// Returns `Ok(None)` if reading would block before a message is complete.
+    /// A peer violating the protocol is an `InvalidData` error, after which
+    /// a close frame with the reason is queued. Losing the connection
+    /// without a close frame is an `UnexpectedEof` error.
+    pub fn read<R: Read>(&mut self, r: &mut R) -> io::Result<Option<Message>> {
+        loop {
+            if let Some(msg) = try!(self.parse()) {
+                return Ok(Some(msg));
+            }
+            if self.received_close {
+                return Ok(None);
+            }
+            let mut buf = [0; 4096];
+            match r.read(&mut buf) {
+                Ok(0) => {
+                    debug!("websocket closed without a close frame");
+                    self.received_close = true;
+                    self.sent_close = true;
+                    self.write_buf.clear();
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "websocket closed without a close frame"));
+                }
+                Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(None),
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Queues a message to be sent by `write`.
+    ///
+    /// Sending a `Message::Close` starts the close handshake, after which
+    /// nothing more can be sent.
+    pub fn send(&mut self, msg: Message) -> io::Result<()> {
+        if self.sent_close {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "websocket is closing"));
+        }
+        match msg {
+            Message::Text(text) => self.queue_message(OpCode::Text, text.into_bytes()),
+            Message::Binary(data) => self.queue_message(OpCode::Binary, data),
+            Message::Ping(data) => try!(self.queue_control(OpCode::Ping, data)),
+            Message::Pong(data) => try!(self.queue_control(OpCode::Pong, data)),
+            Message::Close(Some((code, reason))) => {
+                let mut payload = vec![(code >> 8) as u8, code as u8];
+                payload.extend_from_slice(reason.as_bytes());
+                try!(self.queue_control(OpCode::Close, payload));
+                self.sent_close = true;
+            }
+            Message::Close(None) => {
+                try!(self.queue_control(OpCode::Close, Vec::new()));
+                self.sent_close = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes as much of the queued frames as possible.
+    pub fn write<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match w.write(&self.write_buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "websocket write zero")),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether there are queued frames waiting for `write`.
+    pub fn wants_write(&self) -> bool {
+        !self.write_buf.is_empty()
+    }
+
+    /// Whether the close handshake finished, and the connection should be
+    /// ended.
+    pub fn is_closed(&self) -> bool {
+        self.sent_close && self.received_close && self.write_buf.is_empty()
+    }
+
+    /// The `Next` a `Handler` should return after using this WebSocket.
+    pub fn next(&self) -> Next {
+        if self.is_closed() {
+            Next::end()
+        } else if self.wants_write() {
+            Next::read_and_write()
+        } else {
+            Next::read()
+        }
+    }
+
+    fn parse(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            if self.received_close {
+                return Ok(None);
+            }
+            let (frame, len) = match Frame::parse(&self.read_buf, self.max_message_size) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => return Ok(None),
+                Err(code) => return Err(self.fail(code)),
+            };
+            self.read_buf.drain(..len);
+
+            // clients must mask every frame, and servers must not
+            if frame.mask.is_some() != (self.role == Role::Server) {
+                debug!("websocket frame masking is wrong for {:?}", self.role);
+                return Err(self.fail(close_code::PROTOCOL_ERROR));
+            }
+
+            match frame.opcode {
+                OpCode::Ping => {
+                    if !self.sent_close {
+                        self.queue(Frame::new(OpCode::Pong, frame.payload.clone()));
+                    }
+                    return Ok(Some(Message::Ping(frame.payload)));
+                }
+                OpCode::Pong => return Ok(Some(Message::Pong(frame.payload))),
+                OpCode::Close => return self.recv_close(frame.payload).map(Some),
+                OpCode::Text | OpCode::Binary => {
+                    if self.message.is_some() {
+                        debug!("websocket message started before the last one finished");
+                        return Err(self.fail(close_code::PROTOCOL_ERROR));
+                    }
+                    if frame.fin {
+                        return self.to_message(frame.opcode, frame.payload).map(Some);
+                    }
+                    self.message = Some((frame.opcode, frame.payload));
+                }
+                OpCode::Continuation => {
+                    let (opcode, mut payload) = match self.message.take() {
+                        Some(message) => message,
+                        None => {
+                            debug!("websocket continuation frame without a message");
+                            return Err(self.fail(close_code::PROTOCOL_ERROR));
+                        }
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(close_code::MESSAGE_TOO_BIG));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.to_message(opcode, payload).map(Some);
+                    }
+                    self.message = Some((opcode, payload));
+                }
+            }
+        }
+    }
+
+    fn to_message(&mut self, opcode: OpCode, payload: Vec<u8>) -> io::Result<Message> {
+        match opcode {
+            OpCode::Text => match String::from_utf8(payload) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(close_code::INVALID_DATA)),
+            },
+            _ => Ok(Message::Binary(payload)),
+        }
+    }
+
+    fn recv_close(&mut self, payload: Vec<u8>) -> io::Result<Message> {
+        let close = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(close_code::PROTOCOL_ERROR)),
+            _ => {
+                let code = (payload[0] as u16) << 8 | payload[1] as u16;
+                if !close_code::is_valid(code) {
+                    debug!("websocket close with invalid code {}", code);
+                    return Err(self.fail(close_code::PROTOCOL_ERROR));
+                }
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => Some((code, reason)),
+                    Err(_) => return Err(self.fail(close_code::INVALID_DATA)),
+                }
+            }
+        };
+        trace!("websocket received close {:?}", close);
+        self.received_close = true;
+        if !self.sent_close {
+            // echo the status code back
+            let payload = match close {
+                Some((code, _)) => vec![(code >> 8) as u8, code as u8],
+                None => Vec::new(),
+            };
+            self.sent_close = true;
+            self.queue(Frame::new(OpCode::Close, payload));
+        }
+        Ok(Message::Close(close))
+    }
+
+    /// Fails the connection, queueing a close frame with `code`.
+    fn fail(&mut self, code: u16) -> io::Error {
+        debug!("failing websocket connection with {}", code);
+        self.received_close = true;
+        self.message = None;
+        self.read_buf.clear();
+        if !self.sent_close {
+            self.sent_close = true;
+            self.queue(Frame::new(OpCode::Close, vec![(code >> 8) as u8, code as u8]));
+        }
+        io::Error::new(io::ErrorKind::InvalidData, "websocket protocol error")
+    }
+
+    fn queue_message(&mut self, opcode: OpCode, payload: Vec<u8>) {
+        if self.fragment_size == 0 || payload.len() <= self.fragment_size {
+            self.queue(Frame::new(opcode, payload));
+            return;
+        }
+        let mut opcode = opcode;
+        let mut chunks = payload.chunks(self.fragment_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let mut frame = Frame::new(opcode, chunk.to_vec());
+            frame.fin = chunks.peek().is_none();
+            self.queue(frame);
+            opcode = OpCode::Continuation;
+        }
+    }
+
+    fn queue_control(&mut self, opcode: OpCode, payload: Vec<u8>) -> io::Result<()> {
+        if payload.len() > 125 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "websocket control frame payload too long"));
+        }
+        self.queue(Frame::new(opcode, payload));
+        Ok(())
+    }
+
+    fn queue(&mut self, mut frame: Frame) {
+        if self.role == Role::Client {
+            let n: u32 = rand::random();
+            frame.mask = Some([(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+        }
+        frame.encode(&mut self.write_buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use header::{Headers, SecWebSocketKey, SecWebSocketVersion, SecWebSocketAccept};
+    use method::Method;
+    use status::StatusCode;
+    use super::{accept, accept_key, set_request_headers, set_response_headers, verify_response,
+                Frame, OpCode, Message, WebSocket, close_code};
+
+    fn masked(opcode: OpCode, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Frame::new(opcode, payload.to_vec());
+        frame.fin = fin;
+        frame.mask = Some([1, 2, 3, 4]);
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        buf
+    }
+
+    fn written(ws: &mut WebSocket) -> Vec<Frame> {
+        let mut buf = Vec::new();
+        ws.write(&mut buf).unwrap();
+        let mut frames = Vec::new();
+        let mut pos = 0;
+        while let Some((frame, n)) = Frame::parse(&buf[pos..], 1 << 20).unwrap() {
+            frames.push(frame);
+            pos += n;
+        }
+        frames
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // from RFC 6455 section 1.3
+        assert_eq!(accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut req = Headers::new();
+        let key = set_request_headers(&mut req);
+        let accepted = accept(&Method::Get, &req).unwrap();
+        assert!(accept(&Method::Post, &req).is_err());
+
+        let mut res = Headers::new();
+        set_response_headers(&mut res, accepted);
+        verify_response(&StatusCode::SwitchingProtocols, &res, &key).unwrap();
+        assert!(verify_response(&StatusCode::Ok, &res, &key).is_err());
+
+        res.set(SecWebSocketAccept("wrong".to_owned()));
+        assert!(verify_response(&StatusCode::SwitchingProtocols, &res, &key).is_err());
+    }
+
+    #[test]
+    fn test_accept_validates() {
+        let mut req = Headers::new();
+        set_request_headers(&mut req);
+        req.set(SecWebSocketVersion(8));
+        assert!(accept(&Method::Get, &req).is_err());
+
+        let mut req = Headers::new();
+        set_request_headers(&mut req);
+        req.set(SecWebSocketKey("c2hvcnQ=".to_owned()));
+        assert!(accept(&Method::Get, &req).is_err());
+
+        let mut req = Headers::new();
+        set_request_headers(&mut req);
+        req.remove::<SecWebSocketKey>();
+        assert!(accept(&Method::Get, &req).is_err());
+    }
+
+    #[test]
+    fn test_read_fragmented_with_ping() {
+        let mut raw = masked(OpCode::Text, false, b"Hel");
+        raw.extend(masked(OpCode::Ping, true, b"hi"));
+        raw.extend(masked(OpCode::Continuation, true, b"lo"));
+
+        let mut ws = WebSocket::server();
+        let mut rdr = &raw[..];
+        assert_eq!(ws.read(&mut rdr).unwrap(), Some(Message::Ping(b"hi".to_vec())));
+        assert_eq!(ws.read(&mut rdr).unwrap(), Some(Message::Text("Hello".to_owned())));
+
+        let frames = written(&mut ws);
+        assert_eq!(frames, vec![Frame::new(OpCode::Pong, b"hi".to_vec())]);
+    }
+
+    #[test]
+    fn test_read_would_block() {
+        struct Blocked;
+        impl io::Read for Blocked {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"))
+            }
+        }
+        let mut ws = WebSocket::server();
+        assert_eq!(ws.read(&mut Blocked).unwrap(), None);
+    }
+
+    #[test]
+    fn test_close_handshake() {
+        let mut payload = vec![0x03, 0xE8];
+        payload.extend_from_slice(b"bye");
+        let raw = masked(OpCode::Close, true, &payload);
+
+        let mut ws = WebSocket::server();
+        assert_eq!(ws.read(&mut &raw[..]).unwrap(),
+                   Some(Message::Close(Some((close_code::NORMAL, "bye".to_owned())))));
+        assert!(!ws.is_closed());
+        assert!(ws.send(Message::Text("late".to_owned())).is_err());
+
+        let frames = written(&mut ws);
+        assert_eq!(frames, vec![Frame::new(OpCode::Close, vec![0x03, 0xE8])]);
+        assert!(ws.is_closed());
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        // servers must only receive masked frames
+        let mut raw = Vec::new();
+        Frame::new(OpCode::Text, b"hi".to_vec()).encode(&mut raw);
+        let mut ws = WebSocket::server();
+        assert_eq!(ws.read(&mut &raw[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let frames = written(&mut ws);
+        assert_eq!(frames[0].payload, vec![0x03, 0xEA]);
+        assert!(ws.is_closed());
+
+        // invalid UTF-8
+        let raw = masked(OpCode::Text, true, &[0xff, 0xfe]);
+        let mut ws = WebSocket::server();
+        assert!(ws.read(&mut &raw[..]).is_err());
+        assert_eq!(written(&mut ws)[0].payload, vec![0x03, 0xEF]);
+
+        // continuation without a message
+        let raw = masked(OpCode::Continuation, true, b"lo");
+        let mut ws = WebSocket::server();
+        assert!(ws.read(&mut &raw[..]).is_err());
+
+        // too big
+        let raw = masked(OpCode::Binary, true, &[0; 100]);
+        let mut ws = WebSocket::server().max_message_size(10);
+        assert!(ws.read(&mut &raw[..]).is_err());
+        assert_eq!(written(&mut ws)[0].payload, vec![0x03, 0xF1]);
+    }
+
+    #[test]
+    fn test_client_sends_masked_fragments() {
+        let mut ws = WebSocket::client().fragment_size(4);
+        ws.send(Message::Binary(b"abcdefghij".to_vec())).unwrap();
+        let frames = written(&mut ws);
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|f| f.mask.is_some()));
+        assert_eq!(frames[0].opcode, OpCode::Binary);
+        assert!(!frames[0].fin);
+        assert_eq!(frames[1].opcode, OpCode::Continuation);
+        assert!(frames[2].fin);
+        let payload: Vec<u8> = frames.iter().flat_map(|f| f.payload.clone()).collect();
+        assert_eq!(payload, b"abcdefghij");
+
+        let mut ws = WebSocket::client().fragment_size(0);
+        ws.send(Message::Binary(b"abcdefghij".to_vec())).unwrap();
+        let frames = written(&mut ws);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].fin);
+        assert_eq!(frames[0].payload, b"abcdefghij");
+    }
+
+    #[test]
+    fn test_unexpected_eof() {
+        let mut ws = WebSocket::client();
+        assert_eq!(ws.read(&mut &b""[..]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(ws.is_closed());
+    }
+}
@@ -1,15 +1,20 @@
 #![deny(warnings)]
+extern crate hpack;
 extern crate hyper;
+extern crate rotor;
+extern crate vecio;
 
 use std::io::{self, Read, Write};
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::thread;
 use std::sync::mpsc;
 use std::time::Duration;
 
-use hyper::client::{Handler, Request, Response, HttpConnector};
+use hyper::client::{blocking, Handler, Request, Response, Cookies, MemoryCache, HttpConnector, HttpsConnector, SocksConnector, StaticResolver, StubResolver};
 use hyper::{Method, StatusCode, Next, Encoder, Decoder};
 use hyper::header::Headers;
-use hyper::net::HttpStream;
+use hyper::net::{HttpStream, SslClient, Transport};
+use rotor::mio::{Selector, Token, Evented, EventSet, PollOpt};
 
 fn s(bytes: &[u8]) -> &str {
     ::std::str::from_utf8(bytes.as_ref()).unwrap()
@@ -33,8 +38,11 @@ impl TestHandler {
 
 #[derive(Debug)]
 enum Msg {
+    Informational(Response),
     Head(Response),
     Chunk(Vec<u8>),
+    Trailers(Headers),
+    Upgraded(Vec<u8>),
     Error(hyper::Error),
 }
 
@@ -46,7 +54,7 @@ fn read(opts: &Opts) -> Next {
     }
 }
 
-impl Handler<HttpStream> for TestHandler {
+impl<T: Transport> Handler<T> for TestHandler {
     fn on_request(&mut self, req: &mut Request) -> Next {
         req.set_method(self.opts.method.clone());
         req.headers_mut().extend(self.opts.headers.iter());
@@ -57,7 +65,7 @@ impl Handler<HttpStream> for TestHandler {
         }
     }
 
-    fn on_request_writable(&mut self, encoder: &mut Encoder<HttpStream>) -> Next {
+    fn on_request_writable(&mut self, encoder: &mut Encoder<T>) -> Next {
         if let Some(ref mut body) = self.opts.body {
             let n = encoder.write(body).unwrap();
             *body = &body[n..];
@@ -70,6 +78,10 @@ impl Handler<HttpStream> for TestHandler {
         read(&self.opts)
     }
 
+    fn on_informational(&mut self, res: Response) {
+        self.tx.send(Msg::Informational(res)).unwrap();
+    }
+
     fn on_response(&mut self, res: Response) -> Next {
         use hyper::header;
         // server responses can include a body until eof, if not size is specified
@@ -79,6 +91,11 @@ impl Handler<HttpStream> for TestHandler {
                 has_body = false;
             }
         }
+        // switching protocols hands the transport over to on_upgrade
+        if *res.status() == StatusCode::SwitchingProtocols ||
+            (self.opts.method == Method::Connect && res.status().is_success()) {
+            has_body = false;
+        }
         self.tx.send(Msg::Head(res)).unwrap();
         if has_body {
             read(&self.opts)
@@ -87,11 +104,18 @@ impl Handler<HttpStream> for TestHandler {
         }
     }
 
-    fn on_response_readable(&mut self, decoder: &mut Decoder<HttpStream>) -> Next {
+    fn on_response_readable(&mut self, decoder: &mut Decoder<T>) -> Next {
         let mut v = vec![0; 512];
         match decoder.read(&mut v) {
             Ok(n) => {
                 v.truncate(n);
+                if n == 0 {
+                    if let Some(trailers) = decoder.trailers() {
+                        if trailers.len() > 0 {
+                            self.tx.send(Msg::Trailers(trailers.clone())).unwrap();
+                        }
+                    }
+                }
                 self.tx.send(Msg::Chunk(v)).unwrap();
                 if n == 0 {
                     Next::end()
@@ -110,6 +134,13 @@ impl Handler<HttpStream> for TestHandler {
         self.tx.send(Msg::Error(err)).unwrap();
         Next::remove()
     }
+
+    fn on_upgrade(self, upgraded: hyper::Upgraded<T>) {
+        let (mut transport, buffered) = upgraded.into_parts();
+        // speak the new protocol, to show the transport is still usable
+        transport.write_all(b"ping").unwrap();
+        self.tx.send(Msg::Upgraded(buffered)).unwrap();
+    }
 }
 
 struct Client {
@@ -186,6 +217,133 @@ fn client() -> Client {
     }
 }
 
+/// A stream that claims to have negotiated a protocol with ALPN, without
+/// any TLS.
+#[derive(Debug)]
+struct Negotiated(HttpStream, Option<&'static [u8]>);
+
+impl Read for Negotiated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Negotiated {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Evented for Negotiated {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
+
+#[cfg(not(windows))]
+impl vecio::Writev for Negotiated {
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.0.writev(bufs)
+    }
+}
+
+impl Transport for Negotiated {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.0.take_socket_error()
+    }
+
+    fn negotiated_protocol(&self) -> Option<&[u8]> {
+        self.1
+    }
+}
+
+/// Pretends to do TLS with a server that supports `server` protocols, which
+/// picks the first of those offered that it supports.
+#[derive(Debug)]
+struct FakeAlpn {
+    server: &'static [&'static [u8]],
+    offered: Vec<Vec<u8>>,
+}
+
+impl SslClient for FakeAlpn {
+    type Stream = Negotiated;
+
+    fn wrap_client(&self, stream: HttpStream, _host: &str) -> hyper::Result<Negotiated> {
+        let server = self.server;
+        let picked = self.offered.iter()
+            .filter_map(|offered| server.iter().find(|supported| &supported[..] == &offered[..]))
+            .next()
+            .map(|picked| *picked);
+        Ok(Negotiated(stream, picked))
+    }
+
+    fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) {
+        self.offered = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+    }
+}
+
+static ALPN_H2: &'static [&'static [u8]] = &[b"h2"];
+static ALPN_HTTP1: &'static [&'static [u8]] = &[b"http/1.1"];
+static ALPN_NONE: &'static [&'static [u8]] = &[];
+
+/// A client of `https` URLs over plaintext, to a server that supports the
+/// `server` protocols with ALPN.
+fn alpn_client(server: &'static [&'static [u8]]) -> Client {
+    let ssl = FakeAlpn {
+        server: server,
+        offered: Vec::new(),
+    };
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpsConnector::new(ssl))
+        .max_connections_per_host(1)
+        .build().unwrap();
+    Client {
+        client: Some(c),
+    }
+}
+
+/// Reads an HTTP/2 frame, returning its type, flags, stream id and payload.
+fn read_h2_frame<R: Read>(stream: &mut R) -> (u8, u8, u32, Vec<u8>) {
+    let mut head = [0; 9];
+    stream.read_exact(&mut head).unwrap();
+    let len = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+    let id = ((head[5] as u32) << 24 | (head[6] as u32) << 16 | (head[7] as u32) << 8 | head[8] as u32) & 0x7fff_ffff;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[3], head[4], id, payload)
+}
+
+fn h2_frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len();
+    let mut frame = vec![
+        (len >> 16) as u8, (len >> 8) as u8, len as u8,
+        kind, flags,
+        (id >> 24) as u8, (id >> 16) as u8, (id >> 8) as u8, id as u8,
+    ];
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads the client preface and answers with empty SETTINGS.
+fn h2_accept(inc: &mut ::std::net::TcpStream) {
+    let mut preface = [0; 24];
+    inc.read_exact(&mut preface).unwrap();
+    assert_eq!(&preface[..], &b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"[..]);
+    inc.write_all(&h2_frame(4, 0, 0, &[])).unwrap();
+}
+
 macro_rules! test {
     (
         name: $name:ident,
@@ -388,3 +546,1272 @@ fn client_keep_alive() {
 
     while let Ok(_) = res.recv() {}
 }
+
+/// Reads a request head, and whatever came along with it.
+fn read_head(sock: &mut ::std::net::TcpStream) -> Vec<u8> {
+    let mut req = Vec::new();
+    let mut buf = [0; 4096];
+    while !s(&req).contains("\r\n\r\n") {
+        let n = sock.read(&mut buf).expect("read head");
+        assert!(n > 0, "eof before request head");
+        req.extend_from_slice(&buf[..n]);
+    }
+    req
+}
+
+#[test]
+fn client_repeated_content_length() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let res = client.request(format!("http://{}/", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    sock.read(&mut buf).unwrap();
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5, 5\r\n\r\nhello").unwrap();
+
+    let mut body = Vec::new();
+    while let Ok(msg) = res.recv() {
+        match msg {
+            Msg::Head(head) => assert_eq!(head.status(), &StatusCode::Ok),
+            Msg::Chunk(chunk) => body.extend_from_slice(&chunk),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(body, b"hello");
+}
+
+#[test]
+fn client_expect_continue() {
+    use hyper::header::{ContentLength, Expect};
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let res = client.request(format!("http://{}/", addr), opts()
+        .method(Method::Post)
+        .header(Expect::Continue)
+        .header(ContentLength(7))
+        .body(Some(b"foo bar")));
+
+    let mut sock = server.accept().unwrap().0;
+    let req = read_head(&mut sock);
+    assert!(s(&req).ends_with("\r\n\r\n"), "body sent early: {:?}", s(&req));
+
+    // the body is held back, until the server asks for it
+    sock.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let mut buf = [0; 4096];
+    assert!(sock.read(&mut buf).is_err());
+
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    sock.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+    let mut body = [0; 7];
+    sock.read_exact(&mut body).unwrap();
+    assert_eq!(&body, b"foo bar");
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+    let (head, _) = response(res);
+    assert_eq!(head.status(), &StatusCode::Ok);
+}
+
+#[test]
+fn client_expect_continue_rejected() {
+    use hyper::header::{ContentLength, Expect};
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let res = client.request(format!("http://{}/", addr), opts()
+        .method(Method::Put)
+        .header(Expect::Continue)
+        .header(ContentLength(7))
+        .body(Some(b"foo bar")));
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    read_head(&mut sock);
+    sock.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+    let (head, _) = response(res);
+    assert_eq!(head.status(), &StatusCode::Unauthorized);
+
+    // the body is never sent, and the connection is not reused
+    let mut rest = Vec::new();
+    sock.read_to_end(&mut rest).unwrap();
+    assert_eq!(s(&rest), "");
+}
+
+#[test]
+fn client_expect_continue_timeout() {
+    use hyper::header::{ContentLength, Expect};
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let res = client.request(format!("http://{}/", addr), opts()
+        .method(Method::Post)
+        .header(Expect::Continue)
+        .header(ContentLength(7))
+        .body(Some(b"foo bar")));
+
+    // a server that ignores Expect still gets the body
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut req = read_head(&mut sock);
+    while !s(&req).ends_with("foo bar") {
+        let mut buf = [0; 4096];
+        let n = sock.read(&mut buf).expect("read body");
+        assert!(n > 0, "eof before request body");
+        req.extend_from_slice(&buf[..n]);
+    }
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+    let (head, _) = response(res);
+    assert_eq!(head.status(), &StatusCode::Ok);
+}
+
+#[test]
+fn client_informational() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let res = client.request(format!("http://{}/", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    read_head(&mut sock);
+    sock.write_all(b"\
+        HTTP/1.1 103 Early Hints\r\n\
+        Link: </style.css>; rel=preload; as=style\r\n\
+        \r\n\
+        HTTP/1.1 200 OK\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        hello\
+    ").unwrap();
+
+    match res.recv() {
+        Ok(Msg::Informational(hints)) => {
+            assert_eq!(hints.status(), &StatusCode::EarlyHints);
+            assert_eq!(hints.headers().get_raw("Link").and_then(|raw| raw.one()),
+                       Some(&b"</style.css>; rel=preload; as=style"[..]));
+        }
+        other => panic!("expected informational response, actual: {:?}", other),
+    }
+    let (head, body) = response(res);
+    assert_eq!(head.status(), &StatusCode::Ok);
+    assert_eq!(s(&body), "hello");
+}
+
+#[test]
+fn client_response_trailers() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let res = client.request(format!("http://{}/", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    read_head(&mut sock);
+    sock.write_all(b"\
+        HTTP/1.1 200 OK\r\n\
+        Transfer-Encoding: chunked\r\n\
+        Trailer: Grpc-Status, Grpc-Message\r\n\
+        \r\n\
+        5\r\n\
+        hello\r\n\
+        0\r\n\
+        Grpc-Status: 0\r\n\
+        Grpc-Message: fine\r\n\
+        \r\n\
+    ").unwrap();
+
+    let mut body = Vec::new();
+    let mut trailers = None;
+    while let Ok(msg) = res.recv() {
+        match msg {
+            Msg::Head(head) => assert_eq!(head.status(), &StatusCode::Ok),
+            Msg::Chunk(chunk) => body.extend_from_slice(&chunk),
+            Msg::Trailers(headers) => trailers = Some(headers),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(s(&body), "hello");
+    let trailers = trailers.expect("trailers");
+    assert_eq!(trailers.get_raw("Grpc-Status").and_then(|raw| raw.one()), Some(&b"0"[..]));
+    assert_eq!(trailers.get_raw("Grpc-Message").and_then(|raw| raw.one()), Some(&b"fine"[..]));
+}
+
+/// Answers one request on a kept-alive connection, and then closes it, as
+/// a server whose idle timeout ran out would.
+fn stale_keep_alive(server: &TcpListener, client: &Client) {
+    let addr = server.local_addr().unwrap();
+    let res = client.request(format!("http://{}/a", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    sock.read(&mut buf).expect("read 1");
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("write 1");
+
+    while let Ok(_) = res.recv() {}
+}
+
+#[test]
+fn client_retry_stale_keep_alive() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    stale_keep_alive(&server, &client);
+
+    let res = client.request(format!("http://{}/b", addr), opts());
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    sock.read(&mut buf).expect("read 2");
+    let second_get = b"GET /b HTTP/1.1\r\n";
+    assert_eq!(&buf[..second_get.len()], second_get);
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("write 2");
+
+    match res.recv() {
+        Ok(Msg::Head(head)) => assert_eq!(head.status(), &StatusCode::Ok),
+        other => panic!("expected head, actual: {:?}", other)
+    }
+}
+
+#[test]
+fn client_retry_skips_other_stale_keep_alive() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+
+    // two kept-alive connections, both closed by the server while idle
+    let first = client.request(format!("http://{}/a", addr), opts());
+    let second = client.request(format!("http://{}/a", addr), opts());
+    let mut buf = [0; 4096];
+    for _ in 0..2 {
+        let mut sock = server.accept().unwrap().0;
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        sock.read(&mut buf).expect("read 1");
+        sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("write 1");
+    }
+    while let Ok(_) = first.recv() {}
+    while let Ok(_) = second.recv() {}
+
+    // the retry must not be sent on the other stale connection
+    let res = client.request(format!("http://{}/b", addr), opts());
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    sock.read(&mut buf).expect("read 2");
+    let get = b"GET /b HTTP/1.1\r\n";
+    assert_eq!(&buf[..get.len()], get);
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("write 2");
+
+    match res.recv() {
+        Ok(Msg::Head(head)) => assert_eq!(head.status(), &StatusCode::Ok),
+        other => panic!("expected head, actual: {:?}", other)
+    }
+}
+
+#[test]
+fn client_no_retry_post_stale_keep_alive() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    stale_keep_alive(&server, &client);
+
+    let res = client.request(format!("http://{}/b", addr), opts()
+        .method(Method::Post)
+        .header(hyper::header::ContentLength(3))
+        .body(Some(b"foo")));
+    match res.recv() {
+        Ok(Msg::Error(hyper::Error::Incomplete)) => (),
+        Ok(Msg::Error(hyper::Error::Io(_))) => (),
+        other => panic!("expected error, actual: {:?}", other)
+    }
+}
+
+#[test]
+fn client_max_connections_per_host() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default())
+        .max_connections_per_host(1)
+        .max_queued_per_host(1)
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res1 = client.request(format!("http://{}/a", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    sock.read(&mut buf).expect("read 1");
+
+    // the second waits for the connection, and the third finds the queue full
+    let res2 = client.request(format!("http://{}/b", addr), opts());
+    let res3 = client.request(format!("http://{}/c", addr), opts());
+    match res3.recv() {
+        Ok(Msg::Error(hyper::Error::Full)) => (),
+        other => panic!("expected full, actual: {:?}", other)
+    }
+    server.set_nonblocking(true).unwrap();
+    assert_eq!(server.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("write 1");
+    while let Ok(_) = res1.recv() {}
+
+    sock.read(&mut buf).expect("read 2");
+    let second_get = b"GET /b HTTP/1.1\r\n";
+    assert_eq!(&buf[..second_get.len()], second_get);
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("write 2");
+    match res2.recv() {
+        Ok(Msg::Head(head)) => assert_eq!(head.status(), &StatusCode::Ok),
+        other => panic!("expected head, actual: {:?}", other)
+    }
+}
+
+#[test]
+fn client_cookie_store() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default())
+        .cookie_store(Cookies::new())
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request(format!("http://{}/login", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let n = sock.read(&mut buf).expect("read 1");
+    assert!(!s(&buf[..n]).contains("Cookie:"));
+    sock.write_all(b"\
+        HTTP/1.1 200 OK\r\n\
+        Set-Cookie: session=abc; Path=/; HttpOnly\r\n\
+        Set-Cookie: admin=1; Path=/admin\r\n\
+        Content-Length: 0\r\n\
+        \r\n\
+    ").expect("write 1");
+    while let Ok(_) = res.recv() {}
+
+    let res = client.request(format!("http://{}/account", addr), opts());
+    let n = sock.read(&mut buf).expect("read 2");
+    let expected = "\
+        GET /account HTTP/1.1\r\n\
+        Host: 127.0.0.1:{port}\r\n\
+        Cookie: session=abc\r\n\
+        \r\n\
+        ".replace("{port}", &addr.port().to_string());
+    assert_eq!(s(&buf[..n]), expected);
+    sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("write 2");
+    while let Ok(_) = res.recv() {}
+}
+
+fn cache_client() -> Client {
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default())
+        .cache(MemoryCache::new(16))
+        .build().unwrap();
+    Client {
+        client: Some(c),
+    }
+}
+
+/// Collects the head and body of a response.
+fn response(res: mpsc::Receiver<Msg>) -> (Response, Vec<u8>) {
+    let mut head = None;
+    let mut body = Vec::new();
+    while let Ok(msg) = res.recv() {
+        match msg {
+            Msg::Head(res) => head = Some(res),
+            Msg::Informational(..) => (),
+            Msg::Chunk(chunk) => body.extend_from_slice(&chunk),
+            Msg::Trailers(..) => (),
+            Msg::Error(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+    (head.expect("response head"), body)
+}
+
+#[test]
+fn client_cache_fresh() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = cache_client();
+    let res = client.request(format!("http://{}/a", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    sock.read(&mut buf).expect("read 1");
+    sock.write_all(b"\
+        HTTP/1.1 200 OK\r\n\
+        Cache-Control: max-age=60\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        hello\
+    ").expect("write 1");
+    let (head, body) = response(res);
+    assert_eq!(head.status(), &StatusCode::Ok);
+    assert_eq!(body, b"hello");
+
+    // the second is answered from the cache, without a request
+    let (head, body) = response(client.request(format!("http://{}/a", addr), opts()));
+    assert_eq!(head.status(), &StatusCode::Ok);
+    assert!(head.headers().get_raw("Age").is_some());
+    assert_eq!(body, b"hello");
+    sock.set_nonblocking(true).unwrap();
+    assert_eq!(sock.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    server.set_nonblocking(true).unwrap();
+    assert_eq!(server.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn client_cache_revalidate() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = cache_client();
+    let res = client.request(format!("http://{}/a", addr), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let n = sock.read(&mut buf).expect("read 1");
+    assert!(!s(&buf[..n]).contains("If-None-Match:"));
+    sock.write_all(b"\
+        HTTP/1.1 200 OK\r\n\
+        Cache-Control: no-cache\r\n\
+        ETag: \"v1\"\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        hello\
+    ").expect("write 1");
+    let (_, body) = response(res);
+    assert_eq!(body, b"hello");
+
+    let res = client.request(format!("http://{}/a", addr), opts());
+    let n = sock.read(&mut buf).expect("read 2");
+    assert!(s(&buf[..n]).contains("If-None-Match: \"v1\"\r\n"));
+    sock.write_all(b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n").expect("write 2");
+    let (head, body) = response(res);
+    assert_eq!(head.status(), &StatusCode::Ok);
+    assert_eq!(body, b"hello");
+}
+
+#[test]
+fn client_blocking_keep_alive() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut sock = server.accept().unwrap().0;
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0; 4096];
+        let n = sock.read(&mut buf).expect("read 1");
+        tx.send(s(&buf[..n]).to_owned()).unwrap();
+        sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").expect("write 1");
+
+        // the body may arrive after the head
+        let mut req = String::new();
+        while !req.ends_with("foo") {
+            let n = sock.read(&mut buf).expect("read 2");
+            assert!(n > 0);
+            req.push_str(s(&buf[..n]));
+        }
+        tx.send(req).unwrap();
+        sock.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok").expect("write 2");
+    });
+
+    let client = blocking::Client::new().unwrap();
+    let mut res = client.get(&format!("http://{}/a", addr)).send().unwrap();
+    assert_eq!(res.status(), &StatusCode::Ok);
+    let mut body = String::new();
+    res.read_to_string(&mut body).unwrap();
+    assert_eq!(body, "hello");
+    assert!(rx.recv().unwrap().starts_with("GET /a HTTP/1.1\r\n"));
+
+    // the second request reuses the connection
+    let mut res = client.request(Method::Post, &format!("http://{}/b", addr))
+        .header(hyper::header::ContentType::plaintext())
+        .body("foo")
+        .send().unwrap();
+    assert_eq!(res.status(), &StatusCode::Created);
+    let mut body = String::new();
+    res.read_to_string(&mut body).unwrap();
+    assert_eq!(body, "ok");
+    let req = rx.recv().unwrap();
+    assert!(req.starts_with("POST /b HTTP/1.1\r\n"));
+    assert!(req.contains("Content-Length: 3\r\n"));
+    assert!(req.ends_with("\r\n\r\nfoo"));
+}
+
+#[test]
+fn client_blocking_timeout() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = blocking::Client::new().unwrap()
+        .timeout(Some(Duration::from_millis(100)));
+    match client.get(&format!("http://{}/", addr)).send() {
+        Err(hyper::Error::Timeout) => (),
+        other => panic!("expected timeout, actual: {:?}", other),
+    }
+    drop(server);
+}
+
+#[test]
+fn client_static_resolver() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let resolver = StaticResolver::new().host("example.domain", addr.ip());
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default().resolver(resolver))
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request(format!("http://example.domain:{}/a", addr.port()), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    sock.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let n = sock.read(&mut buf).unwrap();
+    let expected = "GET /a HTTP/1.1\r\nHost: example.domain:";
+    assert_eq!(&s(&buf[..n])[..expected.len()], expected);
+    sock.write_all(REPLY_OK.as_ref()).unwrap();
+    if let Msg::Head(head) = res.recv().unwrap() {
+        assert_eq!(head.status(), &StatusCode::Ok);
+    } else {
+        panic!("we lost the head!");
+    }
+}
+
+#[test]
+fn client_connect_falls_back_to_next_address() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    // nothing listens on 127.0.0.2, so the first attempt is refused
+    let resolver = StaticResolver::new()
+        .host("example.domain", "127.0.0.2".parse().unwrap())
+        .host("example.domain", addr.ip());
+    let connector = HttpConnector::default()
+        .resolver(resolver)
+        .attempt_delay(Duration::from_secs(60));
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(connector)
+        .connect_timeout(Duration::from_secs(5))
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request(format!("http://example.domain:{}/a", addr.port()), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    sock.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let n = sock.read(&mut buf).unwrap();
+    assert!(s(&buf[..n]).starts_with("GET /a HTTP/1.1\r\n"), "{:?}", s(&buf[..n]));
+    sock.write_all(REPLY_OK.as_ref()).unwrap();
+    match res.recv().unwrap() {
+        Msg::Head(head) => assert_eq!(head.status(), &StatusCode::Ok),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn client_connect_fails_on_every_address() {
+    // nothing listens on a port just released
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let resolver = StaticResolver::new()
+        .host("example.domain", "127.0.0.2".parse().unwrap())
+        .host("example.domain", "127.0.0.1".parse().unwrap());
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default().resolver(resolver))
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request(format!("http://example.domain:{}/a", port), opts());
+    match res.recv() {
+        Ok(Msg::Error(hyper::Error::Io(..))) => (),
+        other => panic!("expected connection error, actual: {:?}", other),
+    }
+}
+
+/// Reply to a DNS query for an `A` record with 127.0.0.1, and to others
+/// with no records.
+fn dns_reply(query: &[u8], truncated: bool) -> Vec<u8> {
+    let mut msg = query.to_vec();
+    msg[2] = if truncated { 0x83 } else { 0x81 };
+    msg[3] = 0x80;
+    if !truncated && query[query.len() - 3] == 1 {
+        msg[7] = 1;
+        msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+    }
+    msg
+}
+
+/// A stand-in nameserver, which answers over UDP, or only says the answer
+/// is truncated and answers over TCP.
+fn nameserver(truncated: bool) -> SocketAddr {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 512];
+        while let Ok((n, from)) = udp.recv_from(&mut buf) {
+            udp.send_to(&dns_reply(&buf[..n], truncated), from).unwrap();
+        }
+    });
+    thread::spawn(move || {
+        while let Ok((mut sock, _)) = tcp.accept() {
+            let mut len = [0; 2];
+            while sock.read_exact(&mut len).is_ok() {
+                let mut query = vec![0; ((len[0] as usize) << 8) | len[1] as usize];
+                sock.read_exact(&mut query).unwrap();
+                let reply = dns_reply(&query, false);
+                sock.write_all(&[(reply.len() >> 8) as u8, reply.len() as u8]).unwrap();
+                sock.write_all(&reply).unwrap();
+            }
+        }
+    });
+    addr
+}
+
+fn client_stub_resolver(truncated: bool) {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let resolver = StubResolver::new().nameserver(nameserver(truncated));
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default().resolver(resolver))
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request(format!("http://example.domain:{}/a", addr.port()), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    sock.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let n = sock.read(&mut buf).unwrap();
+    let expected = "GET /a HTTP/1.1\r\nHost: example.domain:";
+    assert_eq!(&s(&buf[..n])[..expected.len()], expected);
+    sock.write_all(REPLY_OK.as_ref()).unwrap();
+    if let Msg::Head(head) = res.recv().unwrap() {
+        assert_eq!(head.status(), &StatusCode::Ok);
+    } else {
+        panic!("we lost the head!");
+    }
+}
+
+#[test]
+fn client_stub_resolver_udp() {
+    client_stub_resolver(false);
+}
+
+#[test]
+fn client_stub_resolver_tcp_fallback() {
+    client_stub_resolver(true);
+}
+
+#[test]
+fn client_stub_resolver_timeout() {
+    // a nameserver that never answers
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let resolver = StubResolver::new()
+        .nameserver(silent.local_addr().unwrap())
+        .timeout(Duration::from_millis(50))
+        .attempts(2);
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default().resolver(resolver))
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request("http://example.domain/a", opts());
+    match res.recv() {
+        Ok(Msg::Error(hyper::Error::Io(ref e))) if e.kind() == io::ErrorKind::TimedOut => (),
+        other => panic!("expected lookup timeout, actual: {:?}", other)
+    }
+    let mut buf = [0; 512];
+    silent.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    // both queries, sent twice
+    for _ in 0..4 {
+        silent.recv_from(&mut buf).unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn client_unix_socket() {
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use hyper::client::UnixConnector;
+
+    let path = ::std::env::temp_dir().join("hyper-test-client-unix-socket.sock");
+    let _ = fs::remove_file(&path);
+    let server = UnixListener::bind(&path).unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(UnixConnector::new())
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request(format!("unix://{}:/v1.24/containers/json?all=1", path.display()), opts());
+
+    let mut sock = server.accept().unwrap().0;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let expected = "\
+        GET /v1.24/containers/json?all=1 HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        \r\n\
+        ";
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while n < buf.len() && n < expected.len() {
+        n += sock.read(&mut buf[n..]).unwrap();
+    }
+    assert_eq!(s(&buf[..n]), expected);
+    sock.write_all(REPLY_OK.as_ref()).unwrap();
+    if let Msg::Head(head) = res.recv().unwrap() {
+        assert_eq!(head.status(), &StatusCode::Ok);
+    } else {
+        panic!("we lost the head!");
+    }
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn client_socks5h_proxy() {
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let socks = SocksConnector::new(&format!("socks5h://user:pass@{}", addr).parse().unwrap()).unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(socks)
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request("http://example.domain/foo", opts());
+
+    let mut inc = proxy.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut greeting = [0; 4];
+    inc.read_exact(&mut greeting).unwrap();
+    assert_eq!(greeting, [5, 2, 0, 2]);
+    inc.write_all(&[5, 2]).unwrap();
+    let mut auth = [0; 11];
+    inc.read_exact(&mut auth).unwrap();
+    assert_eq!(&auth, b"\x01\x04user\x04pass");
+    inc.write_all(&[1, 0]).unwrap();
+    let mut connect = [0; 21];
+    inc.read_exact(&mut connect).unwrap();
+    assert_eq!(&connect, b"\x05\x01\x00\x03\x0eexample.domain\x00\x50");
+    inc.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+
+    let expected = "\
+        GET /foo HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        \r\n\
+        ";
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while n < buf.len() && n < expected.len() {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    assert_eq!(s(&buf[..n]), expected);
+
+    inc.write_all(REPLY_OK.as_ref()).unwrap();
+    if let Msg::Head(head) = res.recv().unwrap() {
+        assert_eq!(head.status(), &StatusCode::Ok);
+    } else {
+        panic!("we lost the head!");
+    }
+}
+
+#[test]
+fn client_socks5_proxy_refused() {
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let connector = HttpConnector::default()
+        .resolver(StaticResolver::new().host("example.domain", "10.0.0.1".parse().unwrap()));
+    let socks = SocksConnector::with_connector(&format!("socks5://{}", addr).parse().unwrap(), connector).unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(socks)
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request("http://example.domain/foo", opts());
+
+    let mut inc = proxy.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut greeting = [0; 3];
+    inc.read_exact(&mut greeting).unwrap();
+    assert_eq!(greeting, [5, 1, 0]);
+    inc.write_all(&[5, 0]).unwrap();
+    // resolved before asking the proxy
+    let mut connect = [0; 10];
+    inc.read_exact(&mut connect).unwrap();
+    assert_eq!(connect, [5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+    inc.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+    match res.recv() {
+        Ok(Msg::Error(hyper::Error::Io(ref e))) if e.kind() == io::ErrorKind::ConnectionRefused => (),
+        other => panic!("expected connection refused, actual: {:?}", other)
+    }
+}
+
+#[test]
+fn client_socks5_proxy_next_address() {
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let resolver = StaticResolver::new()
+        .host("example.domain", "10.0.0.1".parse().unwrap())
+        .host("example.domain", "10.0.0.2".parse().unwrap());
+    let connector = HttpConnector::default().resolver(resolver);
+    let socks = SocksConnector::with_connector(&format!("socks5://{}", addr).parse().unwrap(), connector).unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(socks)
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request("http://example.domain/foo", opts());
+
+    // the proxy cannot reach the first address
+    let mut inc = proxy.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut greeting = [0; 3];
+    inc.read_exact(&mut greeting).unwrap();
+    inc.write_all(&[5, 0]).unwrap();
+    let mut connect = [0; 10];
+    inc.read_exact(&mut connect).unwrap();
+    assert_eq!(connect, [5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+    inc.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+    // so it is asked for the next one
+    let mut inc = proxy.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.read_exact(&mut greeting).unwrap();
+    inc.write_all(&[5, 0]).unwrap();
+    inc.read_exact(&mut connect).unwrap();
+    assert_eq!(connect, [5, 1, 0, 1, 10, 0, 0, 2, 0, 80]);
+    inc.write_all(&[5, 0, 0, 1, 10, 0, 0, 2, 0, 80]).unwrap();
+
+    let mut buf = [0; 4096];
+    let n = inc.read(&mut buf).unwrap();
+    assert!(s(&buf[..n]).starts_with("GET /foo HTTP/1.1\r\n"), "{:?}", s(&buf[..n]));
+    inc.write_all(REPLY_OK.as_ref()).unwrap();
+    match res.recv().unwrap() {
+        Msg::Head(head) => assert_eq!(head.status(), &StatusCode::Ok),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn client_http_proxy() {
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default())
+        .proxy(format!("http://user:pass@{}", addr).parse().unwrap())
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request("http://example.domain/foo?key=val", opts());
+
+    let mut inc = proxy.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let expected = "\
+        GET http://example.domain/foo?key=val HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\
+        \r\n\
+        ";
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while n < buf.len() && n < expected.len() {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    assert_eq!(s(&buf[..n]), expected);
+
+    inc.write_all(REPLY_OK.as_ref()).unwrap();
+    if let Msg::Head(head) = res.recv().unwrap() {
+        assert_eq!(head.status(), &StatusCode::Ok);
+    } else {
+        panic!("we lost the head!");
+    }
+}
+
+#[test]
+fn client_https_proxy_tunnel() {
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let ssl = FakeAlpn {
+        server: ALPN_HTTP1,
+        offered: Vec::new(),
+    };
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpsConnector::new(ssl))
+        .proxy(format!("http://user:pass@{}", addr).parse().unwrap())
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request("https://example.domain/foo", opts());
+
+    let mut inc = proxy.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while !s(&buf[..n]).ends_with("\r\n\r\n") {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    let connect = s(&buf[..n]).to_owned();
+    assert!(connect.starts_with("CONNECT example.domain:443 HTTP/1.1\r\n"), "{:?}", connect);
+    assert!(connect.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"), "{:?}", connect);
+    inc.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").unwrap();
+
+    // the request goes through the tunnel to the host, without the
+    // credentials for the proxy
+    let expected = "GET /foo HTTP/1.1\r\nHost: example.domain\r\n\r\n";
+    n = 0;
+    while n < buf.len() && n < expected.len() {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    assert_eq!(s(&buf[..n]), expected);
+    inc.write_all(REPLY_OK.as_bytes()).unwrap();
+
+    match res.recv().unwrap() {
+        Msg::Head(head) => assert_eq!(head.status(), &StatusCode::Ok),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn client_upgrade_switching_protocols() {
+    use hyper::header::Connection;
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let opts = opts()
+        .header(Connection(vec!["upgrade".parse().unwrap()]));
+    let res = client.request(format!("http://{}/", addr), opts);
+
+    let mut inc = server.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while !s(&buf[..n]).ends_with("\r\n\r\n") {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    assert!(s(&buf[..n]).starts_with("GET / HTTP/1.1\r\n"));
+    inc.write_all(b"\
+        HTTP/1.1 101 Switching Protocols\r\n\
+        Connection: upgrade\r\n\
+        Upgrade: foo\r\n\
+        \r\n\
+        hello\
+    ").unwrap();
+
+    match res.recv().unwrap() {
+        Msg::Head(head) => assert_eq!(head.status(), &StatusCode::SwitchingProtocols),
+        other => panic!("unexpected {:?}", other),
+    }
+    match res.recv().unwrap() {
+        Msg::Upgraded(buffered) => assert_eq!(s(&buffered), "hello"),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // the handler wrote to the transport it was handed
+    let mut ping = [0; 4];
+    inc.read_exact(&mut ping).unwrap();
+    assert_eq!(&ping, b"ping");
+}
+
+#[test]
+fn client_connect_tunnel() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = client();
+    let res = client.request(format!("http://{}/", addr), opts().method(Method::Connect));
+
+    let mut inc = server.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while !s(&buf[..n]).ends_with("\r\n\r\n") {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    // a tunnel is requested with the authority-form
+    let expected = format!("CONNECT {} HTTP/1.1\r\n", addr);
+    assert!(s(&buf[..n]).starts_with(&expected), "{:?}", s(&buf[..n]));
+    inc.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").unwrap();
+
+    match res.recv().unwrap() {
+        Msg::Head(head) => assert_eq!(head.status(), &StatusCode::Ok),
+        other => panic!("unexpected {:?}", other),
+    }
+    match res.recv().unwrap() {
+        Msg::Upgraded(buffered) => assert!(buffered.is_empty()),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let mut ping = [0; 4];
+    inc.read_exact(&mut ping).unwrap();
+    assert_eq!(&ping, b"ping");
+}
+
+#[test]
+fn client_redirect_see_other() {
+    use hyper::client::RedirectPolicy;
+    use hyper::header::ContentLength;
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default())
+        .redirect_policy(RedirectPolicy::limited(5))
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let opts = opts()
+        .method(Method::Post)
+        .header(ContentLength(7))
+        .body(Some(b"foo bar"));
+    let res = client.request(format!("http://{}/a", addr), opts);
+
+    let mut inc = server.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let expected = format!("POST /a HTTP/1.1\r\nHost: {}\r\nContent-Length: 7\r\n\r\nfoo bar", addr);
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while n < buf.len() && n < expected.len() {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    assert_eq!(s(&buf[..n]), expected);
+    inc.write_all(b"\
+        HTTP/1.1 303 See Other\r\n\
+        Location: /b\r\n\
+        Content-Length: 0\r\n\
+        Connection: close\r\n\
+        \r\n\
+    ").unwrap();
+    drop(inc);
+
+    let mut inc = server.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let expected = format!("GET /b HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+    let mut n = 0;
+    while n < buf.len() && n < expected.len() {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    assert_eq!(s(&buf[..n]), expected);
+    inc.write_all(REPLY_OK.as_ref()).unwrap();
+
+    if let Msg::Head(head) = res.recv().unwrap() {
+        assert_eq!(head.status(), &StatusCode::Ok);
+    } else {
+        panic!("we lost the head!");
+    }
+}
+
+#[test]
+fn client_decompress_gzip() {
+    use hyper::header::{ContentEncoding, ContentLength};
+
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let c = hyper::Client::<TestHandler>::configure()
+        .connector(HttpConnector::default())
+        .decompress(true)
+        .build().unwrap();
+    let client = Client {
+        client: Some(c),
+    };
+    let res = client.request(format!("http://{}/", addr), opts());
+
+    let mut inc = server.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    inc.set_write_timeout(Some(Duration::from_secs(5))).unwrap();
+    let expected = format!("GET / HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: gzip, deflate, br\r\n\r\n", addr);
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    while n < buf.len() && n < expected.len() {
+        n += inc.read(&mut buf[n..]).unwrap();
+    }
+    assert_eq!(s(&buf[..n]), expected);
+    inc.write_all(b"\
+        HTTP/1.1 200 OK\r\n\
+        Content-Encoding: gzip\r\n\
+        Content-Length: 44\r\n\
+        \r\n\
+        \x1f\x8b\x08\x00\x00\x00\x00\x00\x02\xff\xf3\x48\xcd\xc9\xc9\xd7\x51\x48\xce\xcf\
+        \x2d\x28\x4a\x2d\x2e\x4e\x4d\x51\x28\xcf\x2f\xca\x49\x51\x04\x00\x05\xbd\x53\x6e\
+        \x18\x00\x00\x00\
+    ").unwrap();
+
+    if let Msg::Head(head) = res.recv().unwrap() {
+        assert_eq!(head.status(), &StatusCode::Ok);
+        assert_eq!(head.headers().get::<ContentEncoding>(), None);
+        assert_eq!(head.headers().get::<ContentLength>(), None);
+    } else {
+        panic!("we lost the head!");
+    }
+    let mut body = Vec::new();
+    loop {
+        match res.recv().unwrap() {
+            Msg::Chunk(ref chunk) if chunk.is_empty() => break,
+            Msg::Chunk(chunk) => body.extend_from_slice(&chunk),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(s(&body), "Hello, compressed world!");
+}
+
+#[test]
+fn client_h2_concurrent_streams() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = alpn_client(ALPN_H2);
+    let paths = ["/a", "/b", "/c"];
+    let responses = paths.iter()
+        .map(|path| client.request(format!("https://{}{}", addr, path), opts()))
+        .collect::<Vec<_>>();
+
+    let mut inc = server.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    h2_accept(&mut inc);
+
+    // every request is a stream of the one connection
+    let mut decoder = hpack::Decoder::new();
+    let mut streams = Vec::new();
+    while streams.len() < paths.len() {
+        let (kind, flags, id, payload) = read_h2_frame(&mut inc);
+        match kind {
+            1 => {
+                let fields = decoder.decode(&payload).unwrap();
+                let path = fields.iter()
+                    .find(|&&(ref name, _)| &name[..] == b":path")
+                    .map(|&(_, ref value)| String::from_utf8(value.clone()).unwrap())
+                    .unwrap();
+                streams.push((id, path));
+            }
+            4 if flags & 1 == 0 => inc.write_all(&h2_frame(4, 1, 0, &[])).unwrap(),
+            _ => (),
+        }
+    }
+    assert_eq!(streams.iter().map(|&(id, _)| id).collect::<Vec<_>>(), vec![1, 3, 5]);
+
+    // answer the streams in reverse, interleaving their bodies
+    let mut encoder = hpack::Encoder::new();
+    for &(id, _) in streams.iter().rev() {
+        let block = encoder.encode(vec![(&b":status"[..], &b"200"[..])]);
+        inc.write_all(&h2_frame(1, 4, id, &block)).unwrap();
+    }
+    for &(id, ref path) in &streams {
+        inc.write_all(&h2_frame(0, 0, id, path.as_bytes())).unwrap();
+    }
+    for &(id, _) in streams.iter().rev() {
+        inc.write_all(&h2_frame(0, 1, id, b"!")).unwrap();
+    }
+
+    for (res, path) in responses.iter().zip(paths.iter()) {
+        let mut body = Vec::new();
+        loop {
+            match res.recv().unwrap() {
+                Msg::Head(head) => assert_eq!(head.status(), &StatusCode::Ok),
+                Msg::Chunk(ref chunk) if chunk.is_empty() => break,
+                Msg::Chunk(chunk) => body.extend_from_slice(&chunk),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(s(&body), format!("{}!", path));
+    }
+
+    server.set_nonblocking(true).unwrap();
+    assert!(server.accept().is_err(), "a second connection was opened");
+}
+
+#[test]
+fn client_alpn_h2() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = alpn_client(ALPN_H2);
+    let res = client.request(format!("https://{}/", addr), opts());
+
+    let mut inc = server.accept().unwrap().0;
+    inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    // the connection starts with the HTTP/2 preface, not an HTTP/1.1 request
+    h2_accept(&mut inc);
+    loop {
+        let (kind, _, id, _) = read_h2_frame(&mut inc);
+        if kind == 1 {
+            assert_eq!(id, 1);
+            break;
+        }
+    }
+    let mut encoder = hpack::Encoder::new();
+    let block = encoder.encode(vec![(&b":status"[..], &b"204"[..])]);
+    // HEADERS with END_HEADERS and END_STREAM
+    inc.write_all(&h2_frame(1, 5, 1, &block)).unwrap();
+
+    match res.recv().unwrap() {
+        Msg::Head(head) => {
+            assert_eq!(head.status(), &StatusCode::NoContent);
+            assert_eq!(head.version(), &hyper::HttpVersion::H2);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn client_alpn_fallback() {
+    for server_protocols in &[ALPN_HTTP1, ALPN_NONE] {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = alpn_client(*server_protocols);
+        let res = client.request(format!("https://{}/", addr), opts());
+
+        let mut inc = server.accept().unwrap().0;
+        inc.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let expected = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        let mut buf = [0; 4096];
+        let mut n = 0;
+        while n < buf.len() && n < expected.len() {
+            n += inc.read(&mut buf[n..]).unwrap();
+        }
+        assert_eq!(s(&buf[..n]), expected);
+        inc.write_all(REPLY_OK.as_bytes()).unwrap();
+
+        match res.recv().unwrap() {
+            Msg::Head(head) => {
+                assert_eq!(head.status(), &StatusCode::Ok);
+                assert_eq!(head.version(), &hyper::HttpVersion::Http11);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
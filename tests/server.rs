@@ -1,5 +1,10 @@
 #![deny(warnings)]
+extern crate flate2;
+extern crate hpack;
 extern crate hyper;
+extern crate rotor;
+extern crate unicase;
+extern crate vecio;
 
 use std::net::{TcpStream, SocketAddr};
 use std::io::{self, Read, Write};
@@ -7,8 +12,9 @@ use std::sync::mpsc;
 use std::time::Duration;
 
 use hyper::{Next, Encoder, Decoder};
-use hyper::net::{HttpListener, HttpStream};
-use hyper::server::{Server, Handler, Request, Response};
+use hyper::net::{Accept, HttpListener, HttpsListener, HttpStream, SslServer, Transport};
+use hyper::server::{Server, Handler, Request, Response, Compression};
+use rotor::mio::{Selector, Token, Evented, EventSet, PollOpt};
 
 struct Serve {
     listening: Option<hyper::server::Listening>,
@@ -92,6 +98,7 @@ enum Reply {
 enum Msg {
     //Head(Request),
     Chunk(Vec<u8>),
+    Upgraded(Vec<u8>),
 }
 
 impl TestHandler {
@@ -104,13 +111,13 @@ impl TestHandler {
     }
 }
 
-impl Handler<HttpStream> for TestHandler {
-    fn on_request(&mut self, _req: Request<HttpStream>) -> Next {
+impl<T: Transport> Handler<T> for TestHandler {
+    fn on_request(&mut self, _req: Request<T>) -> Next {
         //self.tx.send(Msg::Head(req)).unwrap();
         self.next(Next::read())
     }
 
-    fn on_request_readable(&mut self, decoder: &mut Decoder<HttpStream>) -> Next {
+    fn on_request_readable(&mut self, decoder: &mut Decoder<T>) -> Next {
         let mut vec = vec![0; 1024];
         match decoder.read(&mut vec) {
             Ok(0) => {
@@ -151,7 +158,7 @@ impl Handler<HttpStream> for TestHandler {
         }
     }
 
-    fn on_response_writable(&mut self, encoder: &mut Encoder<HttpStream>) -> Next {
+    fn on_response_writable(&mut self, encoder: &mut Encoder<T>) -> Next {
         match self.peeked {
             Some(ref body) => {
                 encoder.write(body).unwrap();
@@ -160,6 +167,13 @@ impl Handler<HttpStream> for TestHandler {
             None => self.next(Next::end())
         }
     }
+
+    fn on_upgrade(self, upgraded: hyper::Upgraded<T>) {
+        let (mut transport, buffered) = upgraded.into_parts();
+        // echo whatever already arrived for the new protocol
+        transport.write_all(&buffered).unwrap();
+        self.tx.send(Msg::Upgraded(buffered)).unwrap();
+    }
 }
 
 fn serve() -> Serve {
@@ -175,14 +189,24 @@ fn serve_n(n: u32) -> Serve {
 }
 
 fn serve_n_with_timeout(n: u32, dur: Option<Duration>) -> Serve {
+    serve_config(n, dur, |server| server)
+}
+
+fn serve_config<F>(n: u32, dur: Option<Duration>, config: F) -> Serve
+where F: FnOnce(Server<HttpListener>) -> Server<HttpListener> {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let listeners = (0..n).map(|_| HttpListener::bind(&addr).unwrap());
+    serve_server(config(Server::new(listeners)), dur)
+}
+
+fn serve_server<A>(server: Server<A>, dur: Option<Duration>) -> Serve
+where A: Accept + Send + 'static {
     use std::thread;
 
     let (msg_tx, msg_rx) = mpsc::channel();
     let (reply_tx, reply_rx) = mpsc::channel();
 
-    let addr = "127.0.0.1:0".parse().unwrap();
-    let listeners = (0..n).map(|_| HttpListener::bind(&addr).unwrap());
-    let (listening, server) = Server::new(listeners)
+    let (listening, server) = server
         .handle(move |_| {
             let mut replies = Vec::new();
             while let Ok(reply) = reply_rx.try_recv() {
@@ -254,7 +278,7 @@ fn server_get_fixed_response() {
     req.write_all(b"\
         GET / HTTP/1.1\r\n\
         Host: example.domain\r\n\
-        Connection: close\r\n
+        Connection: close\r\n\
         \r\n\
     ").unwrap();
     let mut body = String::new();
@@ -276,7 +300,7 @@ fn server_get_chunked_response() {
     req.write_all(b"\
         GET / HTTP/1.1\r\n\
         Host: example.domain\r\n\
-        Connection: close\r\n
+        Connection: close\r\n\
         \r\n\
     ").unwrap();
     let mut body = String::new();
@@ -309,6 +333,357 @@ fn server_post_with_chunked_body() {
     assert_eq!(server.body(), b"qwert");
 }
 
+#[test]
+fn server_rejects_ambiguous_framing() {
+    let smuggled: &[&[u8]] = &[
+        b"POST / HTTP/1.1\r\nHost: example.domain\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        b"POST / HTTP/1.1\r\nHost: example.domain\r\nContent-Length: 4\r\nContent-Length: 5\r\n\r\nhello",
+        b"POST / HTTP/1.1\r\nHost: example.domain\r\nTransfer-Encoding: chunked, identity\r\n\r\nhello",
+        b"POST / HTTP/1.1\nHost: example.domain\nContent-Length: 5\n\nhello",
+        b"POST / HTTP/1.1\r\nHost: example.domain\r\nContent-Length : 5\r\n\r\nhello",
+    ];
+    for raw in smuggled {
+        let server = serve();
+        let mut req = TcpStream::connect(server.addr()).unwrap();
+        req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        req.write_all(raw).unwrap();
+
+        let mut response = String::new();
+        req.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(server.body().is_empty());
+    }
+}
+
+#[test]
+fn server_rejects_unreadable_requests() {
+    let long_uri = format!("GET /{} HTTP/1.1\r\n", vec!["a"; 70_000].concat());
+    let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", vec!["X-Foo: bar\r\n"; 200].concat());
+    let unreadable = vec![
+        (long_uri.into_bytes(), "HTTP/1.1 414 URI Too Long\r\n"),
+        (many_headers.into_bytes(), "HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+        (b"GET / HTTP/2.1\r\nHost: example.domain\r\n\r\n".to_vec(), "HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+        (b"GET / HTTP/1.1\r\nHost\r\n\r\n".to_vec(), "HTTP/1.1 400 Bad Request\r\n"),
+    ];
+    for (raw, status) in unreadable {
+        let server = serve();
+        let mut req = TcpStream::connect(server.addr()).unwrap();
+        req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let _ = req.write_all(&raw);
+
+        let mut response = String::new();
+        req.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with(status), "{}", response);
+        assert!(response.contains("Connection: close\r\n"), "{}", response);
+        assert!(response.ends_with("Content-Length: 0\r\n\r\n"), "{}", response);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn server_unix_socket_rejects_unreadable_requests() {
+    use std::fs;
+    use std::os::unix::net::UnixStream;
+    use std::time::Instant;
+
+    let path = ::std::env::temp_dir().join("hyper-test-server-unix-socket-reject.sock");
+    let _ = fs::remove_file(&path);
+    let _server = serve_server(Server::unix(&path).unwrap(), None);
+
+    let mut req = UnixStream::connect(&path).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let start = Instant::now();
+    // the rest of the URI is read while the 414 is answered, so the
+    // response isn't lost to a reset
+    let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", vec!["a"; 70_000].concat());
+    req.write_all(long_uri.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 414 URI Too Long\r\n"), "{}", response);
+    // the server shut down writing, instead of waiting to time out
+    assert!(start.elapsed() < Duration::from_secs(2));
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn server_error_page() {
+    use hyper::header::ContentType;
+
+    let server = serve_config(1, None, |server| {
+        server.error_page(|err, res| {
+            res.headers_mut().set(ContentType::plaintext());
+            format!("{}", err).into_bytes()
+        })
+    });
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"GET / HTTP/1.1\r\nHost\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; charset=utf-8\r\n"), "{}", response);
+    assert!(response.contains("Content-Length: 23\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nInvalid Header provided"), "{}", response);
+}
+
+#[test]
+fn server_error_page_keeps_content_length() {
+    use hyper::header::{Encoding, TransferEncoding};
+
+    let server = serve_config(1, None, |server| {
+        server.error_page(|_, res| {
+            res.headers_mut().set(TransferEncoding(vec![Encoding::Chunked]));
+            b"oops".to_vec()
+        })
+    });
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"GET / HTTP/1.1\r\nHost\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    assert!(!response.contains("Transfer-Encoding"), "{}", response);
+    assert!(response.contains("Content-Length: 4\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\noops"), "{}", response);
+}
+
+#[test]
+fn server_expect_continue() {
+    let server = serve();
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"\
+        POST / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Expect: 100-continue\r\n\
+        Content-Length: 19\r\n\
+        \r\n\
+    ").unwrap();
+
+    let expected = "HTTP/1.1 100 Continue\r\n\r\n";
+    let mut resp = vec![0; expected.len()];
+    req.read_exact(&mut resp).unwrap();
+    assert_eq!(::std::str::from_utf8(&resp).unwrap(), expected);
+
+    req.write_all(b"I'm a good request.").unwrap();
+    req.read(&mut [0; 256]).unwrap();
+    assert_eq!(server.body(), b"I'm a good request.");
+}
+
+#[test]
+fn server_expect_continue_rejected() {
+    use std::thread;
+
+    struct Reject;
+
+    impl<T: Transport> Handler<T> for Reject {
+        fn on_request(&mut self, _req: Request<T>) -> Next {
+            Next::write()
+        }
+
+        fn on_request_readable(&mut self, _decoder: &mut Decoder<T>) -> Next {
+            panic!("rejected request body was read");
+        }
+
+        fn on_response(&mut self, res: &mut Response) -> Next {
+            res.set_status(hyper::StatusCode::Unauthorized);
+            res.headers_mut().set(hyper::header::ContentLength(0));
+            Next::end()
+        }
+
+        fn on_response_writable(&mut self, _encoder: &mut Encoder<T>) -> Next {
+            Next::end()
+        }
+    }
+
+    let (listening, server) = Server::http(&"127.0.0.1:0".parse().unwrap()).unwrap()
+        .handle(|_| Reject).unwrap();
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let mut req = TcpStream::connect(&listening.addrs()[0]).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"\
+        PUT /artifact HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Expect: 100-continue\r\n\
+        Content-Length: 1000000000\r\n\
+        \r\n\
+    ").unwrap();
+
+    // the connection is closed after the answer, since the body was never read
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+    assert!(!response.contains("100 Continue"), "{}", response);
+    listening.close();
+}
+
+#[test]
+fn server_early_hints() {
+    use std::thread;
+    use hyper::header::ContentLength;
+
+    struct Hints {
+        hinted: bool,
+    }
+
+    impl<T: Transport> Handler<T> for Hints {
+        fn on_request(&mut self, _req: Request<T>) -> Next {
+            Next::write()
+        }
+
+        fn on_request_readable(&mut self, _decoder: &mut Decoder<T>) -> Next {
+            Next::write()
+        }
+
+        fn on_response(&mut self, res: &mut Response) -> Next {
+            if !self.hinted {
+                self.hinted = true;
+                res.set_status(hyper::StatusCode::EarlyHints);
+                res.headers_mut().set_raw("Link", "</style.css>; rel=preload; as=style");
+            } else {
+                res.headers_mut().set(ContentLength(5));
+            }
+            Next::write()
+        }
+
+        fn on_response_writable(&mut self, encoder: &mut Encoder<T>) -> Next {
+            encoder.write(b"hello").unwrap();
+            Next::end()
+        }
+    }
+
+    let (listening, server) = Server::http(&"127.0.0.1:0".parse().unwrap()).unwrap()
+        .handle(|_| Hints { hinted: false }).unwrap();
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let mut req = TcpStream::connect(&listening.addrs()[0]).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Connection: close\r\n\
+        \r\n\
+    ").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    let expected_hints = "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style\r\n\r\n";
+    assert!(response.starts_with(expected_hints), "{}", response);
+    let response = &response[expected_hints.len()..];
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+    // an HTTP/1.0 client does not get informational heads
+    let mut req = TcpStream::connect(&listening.addrs()[0]).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.0\r\n\
+        Host: example.domain\r\n\
+        \r\n\
+    ").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(!response.contains("103 Early Hints"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+    listening.close();
+}
+
+#[test]
+fn server_chunked_trailers() {
+    use std::thread;
+    use hyper::header::{Headers, Te, Trailer, TransferEncoding};
+    use unicase::UniCase;
+
+    struct Trailers {
+        tx: mpsc::Sender<(bool, Option<Vec<u8>>)>,
+        accepts_trailers: bool,
+    }
+
+    impl<T: Transport> Handler<T> for Trailers {
+        fn on_request(&mut self, req: Request<T>) -> Next {
+            self.accepts_trailers = req.headers().get::<Te>().map_or(false, |te| te.accepts_trailers());
+            Next::read()
+        }
+
+        fn on_request_readable(&mut self, decoder: &mut Decoder<T>) -> Next {
+            let mut buf = [0; 64];
+            match decoder.read(&mut buf) {
+                Ok(0) => {
+                    let digest = decoder.trailers()
+                        .and_then(|trailers| trailers.get_raw("Digest"))
+                        .and_then(|raw| raw.one())
+                        .map(|value| value.to_vec());
+                    self.tx.send((self.accepts_trailers, digest)).unwrap();
+                    Next::write()
+                }
+                Ok(_) => Next::read(),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => Next::read(),
+                    _ => panic!("test error: {}", e)
+                }
+            }
+        }
+
+        fn on_response(&mut self, res: &mut Response) -> Next {
+            res.headers_mut().set(TransferEncoding::chunked());
+            res.headers_mut().set(Trailer(vec![UniCase("Grpc-Status".to_owned())]));
+            Next::write()
+        }
+
+        fn on_response_writable(&mut self, encoder: &mut Encoder<T>) -> Next {
+            let mut trailers = Headers::new();
+            trailers.set_raw("Grpc-Status", "0");
+            assert!(encoder.set_trailers(trailers));
+            encoder.write(b"ok").unwrap();
+            encoder.close();
+            Next::end()
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let (listening, server) = Server::http(&"127.0.0.1:0".parse().unwrap()).unwrap()
+        .handle(move |_| Trailers { tx: tx.clone(), accepts_trailers: false }).unwrap();
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let mut req = TcpStream::connect(&listening.addrs()[0]).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"\
+        POST / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        TE: trailers\r\n\
+        Transfer-Encoding: chunked\r\n\
+        Trailer: Digest\r\n\
+        Connection: close\r\n\
+        \r\n\
+        3\r\n\
+        foo\r\n\
+        0\r\n\
+        Digest: sha-256=abc\r\n\
+        \r\n\
+    ").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Trailer: Grpc-Status\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n2\r\nok\r\n0\r\nGrpc-Status: 0\r\n\r\n"), "{}", response);
+    assert_eq!(rx.recv().unwrap(), (true, Some(b"sha-256=abc".to_vec())));
+    listening.close();
+}
+
 /*
 #[test]
 fn server_empty_response() {
@@ -479,3 +854,615 @@ fn server_get_with_body_three_listeners() {
         assert_eq!(server.body(), comparison);
     }
 }
+
+fn serve_h2() -> Serve {
+    serve_config(1, None, |server| server.http2(true))
+}
+
+/// Reads an HTTP/2 frame, returning its type, flags, stream id and payload.
+fn read_h2_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+    let mut head = [0; 9];
+    stream.read_exact(&mut head).unwrap();
+    let len = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+    let id = ((head[5] as u32) << 24 | (head[6] as u32) << 16 | (head[7] as u32) << 8 | head[8] as u32) & 0x7fff_ffff;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[3], head[4], id, payload)
+}
+
+fn h2_frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len();
+    let mut frame = vec![
+        (len >> 16) as u8, (len >> 8) as u8, len as u8,
+        kind, flags,
+        (id >> 24) as u8, (id >> 16) as u8, (id >> 8) as u8, id as u8,
+    ];
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads the response on stream 1, acknowledging the server's SETTINGS.
+fn h2_response(req: &mut TcpStream) -> (Vec<(Vec<u8>, Vec<u8>)>, Vec<u8>) {
+    let mut decoder = hpack::Decoder::new();
+    let mut fields = Vec::new();
+    let mut body = Vec::new();
+    loop {
+        let (kind, flags, id, payload) = read_h2_frame(req);
+        match kind {
+            // HEADERS
+            1 => {
+                assert_eq!(id, 1);
+                fields = decoder.decode(&payload).unwrap();
+            }
+            // DATA
+            0 => {
+                assert_eq!(id, 1);
+                body.extend_from_slice(&payload);
+            }
+            4 if flags & 1 == 0 => req.write_all(&h2_frame(4, 1, 0, &[])).unwrap(),
+            _ => (),
+        }
+        if (kind == 0 || kind == 1) && flags & 1 != 0 {
+            return (fields, body);
+        }
+    }
+}
+
+fn status(fields: &[(Vec<u8>, Vec<u8>)]) -> &str {
+    fields.iter()
+        .find(|&&(ref name, _)| &name[..] == b":status")
+        .map(|&(_, ref value)| ::std::str::from_utf8(value).unwrap())
+        .unwrap()
+}
+
+#[test]
+fn server_h2c_upgrade() {
+    let server = serve_h2();
+    server.reply().body("upgraded");
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Connection: Upgrade, HTTP2-Settings\r\n\
+        Upgrade: h2c\r\n\
+        HTTP2-Settings: AAMAAABkAAQAAP__\r\n\
+        \r\n\
+    ").unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        req.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+
+    // the request is answered on stream 1, once the preface is sent
+    req.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+    req.write_all(&h2_frame(4, 0, 0, &[])).unwrap();
+    let (fields, body) = h2_response(&mut req);
+    assert_eq!(status(&fields), "200");
+    assert_eq!(body, b"upgraded");
+}
+
+#[test]
+fn server_h2c_prior_knowledge() {
+    let server = serve_h2();
+    server.reply().body("world");
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+    req.write_all(&h2_frame(4, 0, 0, &[])).unwrap();
+    // the server's SETTINGS must be the first frame
+    let (kind, flags, _, _) = read_h2_frame(&mut req);
+    assert_eq!((kind, flags), (4, 0));
+
+    let mut encoder = hpack::Encoder::new();
+    let block = encoder.encode(vec![
+        (&b":method"[..], &b"POST"[..]),
+        (&b":scheme"[..], &b"http"[..]),
+        (&b":path"[..], &b"/"[..]),
+        (&b":authority"[..], &b"example.domain"[..]),
+    ]);
+    // HEADERS with END_HEADERS, then DATA with END_STREAM
+    req.write_all(&h2_frame(1, 4, 1, &block)).unwrap();
+    req.write_all(&h2_frame(0, 1, 1, b"hello")).unwrap();
+
+    let (fields, body) = h2_response(&mut req);
+    assert_eq!(status(&fields), "200");
+    assert_eq!(body, b"world");
+    assert_eq!(server.body(), b"hello");
+}
+
+#[test]
+fn server_h2c_disabled_by_default() {
+    let server = serve();
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"), "{}", response);
+}
+
+/// A stream that claims to have negotiated a protocol with ALPN, without
+/// any TLS.
+#[derive(Debug)]
+struct Negotiated(HttpStream, Option<&'static [u8]>);
+
+impl Read for Negotiated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Negotiated {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Evented for Negotiated {
+    fn register(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self, selector: &mut Selector, token: Token, interest: EventSet, opts: PollOpt) -> io::Result<()> {
+        self.0.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.0.deregister(selector)
+    }
+}
+
+#[cfg(not(windows))]
+impl vecio::Writev for Negotiated {
+    fn writev(&mut self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.0.writev(bufs)
+    }
+}
+
+impl Transport for Negotiated {
+    fn take_socket_error(&mut self) -> io::Result<()> {
+        self.0.take_socket_error()
+    }
+
+    fn negotiated_protocol(&self) -> Option<&[u8]> {
+        self.1
+    }
+}
+
+/// Pretends to do TLS with clients that support `client` protocols, picking
+/// the first of those accepted that the client supports.
+#[derive(Debug)]
+struct FakeAlpn {
+    client: &'static [&'static [u8]],
+    accepted: Vec<Vec<u8>>,
+}
+
+impl SslServer for FakeAlpn {
+    type Stream = Negotiated;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<Negotiated> {
+        let client = self.client;
+        let picked = self.accepted.iter()
+            .filter_map(|accepted| client.iter().find(|supported| &supported[..] == &accepted[..]))
+            .next()
+            .map(|picked| *picked);
+        Ok(Negotiated(stream, picked))
+    }
+
+    fn set_alpn_protocols(&mut self, protocols: &[&[u8]]) {
+        self.accepted = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+    }
+}
+
+static ALPN_H2: &'static [&'static [u8]] = &[b"h2", b"http/1.1"];
+static ALPN_HTTP1: &'static [&'static [u8]] = &[b"http/1.1"];
+static ALPN_NONE: &'static [&'static [u8]] = &[];
+
+/// A server over plaintext for clients that support the `client` protocols
+/// with ALPN.
+fn serve_alpn(client: &'static [&'static [u8]]) -> Serve {
+    let ssl = FakeAlpn {
+        client: client,
+        accepted: Vec::new(),
+    };
+    let listener = HttpsListener::new(&"127.0.0.1:0".parse().unwrap(), ssl).unwrap();
+    serve_server(Server::new(Some(listener)), None)
+}
+
+#[test]
+fn server_alpn_h2() {
+    // served as HTTP/2 even though h2c is off
+    let server = serve_alpn(ALPN_H2);
+    server.reply().body("negotiated");
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+    req.write_all(&h2_frame(4, 0, 0, &[])).unwrap();
+
+    let mut encoder = hpack::Encoder::new();
+    let block = encoder.encode(vec![
+        (&b":method"[..], &b"GET"[..]),
+        (&b":scheme"[..], &b"https"[..]),
+        (&b":path"[..], &b"/"[..]),
+        (&b":authority"[..], &b"example.domain"[..]),
+    ]);
+    // HEADERS with END_HEADERS and END_STREAM
+    req.write_all(&h2_frame(1, 5, 1, &block)).unwrap();
+
+    let (fields, body) = h2_response(&mut req);
+    assert_eq!(status(&fields), "200");
+    assert_eq!(body, b"negotiated");
+}
+
+#[test]
+fn server_alpn_fallback() {
+    for client in &[ALPN_HTTP1, ALPN_NONE] {
+        let server = serve_alpn(*client);
+        let mut req = TcpStream::connect(server.addr()).unwrap();
+        req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        req.write_all(b"\
+            GET / HTTP/1.1\r\n\
+            Host: example.domain\r\n\
+            Connection: close\r\n\
+            \r\n\
+        ").unwrap();
+
+        let mut response = String::new();
+        req.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+}
+
+#[test]
+fn server_h2_window_update_zero() {
+    let server = serve_h2();
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+    req.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+    // a WINDOW_UPDATE of the connection, with an increment of 0
+    req.write_all(&[0, 0, 4, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    loop {
+        let (kind, _, id, payload) = read_h2_frame(&mut req);
+        if kind == 7 {
+            assert_eq!(id, 0);
+            // PROTOCOL_ERROR
+            assert_eq!(&payload[4..8], &[0, 0, 0, 1]);
+            break;
+        }
+    }
+}
+
+#[test]
+fn server_upgrade_writes_raw_bytes() {
+    let server = serve();
+    server.reply()
+        .status(hyper::status::StatusCode::SwitchingProtocols)
+        .header(hyper::header::Connection(vec!["upgrade".parse().unwrap()]))
+        .body("raw bytes");
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: foo\r\n\
+        \r\n\
+    ").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+
+    let mut lines = response.lines();
+    assert_eq!(lines.next(), Some("HTTP/1.1 101 Switching Protocols"));
+    assert!(!response.contains("Transfer-Encoding"));
+
+    let n = response.find("\r\n\r\n").unwrap() + 4;
+    assert_eq!(&response[n..], "raw bytes");
+}
+
+#[test]
+fn server_upgrade_hands_over_buffered_bytes() {
+    let server = serve();
+    server.reply()
+        .status(hyper::status::StatusCode::SwitchingProtocols)
+        .header(hyper::header::Connection(vec!["upgrade".parse().unwrap()]));
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: foo\r\n\
+        \r\n\
+        ping\
+    ").unwrap();
+
+    let mut response = String::new();
+    req.read_to_string(&mut response).unwrap();
+
+    let mut lines = response.lines();
+    assert_eq!(lines.next(), Some("HTTP/1.1 101 Switching Protocols"));
+
+    // the handler echoed the bytes it was handed over
+    let n = response.find("\r\n\r\n").unwrap() + 4;
+    assert_eq!(&response[n..], "ping");
+    loop {
+        match server.msg_rx.try_recv() {
+            Ok(Msg::Upgraded(buffered)) => {
+                assert_eq!(buffered, b"ping");
+                break;
+            },
+            Ok(Msg::Chunk(chunk)) => panic!("unexpected body chunk: {:?}", chunk),
+            Err(e) => panic!("on_upgrade was not called: {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn server_websocket_echo() {
+    use std::thread;
+    use hyper::header::SecWebSocketAccept;
+    use hyper::websocket::{self, Message, WebSocket};
+
+    struct Echo {
+        accept: Option<SecWebSocketAccept>,
+        ws: WebSocket,
+    }
+
+    impl<T: Transport> Handler<T> for Echo {
+        fn on_request(&mut self, req: Request<T>) -> Next {
+            self.accept = Some(websocket::accept(req.method(), req.headers()).unwrap());
+            Next::write()
+        }
+
+        fn on_request_readable(&mut self, decoder: &mut Decoder<T>) -> Next {
+            loop {
+                match self.ws.read(decoder) {
+                    Ok(Some(Message::Text(text))) => {
+                        self.ws.send(Message::Text(text)).unwrap();
+                    }
+                    Ok(Some(_)) => (),
+                    Ok(None) => return self.ws.next(),
+                    Err(e) => panic!("websocket read error: {}", e),
+                }
+            }
+        }
+
+        fn on_response(&mut self, res: &mut Response) -> Next {
+            res.set_status(hyper::StatusCode::SwitchingProtocols);
+            websocket::set_response_headers(res.headers_mut(), self.accept.take().unwrap());
+            Next::read()
+        }
+
+        fn on_response_writable(&mut self, encoder: &mut Encoder<T>) -> Next {
+            self.ws.write(encoder).unwrap();
+            self.ws.next()
+        }
+    }
+
+    let (listening, server) = Server::http(&"127.0.0.1:0".parse().unwrap()).unwrap()
+        .handle(|_| Echo { accept: None, ws: WebSocket::server() }).unwrap();
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let mut req = TcpStream::connect(&listening.addrs()[0]).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    // the handshake example from RFC 6455, section 1.3
+    req.write_all(b"\
+        GET /chat HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        \r\n\
+    ").unwrap();
+
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        req.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kZGzzhZRbK+xOo=\r\n"), "{}", head);
+
+    // a masked "Hello" text frame, from RFC 6455, section 5.7
+    req.write_all(&[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]).unwrap();
+    let mut frame = [0; 7];
+    req.read_exact(&mut frame).unwrap();
+    assert_eq!(&frame, b"\x81\x05Hello");
+
+    // a masked, empty close frame is answered, and the connection ends
+    req.write_all(&[0x88, 0x80, 0x37, 0xfa, 0x21, 0x3d]).unwrap();
+    let mut rest = Vec::new();
+    req.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"\x88\x00");
+    listening.close();
+}
+
+#[test]
+fn server_compressed_response() {
+    use flate2::read::GzDecoder;
+    use hyper::header::{ContentType, ETag, EntityTag};
+
+    let server = serve_config(1, None, |server| server.compression(Compression::new().min_size(0)));
+    server.reply()
+        .status(hyper::Ok)
+        .header(ContentType::html())
+        .header(ETag(EntityTag::strong("abc".to_owned())))
+        .body("foo bar baz");
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Accept-Encoding: br;q=1, gzip;q=0.8\r\n\
+        Connection: close\r\n\
+        \r\n\
+    ").unwrap();
+    let mut res = Vec::new();
+    req.read_to_end(&mut res).unwrap();
+    let n = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8(res[..n].to_vec()).unwrap();
+    assert!(head.contains("Content-Encoding: gzip\r\n"), "{:?}", head);
+    assert!(head.contains("Vary: Accept-Encoding\r\n"), "{:?}", head);
+    assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{:?}", head);
+    assert!(head.contains("ETag: W/\"abc\"\r\n"), "{:?}", head);
+
+    // undo the chunked framing, then the gzip
+    let mut rest = &res[n..];
+    let mut gz = Vec::new();
+    loop {
+        let line = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = usize::from_str_radix(::std::str::from_utf8(&rest[..line]).unwrap(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        gz.extend_from_slice(&rest[line + 2..line + 2 + size]);
+        rest = &rest[line + 2 + size + 2..];
+    }
+    let mut body = String::new();
+    GzDecoder::new(&gz[..]).unwrap().read_to_string(&mut body).unwrap();
+    assert_eq!(body, "foo bar baz");
+}
+
+#[test]
+fn server_compression_not_accepted() {
+    use hyper::header::ContentType;
+
+    let server = serve_config(1, None, |server| server.compression(Compression::new().min_size(0)));
+    server.reply()
+        .status(hyper::Ok)
+        .header(ContentType::html())
+        .header(hyper::header::ContentLength(11))
+        .body("foo bar baz");
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Connection: close\r\n\
+        \r\n\
+    ").unwrap();
+    let mut res = String::new();
+    req.read_to_string(&mut res).unwrap();
+    let n = res.find("\r\n\r\n").unwrap() + 4;
+    assert!(!res[..n].contains("Content-Encoding"), "{:?}", res);
+    assert!(res[..n].contains("Vary: Accept-Encoding\r\n"), "{:?}", res);
+    assert_eq!(&res[n..], "foo bar baz");
+}
+
+#[test]
+fn server_compression_skips_http10() {
+    use hyper::header::ContentType;
+
+    let server = serve_config(1, None, |server| server.compression(Compression::new().min_size(0)));
+    server.reply()
+        .status(hyper::Ok)
+        .header(ContentType::html())
+        .header(hyper::header::ContentLength(11))
+        .body("foo bar baz");
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.0\r\n\
+        Host: example.domain\r\n\
+        Accept-Encoding: gzip\r\n\
+        \r\n\
+    ").unwrap();
+    let mut res = String::new();
+    req.read_to_string(&mut res).unwrap();
+    let n = res.find("\r\n\r\n").unwrap() + 4;
+    assert!(res[..n].contains("Content-Length: 11\r\n"), "{:?}", res);
+    assert!(!res[..n].contains("Content-Encoding"), "{:?}", res);
+    assert!(!res[..n].contains("Transfer-Encoding"), "{:?}", res);
+    assert_eq!(&res[n..], "foo bar baz");
+}
+
+#[test]
+fn server_compression_keeps_head_content_length() {
+    use hyper::header::ContentType;
+
+    let server = serve_config(1, None, |server| server.compression(Compression::new().min_size(0)));
+    server.reply()
+        .status(hyper::Ok)
+        .header(ContentType::html())
+        .header(hyper::header::ContentLength(11));
+    let mut req = TcpStream::connect(server.addr()).unwrap();
+    req.write_all(b"\
+        HEAD / HTTP/1.1\r\n\
+        Host: example.domain\r\n\
+        Accept-Encoding: gzip\r\n\
+        Connection: close\r\n\
+        \r\n\
+    ").unwrap();
+    let mut res = String::new();
+    req.read_to_string(&mut res).unwrap();
+    let n = res.find("\r\n\r\n").unwrap() + 4;
+    assert!(res[..n].contains("Content-Length: 11\r\n"), "{:?}", res);
+    assert!(!res[..n].contains("Content-Encoding"), "{:?}", res);
+    assert!(!res[..n].contains("Transfer-Encoding"), "{:?}", res);
+    assert_eq!(&res[n..], "");
+}
+
+#[cfg(unix)]
+#[test]
+fn server_unix_socket() {
+    use std::fs;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    let path = ::std::env::temp_dir().join("hyper-test-server-unix-socket.sock");
+    let _ = fs::remove_file(&path);
+    let (msg_tx, msg_rx) = mpsc::channel();
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let (listening, server) = Server::unix(&path).unwrap()
+        .handle(move |_| {
+            let mut replies = Vec::new();
+            while let Ok(reply) = reply_rx.try_recv() {
+                replies.push(reply);
+            }
+            TestHandler {
+                tx: msg_tx.clone(),
+                timeout: None,
+                reply: replies,
+                peeked: None,
+            }
+        }).unwrap();
+    assert!(listening.addrs().is_empty());
+    thread::spawn(move || {
+        server.run();
+    });
+    let server = Serve {
+        listening: Some(listening),
+        msg_rx: msg_rx,
+        reply_tx: reply_tx,
+    };
+    server.reply()
+        .status(hyper::StatusCode::Ok)
+        .header(hyper::header::ContentLength(18))
+        .body("over a unix socket");
+
+    let mut req = UnixStream::connect(&path).unwrap();
+    req.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    req.write_all(b"\
+        GET / HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Connection: close\r\n\
+        \r\n\
+    ").unwrap();
+    let mut body = String::new();
+    req.read_to_string(&mut body).unwrap();
+    assert!(body.starts_with("HTTP/1.1 200 OK\r\n"), "{}", body);
+    assert!(body.ends_with("over a unix socket"), "{}", body);
+    let _ = fs::remove_file(&path);
+}